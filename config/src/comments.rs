@@ -46,12 +46,56 @@ directory_servers = [\n\
 \t\"199.58.81.140\",\n\
 ]\n\
 \n\
-# Two weeks\n\
-ds_refresh_timeout = 1209600000\n\
+# Directory addresses (ip:port) tried after the directory servers above.\n\
+# If not set, a built in list of Tor's fallback directory mirrors is used.\n\
+# fallback_dirs = [\"185.225.17.3:80\"]\n\
 \n\
+# Identity fingerprints of the trusted directory authorities. A consensus\n\
+# is only accepted if more than half of them signed it.\n\
+authorities = [\n\
+\t\"D586D18309DED4CD6D57C18FDB97EFA96D330566\",\n\
+\t\"14C131DFC5C6F93646BE72FA1401C02A8DF2E8B4\",\n\
+\t\"E8A9C45EDE6D711294FADF8E7951F4DE6CA56B58\",\n\
+\t\"ED03BB616EB2F60BEC80151114BB25CEF515B226\",\n\
+\t\"0232AF901C31A04EE9848595AF9BB7620D4C5B2E\",\n\
+\t\"49015F787433103580E3B66A1707A00E60F2D15B\",\n\
+\t\"EFCBE720AB3A82B99F9E953CD5BF50F7EEFC7B97\",\n\
+\t\"23D15D965BC35114467363C165C4F724B64B4F66\",\n\
+\t\"27102BC123E7AF1D4741AE047E160C91ADC76B21\",\n\
+]\n\
+\n\
+# Relays never to use, and the only relays to use as the first and the\n\
+# last hop of circuits. Entries are relay fingerprints or address ranges\n\
+# in CIDR form. Empty lists mean no restriction.\n\
+# exclude_nodes = [\"0011BD2485AD45D984EC4159C88FC066E5E3300E\", \"10.0.0.0/8\"]\n\
+# entry_nodes = []\n\
+# exit_nodes = []\n\
+\n\
+# How long past its valid-until time a consensus may still be used if we\n\
+# can't get a newer one. One day\n\
+ds_validity_tolerance = 86400000\n\
+\n\
+# How often to check whether the directory information needs refreshing.\n\
 # Ten minutes\n\
 ds_refresh_frequency = 600000\n\
 \n\
+# How long to keep trying directory sources before giving up. Five minutes\n\
+bootstrap_timeout = 300000\n\
+\n\
+# How many unused exit circuits to keep ready\n\
+circuit_pool_size = 3\n\
+\n\
+# How long a circuit is used for new connections after its first one.\n\
+# Ten minutes\n\
+max_circuit_dirtiness = 600000\n\
+\n\
+# Local port to accept SOCKS4, SOCKS4a and SOCKS5 connections on, or 0 for\n\
+# none\n\
+socks_port = 9250\n\
+\n\
+# Local port to accept HTTP CONNECT requests on, or 0 for none\n\
+http_port = 0\n\
+\n\
 [logging]\n\
 \n\
 ##############################################################################\n\
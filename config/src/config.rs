@@ -28,6 +28,45 @@ use toml::Value::Table;
 const TOR_HOME: &str = ".tor2";
 /// The default name for the Tor toml config file
 const TOML_NAME: &str = "tor.toml";
+/// The v3 identity fingerprints of the directory authorities. A consensus
+/// must be signed by more than half of these to be accepted.
+pub const DEFAULT_AUTHORITIES: &[&str] = &[
+	"D586D18309DED4CD6D57C18FDB97EFA96D330566", // moria1
+	"14C131DFC5C6F93646BE72FA1401C02A8DF2E8B4", // tor26
+	"E8A9C45EDE6D711294FADF8E7951F4DE6CA56B58", // dizum
+	"ED03BB616EB2F60BEC80151114BB25CEF515B226", // gabelmoo
+	"0232AF901C31A04EE9848595AF9BB7620D4C5B2E", // dannenberg
+	"49015F787433103580E3B66A1707A00E60F2D15B", // maatuska
+	"EFCBE720AB3A82B99F9E953CD5BF50F7EEFC7B97", // Faravahar
+	"23D15D965BC35114467363C165C4F724B64B4F66", // longclaw
+	"27102BC123E7AF1D4741AE047E160C91ADC76B21", // bastet
+];
+
+/// Directory addresses tried after the configured directory servers: a
+/// selection of the fallback directory mirrors from Tor's
+/// fallback_dirs.inc, as address:dirport. These are long lived relays
+/// that mirror the consensus, so bootstrapping doesn't depend on the
+/// authorities alone. Like Tor's list it needs refreshing now and then.
+pub const DEFAULT_FALLBACK_DIRS: &[&str] = &[
+	"185.225.17.3:80",    // Nebuchadnezzar
+	"81.7.10.193:9002",   // Ichotolot61
+	"163.172.149.155:80", // niij02
+	"5.200.21.144:80",    // libel
+	"81.7.18.7:9030",     // Freebird32
+	"193.234.15.60:80",   // jaures3
+	"51.15.78.0:9030",    // rep
+	"204.11.50.131:9030", // BoingBoing
+	"46.165.230.5:80",    // Dhalgren
+	"193.11.114.43:9030", // mdfnet1
+	"193.11.114.45:9031", // mdfnet2
+	"5.9.158.75:80",      // zwiebeltoralf2
+	"95.216.211.81:80",   // BurningMan
+	"192.87.28.82:9030",  // Smeerboel
+	"31.185.104.19:80",   // Digitalcourage3ip1
+	"31.185.104.20:80",   // Digitalcourage3ip2
+	"31.185.104.21:80",   // Digitalcourage3ip3
+	"176.10.104.240:80",  // DigiGesTor1e1
+];
 
 /// This is the main configuration file for tor
 #[derive(Debug)]
@@ -38,13 +77,36 @@ pub struct TorConfig {
 	pub version: String,
 	/// Directory Servers
 	pub directory_servers: Vec<String>,
+	/// Fallback directory addresses tried after the directory servers
+	pub fallback_dirs: Vec<String>,
+	/// Identity fingerprints of the trusted directory authorities
+	pub authorities: Vec<String>,
+	/// Relays never to use, by fingerprint or CIDR
+	pub exclude_nodes: Vec<String>,
+	/// If not empty, the only relays to use as the first hop
+	pub entry_nodes: Vec<String>,
+	/// If not empty, the only relays to use as the last hop
+	pub exit_nodes: Vec<String>,
 	/// DB Root
 	pub db_root: String,
-	/// Maximum time before refreshing of DS info
-	/// must occur on startup
-	pub ds_refresh_timeout: u64,
-	/// The frequency to refresh the DS info in milliseconds
+	/// How long past its valid-until time a consensus may still be
+	/// used, in milliseconds
+	pub ds_validity_tolerance: u64,
+	/// How often to check whether the DS info needs refreshing,
+	/// in milliseconds
 	pub ds_refresh_frequency: u64,
+	/// How long to keep trying directory sources before giving up,
+	/// in milliseconds
+	pub bootstrap_timeout: u64,
+	/// Number of clean exit circuits to keep ready
+	pub circuit_pool_size: u64,
+	/// How long a circuit is given out for new streams after its first
+	/// use, in milliseconds
+	pub max_circuit_dirtiness: u64,
+	/// Local port to accept SOCKS connections on, 0 for none
+	pub socks_port: u16,
+	/// Local port to accept HTTP CONNECT requests on, 0 for none
+	pub http_port: u16,
 	/// Location of the mainlog file
 	pub mainlog: String,
 	/// Size at which a log rotation occurs for the mainlog
@@ -54,6 +116,10 @@ pub struct TorConfig {
 	pub mainlog_rotationtime: u64,
 	/// Debug
 	pub debug: bool,
+	/// Print what the directory store holds and exit
+	pub inspect_store: bool,
+	/// Delete everything in the directory store and exit
+	pub clear_store: bool,
 }
 
 // include build information
@@ -129,12 +195,30 @@ pub fn get_config() -> Result<TorConfig, Error> {
 	};
 
 	let debug = args.is_present("debug");
+	let inspect_store = args.is_present("inspect_store");
+	let clear_store = args.is_present("clear_store");
 
 	let directory_servers = vec![];
-	// two weeks
-	let ds_refresh_timeout = 14 * 24 * 60 * 60 * 1000;
+	let fallback_dirs = DEFAULT_FALLBACK_DIRS
+		.iter()
+		.map(|a| a.to_string())
+		.collect();
+	let authorities = DEFAULT_AUTHORITIES.iter().map(|a| a.to_string()).collect();
+	let exclude_nodes = vec![];
+	let entry_nodes = vec![];
+	let exit_nodes = vec![];
+	// one day
+	let ds_validity_tolerance = 24 * 60 * 60 * 1000;
 	// 10 minutes
 	let ds_refresh_frequency = 10 * 60 * 1000;
+	// 5 minutes
+	let bootstrap_timeout = 5 * 60 * 1000;
+	let circuit_pool_size = 3;
+	// 10 minutes
+	let max_circuit_dirtiness = 10 * 60 * 1000;
+	// not 9050, so as not to collide with a tor already running
+	let socks_port = 9250;
+	let http_port = 0;
 
 	// mainlog configs
 	let mut config_path = PathBuf::new();
@@ -164,13 +248,25 @@ pub fn get_config() -> Result<TorConfig, Error> {
 		config_file,
 		version,
 		directory_servers,
+		fallback_dirs,
+		authorities,
+		exclude_nodes,
+		entry_nodes,
+		exit_nodes,
 		db_root,
-		ds_refresh_timeout,
+		ds_validity_tolerance,
 		ds_refresh_frequency,
+		bootstrap_timeout,
+		circuit_pool_size,
+		max_circuit_dirtiness,
+		socks_port,
+		http_port,
 		mainlog,
 		mainlog_rotationsize,
 		mainlog_rotationtime,
 		debug,
+		inspect_store,
+		clear_store,
 	};
 
 	// try to get it, if not there, create it
@@ -180,6 +276,34 @@ pub fn get_config() -> Result<TorConfig, Error> {
 	Ok(config)
 }
 
+/// Return the value of an optional array of strings in the general section
+fn get_string_array(general: &Value, name: &str) -> Result<Option<Vec<String>>, Error> {
+	let array = match general.get(name) {
+		Some(array) => array,
+		None => return Ok(None),
+	};
+	let array = match array.as_array() {
+		Some(array) => array,
+		None => {
+			return Err(ErrorKind::TomlError(format!("general.{} must be an array", name)).into());
+		}
+	};
+	let mut ret = vec![];
+	for value in array {
+		match value.as_str() {
+			Some(value) => ret.push(value.to_string()),
+			None => {
+				return Err(ErrorKind::TomlError(format!(
+					"general.{} must be an array of strings",
+					name
+				))
+				.into());
+			}
+		}
+	}
+	Ok(Some(ret))
+}
+
 /// Update the config object based on the passed in values from config file
 fn update_config(config: &mut TorConfig, value: String) -> Result<(), Error> {
 	let value = match value.parse::<Value>()? {
@@ -249,24 +373,39 @@ fn update_config(config: &mut TorConfig, value: String) -> Result<(), Error> {
 		}
 	};
 
-	// get the ds_refresh_timeout
-	config.ds_refresh_timeout = match general.get("ds_refresh_timeout") {
-		Some(ds_refresh_timeout) => match ds_refresh_timeout.as_integer() {
-			Some(ds_refresh_timeout) => ds_refresh_timeout.try_into()?,
+	// get the fallback_dirs, if not specified we use the defaults
+	if let Some(fallback_dirs) = get_string_array(general, "fallback_dirs")? {
+		config.fallback_dirs = fallback_dirs;
+	}
+
+	// get the node restrictions, none if not specified
+	if let Some(exclude_nodes) = get_string_array(general, "exclude_nodes")? {
+		config.exclude_nodes = exclude_nodes;
+	}
+	if let Some(entry_nodes) = get_string_array(general, "entry_nodes")? {
+		config.entry_nodes = entry_nodes;
+	}
+	if let Some(exit_nodes) = get_string_array(general, "exit_nodes")? {
+		config.exit_nodes = exit_nodes;
+	}
+
+	// get the authorities, if not specified we use the defaults
+	if let Some(authorities) = get_string_array(general, "authorities")? {
+		config.authorities = authorities;
+	}
+
+	// get the ds_validity_tolerance, if not specified we use the default
+	if let Some(ds_validity_tolerance) = general.get("ds_validity_tolerance") {
+		config.ds_validity_tolerance = match ds_validity_tolerance.as_integer() {
+			Some(ds_validity_tolerance) => ds_validity_tolerance.try_into()?,
 			None => {
 				return Err(ErrorKind::TomlError(
-					"general.ds_refresh_timeout must be an integer".to_string(),
+					"general.ds_validity_tolerance must be an integer".to_string(),
 				)
 				.into());
 			}
-		},
-		None => {
-			return Err(ErrorKind::TomlError(
-				"general.ds_refresh_timeout must be specified".to_string(),
-			)
-			.into());
-		}
-	};
+		};
+	}
 
 	// get the ds_refresh_frequency
 	config.ds_refresh_frequency = match general.get("ds_refresh_frequency") {
@@ -287,6 +426,71 @@ fn update_config(config: &mut TorConfig, value: String) -> Result<(), Error> {
 		}
 	};
 
+	// get the bootstrap_timeout, if not specified we use the default
+	if let Some(bootstrap_timeout) = general.get("bootstrap_timeout") {
+		config.bootstrap_timeout = match bootstrap_timeout.as_integer() {
+			Some(bootstrap_timeout) => bootstrap_timeout.try_into()?,
+			None => {
+				return Err(ErrorKind::TomlError(
+					"general.bootstrap_timeout must be an integer".to_string(),
+				)
+				.into());
+			}
+		};
+	}
+
+	// get the circuit_pool_size, if not specified we use the default
+	if let Some(circuit_pool_size) = general.get("circuit_pool_size") {
+		config.circuit_pool_size = match circuit_pool_size.as_integer() {
+			Some(circuit_pool_size) => circuit_pool_size.try_into()?,
+			None => {
+				return Err(ErrorKind::TomlError(
+					"general.circuit_pool_size must be an integer".to_string(),
+				)
+				.into());
+			}
+		};
+	}
+
+	// get the max_circuit_dirtiness, if not specified we use the default
+	if let Some(max_circuit_dirtiness) = general.get("max_circuit_dirtiness") {
+		config.max_circuit_dirtiness = match max_circuit_dirtiness.as_integer() {
+			Some(max_circuit_dirtiness) => max_circuit_dirtiness.try_into()?,
+			None => {
+				return Err(ErrorKind::TomlError(
+					"general.max_circuit_dirtiness must be an integer".to_string(),
+				)
+				.into());
+			}
+		};
+	}
+
+	// get the socks_port, if not specified we use the default
+	if let Some(socks_port) = general.get("socks_port") {
+		config.socks_port = match socks_port.as_integer() {
+			Some(socks_port) => socks_port.try_into()?,
+			None => {
+				return Err(ErrorKind::TomlError(
+					"general.socks_port must be an integer".to_string(),
+				)
+				.into());
+			}
+		};
+	}
+
+	// get the http_port, if not specified we use the default
+	if let Some(http_port) = general.get("http_port") {
+		config.http_port = match http_port.as_integer() {
+			Some(http_port) => http_port.try_into()?,
+			None => {
+				return Err(ErrorKind::TomlError(
+					"general.http_port must be an integer".to_string(),
+				)
+				.into());
+			}
+		};
+	}
+
 	// make sure there's a logging section
 	let logging = value.get("logging");
 	let logging = match logging {
@@ -0,0 +1,261 @@
+// Copyright 2021 The BMW Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::parse::{decode_hex, err, parse_time, tokenize, Item};
+use crate::Error;
+use digest::Digest;
+use std::net::SocketAddr;
+use std::time::SystemTime;
+use tor_checkable::signed::SignatureGated;
+use tor_checkable::timed::TimerangeBound;
+use tor_llcrypto::d::Sha1;
+use tor_llcrypto::pk::rsa::{PublicKey, RsaIdentity, ValidatableRsaSignature};
+use tor_llcrypto::pk::ValidatableSignature;
+
+/// The keyword every authority certificate starts with
+const CERT_START: &str = "dir-key-certificate-version";
+
+/// A directory authority key certificate. It binds an authority's
+/// medium-term signing key to its long-term identity key.
+#[derive(Debug, Clone)]
+pub struct AuthCert {
+	/// The authority's directory address, if it included one
+	pub address: Option<SocketAddr>,
+	/// The authority's long-term identity key
+	pub identity_key: PublicKey,
+	/// The authority's medium-term signing key
+	pub signing_key: PublicKey,
+	/// Fingerprint of the identity key
+	pub fingerprint: RsaIdentity,
+	/// When this certificate was published
+	pub published: SystemTime,
+	/// When this certificate expires
+	pub expires: SystemTime,
+}
+
+/// An authority certificate whose signatures and lifetime have not
+/// been checked yet.
+pub type UncheckedAuthCert = SignatureGated<TimerangeBound<AuthCert>>;
+
+/// Parse an RSA public key object
+fn parse_key(item: &Item) -> Result<PublicKey, Error> {
+	let key = match PublicKey::from_der(item.object("RSA PUBLIC KEY")?) {
+		Some(key) => key,
+		None => return Err(err(&format!("invalid key on '{}'", item.keyword))),
+	};
+	if !key.exponent_is(65537) {
+		return Err(err(&format!("invalid exponent on '{}'", item.keyword)));
+	}
+	Ok(key)
+}
+
+/// Parse a hex fingerprint such as the ones in the "fingerprint" and
+/// "dir-source" lines, or in the configured list of authorities.
+pub fn parse_fingerprint(s: &str) -> Result<RsaIdentity, Error> {
+	let bytes = decode_hex(&s.replace(' ', ""), 20)?;
+	match RsaIdentity::from_bytes(&bytes) {
+		Some(id) => Ok(id),
+		None => Err(err(&format!("invalid fingerprint '{}'", s))),
+	}
+}
+
+impl AuthCert {
+	/// Return the digest of the signing key, as used to identify it in
+	/// "directory-signature" lines.
+	pub fn signing_key_digest(&self) -> RsaIdentity {
+		self.signing_key.to_rsa_identity()
+	}
+
+	/// Parse a single authority certificate
+	pub fn parse(text: &str) -> Result<UncheckedAuthCert, Error> {
+		let items = tokenize(text)?;
+		match items.first() {
+			Some(item) if item.keyword == CERT_START => {
+				if item.arg(0)? != "3" {
+					return Err(err("unsupported dir-key-certificate-version"));
+				}
+			}
+			_ => {
+				return Err(err(
+					"certificate must start with dir-key-certificate-version",
+				))
+			}
+		}
+
+		let mut address = None;
+		let mut identity_key = None;
+		let mut signing_key = None;
+		let mut fingerprint = None;
+		let mut published = None;
+		let mut expires = None;
+		let mut crosscert = None;
+		let mut certification = None;
+
+		for item in &items {
+			if certification.is_some() {
+				return Err(err("unexpected item after dir-key-certification"));
+			}
+			match item.keyword {
+				"dir-address" => address = Some(item.parse_arg::<SocketAddr>(0)?),
+				"fingerprint" => fingerprint = Some(parse_fingerprint(&item.args_as_str())?),
+				"dir-key-published" => published = Some(parse_time(item.arg(0)?, item.arg(1)?)?),
+				"dir-key-expires" => expires = Some(parse_time(item.arg(0)?, item.arg(1)?)?),
+				"dir-identity-key" => identity_key = Some(parse_key(item)?),
+				"dir-signing-key" => signing_key = Some(parse_key(item)?),
+				"dir-key-crosscert" => crosscert = Some(item.object("ID SIGNATURE")?),
+				"dir-key-certification" => {
+					let sig = item.object("SIGNATURE")?;
+					let digest = Sha1::digest(&text.as_bytes()[..item.line_end]);
+					certification = Some((sig, digest));
+				}
+				_ => {}
+			}
+		}
+
+		let identity_key = identity_key.ok_or_else(|| err("missing dir-identity-key"))?;
+		let signing_key = signing_key.ok_or_else(|| err("missing dir-signing-key"))?;
+		let fingerprint = fingerprint.ok_or_else(|| err("missing fingerprint"))?;
+		let published = published.ok_or_else(|| err("missing dir-key-published"))?;
+		let expires = expires.ok_or_else(|| err("missing dir-key-expires"))?;
+		let crosscert = crosscert.ok_or_else(|| err("missing dir-key-crosscert"))?;
+		let (certification, digest) =
+			certification.ok_or_else(|| err("missing dir-key-certification"))?;
+
+		if identity_key.to_rsa_identity() != fingerprint {
+			return Err(err("fingerprint does not match dir-identity-key"));
+		}
+
+		// the signing key signs the identity fingerprint, and the
+		// identity key signs the whole certificate.
+		let signatures: Vec<Box<dyn ValidatableSignature>> = vec![
+			Box::new(ValidatableRsaSignature::new(
+				&signing_key,
+				crosscert,
+				fingerprint.as_bytes(),
+			)),
+			Box::new(ValidatableRsaSignature::new(
+				&identity_key,
+				certification,
+				&digest[..],
+			)),
+		];
+
+		let cert = AuthCert {
+			address,
+			identity_key,
+			signing_key,
+			fingerprint,
+			published,
+			expires,
+		};
+		let timed = TimerangeBound::new(cert, published..expires);
+		Ok(SignatureGated::new(timed, signatures))
+	}
+
+	/// Split a document containing any number of concatenated
+	/// certificates, such as the response to "/tor/keys/all", into the
+	/// text of each one.
+	pub fn split(text: &str) -> Vec<&str> {
+		let mut starts = vec![];
+		for (pos, _) in text.match_indices(CERT_START) {
+			if pos == 0 || text.as_bytes()[pos - 1] == b'\n' {
+				starts.push(pos);
+			}
+		}
+		let mut certs = vec![];
+		for (i, start) in starts.iter().enumerate() {
+			let end = starts.get(i + 1).copied().unwrap_or(text.len());
+			certs.push(&text[*start..end]);
+		}
+		certs
+	}
+
+	/// Parse a document containing any number of concatenated
+	/// certificates, such as the response to "/tor/keys/all".
+	pub fn parse_multiple(text: &str) -> Result<Vec<UncheckedAuthCert>, Error> {
+		AuthCert::split(text)
+			.into_iter()
+			.map(AuthCert::parse)
+			.collect()
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use std::time::Duration;
+	use tor_checkable::{SelfSigned, Timebound};
+
+	const CERT: &str = include_str!("../testdata/authcert.txt");
+
+	// the test cert is valid from 2021-01-01 to 2030-01-01
+	fn days(days: u64) -> SystemTime {
+		SystemTime::UNIX_EPOCH + Duration::from_secs(86400 * days)
+	}
+
+	#[test]
+	fn parse_and_check() {
+		// 2021-06-10
+		let cert = AuthCert::parse(CERT)
+			.unwrap()
+			.check_signature()
+			.unwrap()
+			.check_valid_at(&days(18788))
+			.unwrap();
+		assert_eq!(cert.address, Some("127.0.0.1:7000".parse().unwrap()));
+		assert_eq!(
+			cert.fingerprint,
+			parse_fingerprint("60C3DD86DC04B9D3EF9D742457E1A745C447C178").unwrap()
+		);
+		assert_eq!(
+			cert.signing_key_digest(),
+			parse_fingerprint("0B88F2FD876BF536F2DD7737042970851FDB31D8").unwrap()
+		);
+
+		// not yet published ten years after the epoch
+		let cert = AuthCert::parse(CERT).unwrap().check_signature().unwrap();
+		assert!(cert.check_valid_at(&days(3650)).is_err());
+
+		// still valid on 2029-12-31, but expired a day after 2030-01-01
+		let cert = AuthCert::parse(CERT).unwrap().check_signature().unwrap();
+		assert!(cert.check_valid_at(&days(21914)).is_ok());
+		let cert = AuthCert::parse(CERT).unwrap().check_signature().unwrap();
+		assert!(cert.check_valid_at(&days(21916)).is_err());
+	}
+
+	#[test]
+	fn tampered() {
+		let tampered = CERT.replace("127.0.0.1:7000", "127.0.0.1:7001");
+		let cert = AuthCert::parse(&tampered).unwrap();
+		assert!(cert.check_signature().is_err());
+
+		let wrong_fp = CERT.replace(
+			"60C3DD86DC04B9D3EF9D742457E1A745C447C178",
+			"60C3DD86DC04B9D3EF9D742457E1A745C447C179",
+		);
+		assert!(AuthCert::parse(&wrong_fp).is_err());
+	}
+
+	#[test]
+	fn multiple() {
+		let text = format!("{}{}", CERT, CERT);
+		let certs = AuthCert::parse_multiple(&text).unwrap();
+		assert_eq!(certs.len(), 2);
+		assert_eq!(AuthCert::split(&text), vec![CERT, CERT]);
+		for cert in certs {
+			assert!(cert.check_signature().is_ok());
+		}
+		assert!(AuthCert::parse_multiple("").unwrap().is_empty());
+	}
+}
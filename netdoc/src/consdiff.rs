@@ -0,0 +1,259 @@
+// Copyright 2021 The BMW Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::parse::{decode_hex, err};
+use crate::Error;
+use digest::Digest;
+use tor_llcrypto::d::Sha3_256;
+
+/// The first line of every consensus diff
+const DIFF_START: &str = "network-status-diff-version 1";
+
+/// The keyword that ends the signed part of a consensus
+const SIGNATURE_START: &str = "\ndirectory-signature ";
+
+/// Return the SHA3-256 digest of a whole consensus, as used on the hash
+/// line of consensus diffs.
+pub fn consensus_digest(text: &str) -> [u8; 32] {
+	Sha3_256::digest(text.as_bytes()).into()
+}
+
+/// Return the hex encoded digest of a whole consensus.
+pub fn consensus_digest_hex(text: &str) -> String {
+	hex::encode_upper(consensus_digest(text))
+}
+
+/// Return the SHA3-256 digest of a consensus as signed: the text up to
+/// and including the first "directory-signature ". Caches know the
+/// consensuses they can diff from by this digest.
+pub fn consensus_signed_digest(text: &str) -> [u8; 32] {
+	let end = match text.find(SIGNATURE_START) {
+		Some(pos) => pos + SIGNATURE_START.len(),
+		None => text.len(),
+	};
+	Sha3_256::digest(&text.as_bytes()[..end]).into()
+}
+
+/// Return the hex encoded digest of a consensus as signed, for the
+/// X-Or-Diff-From-Consensus header.
+pub fn consensus_signed_digest_hex(text: &str) -> String {
+	hex::encode_upper(consensus_signed_digest(text))
+}
+
+/// Return true if a directory response is a consensus diff rather than
+/// a full consensus.
+pub fn is_diff(text: &str) -> bool {
+	text.starts_with(DIFF_START)
+}
+
+/// A single ed-style command from a diff
+struct Command<'a> {
+	/// First line affected (1-based; 0 only for appending at the start)
+	start: usize,
+	/// Last line affected
+	end: usize,
+	/// 'a', 'c' or 'd'
+	op: u8,
+	/// Lines to insert, for 'a' and 'c'
+	lines: Vec<&'a str>,
+}
+
+/// Parse a line number, where "$" means the last line.
+fn parse_line_num(s: &str, last: usize) -> Result<usize, Error> {
+	if s == "$" {
+		return Ok(last);
+	}
+	s.parse::<usize>()
+		.map_err(|_| err(&format!("invalid line number '{}' in diff", s)))
+}
+
+/// Apply a consensus diff (proposal 140) to the consensus it was made
+/// against, and return the new consensus. Both the base and the result
+/// are checked against the digests given in the diff.
+pub fn apply_diff(base: &str, diff: &str) -> Result<String, Error> {
+	let mut diff_lines = diff.lines();
+	if diff_lines.next() != Some(DIFF_START) {
+		return Err(err("not a consensus diff"));
+	}
+	let hash_line = diff_lines.next().ok_or_else(|| err("missing hash line"))?;
+	let hashes: Vec<&str> = hash_line.split(' ').collect();
+	if hashes.len() != 3 || hashes[0] != "hash" {
+		return Err(err("invalid hash line in diff"));
+	}
+	let base_digest = decode_hex(hashes[1], 32)?;
+	let target_digest = decode_hex(hashes[2], 32)?;
+	if consensus_digest(base)[..] != base_digest[..] {
+		return Err(err("diff is not against our consensus"));
+	}
+
+	let mut lines: Vec<&str> = base.lines().collect();
+
+	// commands must run from the end of the document to the start, so that
+	// the line numbers of the ones still to come stay valid
+	let mut commands = vec![];
+	while let Some(line) = diff_lines.next() {
+		let op = match line.bytes().last() {
+			Some(op @ b'a') | Some(op @ b'c') | Some(op @ b'd') => op,
+			_ => return Err(err(&format!("invalid diff command '{}'", line))),
+		};
+		let range = &line[..line.len() - 1];
+		let (start, end) = match range.find(',') {
+			Some(pos) => (
+				parse_line_num(&range[..pos], lines.len())?,
+				parse_line_num(&range[pos + 1..], lines.len())?,
+			),
+			None => {
+				let n = parse_line_num(range, lines.len())?;
+				(n, n)
+			}
+		};
+		if end < start || end > lines.len() || (start == 0 && op != b'a') {
+			return Err(err(&format!("invalid range in diff command '{}'", line)));
+		}
+		if op == b'a' && start != end {
+			return Err(err("append command with a range"));
+		}
+		let mut new_lines = vec![];
+		if op != b'd' {
+			loop {
+				match diff_lines.next() {
+					Some(".") => break,
+					Some(l) => new_lines.push(l),
+					None => return Err(err("unterminated diff command")),
+				}
+			}
+		}
+		commands.push(Command {
+			start,
+			end,
+			op,
+			lines: new_lines,
+		});
+	}
+
+	let mut last_start = None;
+	for command in commands {
+		if let Some(last_start) = last_start {
+			if command.end >= last_start {
+				return Err(err("diff commands out of order"));
+			}
+		}
+		last_start = Some(command.start);
+		match command.op {
+			b'a' => {
+				let at = command.start;
+				lines.splice(at..at, command.lines);
+			}
+			_ => {
+				lines.splice(command.start - 1..command.end, command.lines);
+			}
+		}
+	}
+
+	let mut result = lines.join("\n");
+	result.push('\n');
+	if consensus_digest(&result)[..] != target_digest[..] {
+		return Err(err("consensus diff produced the wrong digest"));
+	}
+	Ok(result)
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	const BASE: &str = include_str!("../testdata/consensus-microdesc.txt");
+
+	fn make_diff(base: &str, target: &str, commands: &str) -> String {
+		format!(
+			"{}\nhash {} {}\n{}",
+			DIFF_START,
+			consensus_digest_hex(base),
+			consensus_digest_hex(target),
+			commands
+		)
+	}
+
+	#[test]
+	fn apply() {
+		let target = BASE
+			.replace("w Bandwidth=7\n", "w Bandwidth=9\n")
+			.replace("voting-delay 300 300\n", "")
+			.replace(
+				"valid-until 2021-06-10 15:00:00\n",
+				"valid-until 2021-06-10 15:00:00\nextra line\n",
+			);
+		let diff = make_diff(
+			BASE,
+			&target,
+			"17c\nw Bandwidth=9\n.\n7d\n6a\nextra line\n.\n",
+		);
+		assert!(is_diff(&diff));
+		assert!(!is_diff(BASE));
+		assert_eq!(apply_diff(BASE, &diff).unwrap(), target);
+
+		// delete everything after the routers
+		let end = BASE.find("directory-footer").unwrap();
+		let target = &BASE[..end];
+		let diff = make_diff(BASE, target, "24,$d\n");
+		assert_eq!(apply_diff(BASE, &diff).unwrap(), target);
+	}
+
+	#[test]
+	fn rejected() {
+		let target = BASE.replace("w Bandwidth=7\n", "w Bandwidth=9\n");
+
+		// wrong base
+		let diff = make_diff(&target, &target, "17c\nw Bandwidth=9\n.\n");
+		assert!(apply_diff(BASE, &diff).is_err());
+
+		// wrong result
+		let diff = make_diff(BASE, &target, "17c\nw Bandwidth=8\n.\n");
+		assert!(apply_diff(BASE, &diff).is_err());
+
+		// out of order, out of range and malformed commands
+		let diff = make_diff(BASE, &target, "7d\n17c\nw Bandwidth=9\n.\n");
+		assert!(apply_diff(BASE, &diff).is_err());
+		let diff = make_diff(BASE, &target, "100d\n");
+		assert!(apply_diff(BASE, &diff).is_err());
+		let diff = make_diff(BASE, &target, "17c\nw Bandwidth=9\n");
+		assert!(apply_diff(BASE, &diff).is_err());
+		let diff = make_diff(BASE, &target, "17x\n");
+		assert!(apply_diff(BASE, &diff).is_err());
+		assert!(apply_diff(BASE, BASE).is_err());
+	}
+
+	#[test]
+	fn signed_digest() {
+		let end = BASE.find("directory-signature ").unwrap() + "directory-signature ".len();
+		assert_eq!(
+			consensus_signed_digest(BASE),
+			consensus_digest(&BASE[..end])
+		);
+		assert_ne!(consensus_signed_digest(BASE), consensus_digest(BASE));
+
+		// only the signed part counts
+		let resigned = BASE.replace("0B88F2FD876BF536F2DD7737042970851FDB31D8", "AB");
+		assert_eq!(
+			consensus_signed_digest(&resigned),
+			consensus_signed_digest(BASE)
+		);
+		let changed = BASE.replace("w Bandwidth=7\n", "w Bandwidth=9\n");
+		assert_ne!(
+			consensus_signed_digest(&changed),
+			consensus_signed_digest(BASE)
+		);
+		assert_eq!(consensus_signed_digest_hex(BASE).len(), 64);
+	}
+}
@@ -0,0 +1,658 @@
+// Copyright 2021 The BMW Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::authcert::{parse_fingerprint, AuthCert};
+use crate::parse::{decode_b64, err, parse_time, tokenize, Item};
+use crate::{Error, ErrorKind};
+use bitflags::bitflags;
+use digest::Digest;
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::SystemTime;
+use tor_checkable::timed::TimerangeBound;
+use tor_llcrypto::d::{Sha1, Sha256};
+use tor_llcrypto::pk::rsa::{RsaIdentity, ValidatableRsaSignature};
+use tor_llcrypto::pk::{validate_all_sigs, ValidatableSignature};
+use tor_protover::Protocols;
+
+/// The keyword that starts each signature in the footer
+const SIGNATURE_KEYWORD: &str = "directory-signature";
+
+bitflags! {
+	/// The flags an authority may assign to a relay ("s" line)
+	pub struct RelayFlags: u16 {
+		const AUTHORITY = 1 << 0;
+		const BAD_EXIT = 1 << 1;
+		const EXIT = 1 << 2;
+		const FAST = 1 << 3;
+		const GUARD = 1 << 4;
+		const HSDIR = 1 << 5;
+		const NO_ED_CONSENSUS = 1 << 6;
+		const STABLE = 1 << 7;
+		const STALE_DESC = 1 << 8;
+		const RUNNING = 1 << 9;
+		const VALID = 1 << 10;
+		const V2DIR = 1 << 11;
+	}
+}
+
+impl RelayFlags {
+	/// Parse the arguments of an "s" line. Unknown flags are ignored.
+	fn from_args(args: &[&str]) -> RelayFlags {
+		let mut flags = RelayFlags::empty();
+		for arg in args {
+			flags |= match *arg {
+				"Authority" => RelayFlags::AUTHORITY,
+				"BadExit" => RelayFlags::BAD_EXIT,
+				"Exit" => RelayFlags::EXIT,
+				"Fast" => RelayFlags::FAST,
+				"Guard" => RelayFlags::GUARD,
+				"HSDir" => RelayFlags::HSDIR,
+				"NoEdConsensus" => RelayFlags::NO_ED_CONSENSUS,
+				"Stable" => RelayFlags::STABLE,
+				"StaleDesc" => RelayFlags::STALE_DESC,
+				"Running" => RelayFlags::RUNNING,
+				"Valid" => RelayFlags::VALID,
+				"V2Dir" => RelayFlags::V2DIR,
+				_ => RelayFlags::empty(),
+			};
+		}
+		flags
+	}
+}
+
+/// The flavor of a consensus document
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsensusFlavor {
+	/// The full "ns" flavor, which references router descriptors
+	Ns,
+	/// The "microdesc" flavor, which references microdescriptors
+	Microdesc,
+}
+
+/// A set of integer parameters such as those found on the "params" or
+/// "bandwidth-weights" lines.
+#[derive(Debug, Clone, Default)]
+pub struct NetParams {
+	params: BTreeMap<String, i32>,
+}
+
+impl NetParams {
+	/// Parse a list of "key=value" arguments
+	fn from_args(item: &Item) -> Result<NetParams, Error> {
+		let mut params = BTreeMap::new();
+		for arg in &item.args {
+			let mut kv = arg.splitn(2, '=');
+			let key = kv.next().unwrap_or("");
+			let value = kv.next().and_then(|v| v.parse::<i32>().ok());
+			match value {
+				Some(value) if !key.is_empty() => {
+					params.insert(key.to_string(), value);
+				}
+				_ => {
+					return Err(err(&format!(
+						"invalid parameter '{}' on '{}'",
+						arg, item.keyword
+					)))
+				}
+			}
+		}
+		Ok(NetParams { params })
+	}
+
+	/// Return the value of a parameter, if present
+	pub fn get(&self, name: &str) -> Option<i32> {
+		self.params.get(name).copied()
+	}
+
+	/// Return the value of a parameter, or the default if not present
+	pub fn get_or(&self, name: &str, default: i32) -> i32 {
+		self.get(name).unwrap_or(default)
+	}
+
+	/// Iterate over all parameters
+	pub fn iter(&self) -> impl Iterator<Item = (&String, &i32)> {
+		self.params.iter()
+	}
+}
+
+/// An authority that contributed to the consensus ("dir-source" line)
+#[derive(Debug, Clone)]
+pub struct DirSource {
+	/// Nickname of the authority
+	pub nickname: String,
+	/// Identity fingerprint of the authority's long term key
+	pub identity: RsaIdentity,
+	/// Address of the authority
+	pub address: IpAddr,
+	/// Directory port of the authority
+	pub dir_port: u16,
+	/// OR port of the authority
+	pub or_port: u16,
+}
+
+/// The header of a consensus, plus the bandwidth weights from its footer
+#[derive(Debug, Clone)]
+pub struct ConsensusHeader {
+	/// Which flavor of consensus this is
+	pub flavor: ConsensusFlavor,
+	/// The consensus method the authorities used
+	pub consensus_method: u32,
+	/// Time at which this consensus became valid
+	pub valid_after: SystemTime,
+	/// Time after which a newer consensus should be available
+	pub fresh_until: SystemTime,
+	/// Time after which this consensus must not be used
+	pub valid_until: SystemTime,
+	/// The flags the authorities know about
+	pub known_flags: Vec<String>,
+	/// Recommended client versions
+	pub client_versions: Vec<String>,
+	/// Protocols recommended for clients
+	pub recommended_client_protocols: Protocols,
+	/// Protocols required for clients
+	pub required_client_protocols: Protocols,
+	/// Network parameters ("params" line)
+	pub params: NetParams,
+	/// Bandwidth weights for path selection ("bandwidth-weights" line)
+	pub bandwidth_weights: NetParams,
+	/// The authorities that contributed to this consensus
+	pub dir_sources: Vec<DirSource>,
+}
+
+/// The bandwidth of a relay ("w" line)
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RelayWeight {
+	/// The bandwidth value in kilobytes per second
+	pub bandwidth: u32,
+	/// Whether the value was measured by enough bandwidth authorities
+	pub measured: bool,
+}
+
+/// A single entry in a consensus
+#[derive(Debug, Clone)]
+pub struct RouterStatus {
+	/// The relay's nickname
+	pub nickname: String,
+	/// The relay's RSA identity
+	pub rsa_identity: RsaIdentity,
+	/// Digest of the relay's descriptor. For the microdesc flavor this is
+	/// the SHA256 digest of its microdescriptor.
+	pub doc_digest: Vec<u8>,
+	/// When the relay's descriptor was published
+	pub published: SystemTime,
+	/// The relay's OR addresses. The first one is always the IPv4 address
+	/// from the "r" line; the rest come from the "a" lines.
+	pub addrs: Vec<SocketAddr>,
+	/// The relay's directory port, or 0 if none
+	pub dir_port: u16,
+	/// The relay's flags
+	pub flags: RelayFlags,
+	/// The relay's software version ("v" line)
+	pub version: Option<String>,
+	/// The subprotocols the relay supports
+	pub protos: Protocols,
+	/// The relay's bandwidth
+	pub weight: RelayWeight,
+	/// The relay's exit policy summary ("p" line)
+	pub policy_summary: Option<String>,
+}
+
+impl RouterStatus {
+	/// Return the IPv4 OR address from the "r" line
+	pub fn ipv4_addr(&self) -> SocketAddr {
+		self.addrs[0]
+	}
+
+	/// Return true if this relay has all of the specified flags
+	pub fn has_flags(&self, flags: RelayFlags) -> bool {
+		self.flags.contains(flags)
+	}
+}
+
+/// The digest algorithm an authority used to sign a consensus
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DigestAlgo {
+	/// SHA1, the default when no algorithm is given
+	Sha1,
+	/// SHA256
+	Sha256,
+}
+
+/// An authority's signature on a consensus ("directory-signature" line)
+#[derive(Debug, Clone)]
+pub struct ConsensusSignature {
+	/// The digest algorithm that was signed
+	pub algo: DigestAlgo,
+	/// Fingerprint of the authority's identity key
+	pub identity: RsaIdentity,
+	/// Digest of the signing key that made this signature
+	pub signing_key_digest: RsaIdentity,
+	/// The signature itself
+	pub signature: Vec<u8>,
+}
+
+/// A parsed consensus document
+#[derive(Debug, Clone)]
+pub struct Consensus {
+	/// The header fields
+	pub header: ConsensusHeader,
+	/// The relays listed in this consensus
+	pub routers: Vec<RouterStatus>,
+	/// The authority signatures from the footer
+	pub signatures: Vec<ConsensusSignature>,
+	/// SHA1 digest of the signed portion of the document
+	sha1_digest: Vec<u8>,
+	/// SHA256 digest of the signed portion of the document
+	sha256_digest: Vec<u8>,
+}
+
+/// Parse a "pr" style protocol list
+fn parse_protocols(item: &Item) -> Result<Protocols, Error> {
+	item.args_as_str()
+		.parse::<Protocols>()
+		.map_err(|e| err(&format!("invalid protocols on '{}': {}", item.keyword, e)))
+}
+
+/// Build a router status from its "r" line; the other lines are added later.
+/// In the microdesc flavor the digest is omitted here and given on the
+/// "m" line instead.
+fn parse_r_line(item: &Item, flavor: ConsensusFlavor) -> Result<RouterStatus, Error> {
+	let identity = decode_b64(item.arg(1)?)?;
+	let rsa_identity = match RsaIdentity::from_bytes(&identity) {
+		Some(rsa_identity) => rsa_identity,
+		None => return Err(err(&format!("invalid identity '{}'", item.arg(1)?))),
+	};
+	let (doc_digest, n) = match flavor {
+		ConsensusFlavor::Ns => (decode_b64(item.arg(2)?)?, 3),
+		ConsensusFlavor::Microdesc => (vec![], 2),
+	};
+	let published = parse_time(item.arg(n)?, item.arg(n + 1)?)?;
+	let ip: IpAddr = item.parse_arg(n + 2)?;
+	let or_port: u16 = item.parse_arg(n + 3)?;
+	let dir_port: u16 = item.parse_arg(n + 4)?;
+
+	Ok(RouterStatus {
+		nickname: item.arg(0)?.to_string(),
+		rsa_identity,
+		doc_digest,
+		published,
+		addrs: vec![SocketAddr::new(ip, or_port)],
+		dir_port,
+		flags: RelayFlags::empty(),
+		version: None,
+		protos: Protocols::new(),
+		weight: RelayWeight::default(),
+		policy_summary: None,
+	})
+}
+
+/// Parse a "directory-signature" line
+fn parse_signature(item: &Item) -> Result<ConsensusSignature, Error> {
+	let (algo, args) = match item.args.len() {
+		2 => (DigestAlgo::Sha1, &item.args[..]),
+		3 => match item.args[0] {
+			"sha1" => (DigestAlgo::Sha1, &item.args[1..]),
+			"sha256" => (DigestAlgo::Sha256, &item.args[1..]),
+			algo => return Err(err(&format!("unknown signature algorithm '{}'", algo))),
+		},
+		_ => return Err(err("invalid directory-signature line")),
+	};
+	Ok(ConsensusSignature {
+		algo,
+		identity: parse_fingerprint(args[0])?,
+		signing_key_digest: parse_fingerprint(args[1])?,
+		signature: item.object("SIGNATURE")?.to_vec(),
+	})
+}
+
+/// Parse a "w" line
+fn parse_weight(item: &Item) -> Result<RelayWeight, Error> {
+	let mut weight = RelayWeight::default();
+	let mut unmeasured = false;
+	for arg in &item.args {
+		let mut kv = arg.splitn(2, '=');
+		match (kv.next(), kv.next()) {
+			(Some("Bandwidth"), Some(v)) => {
+				weight.bandwidth = v
+					.parse::<u32>()
+					.map_err(|_| err(&format!("invalid bandwidth '{}'", v)))?;
+			}
+			(Some("Unmeasured"), Some("1")) => unmeasured = true,
+			_ => {}
+		}
+	}
+	weight.measured = !unmeasured;
+	Ok(weight)
+}
+
+impl Consensus {
+	/// Parse a consensus document
+	pub fn parse(text: &str) -> Result<Consensus, Error> {
+		let items = tokenize(text)?;
+		let mut items = items.iter();
+
+		let flavor = match items.next() {
+			Some(item) if item.keyword == "network-status-version" => {
+				if item.arg(0)? != "3" {
+					return Err(err("unsupported network-status-version"));
+				}
+				match item.args.get(1) {
+					None => ConsensusFlavor::Ns,
+					Some(&"microdesc") => ConsensusFlavor::Microdesc,
+					Some(flavor) => return Err(err(&format!("unsupported flavor '{}'", flavor))),
+				}
+			}
+			_ => return Err(err("consensus must start with network-status-version")),
+		};
+
+		let mut vote_status = None;
+		let mut consensus_method = None;
+		let mut valid_after = None;
+		let mut fresh_until = None;
+		let mut valid_until = None;
+		let mut known_flags = vec![];
+		let mut client_versions = vec![];
+		let mut recommended_client_protocols = Protocols::new();
+		let mut required_client_protocols = Protocols::new();
+		let mut params = NetParams::default();
+		let mut bandwidth_weights = NetParams::default();
+		let mut dir_sources = vec![];
+		let mut routers: Vec<RouterStatus> = vec![];
+		let mut signatures = vec![];
+		let mut signed_len = None;
+		let mut in_footer = false;
+
+		for item in items {
+			if in_footer {
+				match item.keyword {
+					"bandwidth-weights" => bandwidth_weights = NetParams::from_args(item)?,
+					SIGNATURE_KEYWORD => {
+						// the signed portion runs through the space after the
+						// first "directory-signature" keyword
+						if signed_len.is_none() {
+							signed_len = Some(item.offset + SIGNATURE_KEYWORD.len() + 1);
+						}
+						signatures.push(parse_signature(item)?);
+					}
+					_ => {}
+				}
+				continue;
+			}
+
+			// router status entries start at the first "r" line
+			if item.keyword == "r" {
+				routers.push(parse_r_line(item, flavor)?);
+				continue;
+			}
+			if let Some(router) = routers.last_mut() {
+				match item.keyword {
+					"a" => {
+						let addr: SocketAddr = item.parse_arg(0)?;
+						router.addrs.push(addr);
+					}
+					"s" => router.flags = RelayFlags::from_args(&item.args),
+					"v" => router.version = Some(item.args_as_str()),
+					"pr" => router.protos = parse_protocols(item)?,
+					"w" => router.weight = parse_weight(item)?,
+					"p" => router.policy_summary = Some(item.args_as_str()),
+					"m" if flavor == ConsensusFlavor::Microdesc => {
+						router.doc_digest = decode_b64(item.arg(0)?)?;
+					}
+					"directory-footer" => in_footer = true,
+					_ => {}
+				}
+				continue;
+			}
+
+			match item.keyword {
+				"vote-status" => vote_status = Some(item.arg(0)?),
+				"consensus-method" => consensus_method = Some(item.parse_arg::<u32>(0)?),
+				"valid-after" => valid_after = Some(parse_time(item.arg(0)?, item.arg(1)?)?),
+				"fresh-until" => fresh_until = Some(parse_time(item.arg(0)?, item.arg(1)?)?),
+				"valid-until" => valid_until = Some(parse_time(item.arg(0)?, item.arg(1)?)?),
+				"known-flags" => known_flags = item.args.iter().map(|s| s.to_string()).collect(),
+				"client-versions" => {
+					client_versions = item
+						.args_as_str()
+						.split(',')
+						.filter(|s| !s.is_empty())
+						.map(|s| s.to_string())
+						.collect()
+				}
+				"recommended-client-protocols" => {
+					recommended_client_protocols = parse_protocols(item)?
+				}
+				"required-client-protocols" => required_client_protocols = parse_protocols(item)?,
+				"params" => params = NetParams::from_args(item)?,
+				"dir-source" => {
+					dir_sources.push(DirSource {
+						nickname: item.arg(0)?.to_string(),
+						identity: parse_fingerprint(item.arg(1)?)?,
+						address: item.parse_arg(3)?,
+						dir_port: item.parse_arg(4)?,
+						or_port: item.parse_arg(5)?,
+					});
+				}
+				"directory-footer" => in_footer = true,
+				_ => {}
+			}
+		}
+
+		if vote_status != Some("consensus") {
+			return Err(err("vote-status must be 'consensus'"));
+		}
+		if flavor == ConsensusFlavor::Microdesc {
+			if let Some(router) = routers.iter().find(|r| r.doc_digest.len() != 32) {
+				return Err(err(&format!(
+					"router '{}' has no valid microdescriptor digest",
+					router.nickname
+				)));
+			}
+		}
+		let header = ConsensusHeader {
+			flavor,
+			consensus_method: consensus_method.ok_or_else(|| err("missing consensus-method"))?,
+			valid_after: valid_after.ok_or_else(|| err("missing valid-after"))?,
+			fresh_until: fresh_until.ok_or_else(|| err("missing fresh-until"))?,
+			valid_until: valid_until.ok_or_else(|| err("missing valid-until"))?,
+			known_flags,
+			client_versions,
+			recommended_client_protocols,
+			required_client_protocols,
+			params,
+			bandwidth_weights,
+			dir_sources,
+		};
+
+		let signed = match signed_len {
+			Some(signed_len) => &text.as_bytes()[..signed_len],
+			None => return Err(err("consensus is not signed")),
+		};
+
+		Ok(Consensus {
+			header,
+			routers,
+			signatures,
+			sha1_digest: Sha1::digest(signed).to_vec(),
+			sha256_digest: Sha256::digest(signed).to_vec(),
+		})
+	}
+
+	/// Return this consensus bound to the time it is valid for, from
+	/// valid-after to valid-until.
+	pub fn timebound(self) -> TimerangeBound<Consensus> {
+		let range = self.header.valid_after..self.header.valid_until;
+		TimerangeBound::new(self, range)
+	}
+
+	/// Check that more than half of the given authorities validly signed
+	/// this consensus, using the signing keys from their certificates.
+	/// Certificates should already have been checked.
+	pub fn check_signatures(
+		&self,
+		authorities: &[RsaIdentity],
+		certs: &[AuthCert],
+	) -> Result<(), Error> {
+		let mut signed = 0;
+		for authority in authorities {
+			let valid = self.signatures.iter().any(|sig| {
+				if sig.identity != *authority {
+					return false;
+				}
+				let cert = certs.iter().find(|cert| {
+					cert.fingerprint == sig.identity
+						&& cert.signing_key_digest() == sig.signing_key_digest
+				});
+				let cert = match cert {
+					Some(cert) => cert,
+					None => return false,
+				};
+				let digest = match sig.algo {
+					DigestAlgo::Sha1 => &self.sha1_digest[..],
+					DigestAlgo::Sha256 => &self.sha256_digest[..],
+				};
+				let sigs: Vec<Box<dyn ValidatableSignature>> = vec![Box::new(
+					ValidatableRsaSignature::new(&cert.signing_key, &sig.signature, digest),
+				)];
+				validate_all_sigs(&sigs)
+			});
+			if valid {
+				signed += 1;
+			}
+		}
+
+		if signed * 2 > authorities.len() {
+			Ok(())
+		} else {
+			Err(ErrorKind::ConsensusRejected(format!(
+				"only {} of {} authorities signed the consensus",
+				signed,
+				authorities.len()
+			))
+			.into())
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use std::time::Duration;
+	use tor_checkable::{SelfSigned, Timebound};
+
+	const SAMPLE: &str = include_str!("../testdata/consensus.txt");
+
+	#[test]
+	fn parse_sample() {
+		let consensus = Consensus::parse(SAMPLE).unwrap();
+		let header = &consensus.header;
+		assert_eq!(header.flavor, ConsensusFlavor::Ns);
+		assert_eq!(header.consensus_method, 31);
+		assert_eq!(
+			header.fresh_until.duration_since(header.valid_after).unwrap(),
+			Duration::from_secs(3600)
+		);
+		assert_eq!(header.params.get("cbttestfreq"), Some(10));
+		assert_eq!(header.bandwidth_weights.get("Wgg"), Some(5806));
+		assert_eq!(header.dir_sources[0].nickname, "moria1");
+		assert_eq!(header.client_versions.len(), 2);
+
+		assert_eq!(consensus.routers.len(), 2);
+		let seele = &consensus.routers[0];
+		assert_eq!(seele.nickname, "seele");
+		assert_eq!(seele.addrs.len(), 2);
+		assert_eq!(seele.ipv4_addr(), "104.53.221.159:9001".parse().unwrap());
+		assert!(seele.addrs[1].is_ipv6());
+		assert!(seele.has_flags(RelayFlags::RUNNING | RelayFlags::VALID));
+		assert!(!seele.has_flags(RelayFlags::EXIT));
+		assert_eq!(seele.version.as_deref(), Some("Tor 0.4.5.7"));
+		assert!(seele
+			.protos
+			.supports_known_subver(tor_protover::ProtoKind::FlowCtrl, 1));
+		assert_eq!(seele.weight.bandwidth, 7);
+		assert!(seele.weight.measured);
+
+		let calyx = &consensus.routers[1];
+		assert_eq!(calyx.dir_port, 80);
+		assert!(calyx.has_flags(RelayFlags::EXIT | RelayFlags::GUARD));
+		assert!(!calyx.weight.measured);
+		assert!(calyx
+			.policy_summary
+			.as_ref()
+			.unwrap()
+			.starts_with("accept 20-23"));
+	}
+
+	#[test]
+	fn parse_invalid() {
+		assert!(Consensus::parse("").is_err());
+		assert!(Consensus::parse("network-status-version 2\n").is_err());
+		let missing = SAMPLE.replace("valid-until 2021-06-10 15:00:00\n", "");
+		assert!(Consensus::parse(&missing).is_err());
+		let bad_time = SAMPLE.replace("2021-06-10 13:00:00", "2021-06-10 25:00:00");
+		assert!(Consensus::parse(&bad_time).is_err());
+		let unsigned = &SAMPLE[..SAMPLE.find("directory-signature").unwrap()];
+		assert!(Consensus::parse(unsigned).is_err());
+	}
+
+	#[test]
+	fn lifetime() {
+		let consensus = Consensus::parse(SAMPLE).unwrap();
+		let valid_after = consensus.header.valid_after;
+		let valid_until = consensus.header.valid_until;
+		let late = valid_until + Duration::from_secs(3600);
+
+		let bound = consensus.timebound();
+		let second = Duration::from_secs(1);
+		assert!(bound.is_valid_at(&(valid_after + second)).is_ok());
+		assert!(bound.is_valid_at(&(valid_until - second)).is_ok());
+		assert!(bound.is_valid_at(&late).is_err());
+		assert!(bound.is_valid_at(&(valid_after - second)).is_err());
+
+		let bound = bound.extend_tolerance(Duration::from_secs(86400));
+		assert!(bound.check_valid_at(&late).is_ok());
+	}
+
+	#[test]
+	fn signatures() {
+		let cert = AuthCert::parse(include_str!("../testdata/authcert.txt"))
+			.unwrap()
+			.check_signature()
+			.unwrap()
+			.dangerously_assume_timely();
+		let authority = cert.fingerprint;
+		let other1 = RsaIdentity::from([1; 20]);
+		let other2 = RsaIdentity::from([2; 20]);
+		let certs = vec![cert];
+
+		let consensus = Consensus::parse(SAMPLE).unwrap();
+		assert_eq!(consensus.signatures.len(), 1);
+		assert_eq!(consensus.signatures[0].algo, DigestAlgo::Sha256);
+		assert!(consensus.check_signatures(&[authority], &certs).is_ok());
+		assert!(consensus
+			.check_signatures(&[authority, other1], &certs)
+			.is_err());
+		assert!(consensus
+			.check_signatures(&[authority, other1, other2], &certs)
+			.is_err());
+		assert!(consensus.check_signatures(&[authority], &[]).is_err());
+		assert!(consensus.check_signatures(&[], &certs).is_err());
+
+		let tampered = SAMPLE.replace("Bandwidth=7", "Bandwidth=8");
+		let consensus = Consensus::parse(&tampered).unwrap();
+		let err = consensus.check_signatures(&[authority], &certs).unwrap_err();
+		assert!(matches!(err.kind(), ErrorKind::ConsensusRejected(_)));
+	}
+}
@@ -0,0 +1,168 @@
+// Copyright 2021 The BMW Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::parse::{decode_b64, err, tokenize};
+use crate::Error;
+use digest::Digest;
+use std::convert::TryInto;
+use tor_llcrypto::d::Sha256;
+use tor_llcrypto::pk::curve25519;
+use tor_llcrypto::pk::ed25519::Ed25519Identity;
+
+/// The keyword every microdescriptor starts with
+const MD_START: &str = "onion-key";
+
+/// The SHA256 digest that identifies a microdescriptor
+pub type MdDigest = [u8; 32];
+
+/// A microdescriptor: the part of a relay's descriptor that clients need
+/// in order to build circuits through it.
+#[derive(Debug, Clone)]
+pub struct Microdesc {
+	/// SHA256 digest of the microdescriptor text
+	pub digest: MdDigest,
+	/// The relay's curve25519 key for the ntor handshake
+	pub ntor_onion_key: curve25519::PublicKey,
+	/// The relay's declared family members, as given
+	pub family: Vec<String>,
+	/// The relay's ed25519 identity, if it has one
+	pub ed25519_id: Option<Ed25519Identity>,
+	/// The relay's IPv4 exit policy summary ("p" line)
+	pub policy_summary: Option<String>,
+	/// The relay's IPv6 exit policy summary ("p6" line)
+	pub ipv6_policy_summary: Option<String>,
+}
+
+impl Microdesc {
+	/// Parse a single microdescriptor. The digest is computed over
+	/// exactly the text given, so it must not include anything else.
+	pub fn parse(text: &str) -> Result<Microdesc, Error> {
+		let items = tokenize(text)?;
+		match items.first() {
+			Some(item) if item.keyword == MD_START => {}
+			_ => return Err(err("microdescriptor must start with onion-key")),
+		}
+
+		let mut ntor_onion_key = None;
+		let mut family = vec![];
+		let mut ed25519_id = None;
+		let mut policy_summary = None;
+		let mut ipv6_policy_summary = None;
+
+		for item in &items[1..] {
+			match item.keyword {
+				MD_START => return Err(err("more than one onion-key in microdescriptor")),
+				"ntor-onion-key" => {
+					let key: [u8; 32] = decode_b64(item.arg(0)?)?
+						.as_slice()
+						.try_into()
+						.map_err(|_| err("ntor-onion-key is not 32 bytes"))?;
+					ntor_onion_key = Some(curve25519::PublicKey::from(key));
+				}
+				"family" => family = item.args.iter().map(|s| s.to_string()).collect(),
+				"id" if item.arg(0)? == "ed25519" => {
+					let id = decode_b64(item.arg(1)?)?;
+					match Ed25519Identity::from_bytes(&id) {
+						Some(id) => ed25519_id = Some(id),
+						None => return Err(err("invalid ed25519 id")),
+					}
+				}
+				"p" => policy_summary = Some(item.args_as_str()),
+				"p6" => ipv6_policy_summary = Some(item.args_as_str()),
+				_ => {}
+			}
+		}
+
+		Ok(Microdesc {
+			digest: Sha256::digest(text.as_bytes()).into(),
+			ntor_onion_key: ntor_onion_key.ok_or_else(|| err("missing ntor-onion-key"))?,
+			family,
+			ed25519_id,
+			policy_summary,
+			ipv6_policy_summary,
+		})
+	}
+
+	/// Split a response containing any number of concatenated
+	/// microdescriptors, such as the response to "/tor/micro/d/...",
+	/// into the text of each one.
+	pub fn split(text: &str) -> Vec<&str> {
+		let mut starts = vec![];
+		for (pos, _) in text.match_indices(MD_START) {
+			// "ntor-onion-key" also contains the keyword, so require it to
+			// start a line.
+			if pos == 0 || text.as_bytes()[pos - 1] == b'\n' {
+				starts.push(pos);
+			}
+		}
+		let mut mds = vec![];
+		for (i, start) in starts.iter().enumerate() {
+			let end = starts.get(i + 1).copied().unwrap_or(text.len());
+			mds.push(&text[*start..end]);
+		}
+		mds
+	}
+}
+
+/// Encode a microdescriptor digest the way it is written in a consensus
+/// "m" line and in download URLs.
+pub fn encode_digest(digest: &MdDigest) -> String {
+	base64::encode_config(digest, base64::STANDARD_NO_PAD)
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::consensus::{Consensus, ConsensusFlavor};
+
+	const MICRODESCS: &str = include_str!("../testdata/microdescs.txt");
+	const CONSENSUS: &str = include_str!("../testdata/consensus-microdesc.txt");
+
+	#[test]
+	fn parse_microdescs() {
+		let texts = Microdesc::split(MICRODESCS);
+		assert_eq!(texts.len(), 2);
+		let md1 = Microdesc::parse(texts[0]).unwrap();
+		let md2 = Microdesc::parse(texts[1]).unwrap();
+
+		assert_eq!(md1.ntor_onion_key.as_bytes()[1], 1);
+		assert_eq!(md1.family.len(), 2);
+		assert_eq!(md1.policy_summary.as_deref(), Some("accept 80,443"));
+		assert!(md1.ipv6_policy_summary.is_none());
+		assert_eq!(md1.ed25519_id.unwrap().as_bytes(), &[7; 32]);
+
+		assert_eq!(md2.ntor_onion_key.as_bytes()[0], 32);
+		assert!(md2.family.is_empty());
+		assert_eq!(md2.ipv6_policy_summary.as_deref(), Some("accept 1-65535"));
+
+		// the digests are the ones listed in the consensus
+		let consensus = Consensus::parse(CONSENSUS).unwrap();
+		assert_eq!(consensus.header.flavor, ConsensusFlavor::Microdesc);
+		assert_eq!(consensus.routers[0].doc_digest, md1.digest.to_vec());
+		assert_eq!(consensus.routers[1].doc_digest, md2.digest.to_vec());
+		assert_eq!(
+			encode_digest(&md1.digest),
+			"5h2eT4roudF1NO1/3osl481LeVYWmVazHgE3PkoV+Ag"
+		);
+	}
+
+	#[test]
+	fn parse_invalid() {
+		assert!(Microdesc::parse("").is_err());
+		assert!(Microdesc::parse("onion-key\n").is_err());
+		assert!(Microdesc::parse(MICRODESCS).is_err());
+		assert!(Microdesc::parse("onion-key\nntor-onion-key AAAA\n").is_err());
+		assert!(Microdesc::split("").is_empty());
+	}
+}
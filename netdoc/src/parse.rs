@@ -0,0 +1,172 @@
+// Copyright 2021 The BMW Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::{Error, ErrorKind};
+use chrono::{NaiveDateTime, TimeZone, Utc};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+
+/// An object (signature, key, ...) that follows a keyword line
+pub(crate) struct Object {
+	/// The tag from the BEGIN/END lines, e.g. "SIGNATURE"
+	pub tag: String,
+	/// The base64 decoded body of the object
+	pub data: Vec<u8>,
+}
+
+/// A single keyword line from a directory document
+pub(crate) struct Item<'a> {
+	/// The keyword that starts the line
+	pub keyword: &'a str,
+	/// The remaining whitespace separated arguments
+	pub args: Vec<&'a str>,
+	/// The object following this line, if any
+	pub object: Option<Object>,
+	/// Offset into the document of the first byte of this item
+	pub offset: usize,
+	/// Offset into the document just past the end of the keyword line
+	pub line_end: usize,
+}
+
+impl<'a> Item<'a> {
+	/// Return the nth argument or an error naming the keyword
+	pub fn arg(&self, n: usize) -> Result<&'a str, Error> {
+		match self.args.get(n) {
+			Some(arg) => Ok(arg),
+			None => Err(err(&format!(
+				"'{}' is missing argument {}",
+				self.keyword, n
+			))),
+		}
+	}
+
+	/// Parse the nth argument with FromStr
+	pub fn parse_arg<T: FromStr>(&self, n: usize) -> Result<T, Error> {
+		let arg = self.arg(n)?;
+		arg.parse::<T>()
+			.map_err(|_| err(&format!("'{}' has invalid argument '{}'", self.keyword, arg)))
+	}
+
+	/// All arguments joined back together with single spaces
+	pub fn args_as_str(&self) -> String {
+		self.args.join(" ")
+	}
+
+	/// Return the object following this item, checking its tag
+	pub fn object(&self, tag: &str) -> Result<&'_ [u8], Error> {
+		match &self.object {
+			Some(object) if object.tag == tag => Ok(&object.data[..]),
+			Some(object) => Err(err(&format!(
+				"'{}' has a {} object, expected {}",
+				self.keyword, object.tag, tag
+			))),
+			None => Err(err(&format!("'{}' is missing its {} object", self.keyword, tag))),
+		}
+	}
+}
+
+/// Build a NetDocError
+pub(crate) fn err(msg: &str) -> Error {
+	ErrorKind::NetDocError(msg.to_string()).into()
+}
+
+/// Split a document into items. Every Tor directory document is a sequence
+/// of keyword lines, each optionally followed by a PEM-style object.
+pub(crate) fn tokenize(doc: &str) -> Result<Vec<Item<'_>>, Error> {
+	let mut lines = vec![];
+	let mut offset = 0;
+	for line in doc.split('\n') {
+		lines.push((offset, line.len(), line.trim_end_matches('\r')));
+		offset += line.len() + 1;
+	}
+
+	let mut items = vec![];
+	let mut i = 0;
+	while i < lines.len() {
+		let (offset, len, line) = lines[i];
+		let line_end = std::cmp::min(offset + len + 1, doc.len());
+		i += 1;
+		let mut parts = line.split_whitespace();
+		let keyword = match parts.next() {
+			Some(keyword) => keyword,
+			None => continue,
+		};
+		if keyword.starts_with("-----") {
+			return Err(err(&format!("unexpected object at offset {}", offset)));
+		}
+		let args = parts.collect::<Vec<&str>>();
+
+		let mut object = None;
+		if i < lines.len() && lines[i].2.starts_with("-----BEGIN ") {
+			let tag = lines[i]
+				.2
+				.trim_start_matches("-----BEGIN ")
+				.trim_end_matches("-----")
+				.to_string();
+			let end = format!("-----END {}-----", tag);
+			i += 1;
+			let mut body = String::new();
+			loop {
+				if i >= lines.len() {
+					return Err(err(&format!("unterminated {} object", tag)));
+				}
+				let line = lines[i].2;
+				i += 1;
+				if line == end {
+					break;
+				}
+				body.push_str(line.trim());
+			}
+			let data = base64::decode(&body)
+				.map_err(|e| err(&format!("invalid base64 in {} object: {}", tag, e)))?;
+			object = Some(Object { tag, data });
+		}
+
+		items.push(Item {
+			keyword,
+			args,
+			object,
+			offset,
+			line_end,
+		});
+	}
+
+	Ok(items)
+}
+
+/// Parse a "YYYY-MM-DD HH:MM:SS" timestamp split across two arguments
+pub(crate) fn parse_time(date: &str, time: &str) -> Result<SystemTime, Error> {
+	let dt = NaiveDateTime::parse_from_str(&format!("{} {}", date, time), "%Y-%m-%d %H:%M:%S")
+		.map_err(|e| err(&format!("invalid time '{} {}': {}", date, time, e)))?;
+	let secs = Utc.from_utc_datetime(&dt).timestamp();
+	if secs < 0 {
+		return Err(err(&format!("time before epoch '{} {}'", date, time)));
+	}
+	Ok(SystemTime::UNIX_EPOCH + Duration::from_secs(secs as u64))
+}
+
+/// Decode base64 that may or may not have its trailing padding
+pub(crate) fn decode_b64(s: &str) -> Result<Vec<u8>, Error> {
+	base64::decode_config(s.trim_end_matches('='), base64::STANDARD_NO_PAD)
+		.map_err(|e| err(&format!("invalid base64 '{}': {}", s, e)))
+}
+
+/// Decode a hex string of a known length
+pub(crate) fn decode_hex(s: &str, len: usize) -> Result<Vec<u8>, Error> {
+	let bytes = hex::decode(s).map_err(|e| err(&format!("invalid hex '{}': {}", s, e)))?;
+	if bytes.len() != len {
+		return Err(err(&format!("hex '{}' is not {} bytes", s, len)));
+	}
+	Ok(bytes)
+}
@@ -0,0 +1,527 @@
+// Copyright 2021 The BMW Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::parse::err;
+use crate::Error;
+use std::fmt;
+use std::net::IpAddr;
+
+/// An inclusive range of ports
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortRange {
+	/// The first port in the range
+	pub lo: u16,
+	/// The last port in the range
+	pub hi: u16,
+}
+
+impl PortRange {
+	/// Parse "N" or "N-M". Port 0 is not a valid port.
+	fn parse(s: &str) -> Result<PortRange, Error> {
+		let invalid = || err(&format!("invalid port range '{}'", s));
+		let mut parts = s.splitn(2, '-');
+		let lo: u16 = parts.next().unwrap_or("").parse().map_err(|_| invalid())?;
+		let hi: u16 = match parts.next() {
+			Some(hi) => hi.parse().map_err(|_| invalid())?,
+			None => lo,
+		};
+		if lo == 0 || lo > hi {
+			return Err(invalid());
+		}
+		Ok(PortRange { lo, hi })
+	}
+
+	/// Return true if `port` is in this range.
+	pub fn contains(&self, port: u16) -> bool {
+		self.lo <= port && port <= self.hi
+	}
+}
+
+impl fmt::Display for PortRange {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		if self.lo == self.hi {
+			write!(f, "{}", self.lo)
+		} else {
+			write!(f, "{}-{}", self.lo, self.hi)
+		}
+	}
+}
+
+/// An exit policy summary, as in the "p" and "p6" lines of consensuses
+/// and microdescriptors: "accept" or "reject" followed by a list of
+/// ports and port ranges. It says nothing about addresses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortPolicy {
+	/// the ports that are allowed, sorted and with no two ranges touching
+	allowed: Vec<PortRange>,
+}
+
+impl PortPolicy {
+	/// Parse a summary such as "accept 80,443" or "reject 1-1024". An
+	/// "accept" summary allows exactly the listed ports; a "reject"
+	/// summary allows every port except those listed.
+	pub fn parse(s: &str) -> Result<PortPolicy, Error> {
+		let mut words = s.split_whitespace();
+		let accept = match words.next() {
+			Some("accept") => true,
+			Some("reject") => false,
+			_ => return Err(err(&format!("invalid policy summary '{}'", s))),
+		};
+		let ports = match (words.next(), words.next()) {
+			(Some(ports), None) => ports,
+			_ => return Err(err(&format!("invalid policy summary '{}'", s))),
+		};
+		let mut ranges = vec![];
+		for range in ports.split(',') {
+			ranges.push(PortRange::parse(range)?);
+		}
+		let listed = normalize(ranges);
+		let allowed = if accept { listed } else { invert(&listed) };
+		Ok(PortPolicy { allowed })
+	}
+
+	/// A policy that allows no ports, for relays that don't say
+	pub fn reject_all() -> PortPolicy {
+		PortPolicy { allowed: vec![] }
+	}
+
+	/// Return true if the policy allows exiting to `port`.
+	pub fn allows_port(&self, port: u16) -> bool {
+		// the ranges are sorted and don't overlap
+		match self.allowed.binary_search_by(|range| range.hi.cmp(&port)) {
+			Ok(_) => true,
+			Err(i) => self
+				.allowed
+				.get(i)
+				.map(|range| range.contains(port))
+				.unwrap_or(false),
+		}
+	}
+
+	/// Return true if the policy allows at least one port. A relay whose
+	/// policy allows none is no use as an exit, whatever its flags say.
+	pub fn allows_some_port(&self) -> bool {
+		!self.allowed.is_empty()
+	}
+}
+
+/// Write the policy as the shorter of its accept and reject forms.
+impl fmt::Display for PortPolicy {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let rejected = invert(&self.allowed);
+		let (keyword, ranges) = if self.allowed.is_empty() {
+			("reject", &rejected)
+		} else if rejected.is_empty() || self.allowed.len() <= rejected.len() {
+			("accept", &self.allowed)
+		} else {
+			("reject", &rejected)
+		};
+		let ranges: Vec<String> = ranges.iter().map(|range| range.to_string()).collect();
+		write!(f, "{} {}", keyword, ranges.join(","))
+	}
+}
+
+// sort the ranges and merge the ones that overlap or touch
+fn normalize(mut ranges: Vec<PortRange>) -> Vec<PortRange> {
+	ranges.sort_by_key(|range| range.lo);
+	let mut merged: Vec<PortRange> = vec![];
+	for range in ranges {
+		match merged.last_mut() {
+			Some(last) if range.lo as u32 <= last.hi as u32 + 1 => {
+				last.hi = last.hi.max(range.hi);
+			}
+			_ => merged.push(range),
+		}
+	}
+	merged
+}
+
+// the ports 1-65535 not in the normalized `ranges`
+fn invert(ranges: &[PortRange]) -> Vec<PortRange> {
+	let mut inverted = vec![];
+	let mut next: u32 = 1;
+	for range in ranges {
+		if (range.lo as u32) > next {
+			inverted.push(PortRange {
+				lo: next as u16,
+				hi: range.lo - 1,
+			});
+		}
+		next = range.hi as u32 + 1;
+	}
+	if next <= u16::MAX as u32 {
+		inverted.push(PortRange {
+			lo: next as u16,
+			hi: u16::MAX,
+		});
+	}
+	inverted
+}
+
+/// The addresses a rule applies to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AddrPattern {
+	/// "*": every address
+	Any,
+	/// "*4": every IPv4 address
+	AnyV4,
+	/// "*6": every IPv6 address
+	AnyV6,
+	/// an address and prefix length
+	Net(IpAddr, u8),
+}
+
+impl AddrPattern {
+	fn parse(s: &str) -> Result<AddrPattern, Error> {
+		let invalid = || err(&format!("invalid address pattern '{}'", s));
+		match s {
+			"*" => return Ok(AddrPattern::Any),
+			"*4" => return Ok(AddrPattern::AnyV4),
+			"*6" => return Ok(AddrPattern::AnyV6),
+			_ => {}
+		}
+		let mut parts = s.splitn(2, '/');
+		let addr = parts.next().unwrap_or("");
+		// IPv6 addresses must be in brackets, IPv4 ones must not
+		let addr: IpAddr = match addr.strip_prefix('[').and_then(|a| a.strip_suffix(']')) {
+			Some(addr) => IpAddr::V6(addr.parse().map_err(|_| invalid())?),
+			None => IpAddr::V4(addr.parse().map_err(|_| invalid())?),
+		};
+		let max = if addr.is_ipv4() { 32 } else { 128 };
+		let bits = match parts.next() {
+			Some(bits) => bits.parse().map_err(|_| invalid())?,
+			None => max,
+		};
+		if bits > max {
+			return Err(invalid());
+		}
+		Ok(AddrPattern::Net(addr, bits))
+	}
+
+	/// Return true if `addr` is one of the addresses this pattern covers.
+	pub fn matches(&self, addr: IpAddr) -> bool {
+		match self {
+			AddrPattern::Any => true,
+			AddrPattern::AnyV4 => addr.is_ipv4(),
+			AddrPattern::AnyV6 => addr.is_ipv6(),
+			AddrPattern::Net(net, bits) => match (net, addr) {
+				(IpAddr::V4(net), IpAddr::V4(addr)) => {
+					prefix_matches(&net.octets(), &addr.octets(), *bits)
+				}
+				(IpAddr::V6(net), IpAddr::V6(addr)) => {
+					prefix_matches(&net.octets(), &addr.octets(), *bits)
+				}
+				_ => false,
+			},
+		}
+	}
+}
+
+// true if the first `bits` bits of the two addresses are the same
+fn prefix_matches(a: &[u8], b: &[u8], bits: u8) -> bool {
+	let bytes = bits as usize / 8;
+	if a[..bytes] != b[..bytes] {
+		return false;
+	}
+	match bits % 8 {
+		0 => true,
+		rest => {
+			let mask = 0xffu8 << (8 - rest);
+			a[bytes] & mask == b[bytes] & mask
+		}
+	}
+}
+
+/// One "accept" or "reject" line of a full exit policy
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddrPolicyRule {
+	/// Whether matching connections are allowed rather than refused
+	pub accept: bool,
+	/// The addresses the rule applies to
+	pub addrs: AddrPattern,
+	/// The ports the rule applies to
+	pub ports: PortRange,
+}
+
+impl AddrPolicyRule {
+	/// Parse "accept" or "reject" followed by ADDR[/BITS]:PORT[-PORT],
+	/// where either side may be "*". "accept6" and "reject6" rules only
+	/// apply to IPv6 addresses, so "*" means "*6" there and IPv4 patterns
+	/// are refused.
+	pub fn parse(s: &str) -> Result<AddrPolicyRule, Error> {
+		let invalid = || err(&format!("invalid policy rule '{}'", s));
+		let mut words = s.split_whitespace();
+		let (accept, v6_only) = match words.next() {
+			Some("accept") => (true, false),
+			Some("accept6") => (true, true),
+			Some("reject") => (false, false),
+			Some("reject6") => (false, true),
+			_ => return Err(invalid()),
+		};
+		let pattern = match (words.next(), words.next()) {
+			(Some(pattern), None) => pattern,
+			_ => return Err(invalid()),
+		};
+		let colon = pattern.rfind(':').ok_or_else(invalid)?;
+		let mut addrs = AddrPattern::parse(&pattern[..colon])?;
+		if v6_only {
+			addrs = match addrs {
+				AddrPattern::Any | AddrPattern::AnyV6 => AddrPattern::AnyV6,
+				AddrPattern::Net(IpAddr::V6(_), _) => addrs,
+				_ => return Err(invalid()),
+			};
+		}
+		let ports = match &pattern[colon + 1..] {
+			"*" => PortRange {
+				lo: 1,
+				hi: u16::MAX,
+			},
+			ports => PortRange::parse(ports)?,
+		};
+		Ok(AddrPolicyRule {
+			accept,
+			addrs,
+			ports,
+		})
+	}
+
+	/// Return true if the rule applies to exiting to `addr` on `port`.
+	pub fn matches(&self, addr: IpAddr, port: u16) -> bool {
+		self.addrs.matches(addr) && self.ports.contains(port)
+	}
+}
+
+/// A full exit policy: rules on addresses and ports, as in the accept and
+/// reject lines of router descriptors. The first matching rule decides.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AddrPolicy {
+	/// The rules, in the order they are checked
+	pub rules: Vec<AddrPolicyRule>,
+}
+
+impl AddrPolicy {
+	/// Parse one rule per line. Empty lines are skipped.
+	pub fn parse(text: &str) -> Result<AddrPolicy, Error> {
+		let mut rules = vec![];
+		for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+			rules.push(AddrPolicyRule::parse(line)?);
+		}
+		Ok(AddrPolicy { rules })
+	}
+
+	/// Return true if the policy allows exiting to `addr` on `port`. An
+	/// address that no rule matches is allowed.
+	pub fn allows(&self, addr: IpAddr, port: u16) -> bool {
+		self.rules
+			.iter()
+			.find(|rule| rule.matches(addr, port))
+			.map(|rule| rule.accept)
+			.unwrap_or(true)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use proptest::prelude::*;
+	use std::net::{Ipv4Addr, Ipv6Addr};
+
+	#[test]
+	fn summary() {
+		let policy = PortPolicy::parse("accept 22,80,443,6660-6669").unwrap();
+		assert!(policy.allows_port(22));
+		assert!(policy.allows_port(6660));
+		assert!(policy.allows_port(6669));
+		assert!(!policy.allows_port(6670));
+		assert!(!policy.allows_port(21));
+		assert!(!policy.allows_port(1));
+
+		let policy = PortPolicy::parse("reject 1-24,26-65535").unwrap();
+		assert!(policy.allows_port(25));
+		assert!(!policy.allows_port(24));
+		assert!(!policy.allows_port(65535));
+		assert_eq!(policy.to_string(), "accept 25");
+
+		let policy = PortPolicy::parse("reject 1-65535").unwrap();
+		assert!(!policy.allows_some_port());
+		assert_eq!(policy, PortPolicy::reject_all());
+		assert_eq!(policy.to_string(), "reject 1-65535");
+
+		for bad in &[
+			"",
+			"accept",
+			"allow 80",
+			"accept 0",
+			"accept 80-22",
+			"accept 65536",
+			"accept 80, 443",
+			"accept 80,",
+		] {
+			assert!(PortPolicy::parse(bad).is_err(), "{} accepted", bad);
+		}
+	}
+
+	#[test]
+	fn full_policy() {
+		let policy = AddrPolicy::parse(
+			"reject 0.0.0.0/8:*
+			reject 10.0.0.0/8:*
+			reject [fc00::]/7:*
+			accept *:80
+			accept *4:443
+			accept 192.0.2.1:22-23
+			reject *:*",
+		)
+		.unwrap();
+		let v4 = |a, b, c, d| IpAddr::V4(Ipv4Addr::new(a, b, c, d));
+		assert!(policy.allows(v4(198, 51, 100, 1), 80));
+		assert!(!policy.allows(v4(10, 1, 2, 3), 80));
+		assert!(policy.allows(v4(11, 1, 2, 3), 443));
+		assert!(policy.allows(v4(192, 0, 2, 1), 23));
+		assert!(!policy.allows(v4(192, 0, 2, 2), 23));
+		assert!(!policy.allows(v4(192, 0, 2, 1), 24));
+
+		let v6: IpAddr = "2001:db8::1".parse().unwrap();
+		assert!(policy.allows(v6, 80));
+		assert!(!policy.allows(v6, 443));
+		assert!(!policy.allows("fd00::1".parse().unwrap(), 80));
+
+		// no matching rule means accept
+		assert!(AddrPolicy::default().allows(v6, 25));
+
+		// the 6 rules leave IPv4 alone
+		let policy = AddrPolicy::parse(
+			"reject6 [2001:db8::]/32:*
+			accept6 *:80
+			reject6 *:*",
+		)
+		.unwrap();
+		assert_eq!(policy.rules[1].addrs, AddrPattern::AnyV6);
+		assert!(!policy.allows(v6, 80));
+		assert!(policy.allows("2001:db9::1".parse().unwrap(), 80));
+		assert!(!policy.allows("2001:db9::1".parse().unwrap(), 443));
+		assert!(policy.allows(v4(198, 51, 100, 1), 443));
+
+		for bad in &[
+			"accept *",
+			"accept 1.2.3.4",
+			"accept 1.2.3.4/33:*",
+			"accept [::1]/129:*",
+			"accept 1.2.3.4:0",
+			"accept ::1:80",
+			"accept6 1.2.3.4:80",
+			"reject6 *4:*",
+			"permit *:*",
+		] {
+			assert!(AddrPolicyRule::parse(bad).is_err(), "{} accepted", bad);
+		}
+	}
+
+	fn port_range() -> impl Strategy<Value = PortRange> {
+		(1..=u16::MAX, 1..=u16::MAX).prop_map(|(a, b)| PortRange {
+			lo: a.min(b),
+			hi: a.max(b),
+		})
+	}
+
+	fn summary_text() -> impl Strategy<Value = (bool, Vec<PortRange>)> {
+		(any::<bool>(), prop::collection::vec(port_range(), 1..8))
+	}
+
+	fn format_summary(accept: bool, ranges: &[PortRange]) -> String {
+		let ranges: Vec<String> = ranges.iter().map(|range| range.to_string()).collect();
+		let keyword = if accept { "accept" } else { "reject" };
+		format!("{} {}", keyword, ranges.join(","))
+	}
+
+	fn addr() -> impl Strategy<Value = IpAddr> {
+		prop_oneof![
+			any::<u32>().prop_map(|a| IpAddr::V4(Ipv4Addr::from(a))),
+			any::<u128>().prop_map(|a| IpAddr::V6(Ipv6Addr::from(a))),
+		]
+	}
+
+	proptest! {
+		// a port is allowed exactly when it is listed in an accept summary,
+		// or not listed in a reject summary
+		#[test]
+		fn summary_matches_ranges((accept, ranges) in summary_text(), port in 1..=u16::MAX) {
+			let policy = PortPolicy::parse(&format_summary(accept, &ranges)).unwrap();
+			let listed = ranges.iter().any(|range| range.contains(port));
+			prop_assert_eq!(policy.allows_port(port), listed == accept);
+		}
+
+		// the ends of a range are inside it and their neighbours aren't
+		#[test]
+		fn range_ends(range in port_range()) {
+			let policy = PortPolicy::parse(&format_summary(true, &[range])).unwrap();
+			prop_assert!(policy.allows_port(range.lo));
+			prop_assert!(policy.allows_port(range.hi));
+			if range.lo > 1 {
+				prop_assert!(!policy.allows_port(range.lo - 1));
+			}
+			if range.hi < u16::MAX {
+				prop_assert!(!policy.allows_port(range.hi + 1));
+			}
+		}
+
+		#[test]
+		fn summary_round_trip((accept, ranges) in summary_text()) {
+			let policy = PortPolicy::parse(&format_summary(accept, &ranges)).unwrap();
+			prop_assert_eq!(PortPolicy::parse(&policy.to_string()).unwrap(), policy);
+		}
+
+		// accepting and rejecting the same ports are complements
+		#[test]
+		fn accept_reject_complement((_, ranges) in summary_text(), port in 1..=u16::MAX) {
+			let accept = PortPolicy::parse(&format_summary(true, &ranges)).unwrap();
+			let reject = PortPolicy::parse(&format_summary(false, &ranges)).unwrap();
+			prop_assert_ne!(accept.allows_port(port), reject.allows_port(port));
+		}
+
+		// whatever follows "accept *:*" or "reject *:*" never matters
+		#[test]
+		fn first_rule_wins(
+			accept in any::<bool>(),
+			rest in prop::collection::vec((any::<bool>(), port_range()), 0..5),
+			addr in addr(),
+			port in 1..=u16::MAX,
+		) {
+			let mut text = format!("{} *:*\n", if accept { "accept" } else { "reject" });
+			for (accept, ports) in rest {
+				let keyword = if accept { "accept" } else { "reject" };
+				text.push_str(&format!("{} *:{}\n", keyword, ports));
+			}
+			let policy = AddrPolicy::parse(&text).unwrap();
+			prop_assert_eq!(policy.allows(addr, port), accept);
+		}
+
+		// "reject6 *:*" only rejects IPv6, so IPv4 falls through to the
+		// rule after it
+		#[test]
+		fn v6_rules_skip_v4(addr in addr(), port in 1..=u16::MAX) {
+			let policy = AddrPolicy::parse("reject6 *:*\naccept *:*").unwrap();
+			prop_assert_eq!(policy.allows(addr, port), addr.is_ipv4());
+		}
+
+		// a specific accept before "reject *:*" allows just what it lists
+		#[test]
+		fn accept_then_reject(ports in port_range(), addr in addr(), port in 1..=u16::MAX) {
+			let policy = AddrPolicy::parse(&format!("accept *:{}\nreject *:*", ports)).unwrap();
+			prop_assert_eq!(policy.allows(addr, port), ports.contains(port));
+			let policy = AddrPolicy::parse(&format!("reject *:{}\naccept *:*", ports)).unwrap();
+			prop_assert_eq!(policy.allows(addr, port), !ports.contains(port));
+		}
+	}
+}
@@ -12,9 +12,11 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use tor_config::config::{get_config, TorConfig};
-use tor_tcp::circuit::build_circuit;
-use tor_tcp::ds_load::{build_ds_context, get_latest_valid_dsinfo, start_dsinfo_refresh_thread};
+use tor::{
+	get_config, start_http_listener, start_socks_listener, BootstrapReporter, TorClient, TorConfig,
+};
+use tor_tcp::ds_load::build_ds_context;
+use tor_tcp::ds_store;
 use tor_util as util;
 use util::logger::Log;
 use util::Error;
@@ -25,6 +27,7 @@ use chrono::Local;
 use chrono::Utc;
 use lazy_static::lazy_static;
 use num_format::{Locale, ToFormattedString};
+use std::net::{Ipv4Addr, SocketAddr};
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::sync::RwLock;
@@ -92,10 +95,24 @@ fn print_config(config: &TorConfig, mainlog: Arc<Mutex<Log>>) -> Result<(), Erro
 	)?;
 
 	show_param(
-		"ds_refresh_timeout",
+		"fallback_dirs.len",
+		&format!("{}", &config.fallback_dirs.len()),
+		mainlog.clone(),
+	)?;
+
+	show_param(
+		"authorities.len",
+		&format!("{}", &config.authorities.len()),
+		mainlog.clone(),
+	)?;
+
+	show_param(
+		"ds_validity_tolerance",
 		&format!(
 			"{} ms",
-			&config.ds_refresh_timeout.to_formatted_string(&Locale::en)
+			&config
+				.ds_validity_tolerance
+				.to_formatted_string(&Locale::en)
 		),
 		mainlog.clone(),
 	)?;
@@ -109,6 +126,27 @@ fn print_config(config: &TorConfig, mainlog: Arc<Mutex<Log>>) -> Result<(), Erro
 		mainlog.clone(),
 	)?;
 
+	show_param(
+		"bootstrap_timeout",
+		&format!(
+			"{} ms",
+			&config.bootstrap_timeout.to_formatted_string(&Locale::en)
+		),
+		mainlog.clone(),
+	)?;
+
+	show_param(
+		"socks_port",
+		&format!("{}", &config.socks_port),
+		mainlog.clone(),
+	)?;
+
+	show_param(
+		"http_port",
+		&format!("{}", &config.http_port),
+		mainlog.clone(),
+	)?;
+
 	show_param("mainlog", &config.mainlog, mainlog.clone())?;
 
 	show_param(
@@ -156,9 +194,32 @@ fn print_config(config: &TorConfig, mainlog: Arc<Mutex<Log>>) -> Result<(), Erro
 	Ok(())
 }
 
+// handle --inspect-store and --clear-store
+fn store_command(config: &TorConfig) -> Result<(), Error> {
+	let ds_context = build_ds_context(config)?;
+	if config.clear_store {
+		ds_store::clear(&ds_context)?;
+		println!("Directory store cleared.");
+	}
+	if config.inspect_store {
+		let summary = ds_store::inspect(&ds_context)?;
+		println!("schema version  : {}", summary.schema_version);
+		println!("hosts           : {:?}", summary.hosts);
+		println!("consensus bytes : {:?}", summary.consensus_size);
+		println!("microdescs      : {}", summary.microdescs);
+		println!("authority certs : {}", summary.authcerts);
+		println!("failing sources : {}", summary.failing_sources);
+		println!("guard state     : {}", summary.guard_state);
+		println!("timeout state   : {}", summary.timeout_state);
+	}
+	Ok(())
+}
+
 fn main_with_result() -> Result<(), Error> {
-	let stop_state = Arc::new(RwLock::new(StopState::new()));
 	let config = get_config()?;
+	if config.inspect_store || config.clear_store {
+		return store_command(&config);
+	}
 	//let mainlog = Arc::new(Mutex::new(Log::new()));
 	let mainlog = &MAINLOG;
 	{
@@ -175,9 +236,30 @@ fn main_with_result() -> Result<(), Error> {
 
 	print_config(&config, (*mainlog).clone())?;
 
-	let ds_context = build_ds_context(&config)?;
-	let ds_info = get_latest_valid_dsinfo(&config, &ds_context)?;
-	let runtime = Box::leak(Box::new(tor_rtcompat::create_runtime()?));
+	let runtime = tor_rtcompat::create_runtime()?;
+	let bootstrap = Arc::new(BootstrapReporter::new(mainlog));
+	let client = Arc::new(TorClient::bootstrap_with_reporter(
+		&config,
+		runtime.clone(),
+		bootstrap,
+	)?);
+
+	// the listeners run until we exit
+	let stop_state = Arc::new(RwLock::new(StopState::new()));
+	if config.socks_port != 0 {
+		let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, config.socks_port));
+		start_socks_listener(
+			runtime.clone(),
+			addr,
+			client.clone(),
+			mainlog,
+			stop_state.clone(),
+		)?;
+	}
+	if config.http_port != 0 {
+		let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, config.http_port));
+		start_http_listener(runtime, addr, client, mainlog, stop_state)?;
+	}
 
 	{
 		let mut mainlog = mainlog.lock()?;
@@ -187,35 +269,8 @@ fn main_with_result() -> Result<(), Error> {
 		(*mainlog).update_show_timestamp(true)?;
 	}
 
-	build_circuit(&ds_info, &mainlog, runtime)?;
-	start_dsinfo_refresh_thread(&config, stop_state.clone(), (*mainlog).clone())?;
-
-	{
-		let mut mainlog = mainlog.lock()?;
-		(*mainlog).log(&format!("Found {} hosts.", ds_info.hosts.len()))?;
-		for i in 0..10 {
-			(*mainlog).log(&format!("host[{}]={:?}.", i, ds_info.hosts[i]))?;
-		}
-	}
-
-	let mut count = 0;
+	// the client's and the listeners' threads do the work from here on
 	loop {
-		{
-			let mut mainlog = mainlog.lock()?;
-			(*mainlog).log("test1")?;
-			(*mainlog).log("test2")?;
-		}
-		std::thread::sleep(std::time::Duration::from_secs(5));
-		count += 1;
-		if count >= 3000 {
-			break;
-		}
+		std::thread::park();
 	}
-
-	{
-		let stop_state = stop_state.write()?;
-		stop_state.stop();
-	}
-	std::thread::sleep(std::time::Duration::from_secs(5));
-	Ok(())
 }
@@ -0,0 +1,28 @@
+// Copyright 2021 The BMW Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A Tor client for BitcoinMW. Bootstrap a [`TorClient`] from a
+//! [`TorConfig`] and a runtime, then open streams and look up names
+//! through it, or serve them to SOCKS and HTTP proxy clients with
+//! [`start_socks_listener`] and [`start_http_listener`].
+
+pub use tor_config::config::{get_config, TorConfig};
+pub use tor_rtcompat::{create_runtime, Runtime};
+pub use tor_tcp::bootstrap::{BootstrapPhase, BootstrapReporter, BootstrapStatus};
+pub use tor_tcp::circmgr::{IsolationToken, StreamIsolation};
+pub use tor_tcp::client::{DataStream, StreamError, TorClient};
+pub use tor_tcp::http::start_http_listener;
+pub use tor_tcp::proxy::StreamProvider;
+pub use tor_tcp::socks::start_socks_listener;
+pub use tor_util::{Error, ErrorKind};
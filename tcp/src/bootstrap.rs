@@ -0,0 +1,276 @@
+// Copyright 2021 The BMW Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! How far bootstrapping has got, for showing progress to users.
+
+use crate::circuit::log;
+use futures::channel::mpsc;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use tor_util::logger::Log;
+
+/// The steps of bootstrapping, in the order they happen
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootstrapPhase {
+	/// Nothing done yet
+	Starting,
+	/// Loading the consensus, or fetching it if we have none we can use
+	Directory,
+	/// Loading the microdescriptors the consensus lists
+	Microdescs,
+	/// Connecting to the first guard
+	Channel,
+	/// Building the first circuit
+	Circuit,
+	/// Ready to open streams
+	Done,
+}
+
+impl BootstrapPhase {
+	/// How far through bootstrapping starting this phase puts us, in
+	/// percent
+	pub fn percent(&self) -> u8 {
+		match self {
+			BootstrapPhase::Starting => 0,
+			BootstrapPhase::Directory => 5,
+			BootstrapPhase::Microdescs => 10,
+			BootstrapPhase::Channel => 80,
+			BootstrapPhase::Circuit => 90,
+			BootstrapPhase::Done => 100,
+		}
+	}
+}
+
+impl fmt::Display for BootstrapPhase {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let description = match self {
+			BootstrapPhase::Starting => "starting",
+			BootstrapPhase::Directory => "loading the directory",
+			BootstrapPhase::Microdescs => "loading relay descriptors",
+			BootstrapPhase::Channel => "connecting to a guard",
+			BootstrapPhase::Circuit => "building a circuit",
+			BootstrapPhase::Done => "done",
+		};
+		write!(f, "{}", description)
+	}
+}
+
+/// Where bootstrapping is, and what is holding it up if it is stuck
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BootstrapStatus {
+	/// The phase bootstrapping has reached
+	pub phase: BootstrapPhase,
+	/// How far along bootstrapping is, from 0 to 100
+	pub percent: u8,
+	/// Why bootstrapping can't carry on, if it can't
+	pub blocked: Option<String>,
+}
+
+impl BootstrapStatus {
+	/// Return true once the client is ready to use
+	pub fn is_done(&self) -> bool {
+		self.phase == BootstrapPhase::Done
+	}
+}
+
+impl fmt::Display for BootstrapStatus {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}%: {}", self.percent, self.phase)?;
+		if let Some(reason) = &self.blocked {
+			write!(f, " (blocked: {})", reason)?;
+		}
+		Ok(())
+	}
+}
+
+// the microdescriptors phase runs from its own percentage up to where the
+// next one starts, as more of them are loaded
+pub(crate) fn microdescs_percent(have: usize, listed: usize) -> u8 {
+	let start = BootstrapPhase::Microdescs.percent() as usize;
+	let end = BootstrapPhase::Channel.percent() as usize;
+	if listed == 0 {
+		return start as u8;
+	}
+	(start + (end - start - 1) * have / listed) as u8
+}
+
+struct Inner {
+	status: BootstrapStatus,
+	subscribers: Vec<mpsc::UnboundedSender<BootstrapStatus>>,
+}
+
+impl Inner {
+	fn notify(&mut self, status: BootstrapStatus) {
+		self.subscribers
+			.retain(|sender| sender.unbounded_send(status.clone()).is_ok());
+		self.status = status;
+	}
+}
+
+/// Keeps track of the bootstrap status, logs each change to it, and
+/// sends each change to whoever is watching.
+pub struct BootstrapReporter {
+	mainlog: &'static Arc<Mutex<Log>>,
+	inner: Mutex<Inner>,
+}
+
+impl BootstrapReporter {
+	/// A reporter that logs to `mainlog`, starting at 0%
+	pub fn new(mainlog: &'static Arc<Mutex<Log>>) -> BootstrapReporter {
+		BootstrapReporter {
+			mainlog,
+			inner: Mutex::new(Inner {
+				status: BootstrapStatus {
+					phase: BootstrapPhase::Starting,
+					percent: 0,
+					blocked: None,
+				},
+				subscribers: vec![],
+			}),
+		}
+	}
+
+	/// The log status changes are written to
+	pub fn mainlog(&self) -> &'static Arc<Mutex<Log>> {
+		self.mainlog
+	}
+
+	/// The status right now
+	pub fn status(&self) -> BootstrapStatus {
+		self.inner.lock().unwrap().status.clone()
+	}
+
+	/// A stream of status changes, starting with the status right now
+	pub fn events(&self) -> mpsc::UnboundedReceiver<BootstrapStatus> {
+		let (sender, receiver) = mpsc::unbounded();
+		let mut inner = self.inner.lock().unwrap();
+		// can't fail, the receiver is still here
+		let _ = sender.unbounded_send(inner.status.clone());
+		inner.subscribers.push(sender);
+		receiver
+	}
+
+	/// Move on to `phase`, at the phase's own percentage.
+	pub(crate) fn advance(&self, phase: BootstrapPhase) {
+		self.advance_to(phase, phase.percent());
+	}
+
+	/// Move on to `phase` at `percent`. Bootstrapping never goes backwards,
+	/// so anything short of where it already is is ignored, unless it was
+	/// blocked there.
+	pub(crate) fn advance_to(&self, phase: BootstrapPhase, percent: u8) {
+		let mut inner = self.inner.lock().unwrap();
+		let current = &inner.status;
+		if percent < current.percent || (percent == current.percent && current.blocked.is_none()) {
+			return;
+		}
+		let status = BootstrapStatus {
+			phase,
+			percent,
+			blocked: None,
+		};
+		log(self.mainlog, &format!("Bootstrapped {}", status));
+		inner.notify(status);
+	}
+
+	/// Note that bootstrapping is stuck where it is, and why.
+	pub(crate) fn block(&self, reason: &str) {
+		let mut inner = self.inner.lock().unwrap();
+		let status = BootstrapStatus {
+			blocked: Some(reason.to_string()),
+			..inner.status.clone()
+		};
+		log(self.mainlog, &format!("Bootstrap blocked at {}", status));
+		inner.notify(status);
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use futures::executor::block_on;
+	use futures::stream::StreamExt;
+
+	fn reporter() -> BootstrapReporter {
+		BootstrapReporter::new(Box::leak(Box::new(Arc::new(Mutex::new(Log::new())))))
+	}
+
+	#[test]
+	fn advance() {
+		let reporter = reporter();
+		assert_eq!(reporter.status().percent, 0);
+		reporter.advance(BootstrapPhase::Directory);
+		reporter.advance_to(BootstrapPhase::Microdescs, 40);
+		let status = reporter.status();
+		assert_eq!(status.phase, BootstrapPhase::Microdescs);
+		assert_eq!(status.percent, 40);
+		assert_eq!(status.to_string(), "40%: loading relay descriptors");
+
+		// never goes backwards
+		reporter.advance(BootstrapPhase::Microdescs);
+		assert_eq!(reporter.status().percent, 40);
+		reporter.advance(BootstrapPhase::Done);
+		reporter.advance(BootstrapPhase::Circuit);
+		assert!(reporter.status().is_done());
+	}
+
+	#[test]
+	fn microdescs() {
+		assert_eq!(microdescs_percent(0, 0), 10);
+		assert_eq!(microdescs_percent(0, 6000), 10);
+		assert_eq!(microdescs_percent(3000, 6000), 44);
+		// having them all still leaves the next phase to go
+		assert_eq!(microdescs_percent(6000, 6000), 79);
+	}
+
+	#[test]
+	fn block() {
+		let reporter = reporter();
+		reporter.advance(BootstrapPhase::Channel);
+		reporter.block("no usable guard");
+		let status = reporter.status();
+		assert_eq!(status.phase, BootstrapPhase::Channel);
+		assert_eq!(status.blocked, Some("no usable guard".to_string()));
+		assert_eq!(
+			status.to_string(),
+			"80%: connecting to a guard (blocked: no usable guard)"
+		);
+
+		// trying the same phase again clears it
+		reporter.advance(BootstrapPhase::Channel);
+		assert_eq!(reporter.status().blocked, None);
+	}
+
+	#[test]
+	fn events() {
+		let reporter = reporter();
+		reporter.advance(BootstrapPhase::Directory);
+		let events = reporter.events();
+		let dropped = reporter.events();
+		drop(dropped);
+		reporter.advance(BootstrapPhase::Channel);
+		reporter.block("connection refused");
+		reporter.advance(BootstrapPhase::Channel);
+		drop(reporter);
+
+		let events: Vec<(u8, bool)> = block_on(events.collect::<Vec<_>>())
+			.iter()
+			.map(|status| (status.percent, status.blocked.is_some()))
+			.collect();
+		assert_eq!(
+			events,
+			vec![(5, false), (80, false), (80, true), (80, false)]
+		);
+	}
+}
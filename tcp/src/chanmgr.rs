@@ -0,0 +1,503 @@
+// Copyright 2021 The BMW Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::channel::launch_channel;
+use futures::channel::oneshot;
+use futures::future::{BoxFuture, FutureExt, Shared};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tor_linkspec::{ChanTarget, OwnedChanTarget};
+use tor_llcrypto::pk::ed25519::Ed25519Identity;
+use tor_llcrypto::pk::rsa::RsaIdentity;
+use tor_proto::channel::Channel;
+use tor_rtcompat::Runtime;
+use tor_util::logger::Log;
+use tor_util::{Error, ErrorKind};
+
+// channels that carry no circuits are forgotten after this long without
+// being asked for
+const IDLE_TIMEOUT: Duration = Duration::from_secs(3 * 60);
+
+/// What the channel manager needs from a channel, so that it can be tested
+/// with fake ones
+pub trait AbstractChannel: Send + Sync + 'static {
+	/// The Ed25519 identity the relay proved during the handshake
+	fn peer_ed25519_id(&self) -> &Ed25519Identity;
+	/// Return an error unless the channel goes to the relay `target` names
+	fn check_match<T: ChanTarget + ?Sized>(&self, target: &T) -> tor_proto::Result<()>;
+	/// Return true if the channel is closed or closing, and so can't be
+	/// handed out any more
+	fn is_closing(&self) -> bool;
+}
+
+impl AbstractChannel for Channel {
+	fn peer_ed25519_id(&self) -> &Ed25519Identity {
+		Channel::peer_ed25519_id(self)
+	}
+	fn check_match<T: ChanTarget + ?Sized>(&self, target: &T) -> tor_proto::Result<()> {
+		Channel::check_match(self, target)
+	}
+	fn is_closing(&self) -> bool {
+		Channel::is_closing(self)
+	}
+}
+
+/// Opens channels to relays for the channel manager
+pub trait ChannelFactory: Send + Sync + 'static {
+	/// The kind of channel this factory opens
+	type Channel: AbstractChannel;
+	/// Open a channel to `target` and finish the handshake with it
+	fn build_channel(
+		&self,
+		target: &OwnedChanTarget,
+	) -> BoxFuture<'static, Result<Arc<Self::Channel>, Error>>;
+}
+
+/// Opens real channels with the runtime
+pub struct ChanBuilder<R: Runtime> {
+	runtime: R,
+	mainlog: &'static Arc<Mutex<Log>>,
+}
+
+impl<R: Runtime> ChannelFactory for ChanBuilder<R> {
+	type Channel = Channel;
+
+	fn build_channel(
+		&self,
+		target: &OwnedChanTarget,
+	) -> BoxFuture<'static, Result<Arc<Channel>, Error>> {
+		let runtime = self.runtime.clone();
+		let target = target.clone();
+		let mainlog = self.mainlog;
+		async move { launch_channel(&runtime, &target, mainlog).await }.boxed()
+	}
+}
+
+// a launch in progress, which callers wanting the same relay wait for
+// rather than connecting again
+type Pending<C> = Shared<oneshot::Receiver<Result<Arc<C>, ErrorKind>>>;
+
+enum ChanState<C> {
+	Open {
+		channel: Arc<C>,
+		last_used: Instant,
+	},
+	Building {
+		/// tells this launch apart from later ones to the same relay
+		attempt: u64,
+		pending: Pending<C>,
+	},
+}
+
+struct Channels<C> {
+	map: HashMap<RsaIdentity, ChanState<C>>,
+	next_attempt: u64,
+}
+
+enum Action<C> {
+	Wait(u64, Pending<C>),
+	Launch(u64, oneshot::Sender<Result<Arc<C>, ErrorKind>>),
+}
+
+/// Keeps one channel per relay and hands it out to everyone who asks for
+/// that relay. Channels are looked up by RSA identity, which every relay
+/// has; the Ed25519 identity, where the caller knows it, is confirmed with
+/// `check_match` before a channel is handed out.
+pub struct AbstractChanMgr<F: ChannelFactory> {
+	factory: F,
+	channels: Mutex<Channels<F::Channel>>,
+}
+
+impl<F: ChannelFactory> AbstractChanMgr<F> {
+	/// A manager with no channels yet, which opens them with `factory`
+	pub fn new(factory: F) -> AbstractChanMgr<F> {
+		AbstractChanMgr {
+			factory,
+			channels: Mutex::new(Channels {
+				map: HashMap::new(),
+				next_attempt: 0,
+			}),
+		}
+	}
+
+	/// Return the open channel to `target`, opening one if there is none.
+	/// Callers that ask for a relay while a channel to it is being opened
+	/// share that channel rather than opening their own.
+	pub async fn get_or_launch<T: ChanTarget + ?Sized>(
+		&self,
+		target: &T,
+		now: Instant,
+	) -> Result<Arc<F::Channel>, Error> {
+		let id = match target.rsa_identity() {
+			Some(id) => id,
+			None => {
+				return Err(
+					ErrorKind::TcpConnectError("relay has no RSA identity".to_string()).into(),
+				)
+			}
+		};
+
+		loop {
+			let action = {
+				let mut channels = self.channels.lock().unwrap();
+				match channels.map.get_mut(&id) {
+					Some(ChanState::Open { channel, last_used }) if !channel.is_closing() => {
+						check_identity(&**channel, target)?;
+						*last_used = now;
+						return Ok(channel.clone());
+					}
+					Some(ChanState::Building { attempt, pending }) => {
+						Action::Wait(*attempt, pending.clone())
+					}
+					_ => {
+						let attempt = channels.next_attempt;
+						channels.next_attempt += 1;
+						let (sender, receiver) = oneshot::channel();
+						channels.map.insert(
+							id,
+							ChanState::Building {
+								attempt,
+								pending: receiver.shared(),
+							},
+						);
+						Action::Launch(attempt, sender)
+					}
+				}
+			};
+
+			match action {
+				Action::Wait(attempt, pending) => match pending.await {
+					Ok(Ok(channel)) => {
+						check_identity(&*channel, target)?;
+						return Ok(channel);
+					}
+					Ok(Err(kind)) => return Err(kind.into()),
+					// the launching caller went away before finishing, so
+					// forget its launch and try again
+					Err(_) => self.forget(&id, attempt),
+				},
+				Action::Launch(attempt, sender) => {
+					let result = self
+						.factory
+						.build_channel(&OwnedChanTarget::from_chan_target(target))
+						.await;
+					{
+						let mut channels = self.channels.lock().unwrap();
+						let ours = matches!(
+							channels.map.get(&id),
+							Some(ChanState::Building { attempt: current, .. }) if *current == attempt
+						);
+						match &result {
+							Ok(channel) if ours => {
+								channels.map.insert(
+									id,
+									ChanState::Open {
+										channel: channel.clone(),
+										last_used: now,
+									},
+								);
+							}
+							Err(_) if ours => {
+								channels.map.remove(&id);
+							}
+							_ => {}
+						}
+					}
+					let _ = sender.send(match &result {
+						Ok(channel) => Ok(channel.clone()),
+						Err(e) => Err(e.kind()),
+					});
+					let channel = result?;
+					check_identity(&*channel, target)?;
+					return Ok(channel);
+				}
+			}
+		}
+	}
+
+	// drop a launch that will never finish, unless another has replaced it
+	fn forget(&self, id: &RsaIdentity, attempt: u64) {
+		let mut channels = self.channels.lock().unwrap();
+		if let Some(ChanState::Building {
+			attempt: current, ..
+		}) = channels.map.get(id)
+		{
+			if *current == attempt {
+				channels.map.remove(id);
+			}
+		}
+	}
+
+	/// Forget channels that have closed, and ones that carry no circuits
+	/// and haven't been asked for lately. Dropping the last reference to a
+	/// channel closes it.
+	pub fn expire_channels(&self, now: Instant) {
+		self.channels
+			.lock()
+			.unwrap()
+			.map
+			.retain(|_, state| match state {
+				ChanState::Open { channel, last_used } => {
+					!channel.is_closing()
+						&& (Arc::strong_count(channel) > 1
+							|| now.saturating_duration_since(*last_used) < IDLE_TIMEOUT)
+				}
+				ChanState::Building { .. } => true,
+			});
+	}
+
+	/// How many channels are open or being opened
+	pub fn len(&self) -> usize {
+		self.channels.lock().unwrap().map.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+}
+
+// make sure a channel goes to the relay the caller asked for. Relays that
+// don't list an Ed25519 identity are matched by RSA identity alone.
+fn check_identity<C: AbstractChannel, T: ChanTarget + ?Sized>(
+	channel: &C,
+	target: &T,
+) -> Result<(), Error> {
+	let mut expected = OwnedChanTarget::from_chan_target(target);
+	if expected.ed_identity().is_none() {
+		expected.set_ed_identity(*channel.peer_ed25519_id());
+	}
+	channel
+		.check_match(&expected)
+		.map_err(|e| ErrorKind::TcpConnectError(e.to_string()).into())
+}
+
+/// A channel manager that opens real channels with the runtime
+pub struct ChanMgr<R: Runtime> {
+	runtime: R,
+	mgr: AbstractChanMgr<ChanBuilder<R>>,
+}
+
+impl<R: Runtime> ChanMgr<R> {
+	pub fn new(runtime: R, mainlog: &'static Arc<Mutex<Log>>) -> ChanMgr<R> {
+		ChanMgr {
+			mgr: AbstractChanMgr::new(ChanBuilder {
+				runtime: runtime.clone(),
+				mainlog,
+			}),
+			runtime,
+		}
+	}
+
+	/// Return the open channel to `target`, opening one if there is none.
+	pub async fn get_or_launch<T: ChanTarget + ?Sized>(
+		&self,
+		target: &T,
+	) -> Result<Arc<Channel>, Error> {
+		self.mgr.get_or_launch(target, self.runtime.now()).await
+	}
+
+	/// Forget closed and idle channels.
+	pub fn expire_channels(&self) {
+		self.mgr.expire_channels(self.runtime.now());
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use futures::executor::block_on;
+	use futures::future::join;
+	use std::future::Future;
+	use std::pin::Pin;
+	use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+	use std::task::{Context, Poll};
+
+	struct FakeChannel {
+		ed_id: Ed25519Identity,
+		rsa_id: RsaIdentity,
+		closing: AtomicBool,
+	}
+
+	impl AbstractChannel for FakeChannel {
+		fn peer_ed25519_id(&self) -> &Ed25519Identity {
+			&self.ed_id
+		}
+		fn check_match<T: ChanTarget + ?Sized>(&self, target: &T) -> tor_proto::Result<()> {
+			if target.ed_identity() != Some(self.ed_id)
+				|| target.rsa_identity() != Some(self.rsa_id)
+			{
+				return Err(tor_proto::Error::ChanMismatch("wrong relay".to_string()));
+			}
+			Ok(())
+		}
+		fn is_closing(&self) -> bool {
+			self.closing.load(Ordering::SeqCst)
+		}
+	}
+
+	// gives other futures a turn, so that launches overlap
+	struct YieldOnce(bool);
+
+	impl Future for YieldOnce {
+		type Output = ();
+		fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+			if self.0 {
+				Poll::Ready(())
+			} else {
+				self.0 = true;
+				cx.waker().wake_by_ref();
+				Poll::Pending
+			}
+		}
+	}
+
+	// connects to every relay as the one with Ed25519 identity [n; 32],
+	// where n is the first byte of its RSA identity
+	#[derive(Default)]
+	struct FakeFactory {
+		launches: Arc<AtomicUsize>,
+		fail: Arc<AtomicBool>,
+	}
+
+	impl ChannelFactory for FakeFactory {
+		type Channel = FakeChannel;
+
+		fn build_channel(
+			&self,
+			target: &OwnedChanTarget,
+		) -> BoxFuture<'static, Result<Arc<FakeChannel>, Error>> {
+			self.launches.fetch_add(1, Ordering::SeqCst);
+			let rsa_id = target.rsa_identity().unwrap();
+			let fail = self.fail.load(Ordering::SeqCst);
+			async move {
+				YieldOnce(false).await;
+				if fail {
+					return Err(ErrorKind::TcpConnectError("refused".to_string()).into());
+				}
+				Ok(Arc::new(FakeChannel {
+					ed_id: [rsa_id.as_bytes()[0]; 32].into(),
+					rsa_id,
+					closing: AtomicBool::new(false),
+				}))
+			}
+			.boxed()
+		}
+	}
+
+	fn target(n: u8, ed_id: Option<u8>) -> OwnedChanTarget {
+		OwnedChanTarget::new(
+			vec!["127.0.0.1:9001".parse().unwrap()],
+			ed_id.map(|ed_id| [ed_id; 32].into()),
+			Some([n; 20].into()),
+		)
+	}
+
+	fn mgr() -> (
+		AbstractChanMgr<FakeFactory>,
+		Arc<AtomicUsize>,
+		Arc<AtomicBool>,
+	) {
+		let factory = FakeFactory::default();
+		let launches = factory.launches.clone();
+		let fail = factory.fail.clone();
+		(AbstractChanMgr::new(factory), launches, fail)
+	}
+
+	#[test]
+	fn reuse() {
+		let (mgr, launches, _) = mgr();
+		let now = Instant::now();
+		let a = block_on(mgr.get_or_launch(&target(1, Some(1)), now)).unwrap();
+		let b = block_on(mgr.get_or_launch(&target(1, None), now)).unwrap();
+		assert!(Arc::ptr_eq(&a, &b));
+		assert_eq!(launches.load(Ordering::SeqCst), 1);
+
+		let c = block_on(mgr.get_or_launch(&target(2, None), now)).unwrap();
+		assert!(!Arc::ptr_eq(&a, &c));
+		assert_eq!(launches.load(Ordering::SeqCst), 2);
+		assert_eq!(mgr.len(), 2);
+
+		// a closed channel is replaced
+		a.closing.store(true, Ordering::SeqCst);
+		let d = block_on(mgr.get_or_launch(&target(1, None), now)).unwrap();
+		assert!(!Arc::ptr_eq(&a, &d));
+		assert_eq!(launches.load(Ordering::SeqCst), 3);
+	}
+
+	#[test]
+	fn coalesce() {
+		let (mgr, launches, _) = mgr();
+		let now = Instant::now();
+		let first = target(1, None);
+		let second = target(1, Some(1));
+		let (a, b) = block_on(join(
+			mgr.get_or_launch(&first, now),
+			mgr.get_or_launch(&second, now),
+		));
+		assert!(Arc::ptr_eq(&a.unwrap(), &b.unwrap()));
+		assert_eq!(launches.load(Ordering::SeqCst), 1);
+
+		// a failed launch fails everyone waiting on it, and is tried again
+		// on the next request
+		let (mgr, launches, fail) = self::mgr();
+		fail.store(true, Ordering::SeqCst);
+		let (a, b) = block_on(join(
+			mgr.get_or_launch(&first, now),
+			mgr.get_or_launch(&second, now),
+		));
+		assert!(a.is_err());
+		assert!(b.is_err());
+		assert_eq!(launches.load(Ordering::SeqCst), 1);
+		assert!(mgr.is_empty());
+		fail.store(false, Ordering::SeqCst);
+		assert!(block_on(mgr.get_or_launch(&first, now)).is_ok());
+		assert_eq!(launches.load(Ordering::SeqCst), 2);
+	}
+
+	#[test]
+	fn identity() {
+		let (mgr, _, _) = mgr();
+		let now = Instant::now();
+		// the relay at [3; 20] turns out to have a different Ed25519
+		// identity than we were told
+		assert!(block_on(mgr.get_or_launch(&target(3, Some(4)), now)).is_err());
+		assert!(block_on(mgr.get_or_launch(&target(3, Some(3)), now)).is_ok());
+		assert!(block_on(mgr.get_or_launch(&target(3, Some(4)), now)).is_err());
+		let no_rsa = OwnedChanTarget::new(vec![], Some([3; 32].into()), None);
+		assert!(block_on(mgr.get_or_launch(&no_rsa, now)).is_err());
+	}
+
+	#[test]
+	fn expiry() {
+		let (mgr, _, _) = mgr();
+		let start = Instant::now();
+		let held = block_on(mgr.get_or_launch(&target(1, None), start)).unwrap();
+		drop(block_on(mgr.get_or_launch(&target(2, None), start)).unwrap());
+		let closing = block_on(mgr.get_or_launch(&target(3, None), start)).unwrap();
+
+		mgr.expire_channels(start + Duration::from_secs(60));
+		assert_eq!(mgr.len(), 3);
+		closing.closing.store(true, Ordering::SeqCst);
+		mgr.expire_channels(start + Duration::from_secs(60));
+		assert_eq!(mgr.len(), 2);
+
+		// the idle channel goes, the one still in use stays
+		mgr.expire_channels(start + IDLE_TIMEOUT);
+		assert_eq!(mgr.len(), 1);
+		drop(held);
+		mgr.expire_channels(start + IDLE_TIMEOUT);
+		assert!(mgr.is_empty());
+	}
+}
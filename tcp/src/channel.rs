@@ -12,194 +12,144 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::circuit::log;
+use crate::happy_eyeballs::{race, AddrFamilies, ATTEMPT_DELAY};
+use futures::task::SpawnExt;
+use lazy_static::lazy_static;
+use std::fmt;
 use std::net::SocketAddr;
-use std::sync::Arc;
-use std::sync::Mutex;
-use tor_linkspec::OwnedChanTarget;
-use tor_proto::channel::Channel;
-use tor_proto::channel::ChannelBuilder;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tor_linkspec::{ChanTarget, OwnedChanTarget};
+use tor_proto::channel::{Channel, ChannelBuilder, ChannelReactor};
 use tor_rtcompat::tls::TlsConnector;
-use tor_rtcompat::CertifiedConn;
-use tor_rtcompat::Runtime;
+use tor_rtcompat::{CertifiedConn, Runtime, SleepProviderExt};
 use tor_util::logger::Log;
 use tor_util::{Error, ErrorKind};
 
-use futures::task::SpawnExt;
-use tokio::sync::mpsc;
+// how long one address gets to finish the TLS and link handshakes
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+lazy_static! {
+	// how connections over each address family have been going
+	static ref ADDR_FAMILIES: Mutex<AddrFamilies> = Mutex::new(AddrFamilies::default());
+}
 
-pub struct TorChannel {
-	pub channel: Option<Arc<Channel>>,
-	pub error: Option<Error>,
+/// Why a channel couldn't be opened
+#[derive(Debug, Clone)]
+pub enum ChannelError {
+	/// The relay has no addresses to connect to
+	NoAddresses,
+	/// The TCP connection or TLS handshake failed
+	Io(SocketAddr, Arc<std::io::Error>),
+	/// The relay didn't present a TLS certificate
+	NoPeerCert(SocketAddr),
+	/// The link handshake failed, or the relay isn't the one we wanted
+	Proto(SocketAddr, tor_proto::Error),
+	/// The handshakes didn't finish in time
+	Timeout(SocketAddr, Duration),
 }
 
-impl TorChannel {
-	pub fn new() -> TorChannel {
-		TorChannel {
-			channel: None,
-			error: None,
+impl fmt::Display for ChannelError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			ChannelError::NoAddresses => write!(f, "relay has no addresses"),
+			ChannelError::Io(addr, e) => write!(f, "could not connect to {}: {}", addr, e),
+			ChannelError::NoPeerCert(addr) => write!(f, "{} sent no TLS certificate", addr),
+			ChannelError::Proto(addr, e) => write!(f, "handshake with {} failed: {}", addr, e),
+			ChannelError::Timeout(addr, timeout) => {
+				write!(f, "{} did not answer within {:?}", addr, timeout)
+			}
 		}
 	}
 }
 
-fn connect(
-	host: String,
-	port: u16,
-	runtime: &mut impl Runtime,
-	mainlog: &'static Arc<Mutex<Log>>,
-	timeout: u64,
-	channel_wrapper: Arc<Mutex<TorChannel>>,
-) -> Result<(), Error> {
-	let addr: SocketAddr = format!("{}:{}", host, port,).parse::<SocketAddr>()?.into();
-	let mut cb = ChannelBuilder::new();
-	cb.set_declared_addr(addr);
-	let tls_connector = runtime.tls_connector();
-	let (tx, mut rx) = mpsc::channel(1);
-	let tx_arc = Arc::new(tx);
-	let tx_arc_clone = tx_arc.clone();
+impl std::error::Error for ChannelError {}
 
-	runtime.spawn(async move {
-		std::thread::sleep(std::time::Duration::from_millis(timeout));
-		let _ = tx_arc.send(None).await;
-	})?;
+impl From<ChannelError> for Error {
+	fn from(e: ChannelError) -> Error {
+		ErrorKind::TcpConnectError(e.to_string()).into()
+	}
+}
 
-	let channel_wrapper_clone = channel_wrapper.clone();
-	runtime.spawn(async move {
-		{
-			let mut mainlog = mainlog.lock().unwrap();
-			let _ = mainlog
-				.log(&format!("connecting to: {}", addr))
-				.map_err(|e| {
-					println!("Logging error: {}", e.to_string(),);
-				});
-		}
-		let tls = tls_connector
-			.connect_unvalidated(&addr, "ignored")
-			.await
-			.map_err(|e| {
-				let mut mainlog = mainlog.lock().unwrap();
-				let _ = mainlog
-					.log(&format!("connect error: {}", e.to_string()))
-					.map_err(|e| {
-						println!("Logging error: {}", e.to_string(),);
-					});
-			})
-			.ok();
+// open a channel to one of the target's addresses, checking that the relay
+// there has the target's identities
+async fn connect_addr<R: Runtime>(
+	runtime: &R,
+	addr: SocketAddr,
+	target: &OwnedChanTarget,
+) -> Result<(Arc<Channel>, ChannelReactor<R::TlsStream>), ChannelError> {
+	let io = |e| ChannelError::Io(addr, Arc::new(e));
+	let proto = |e| ChannelError::Proto(addr, e);
 
-		if tls.is_none() {
-			{
-				let mut channel_wrapper = channel_wrapper_clone.lock().unwrap();
-				channel_wrapper.error =
-					Some(ErrorKind::TcpConnectError("no tls connector".to_string()).into());
-			}
-			let _ = tx_arc_clone.send(None).await;
-		} else {
-			let tls = tls.unwrap();
-			let peer_cert = tls.peer_certificate();
-			if peer_cert.is_err() {
-				{
-					let mut channel_wrapper = channel_wrapper_clone.lock().unwrap();
-					channel_wrapper.error =
-						Some(ErrorKind::TcpConnectError("peer_cert error".to_string()).into());
-				}
-				let _ = tx_arc_clone.send(None).await;
-			} else {
-				let peer_cert = peer_cert.unwrap();
-				if peer_cert.is_none() {
-					{
-						let mut channel_wrapper = channel_wrapper_clone.lock().unwrap();
-						channel_wrapper.error = Some(
-							ErrorKind::TcpConnectError("peer_cert is_none".to_string()).into(),
-						);
-					}
-					let _ = tx_arc_clone.send(None).await;
-				} else {
-					let peer_cert = peer_cert.unwrap();
-					let outboundhs = cb.launch(tls);
-					let channel = outboundhs.connect();
-					let channel = channel.await;
-					if channel.is_err() {
-						{
-							let mut channel_wrapper = channel_wrapper_clone.lock().unwrap();
-							channel_wrapper.error = Some(
-								ErrorKind::TcpConnectError("channel error".to_string()).into(),
-							);
-						}
-						let _ = tx_arc_clone.send(None).await;
-					} else {
-						let channel = channel.unwrap();
-						let mut target = OwnedChanTarget::new(vec![addr], None, None);
-						let channel = channel.check(&mut target, &peer_cert, None);
-						if channel.is_err() {
-							{
-								let mut channel_wrapper = channel_wrapper_clone.lock().unwrap();
-								channel_wrapper.error = Some(
-									ErrorKind::TcpConnectError("channel error".to_string()).into(),
-								);
-							}
-							let _ = tx_arc_clone.send(None).await;
-						} else {
-							let channel = channel.unwrap();
-							let res = channel.finish().await;
-							if res.is_err() {
-								{
-									let mut channel_wrapper = channel_wrapper_clone.lock().unwrap();
-									channel_wrapper.error = Some(
-										ErrorKind::TcpConnectError(
-											"channel finish error".to_string(),
-										)
-										.into(),
-									);
-								}
-								let _ = tx_arc_clone.send(None).await;
-							} else {
-								let (channel, reactor) = res.unwrap();
-								let _ = tx_arc_clone.send(Some((channel, reactor))).await;
-							}
-						}
-					}
-				}
-			}
-		}
-	})?;
+	let tls = runtime
+		.tls_connector()
+		.connect_unvalidated(&addr, "ignored")
+		.await
+		.map_err(io)?;
+	let peer_cert = tls
+		.peer_certificate()
+		.map_err(io)?
+		.ok_or(ChannelError::NoPeerCert(addr))?;
 
-	runtime.block_on(async {
-		let res = rx.recv().await;
-		if res.is_some() {
-			let res = res.unwrap();
-			if res.is_some() {
-				let (channel, reactor) = res.unwrap();
-				let _ = runtime.spawn(async {
-					let x = reactor.run().await;
-				});
-				let mut channel_wrapper = channel_wrapper.lock().unwrap();
-				channel_wrapper.channel = Some(channel);
-			} else {
-				let mut channel_wrapper = channel_wrapper.lock().unwrap();
-				channel_wrapper.channel = None;
-			}
-		}
-	});
+	let mut builder = ChannelBuilder::new();
+	builder.set_declared_addr(addr);
+	let unverified = builder.launch(tls).connect().await.map_err(proto)?;
+	// identities the target doesn't know are filled in from the handshake
+	let mut expected = target.clone();
+	let verified = unverified
+		.check(&mut expected, &peer_cert, Some(runtime.wallclock()))
+		.map_err(proto)?;
+	verified.finish().await.map_err(proto)
+}
 
-	{
-		let mut channel_wrapper = channel_wrapper.lock().unwrap();
-		if channel_wrapper.channel.is_none() && channel_wrapper.error.is_none() {
-			// it means it was a timeout so mark error
-			channel_wrapper.error =
-				Some(ErrorKind::TcpConnectError("connect timeout".to_string()).into());
+/// Open a channel to a relay, racing its addresses as RFC 8305 does: the
+/// next address is tried when the last attempt fails or has taken longer
+/// than `ATTEMPT_DELAY`, and the first channel to open wins. Addresses of
+/// the family that has been working from this host are tried first. The
+/// returned reactor must be run for the channel to work.
+pub async fn connect_channel<R: Runtime>(
+	runtime: &R,
+	target: &OwnedChanTarget,
+) -> Result<(Arc<Channel>, ChannelReactor<R::TlsStream>), ChannelError> {
+	let addrs = ADDR_FAMILIES.lock().unwrap().sort(target.addrs());
+	let attempt = |addr| async move {
+		match runtime
+			.timeout(CONNECT_TIMEOUT, connect_addr(runtime, addr, target))
+			.await
+		{
+			Ok(result) => result,
+			Err(_) => Err(ChannelError::Timeout(addr, CONNECT_TIMEOUT)),
 		}
-	}
-	Ok(())
+	};
+	race(
+		addrs,
+		ATTEMPT_DELAY,
+		|delay| runtime.sleep(delay),
+		attempt,
+		&ADDR_FAMILIES,
+	)
+	.await
+	.map_err(|mut errors| errors.pop().unwrap_or(ChannelError::NoAddresses))
 }
 
-pub fn build_channel(
-	host: String,
-	port: u16,
-	runtime: &mut impl Runtime,
+/// Open a channel to a relay and start its reactor.
+pub async fn launch_channel<R: Runtime>(
+	runtime: &R,
+	target: &OwnedChanTarget,
 	mainlog: &'static Arc<Mutex<Log>>,
-	connect_timeout: u64,
-	tor_channel: Arc<Mutex<TorChannel>>,
-) -> Result<(), Error> {
-	connect(host, port, runtime, mainlog, connect_timeout, tor_channel)?;
-
-	Ok(())
+) -> Result<Arc<Channel>, Error> {
+	log(mainlog, &format!("connecting to: {:?}", target.addrs()));
+	let (channel, reactor) = match connect_channel(runtime, target).await {
+		Ok(opened) => opened,
+		Err(e) => {
+			log(mainlog, &format!("connect error: {}", e));
+			return Err(e.into());
+		}
+	};
+	runtime.spawn(async move {
+		let _ = reactor.run().await;
+	})?;
+	log(mainlog, &format!("connect complete: {:?}", channel));
+	Ok(channel)
 }
@@ -0,0 +1,1183 @@
+// Copyright 2021 The BMW Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::bootstrap::{BootstrapPhase, BootstrapReporter};
+use crate::chanmgr::ChanMgr;
+use crate::circuit::{create_firsthop, extend_hops, log};
+use crate::ds_load::{get_netdir, store_guards, store_timeouts, DSContext};
+use crate::guard::GuardMgr;
+use crate::netdir::NetDir;
+use crate::path::{pick_path, NodeRestrictions};
+use crate::timeout::{CbtParams, TimeoutEstimator};
+use futures::channel::oneshot;
+use futures::future::{BoxFuture, FutureExt};
+use futures::task::{Spawn, SpawnExt};
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use tor_config::config::TorConfig;
+use tor_linkspec::OwnedCircTarget;
+use tor_netdoc::policy::PortPolicy;
+use tor_proto::channel::Channel;
+use tor_proto::circuit::ClientCirc;
+use tor_rtcompat::{Runtime, SleepProvider, SleepProviderExt};
+use tor_util::logger::Log;
+use tor_util::StopState;
+use tor_util::{Error, ErrorKind};
+
+// ports we expect to be asked for before anything has been (path-spec 2.1.1)
+const DEFAULT_PREDICTED_PORTS: &[u16] = &[80];
+// a port stays predicted for this long after it was last asked for
+const PREDICTION_LIFETIME: Duration = Duration::from_secs(60 * 60);
+// connections to these ports tend to stay open, so their exits must be
+// Stable (path-spec 2.2)
+const LONG_LIVED_PORTS: &[u16] = &[
+	21, 22, 706, 1863, 5050, 5190, 5222, 5223, 6523, 6667, 6697, 8300,
+];
+// how often the pool thread reloads the directory and saves guard state
+const NETDIR_RELOAD: Duration = Duration::from_secs(10 * 60);
+// how long the pool thread waits after a failed build
+const PREEMPTIVE_RETRY: Duration = Duration::from_secs(10);
+
+/// What a circuit is wanted for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CircUsage {
+	/// Connecting to the given ports through an exit
+	Exit(Vec<u16>),
+	/// Reaching onion services. These are three hop circuits that are
+	/// never used for exit traffic.
+	OnionService,
+}
+
+/// What an open circuit can be used for
+pub enum CircKind {
+	/// Connecting through an exit with this policy
+	Exit(PortPolicy),
+	/// Reaching onion services
+	OnionService,
+}
+
+impl CircKind {
+	fn supports(&self, usage: &CircUsage) -> bool {
+		match (self, usage) {
+			(CircKind::Exit(policy), CircUsage::Exit(ports)) => {
+				ports.iter().all(|port| policy.allows_port(*port))
+			}
+			(CircKind::OnionService, CircUsage::OnionService) => true,
+			_ => false,
+		}
+	}
+}
+
+/// A caller-chosen tag for circuit requests. Requests with different
+/// tokens never share a circuit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IsolationToken(u64);
+
+impl IsolationToken {
+	/// A token that no other call has returned
+	pub fn new() -> IsolationToken {
+		static NEXT: AtomicU64 = AtomicU64::new(1);
+		IsolationToken(NEXT.fetch_add(1, Ordering::Relaxed))
+	}
+
+	/// The token of requests that don't ask to be isolated by token
+	pub fn no_isolation() -> IsolationToken {
+		IsolationToken(0)
+	}
+}
+
+impl Default for IsolationToken {
+	fn default() -> IsolationToken {
+		IsolationToken::no_isolation()
+	}
+}
+
+/// What a circuit request must be kept apart from. Two requests may share
+/// a circuit only if they agree on everything set here, so a request that
+/// sets nothing shares with every other such request.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StreamIsolation {
+	token: IsolationToken,
+	dest_port: Option<u16>,
+	dest_addr: Option<String>,
+	socks_auth: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+impl StreamIsolation {
+	pub fn new() -> StreamIsolation {
+		StreamIsolation::default()
+	}
+
+	/// Isolate by a caller-chosen token
+	pub fn token(mut self, token: IsolationToken) -> StreamIsolation {
+		self.token = token;
+		self
+	}
+
+	/// Isolate by destination port
+	pub fn dest_port(mut self, port: u16) -> StreamIsolation {
+		self.dest_port = Some(port);
+		self
+	}
+
+	/// Isolate by destination hostname or address
+	pub fn dest_addr(mut self, addr: &str) -> StreamIsolation {
+		self.dest_addr = Some(addr.to_lowercase());
+		self
+	}
+
+	/// Isolate by the username and password given to the SOCKS proxy
+	pub fn socks_auth(mut self, username: &[u8], password: &[u8]) -> StreamIsolation {
+		self.socks_auth = Some((username.to_vec(), password.to_vec()));
+		self
+	}
+
+	/// Whether a request with this isolation may share a circuit with one
+	/// with `other`
+	pub fn compatible(&self, other: &StreamIsolation) -> bool {
+		self == other
+	}
+}
+
+/// What the circuit manager needs from a circuit, so that it can be
+/// tested with fake ones
+pub trait PoolCirc: Send + Sync + 'static {
+	fn is_closing(&self) -> bool;
+	fn terminate(&self) -> BoxFuture<'_, ()>;
+}
+
+impl PoolCirc for ClientCirc {
+	fn is_closing(&self) -> bool {
+		ClientCirc::is_closing(self)
+	}
+	fn terminate(&self) -> BoxFuture<'_, ()> {
+		ClientCirc::terminate(self).boxed()
+	}
+}
+
+struct OpenCirc<C> {
+	circ: Arc<C>,
+	kind: CircKind,
+	/// when it was first handed out. Circuits that have never been used
+	/// are clean.
+	dirty_since: Option<Instant>,
+	/// the isolation of the requests it was handed out for. Clean
+	/// circuits take on that of the first.
+	isolation: Option<StreamIsolation>,
+}
+
+/// How many circuits to keep ready and how long to use them
+#[derive(Debug, Clone)]
+pub struct CircMgrConfig {
+	/// clean exit circuits to keep open
+	pub pool_size: usize,
+	/// how long after it was first used a circuit may still be given out
+	pub max_dirtiness: Duration,
+}
+
+impl CircMgrConfig {
+	pub fn from_config(config: &TorConfig) -> CircMgrConfig {
+		CircMgrConfig {
+			pool_size: config.circuit_pool_size as usize,
+			max_dirtiness: Duration::from_millis(config.max_circuit_dirtiness),
+		}
+	}
+}
+
+struct State<C> {
+	circs: Vec<OpenCirc<C>>,
+	/// ports asked for, and when they were last asked for
+	predicted: Vec<(u16, Instant)>,
+}
+
+impl<C> Default for State<C> {
+	fn default() -> State<C> {
+		State {
+			circs: vec![],
+			predicted: vec![],
+		}
+	}
+}
+
+impl<C: PoolCirc> State<C> {
+	// hand out an open circuit that can serve `usage` and carries nothing
+	// `isolation` must be kept apart from, preferring ones already in use
+	fn take(
+		&mut self,
+		usage: &CircUsage,
+		isolation: &StreamIsolation,
+		now: Instant,
+	) -> Option<Arc<C>> {
+		let open = self
+			.circs
+			.iter_mut()
+			.filter(|open| open.kind.supports(usage))
+			.filter(|open| match &open.isolation {
+				Some(used_by) => used_by.compatible(isolation),
+				None => true,
+			})
+			.max_by_key(|open| open.dirty_since.is_some())?;
+		open.dirty_since.get_or_insert(now);
+		open.isolation.get_or_insert_with(|| isolation.clone());
+		Some(open.circ.clone())
+	}
+
+	// a circuit was had for `usage`, so its ports are worth keeping
+	// circuits ready for
+	fn predict(&mut self, usage: &CircUsage, now: Instant) {
+		if let CircUsage::Exit(ports) = usage {
+			self.predicted.retain(|(port, _)| !ports.contains(port));
+			self.predicted.extend(ports.iter().map(|port| (*port, now)));
+		}
+	}
+
+	fn retire(&mut self, now: Instant, max_dirtiness: Duration) {
+		self.circs.retain(|open| {
+			!open.circ.is_closing()
+				&& open
+					.dirty_since
+					.map(|since| now.duration_since(since) < max_dirtiness)
+					.unwrap_or(true)
+		});
+	}
+}
+
+// a circuit being built, and what it will be usable for
+type Building<'a, C> = BoxFuture<'a, Result<(Arc<C>, CircKind), Error>>;
+
+/// Builds circuits for the circuit manager
+pub trait CircuitFactory: Send + Sync + 'static {
+	type Circ: PoolCirc;
+	/// Build a circuit for `usage`, and say what it can be used for
+	fn build_circuit<'a>(&'a self, usage: &'a CircUsage) -> Building<'a, Self::Circ>;
+}
+
+/// Builds real circuits from the current directory, through our guards
+/// and with the learned build timeouts
+pub struct CircBuilder<R: Runtime> {
+	runtime: R,
+	mainlog: &'static Arc<Mutex<Log>>,
+	restrictions: NodeRestrictions,
+	netdir: RwLock<Option<Arc<NetDir>>>,
+	guards: Mutex<GuardMgr>,
+	/// shared with the directory fetches
+	chanmgr: Arc<ChanMgr<R>>,
+	/// shared with the builds still running after their caller gave up
+	timeouts: Arc<Mutex<TimeoutEstimator>>,
+	bootstrap: Arc<BootstrapReporter>,
+}
+
+impl<R: Runtime> CircuitFactory for CircBuilder<R> {
+	type Circ = ClientCirc;
+
+	fn build_circuit<'a>(&'a self, usage: &'a CircUsage) -> Building<'a, ClientCirc> {
+		async move {
+			let netdir = self.netdir()?;
+			let (circ, kind) = match usage {
+				CircUsage::Exit(ports) => {
+					let (circ, policy) = self.build_path(&netdir, ports).await?;
+					(circ, CircKind::Exit(policy))
+				}
+				CircUsage::OnionService => {
+					let (circ, _) = self.build_path(&netdir, &[]).await?;
+					(circ, CircKind::OnionService)
+				}
+			};
+			log(
+				self.mainlog,
+				&format!("created circuit {} for {:?}", circ.unique_id(), usage),
+			);
+			Ok((circ, kind))
+		}
+		.boxed()
+	}
+}
+
+impl<R: Runtime> CircBuilder<R> {
+	fn netdir(&self) -> Result<Arc<NetDir>, Error> {
+		match &*self.netdir.read().unwrap() {
+			Some(netdir) => Ok(netdir.clone()),
+			None => Err(
+				ErrorKind::CircuitError("no directory to build circuits from".to_string()).into(),
+			),
+		}
+	}
+
+	// build a three hop circuit through one of our guards to an exit that
+	// allows `ports`, and tell the guard manager how that went. Returns
+	// the exit's policy.
+	async fn build_path(
+		&self,
+		netdir: &NetDir,
+		ports: &[u16],
+	) -> Result<(Arc<ClientCirc>, PortPolicy), Error> {
+		let need_stable = ports.iter().any(|port| LONG_LIVED_PORTS.contains(port));
+		let path = {
+			let mut guards = self.guards.lock().unwrap();
+			let guard = match guards.pick_first_hop(netdir, SystemTime::now()) {
+				Some(guard) => guard,
+				None => return Err(ErrorKind::CircuitError("no usable guard".to_string()).into()),
+			};
+			pick_path(
+				&mut rand::thread_rng(),
+				netdir,
+				Some(guard),
+				&self.restrictions,
+				ports,
+				need_stable,
+			)?
+		};
+		let guard_id = path.guard.rs.rsa_identity;
+		log(
+			self.mainlog,
+			&format!(
+				"building circuit through {}, {} and {}",
+				path.guard.rs.nickname, path.middle.rs.nickname, path.exit.rs.nickname
+			),
+		);
+
+		// only failing to reach the guard itself counts against it
+		let channel = match self.chanmgr.get_or_launch(&path.guard).await {
+			Ok(channel) => channel,
+			Err(e) => {
+				self.guards
+					.lock()
+					.unwrap()
+					.note_failure(&guard_id, SystemTime::now());
+				return Err(e);
+			}
+		};
+		self.bootstrap.advance(BootstrapPhase::Circuit);
+		let circ = self
+			.build_timed(
+				channel,
+				OwnedCircTarget::from_circ_target(&path.guard),
+				vec![
+					OwnedCircTarget::from_circ_target(&path.middle),
+					OwnedCircTarget::from_circ_target(&path.exit),
+				],
+			)
+			.await?;
+		self.guards
+			.lock()
+			.unwrap()
+			.note_success(&guard_id, SystemTime::now());
+		Ok((circ, path.exit.ipv4_policy()))
+	}
+
+	// Build a circuit over `channel` through `hops` with the learned
+	// build timeouts
+	async fn build_timed(
+		&self,
+		channel: Arc<Channel>,
+		first: OwnedCircTarget,
+		hops: Vec<OwnedCircTarget>,
+	) -> Result<Arc<ClientCirc>, Error> {
+		let runtime = self.runtime.clone();
+		let timeouts = self.timeouts.clone();
+		let build = async move {
+			let circ = create_firsthop(&channel, &first, &runtime).await?;
+			timeouts.lock().unwrap().note_network_live(runtime.now());
+			extend_hops(&circ, &hops).await?;
+			Ok(circ)
+		};
+		run_timed(&self.runtime, &self.timeouts, build).await
+	}
+}
+
+/// Keeps a pool of clean exit circuits ready, and hands out open circuits
+/// again to requests they can serve until they have been in use for
+/// `max_dirtiness`.
+pub struct AbstractCircMgr<F: CircuitFactory> {
+	factory: F,
+	config: CircMgrConfig,
+	state: Mutex<State<F::Circ>>,
+}
+
+impl<F: CircuitFactory> AbstractCircMgr<F> {
+	pub fn new(factory: F, config: CircMgrConfig) -> AbstractCircMgr<F> {
+		AbstractCircMgr {
+			factory,
+			config,
+			state: Mutex::new(State::default()),
+		}
+	}
+
+	/// Return an open circuit for `usage`, building one if none of the
+	/// open circuits can serve it without breaking `isolation`. Circuits
+	/// already in use are preferred, so that the clean ones are kept for
+	/// later. Circuits handed out now count as in use since `now`.
+	pub async fn get_or_launch(
+		&self,
+		usage: &CircUsage,
+		isolation: &StreamIsolation,
+		now: Instant,
+	) -> Result<Arc<F::Circ>, Error> {
+		self.retire(now);
+		{
+			let mut state = self.state.lock().unwrap();
+			if let Some(circ) = state.take(usage, isolation, now) {
+				state.predict(usage, now);
+				return Ok(circ);
+			}
+		}
+
+		let mut open = self.build(usage).await?;
+		let circ = open.circ.clone();
+		open.dirty_since = Some(now);
+		open.isolation = Some(isolation.clone());
+		let mut state = self.state.lock().unwrap();
+		state.circs.push(open);
+		state.predict(usage, now);
+		Ok(circ)
+	}
+
+	/// Drop circuits that are closing or have been in use for too long.
+	/// Streams already on them can carry on; they just aren't given out
+	/// again.
+	pub fn retire(&self, now: Instant) {
+		self.state
+			.lock()
+			.unwrap()
+			.retire(now, self.config.max_dirtiness);
+	}
+
+	/// The ports that have been asked for lately, or the defaults if
+	/// none have
+	fn predicted_ports(&self, now: Instant) -> Vec<u16> {
+		let mut state = self.state.lock().unwrap();
+		state
+			.predicted
+			.retain(|(_, last)| now.duration_since(*last) < PREDICTION_LIFETIME);
+		if state.predicted.is_empty() {
+			DEFAULT_PREDICTED_PORTS.to_vec()
+		} else {
+			state.predicted.iter().map(|(port, _)| *port).collect()
+		}
+	}
+
+	/// Build clean exit circuits until there are `pool_size` of them and
+	/// every port predicted at `now` is allowed by at least one. Ports
+	/// that no circuit could be built for are left uncovered, and the last
+	/// such failure is returned once the rest are done.
+	pub async fn launch_preemptive(&self, now: Instant) -> Result<(), Error> {
+		let mut failed = vec![];
+		let mut error = None;
+		loop {
+			self.retire(now);
+			let mut predicted = self.predicted_ports(now);
+			predicted.retain(|port| !failed.contains(port));
+			let (clean, uncovered) = {
+				let state = self.state.lock().unwrap();
+				let clean: Vec<&OpenCirc<F::Circ>> = state
+					.circs
+					.iter()
+					.filter(|open| open.dirty_since.is_none())
+					.filter(|open| matches!(open.kind, CircKind::Exit(_)))
+					.collect();
+				let uncovered: Vec<u16> = predicted
+					.into_iter()
+					.filter(|port| {
+						!clean
+							.iter()
+							.any(|open| open.kind.supports(&CircUsage::Exit(vec![*port])))
+					})
+					.collect();
+				(clean.len(), uncovered)
+			};
+			if clean >= self.config.pool_size && uncovered.is_empty() {
+				return error.map_or(Ok(()), Err);
+			}
+
+			// one port at a time, since there may be no exit that allows
+			// them all, and any exit once they are covered. A port we
+			// can't build for mustn't hold up the others.
+			let port = uncovered.first().copied();
+			let usage = CircUsage::Exit(port.into_iter().collect());
+			match self.build(&usage).await {
+				Ok(open) => self.state.lock().unwrap().circs.push(open),
+				Err(e) => match port {
+					Some(port) => {
+						failed.push(port);
+						error = Some(e);
+					}
+					None => return Err(e),
+				},
+			}
+		}
+	}
+
+	async fn build(&self, usage: &CircUsage) -> Result<OpenCirc<F::Circ>, Error> {
+		let (circ, kind) = self.factory.build_circuit(usage).await?;
+		Ok(OpenCirc {
+			circ,
+			kind,
+			dirty_since: None,
+			isolation: None,
+		})
+	}
+}
+
+/// Builds real circuits, keeps a pool of clean exit circuits ready, and
+/// hands out open circuits again to requests they can serve until they
+/// have been in use for `max_dirtiness`.
+pub struct CircMgr<R: Runtime> {
+	mgr: AbstractCircMgr<CircBuilder<R>>,
+}
+
+impl<R: Runtime> CircMgr<R> {
+	/// A circuit manager that opens its channels with `chanmgr` and logs
+	/// to the log of `bootstrap`
+	pub fn new(
+		runtime: R,
+		config: CircMgrConfig,
+		chanmgr: Arc<ChanMgr<R>>,
+		restrictions: NodeRestrictions,
+		guards: GuardMgr,
+		timeouts: TimeoutEstimator,
+		bootstrap: Arc<BootstrapReporter>,
+	) -> CircMgr<R> {
+		let builder = CircBuilder {
+			chanmgr,
+			runtime,
+			mainlog: bootstrap.mainlog(),
+			restrictions,
+			netdir: RwLock::new(None),
+			guards: Mutex::new(guards),
+			timeouts: Arc::new(Mutex::new(timeouts)),
+			bootstrap,
+		};
+		CircMgr {
+			mgr: AbstractCircMgr::new(builder, config),
+		}
+	}
+
+	/// Use a new directory for the circuits built from now on, and bring
+	/// the guards and build timeout parameters up to date with it.
+	pub fn set_netdir(&self, netdir: Arc<NetDir>) {
+		let builder = &self.mgr.factory;
+		builder
+			.timeouts
+			.lock()
+			.unwrap()
+			.set_params(CbtParams::from_consensus(netdir.consensus()));
+		builder.guards.lock().unwrap().update(
+			&mut rand::thread_rng(),
+			&netdir,
+			&builder.restrictions,
+			SystemTime::now(),
+		);
+		*builder.netdir.write().unwrap() = Some(netdir);
+	}
+
+	/// The directory circuits are built from, once there is one
+	pub fn netdir(&self) -> Option<Arc<NetDir>> {
+		self.mgr.factory.netdir.read().unwrap().clone()
+	}
+
+	/// The current guard state, for saving
+	pub fn guards(&self) -> GuardMgr {
+		self.mgr.factory.guards.lock().unwrap().clone()
+	}
+
+	/// The learned circuit build times, for saving
+	pub fn timeouts(&self) -> TimeoutEstimator {
+		self.mgr.factory.timeouts.lock().unwrap().clone()
+	}
+
+	/// Return an open circuit for `usage`, building one if none of the
+	/// open circuits can serve it without breaking `isolation`.
+	pub async fn get_or_launch(
+		&self,
+		usage: &CircUsage,
+		isolation: &StreamIsolation,
+	) -> Result<Arc<ClientCirc>, Error> {
+		let now = self.mgr.factory.runtime.now();
+		self.mgr.get_or_launch(usage, isolation, now).await
+	}
+
+	/// Drop circuits that are closing or have been in use for too long.
+	pub fn retire(&self, now: Instant) {
+		self.mgr.retire(now);
+	}
+
+	/// Build clean exit circuits until there are `pool_size` of them and
+	/// every predicted port is allowed by at least one.
+	pub async fn launch_preemptive(&self) -> Result<(), Error> {
+		self.mgr
+			.launch_preemptive(self.mgr.factory.runtime.now())
+			.await
+	}
+}
+
+// Run `build`, giving up on it after the learned build timeout. The build
+// itself carries on in the background until the close timeout so that its
+// time is still measured; a circuit finished after we gave up on it is
+// closed.
+async fn run_timed<S, C, F>(
+	runtime: &S,
+	timeouts: &Arc<Mutex<TimeoutEstimator>>,
+	build: F,
+) -> Result<Arc<C>, Error>
+where
+	S: SleepProvider + Spawn + Clone + Send + 'static,
+	C: PoolCirc + Send + Sync + 'static,
+	F: Future<Output = Result<Arc<C>, Error>> + Send + 'static,
+{
+	let (timeout, close_timeout) = {
+		let timeouts = timeouts.lock().unwrap();
+		(timeouts.timeout(), timeouts.close_timeout())
+	};
+	let (sender, receiver) = oneshot::channel();
+	let background = runtime.clone();
+	let timeouts = timeouts.clone();
+	let started = runtime.now();
+	runtime.spawn(async move {
+		let result = match background.timeout(close_timeout, build).await {
+			Ok(result) => {
+				if result.is_ok() {
+					let elapsed = background.now().saturating_duration_since(started);
+					timeouts.lock().unwrap().note_circ_built(elapsed);
+				}
+				result
+			}
+			Err(_) => {
+				let elapsed = background.now().saturating_duration_since(started);
+				timeouts
+					.lock()
+					.unwrap()
+					.note_circ_abandoned(elapsed, started);
+				Err(ErrorKind::CircuitError("circuit build timed out".to_string()).into())
+			}
+		};
+		if let Err(Ok(circ)) = sender.send(result) {
+			circ.terminate().await;
+		}
+	})?;
+
+	match runtime.timeout(timeout, receiver).await {
+		Ok(Ok(result)) => result,
+		Ok(Err(_)) => Err(ErrorKind::CircuitError("circuit build abandoned".to_string()).into()),
+		Err(_) => Err(ErrorKind::CircuitError(format!(
+			"circuit build timed out after {:?}",
+			timeout
+		))
+		.into()),
+	}
+}
+
+/// Keep the circuit pool topped up in the background, reload the
+/// directory as it is refreshed, and save the guard state now and then.
+pub fn start_circuit_pool_thread<R: Runtime>(
+	circmgr: Arc<CircMgr<R>>,
+	context: DSContext,
+	stop_state: Arc<RwLock<StopState>>,
+) {
+	thread::spawn(move || {
+		let mainlog = circmgr.mgr.factory.mainlog;
+		let runtime = circmgr.mgr.factory.runtime.clone();
+		let mut last_reload = Instant::now();
+		let mut next_launch = Instant::now();
+		loop {
+			if last_reload.elapsed() >= NETDIR_RELOAD {
+				last_reload = Instant::now();
+				match get_netdir(&context) {
+					Ok(Some(netdir)) => circmgr.set_netdir(Arc::new(netdir)),
+					Ok(None) => {}
+					Err(e) => log(
+						mainlog,
+						&format!("could not load directory for circuits: {}", e),
+					),
+				}
+				if let Err(e) = store_guards(&context, &circmgr.guards()) {
+					log(mainlog, &format!("could not save guard state: {}", e));
+				}
+				if let Err(e) = store_timeouts(&context, &circmgr.timeouts()) {
+					log(
+						mainlog,
+						&format!("could not save circuit build times: {}", e),
+					);
+				}
+			}
+			circmgr.mgr.factory.chanmgr.expire_channels();
+			if Instant::now() >= next_launch {
+				if let Err(e) = runtime.block_on(circmgr.launch_preemptive()) {
+					log(
+						mainlog,
+						&format!("could not build preemptive circuit: {}", e),
+					);
+					next_launch = Instant::now() + PREEMPTIVE_RETRY;
+				}
+			}
+
+			std::thread::sleep(std::time::Duration::from_millis(100));
+			let stop_state = stop_state.read().unwrap();
+			if stop_state.is_stopped() {
+				break;
+			}
+		}
+	});
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use futures::executor::block_on;
+	use futures::task::{FutureObj, SpawnError};
+	use std::pin::Pin;
+	use std::sync::atomic::{AtomicBool, AtomicUsize};
+	use std::task::{Context, Poll, Waker};
+
+	// stands in for a ClientCirc, with ids from the same kind of counter
+	struct FakeCirc {
+		id: usize,
+		closed: AtomicBool,
+	}
+
+	impl FakeCirc {
+		fn new() -> FakeCirc {
+			static NEXT: AtomicUsize = AtomicUsize::new(1);
+			FakeCirc {
+				id: NEXT.fetch_add(1, Ordering::Relaxed),
+				closed: AtomicBool::new(false),
+			}
+		}
+
+		fn unique_id(&self) -> usize {
+			self.id
+		}
+	}
+
+	impl PoolCirc for FakeCirc {
+		fn is_closing(&self) -> bool {
+			self.closed.load(Ordering::SeqCst)
+		}
+		fn terminate(&self) -> BoxFuture<'_, ()> {
+			self.closed.store(true, Ordering::SeqCst);
+			futures::future::ready(()).boxed()
+		}
+	}
+
+	struct MockClock {
+		now: Instant,
+		/// how many sleeps have been asked for
+		sleeps: usize,
+		wakers: Vec<Waker>,
+	}
+
+	// a runtime whose clock only moves when the test moves it. Spawned
+	// tasks each get a thread.
+	#[derive(Clone)]
+	struct MockSleep {
+		clock: Arc<Mutex<MockClock>>,
+		spawned: Arc<Mutex<Vec<thread::JoinHandle<()>>>>,
+	}
+
+	impl MockSleep {
+		fn new() -> MockSleep {
+			MockSleep {
+				clock: Arc::new(Mutex::new(MockClock {
+					now: Instant::now(),
+					sleeps: 0,
+					wakers: vec![],
+				})),
+				spawned: Arc::new(Mutex::new(vec![])),
+			}
+		}
+
+		fn advance(&self, duration: Duration) {
+			let mut clock = self.clock.lock().unwrap();
+			clock.now += duration;
+			for waker in clock.wakers.drain(..) {
+				waker.wake();
+			}
+		}
+
+		fn wait_for_sleeps(&self, count: usize) {
+			while self.clock.lock().unwrap().sleeps < count {
+				thread::sleep(Duration::from_millis(1));
+			}
+		}
+
+		fn join_spawned(&self) {
+			let spawned: Vec<_> = self.spawned.lock().unwrap().drain(..).collect();
+			for handle in spawned {
+				handle.join().unwrap();
+			}
+		}
+	}
+
+	struct MockSleepFuture {
+		clock: Arc<Mutex<MockClock>>,
+		until: Instant,
+	}
+
+	impl Future for MockSleepFuture {
+		type Output = ();
+		fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+			let mut clock = self.clock.lock().unwrap();
+			if clock.now >= self.until {
+				Poll::Ready(())
+			} else {
+				clock.wakers.push(cx.waker().clone());
+				Poll::Pending
+			}
+		}
+	}
+
+	impl SleepProvider for MockSleep {
+		type SleepFuture = MockSleepFuture;
+		fn sleep(&self, duration: Duration) -> MockSleepFuture {
+			let mut clock = self.clock.lock().unwrap();
+			clock.sleeps += 1;
+			MockSleepFuture {
+				clock: self.clock.clone(),
+				until: clock.now + duration,
+			}
+		}
+		fn now(&self) -> Instant {
+			self.clock.lock().unwrap().now
+		}
+	}
+
+	impl Spawn for MockSleep {
+		fn spawn_obj(&self, future: FutureObj<'static, ()>) -> Result<(), SpawnError> {
+			let handle = thread::spawn(move || block_on(future));
+			self.spawned.lock().unwrap().push(handle);
+			Ok(())
+		}
+	}
+
+	// builds exit circuits through the first exit policy that allows the
+	// ports, and remembers what it was asked for
+	struct FakeFactory {
+		policies: Vec<&'static str>,
+		built: Mutex<Vec<CircUsage>>,
+	}
+
+	impl CircuitFactory for FakeFactory {
+		type Circ = FakeCirc;
+
+		fn build_circuit<'a>(&'a self, usage: &'a CircUsage) -> Building<'a, FakeCirc> {
+			futures::future::ready(self.build(usage)).boxed()
+		}
+	}
+
+	impl FakeFactory {
+		fn build(&self, usage: &CircUsage) -> Result<(Arc<FakeCirc>, CircKind), Error> {
+			self.built.lock().unwrap().push(usage.clone());
+			let kind = match usage {
+				CircUsage::Exit(ports) => {
+					let policy = self
+						.policies
+						.iter()
+						.map(|policy| PortPolicy::parse(policy).unwrap())
+						.find(|policy| ports.iter().all(|port| policy.allows_port(*port)));
+					match policy {
+						Some(policy) => CircKind::Exit(policy),
+						None => return Err(ErrorKind::CircuitError("no exit".to_string()).into()),
+					}
+				}
+				CircUsage::OnionService => CircKind::OnionService,
+			};
+			Ok((Arc::new(FakeCirc::new()), kind))
+		}
+	}
+
+	fn mgr(policies: &[&'static str], pool_size: usize) -> AbstractCircMgr<FakeFactory> {
+		AbstractCircMgr::new(
+			FakeFactory {
+				policies: policies.to_vec(),
+				built: Mutex::new(vec![]),
+			},
+			CircMgrConfig {
+				pool_size,
+				max_dirtiness: Duration::from_secs(600),
+			},
+		)
+	}
+
+	// a pool of `count` clean exit circuits that allow every port
+	fn pool(count: usize) -> State<FakeCirc> {
+		let mut state = State::default();
+		for _ in 0..count {
+			state.circs.push(OpenCirc {
+				circ: Arc::new(FakeCirc::new()),
+				kind: CircKind::Exit(PortPolicy::parse("accept 1-65535").unwrap()),
+				dirty_since: None,
+				isolation: None,
+			});
+		}
+		state
+	}
+
+	fn take(state: &mut State<FakeCirc>, isolation: &StreamIsolation) -> Option<usize> {
+		state
+			.take(&CircUsage::Exit(vec![443]), isolation, Instant::now())
+			.map(|circ| circ.unique_id())
+	}
+
+	#[test]
+	fn tokens() {
+		let wallet = IsolationToken::new();
+		let gossip = IsolationToken::new();
+		assert_ne!(wallet, gossip);
+		assert_ne!(wallet, IsolationToken::no_isolation());
+		assert_eq!(IsolationToken::default(), IsolationToken::no_isolation());
+
+		let mut state = pool(2);
+		let a = take(&mut state, &StreamIsolation::new().token(wallet)).unwrap();
+		let b = take(&mut state, &StreamIsolation::new().token(gossip)).unwrap();
+		assert_ne!(a, b);
+		assert_eq!(
+			take(&mut state, &StreamIsolation::new().token(wallet)),
+			Some(a)
+		);
+		assert_eq!(
+			take(&mut state, &StreamIsolation::new().token(gossip)),
+			Some(b)
+		);
+		// both circuits are taken, so another wallet needs a new one
+		let other = StreamIsolation::new().token(IsolationToken::new());
+		assert_eq!(take(&mut state, &other), None);
+		assert_eq!(take(&mut state, &StreamIsolation::new()), None);
+	}
+
+	#[test]
+	fn destinations() {
+		let mut state = pool(3);
+		let port = take(&mut state, &StreamIsolation::new().dest_port(443)).unwrap();
+		let addr = take(&mut state, &StreamIsolation::new().dest_addr("example.com")).unwrap();
+		let other_addr = take(&mut state, &StreamIsolation::new().dest_addr("example.org"));
+		assert_ne!(port, addr);
+		assert!(other_addr.is_some());
+		assert_ne!(other_addr, Some(addr));
+		assert_eq!(
+			take(&mut state, &StreamIsolation::new().dest_addr("EXAMPLE.com")),
+			Some(addr)
+		);
+		assert_eq!(
+			take(&mut state, &StreamIsolation::new().dest_port(443)),
+			Some(port)
+		);
+		assert_eq!(
+			take(&mut state, &StreamIsolation::new().dest_port(80)),
+			None
+		);
+	}
+
+	#[test]
+	fn socks_auth() {
+		let mut state = pool(2);
+		let alice = StreamIsolation::new().socks_auth(b"alice", b"");
+		let bob = StreamIsolation::new().socks_auth(b"bob", b"");
+		let a = take(&mut state, &alice).unwrap();
+		let b = take(&mut state, &bob).unwrap();
+		assert_ne!(a, b);
+		assert_eq!(take(&mut state, &alice), Some(a));
+		assert_eq!(
+			take(
+				&mut state,
+				&StreamIsolation::new().socks_auth(b"alice", b"x")
+			),
+			None
+		);
+	}
+
+	#[test]
+	fn no_isolation() {
+		let mut state = pool(2);
+		let a = take(&mut state, &StreamIsolation::new()).unwrap();
+		assert_eq!(take(&mut state, &StreamIsolation::new()), Some(a));
+		// an isolated request doesn't join the shared circuit, and a
+		// request without isolation doesn't join the isolated one
+		let isolated = StreamIsolation::new().token(IsolationToken::new());
+		let b = take(&mut state, &isolated).unwrap();
+		assert_ne!(a, b);
+		assert_eq!(take(&mut state, &StreamIsolation::new()), Some(a));
+	}
+
+	#[test]
+	fn get_or_launch() {
+		let mgr = mgr(&["accept 1-65535"], 0);
+		let exit = CircUsage::Exit(vec![443]);
+		let launch = |usage: &CircUsage, isolation: &StreamIsolation| {
+			block_on(mgr.get_or_launch(usage, isolation, Instant::now()))
+				.unwrap()
+				.unique_id()
+		};
+		let alice = StreamIsolation::new().socks_auth(b"alice", b"");
+		let bob = StreamIsolation::new().socks_auth(b"bob", b"");
+		let a = launch(&exit, &alice);
+		let b = launch(&exit, &bob);
+		assert_ne!(a, b);
+		assert_eq!(launch(&exit, &alice), a);
+		assert_eq!(launch(&CircUsage::Exit(vec![80]), &bob), b);
+
+		let isolated = StreamIsolation::new().token(IsolationToken::new());
+		let c = launch(&exit, &isolated);
+		assert_ne!(c, a);
+		assert_ne!(c, b);
+		// exit circuits don't serve other usages
+		let d = launch(&CircUsage::OnionService, &alice);
+		assert_ne!(d, a);
+		assert_eq!(launch(&CircUsage::OnionService, &alice), d);
+		assert_eq!(mgr.factory.built.lock().unwrap().len(), 4);
+	}
+
+	#[test]
+	fn preemptive() {
+		let mgr = mgr(&["accept 80,443", "accept 6667"], 3);
+		let built = || mgr.factory.built.lock().unwrap().clone();
+		let exit = |ports: &[u16]| CircUsage::Exit(ports.to_vec());
+		let now = Instant::now();
+
+		// nothing asked for yet, so port 80 is predicted
+		block_on(mgr.launch_preemptive(now)).unwrap();
+		assert_eq!(built(), vec![exit(&[80]), exit(&[]), exit(&[])]);
+		block_on(mgr.launch_preemptive(now)).unwrap();
+		assert_eq!(built().len(), 3);
+
+		// once the clean circuits are taken, both ports are uncovered. No
+		// exit allows both, so each gets its own circuit.
+		let isolated = || StreamIsolation::new().token(IsolationToken::new());
+		for _ in 0..3 {
+			block_on(mgr.get_or_launch(&exit(&[443]), &isolated(), now)).unwrap();
+		}
+		block_on(mgr.get_or_launch(&exit(&[6667]), &isolated(), now)).unwrap();
+		assert_eq!(built().len(), 4);
+		block_on(mgr.launch_preemptive(now)).unwrap();
+		assert_eq!(built()[4..], [exit(&[443]), exit(&[6667]), exit(&[])]);
+		let state = mgr.state.lock().unwrap();
+		let clean = state.circs.iter().filter(|open| open.dirty_since.is_none());
+		assert_eq!(clean.count(), 3);
+		drop(state);
+
+		// predictions run out when the ports aren't asked for again
+		assert_eq!(
+			mgr.predicted_ports(now + PREDICTION_LIFETIME / 2),
+			[443, 6667]
+		);
+		assert_eq!(mgr.predicted_ports(now + PREDICTION_LIFETIME), [80]);
+	}
+
+	#[test]
+	fn preemptive_failures() {
+		let mgr = mgr(&["accept 80,443"], 2);
+		let built = || mgr.factory.built.lock().unwrap().clone();
+		let exit = |ports: &[u16]| CircUsage::Exit(ports.to_vec());
+		let now = Instant::now();
+
+		// a port no exit allows isn't predicted
+		let smtp = block_on(mgr.get_or_launch(&exit(&[25]), &StreamIsolation::new(), now));
+		assert!(smtp.is_err());
+		assert_eq!(mgr.predicted_ports(now), [80]);
+
+		// and one that stops being allowed doesn't keep the others from
+		// being covered or the pool from filling
+		mgr.state.lock().unwrap().predicted = vec![(25, now), (443, now)];
+		assert!(block_on(mgr.launch_preemptive(now)).is_err());
+		assert_eq!(built()[1..], [exit(&[25]), exit(&[443]), exit(&[])]);
+	}
+
+	#[test]
+	fn retire() {
+		let mut state = pool(1);
+		let start = Instant::now();
+		let isolated = StreamIsolation::new().token(IsolationToken::new());
+		assert!(state
+			.take(&CircUsage::Exit(vec![443]), &isolated, start)
+			.is_some());
+		let max_dirtiness = Duration::from_secs(600);
+		state.retire(start + Duration::from_secs(599), max_dirtiness);
+		assert_eq!(state.circs.len(), 1);
+		state.retire(start + max_dirtiness, max_dirtiness);
+		assert!(state.circs.is_empty());
+	}
+
+	#[test]
+	fn build_timeouts() {
+		let mut estimator = TimeoutEstimator::default();
+		for i in 0..200 {
+			estimator.note_circ_built(Duration::from_millis(1000 + (i % 20) * 100));
+		}
+		let timeouts = Arc::new(Mutex::new(estimator));
+		let runtime = MockSleep::new();
+		let current = || {
+			let timeouts = timeouts.lock().unwrap();
+			(timeouts.timeout(), timeouts.close_timeout())
+		};
+		let build_times = || timeouts.lock().unwrap().build_times();
+
+		// start a build that takes `took` and wait until it and both
+		// timeouts are sleeping
+		let launch = |took: Duration| {
+			let circ = Arc::new(FakeCirc::new());
+			let built = circ.clone();
+			let (runtime, timeouts) = (runtime.clone(), timeouts.clone());
+			let sleeps = runtime.clock.lock().unwrap().sleeps;
+			let build = {
+				let (runtime, timeouts) = (runtime.clone(), timeouts.clone());
+				async move {
+					timeouts.lock().unwrap().note_network_live(runtime.now());
+					runtime.sleep(took).await;
+					Ok(built)
+				}
+			};
+			let waiting = runtime.clone();
+			let handle = thread::spawn(move || {
+				block_on(run_timed(&runtime, &timeouts, build)).map(|circ| circ.unique_id())
+			});
+			waiting.wait_for_sleeps(sleeps + 3);
+			(circ, handle)
+		};
+		let timed_out = |result: Result<usize, Error>| matches!(result, Err(e) if matches!(e.kind(), ErrorKind::CircuitError(_)));
+
+		// in time
+		let (timeout, close_timeout) = current();
+		assert!(timeout < close_timeout);
+		let (circ, handle) = launch(timeout / 2);
+		runtime.advance(timeout / 2);
+		assert_eq!(handle.join().unwrap().unwrap(), circ.unique_id());
+		runtime.join_spawned();
+		assert!(!circ.is_closing());
+		assert_eq!(build_times(), 201);
+
+		// after the timeout: we give up, but the build is still measured
+		// and the circuit closed
+		let (timeout, close_timeout) = current();
+		let late = (close_timeout - timeout) / 2;
+		let (circ, handle) = launch(timeout + late);
+		runtime.advance(timeout);
+		assert!(timed_out(handle.join().unwrap()));
+		runtime.advance(late);
+		runtime.join_spawned();
+		assert!(circ.is_closing());
+		assert_eq!(build_times(), 202);
+
+		// after the close timeout it is abandoned, which raises the timeout
+		let (timeout, close_timeout) = current();
+		let (circ, handle) = launch(close_timeout + Duration::from_secs(1));
+		runtime.advance(timeout);
+		assert!(timed_out(handle.join().unwrap()));
+		runtime.advance(close_timeout - timeout);
+		runtime.join_spawned();
+		assert!(!circ.is_closing());
+		assert_eq!(build_times(), 202);
+		assert!(current().0 > timeout);
+	}
+}
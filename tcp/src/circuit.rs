@@ -11,74 +11,127 @@
 // WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 // See the License for the specific language governing permissions and
 // limitations under the License.
-
-use crate::channel::build_channel;
-use crate::channel::TorChannel;
-use crate::ds_load::DSInfo;
+use crate::chanmgr::ChanMgr;
+use crate::netdir::{NetDir, Relay};
+use crate::path::NodeRestrictions;
+use futures::task::SpawnExt;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use std::future::Future;
 use std::sync::Arc;
 use std::sync::Mutex;
-use tor_cell::chancell::{msg, ChanCell};
+use tor_linkspec::CircTarget;
+use tor_llcrypto::pk::rsa::RsaIdentity;
+use tor_netdoc::consensus::RelayFlags;
+use tor_proto::channel::Channel;
+use tor_proto::circuit::{CircParameters, ClientCirc};
 use tor_rtcompat::Runtime;
 
 use tor_util::logger::Log;
-use tor_util::Error;
+use tor_util::{Error, ErrorKind};
 
-pub struct Circuit {}
+// how many directory caches to try before giving up on a directory circuit
+const DIR_CIRC_ATTEMPTS: usize = 3;
 
-pub fn build_circuit(
-	dsinfo: &DSInfo,
-	mainlog: &'static Arc<Mutex<Log>>,
-	runtime: &mut impl Runtime,
-) -> Result<Circuit, Error> {
-	let tor_channel = Arc::new(Mutex::new(TorChannel::new()));
-	let res = build_channel(
-		dsinfo.hosts[5].host.clone(),
-		dsinfo.hosts[5].port,
-		runtime,
-		mainlog,
-		5_000_000_000,
-		tor_channel.clone(),
-	);
+fn circuit_error(e: tor_proto::Error) -> Error {
+	ErrorKind::CircuitError(e.to_string()).into()
+}
 
-	match res {
-		Err(e) => {
-			let mut mainlog = mainlog.lock()?;
-			mainlog
-				.log(&format!(
-					"Error occurred while connecting to channel: {}",
-					e
-				))
-				.map_err(|e| {
-					println!("logging error occurred: {}", e);
-				})
-				.ok();
-		}
-		Ok(_) => {
-			let tor_channel = tor_channel.lock().unwrap();
-			let channel = tor_channel.channel.as_ref();
-			let error = tor_channel.error.as_ref();
+pub(crate) fn log(mainlog: &Arc<Mutex<Log>>, msg: &str) {
+	let mut mainlog = mainlog.lock().unwrap();
+	mainlog
+		.log(msg)
+		.map_err(|e| {
+			println!("logging error occurred: {}", e);
+		})
+		.ok();
+}
 
-			{
-				let mut mainlog = mainlog.lock().unwrap();
-				mainlog
-					.log(&format!(
-						"connect complete chan={:?}, err={:?}",
-						channel, error
-					))
-					.map_err(|e| {
-						println!("logging error occurred: {}", e);
-					})
-					.ok();
-			}
+/// Create a one hop circuit to the relay at the other end of a channel.
+pub async fn create_firsthop<T: CircTarget>(
+	channel: &Arc<Channel>,
+	target: &T,
+	runtime: &impl Runtime,
+) -> Result<Arc<ClientCirc>, Error> {
+	let mut rng = StdRng::from_entropy();
+	let (pending, reactor) = channel.new_circ(&mut rng).await.map_err(circuit_error)?;
+	runtime.spawn(async move {
+		let _ = reactor.run().await;
+	})?;
+	pending
+		.create_firsthop_ntor(&mut rng, target, &CircParameters::default())
+		.await
+		.map_err(circuit_error)
+}
 
-			// send a cell
-			let cell = ChanCell::new(5.into(), msg::Create2::new(2, &b"abc"[..]).into());
-			runtime.block_on(async move {
-				let e = channel.unwrap().send_cell(cell).await;
-				println!("result of e: {:?}", e);
-			});
+/// Create a one hop circuit to a relay, over the channel `chanmgr` has
+/// to it or opens.
+pub async fn build_firsthop<R: Runtime>(
+	relay: &Relay<'_>,
+	chanmgr: &ChanMgr<R>,
+	runtime: &R,
+) -> Result<Arc<ClientCirc>, Error> {
+	let channel = chanmgr.get_or_launch(relay).await?;
+	create_firsthop(&channel, relay, runtime).await
+}
+
+/// Extend a circuit through each of `hops` in turn. The circuit is closed
+/// if that fails.
+pub async fn extend_hops<T: CircTarget>(circ: &ClientCirc, hops: &[T]) -> Result<(), Error> {
+	let mut rng = StdRng::from_entropy();
+	for hop in hops {
+		if let Err(e) = circ
+			.extend_ntor(&mut rng, hop, &CircParameters::default())
+			.await
+		{
+			circ.terminate().await;
+			return Err(circuit_error(e));
 		}
 	}
+	Ok(())
+}
+
+/// Build a one hop circuit to a random directory cache, for fetching
+/// directory documents with BEGIN_DIR, and return it with the identity of
+/// the cache. Excluded relays and those `usable` turns down are not used.
+/// The caches are picked before the returned future is first polled, and
+/// reached over the channels `chanmgr` shares.
+pub fn build_dir_circuit<'a, R: Runtime>(
+	netdir: &'a NetDir,
+	restrictions: &NodeRestrictions,
+	usable: &dyn Fn(&Relay) -> bool,
+	chanmgr: &'a ChanMgr<R>,
+	mainlog: &'static Arc<Mutex<Log>>,
+	runtime: &'a R,
+) -> impl Future<Output = Result<(Arc<ClientCirc>, RsaIdentity), Error>> + Send + 'a {
+	let mut caches: Vec<Relay<'a>> = netdir
+		.relays()
+		.filter(|relay| relay.rs.has_flags(RelayFlags::V2DIR | RelayFlags::RUNNING))
+		.filter(|relay| !restrictions.exclude.contains(relay))
+		.filter(|relay| usable(relay))
+		.collect();
+	caches.shuffle(&mut rand::thread_rng());
 
-	Ok(Circuit {})
+	async move {
+		let mut error = None;
+		for relay in caches.iter().take(DIR_CIRC_ATTEMPTS) {
+			match build_firsthop(relay, chanmgr, runtime).await {
+				Ok(circ) => return Ok((circ, relay.rs.rsa_identity)),
+				Err(e) => {
+					log(
+						mainlog,
+						&format!(
+							"could not build directory circuit to {}: {}",
+							relay.rs.nickname, e
+						),
+					);
+					error = Some(e);
+				}
+			}
+		}
+		Err(error.unwrap_or_else(|| {
+			ErrorKind::CircuitError("no usable directory caches".to_string()).into()
+		}))
+	}
 }
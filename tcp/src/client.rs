@@ -0,0 +1,378 @@
+// Copyright 2021 The BMW Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A Tor client: bootstrap once, then open streams and look up names
+//! through Tor.
+
+use crate::bootstrap::{microdescs_percent, BootstrapPhase, BootstrapReporter, BootstrapStatus};
+use crate::chanmgr::ChanMgr;
+use crate::circmgr::{
+	start_circuit_pool_thread, CircMgr, CircMgrConfig, CircUsage, StreamIsolation,
+};
+use crate::circuit::log;
+use crate::ds_load::{
+	build_ds_context, get_latest_valid_dsinfo, get_netdir, load_timeouts,
+	start_dsinfo_refresh_thread, store_guards, store_timeouts, update_guards,
+};
+use crate::netdir::Liveness;
+use crate::proxy::StreamProvider;
+use futures::channel::mpsc;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+use tor_cell::relaycell::msg::EndReason;
+use tor_config::config::TorConfig;
+use tor_proto::circuit::ClientCirc;
+use tor_rtcompat::Runtime;
+use tor_util::logger::Log;
+use tor_util::StopState;
+use tor_util::{Error, ErrorKind};
+
+pub use tor_proto::stream::DataStream;
+
+/// A bootstrapped Tor client. The directory is kept fresh and the
+/// circuit pool topped up in the background until the client is dropped.
+///
+/// `connect` and `resolve` block until they are done, so they must not be
+/// called from a task running on the client's runtime.
+pub struct TorClient<R: Runtime> {
+	runtime: R,
+	bootstrap: Arc<BootstrapReporter>,
+	circmgr: Arc<CircMgr<R>>,
+	stop_state: Arc<RwLock<StopState>>,
+}
+
+impl<R: Runtime> TorClient<R> {
+	/// Bootstrap a client that logs to `mainlog`, which is set up as
+	/// `config` says first.
+	pub fn bootstrap(
+		config: &TorConfig,
+		runtime: R,
+		mainlog: &'static Arc<Mutex<Log>>,
+	) -> Result<TorClient<R>, Error> {
+		{
+			let mut mainlog = mainlog.lock()?;
+			mainlog.config(
+				&config.mainlog,
+				config.mainlog_rotationsize,
+				config.mainlog_rotationtime.into(),
+				true,
+				"MainLog - Tor (Rust)\n\
+------------------------------------------------------------------------------",
+			)?;
+			if !config.debug {
+				mainlog.update_show_stdout(false)?;
+			}
+		}
+		Self::bootstrap_with_reporter(config, runtime, Arc::new(BootstrapReporter::new(mainlog)))
+	}
+
+	/// Bootstrap a client that reports its progress to `bootstrap`, and
+	/// logs to the reporter's log, which must already be configured: load
+	/// the directory, fetching it first if what is stored is missing or due
+	/// for a refresh, pick guards, and build a first circuit. If that
+	/// fails, the reporter is left blocked with the error.
+	pub fn bootstrap_with_reporter(
+		config: &TorConfig,
+		runtime: R,
+		bootstrap: Arc<BootstrapReporter>,
+	) -> Result<TorClient<R>, Error> {
+		let result = Self::launch(config, runtime, bootstrap.clone());
+		if let Err(e) = &result {
+			bootstrap.block(&e.kind().to_string());
+		}
+		result
+	}
+
+	fn launch(
+		config: &TorConfig,
+		mut runtime: R,
+		bootstrap: Arc<BootstrapReporter>,
+	) -> Result<TorClient<R>, Error> {
+		let mainlog = bootstrap.mainlog();
+		bootstrap.advance(BootstrapPhase::Directory);
+		let context = build_ds_context(config)?;
+		// directory fetches and circuits share one channel per relay
+		let chanmgr = Arc::new(ChanMgr::new(runtime.clone(), mainlog));
+		let ds_info =
+			get_latest_valid_dsinfo(&context, &chanmgr, mainlog, &mut runtime, Some(&bootstrap))?;
+		let netdir = match get_netdir(&context)? {
+			Some(netdir) => Arc::new(netdir),
+			None => return Err(ErrorKind::NetDocError("no consensus stored".to_string()).into()),
+		};
+		log(mainlog, &format!("Found {} hosts.", ds_info.hosts.len()));
+		log(
+			mainlog,
+			&format!("Directory is {:?}.", netdir.liveness(SystemTime::now())),
+		);
+		let (have, listed) = netdir.microdesc_coverage();
+		log(
+			mainlog,
+			&format!("Have {} of {} microdescriptors.", have, listed),
+		);
+		bootstrap.advance_to(BootstrapPhase::Microdescs, microdescs_percent(have, listed));
+
+		let guards = update_guards(&context, &netdir)?;
+		let circmgr = Arc::new(CircMgr::new(
+			runtime.clone(),
+			CircMgrConfig::from_config(config),
+			chanmgr.clone(),
+			context.restrictions.clone(),
+			guards,
+			load_timeouts(&context)?,
+			bootstrap.clone(),
+		));
+		circmgr.set_netdir(netdir);
+		bootstrap.advance(BootstrapPhase::Channel);
+		let result = runtime
+			.block_on(circmgr.get_or_launch(&CircUsage::Exit(vec![]), &StreamIsolation::new()));
+		// the guards we tried are worth keeping whether or not it worked
+		store_guards(&context, &circmgr.guards())?;
+		store_timeouts(&context, &circmgr.timeouts())?;
+		result?;
+
+		let stop_state = Arc::new(RwLock::new(StopState::new()));
+		start_circuit_pool_thread(circmgr.clone(), context, stop_state.clone());
+		start_dsinfo_refresh_thread(
+			config,
+			stop_state.clone(),
+			chanmgr,
+			mainlog,
+			runtime.clone(),
+		)?;
+		bootstrap.advance(BootstrapPhase::Done);
+
+		Ok(TorClient {
+			runtime,
+			bootstrap,
+			circmgr,
+			stop_state,
+		})
+	}
+
+	/// The log this client writes to
+	pub fn mainlog(&self) -> &'static Arc<Mutex<Log>> {
+		self.bootstrap.mainlog()
+	}
+
+	/// How bootstrapping went
+	pub fn bootstrap_status(&self) -> BootstrapStatus {
+		self.bootstrap.status()
+	}
+
+	/// A stream of bootstrap status changes, starting with the status
+	/// right now
+	pub fn bootstrap_events(&self) -> mpsc::UnboundedReceiver<BootstrapStatus> {
+		self.bootstrap.events()
+	}
+
+	/// How current the directory in use is. It is refreshed in the
+	/// background, so this only stays short of live if refreshing fails.
+	pub fn directory_liveness(&self) -> Liveness {
+		let netdir = self
+			.circmgr
+			.netdir()
+			.expect("a bootstrapped client has a directory");
+		netdir.liveness(self.runtime.wallclock())
+	}
+
+	/// Open a stream to `host`:`port` through an exit. The exit looks up
+	/// `host`, so it can be a hostname.
+	pub async fn connect(&self, host: &str, port: u16) -> Result<DataStream, StreamError> {
+		self.connect_with_isolation(host, port, &StreamIsolation::new())
+			.await
+	}
+
+	/// Open a stream as `connect` does, on a circuit that is only shared
+	/// with streams `isolation` is compatible with.
+	pub async fn connect_with_isolation(
+		&self,
+		host: &str,
+		port: u16,
+		isolation: &StreamIsolation,
+	) -> Result<DataStream, StreamError> {
+		let circ = self
+			.circmgr
+			.get_or_launch(&CircUsage::Exit(vec![port]), isolation)
+			.await
+			.map_err(StreamError::NoCircuit)?;
+		circ.begin_stream(host, port, None)
+			.await
+			.map_err(StreamError::Proto)
+	}
+
+	/// `connect_with_isolation`, for callers that aren't async
+	pub fn connect_blocking(
+		&self,
+		host: &str,
+		port: u16,
+		isolation: &StreamIsolation,
+	) -> Result<DataStream, StreamError> {
+		self.runtime
+			.block_on(self.connect_with_isolation(host, port, isolation))
+	}
+
+	/// Look up the addresses for `host` at an exit, so that the lookup
+	/// never goes to the local resolver.
+	pub async fn resolve(&self, host: &str) -> Result<Vec<IpAddr>, StreamError> {
+		self.resolve_with_isolation(host, &StreamIsolation::new())
+			.await
+	}
+
+	/// Look up `host` as `resolve` does, on a circuit that is only shared
+	/// with streams `isolation` is compatible with.
+	pub async fn resolve_with_isolation(
+		&self,
+		host: &str,
+		isolation: &StreamIsolation,
+	) -> Result<Vec<IpAddr>, StreamError> {
+		let circ = self.lookup_circ(isolation).await?;
+		let addrs = circ.resolve(host).await.map_err(StreamError::Proto)?;
+		if addrs.is_empty() {
+			return Err(StreamError::NotFound(host.to_string()));
+		}
+		Ok(addrs)
+	}
+
+	/// `resolve_with_isolation`, for callers that aren't async
+	pub fn resolve_blocking(
+		&self,
+		host: &str,
+		isolation: &StreamIsolation,
+	) -> Result<Vec<IpAddr>, StreamError> {
+		self.runtime
+			.block_on(self.resolve_with_isolation(host, isolation))
+	}
+
+	/// Look up the hostnames for `addr` at an exit.
+	pub async fn resolve_ptr(&self, addr: IpAddr) -> Result<Vec<String>, StreamError> {
+		self.resolve_ptr_with_isolation(addr, &StreamIsolation::new())
+			.await
+	}
+
+	/// Look up `addr` as `resolve_ptr` does, on a circuit that is only
+	/// shared with streams `isolation` is compatible with.
+	pub async fn resolve_ptr_with_isolation(
+		&self,
+		addr: IpAddr,
+		isolation: &StreamIsolation,
+	) -> Result<Vec<String>, StreamError> {
+		let circ = self.lookup_circ(isolation).await?;
+		let names = circ.resolve_ptr(addr).await.map_err(StreamError::Proto)?;
+		if names.is_empty() {
+			return Err(StreamError::NotFound(addr.to_string()));
+		}
+		Ok(names)
+	}
+
+	/// `resolve_ptr_with_isolation`, for callers that aren't async
+	pub fn resolve_ptr_blocking(
+		&self,
+		addr: IpAddr,
+		isolation: &StreamIsolation,
+	) -> Result<Vec<String>, StreamError> {
+		self.runtime
+			.block_on(self.resolve_ptr_with_isolation(addr, isolation))
+	}
+
+	// any exit can do lookups
+	async fn lookup_circ(
+		&self,
+		isolation: &StreamIsolation,
+	) -> Result<Arc<ClientCirc>, StreamError> {
+		self.circmgr
+			.get_or_launch(&CircUsage::Exit(vec![]), isolation)
+			.await
+			.map_err(StreamError::NoCircuit)
+	}
+}
+
+impl<R: Runtime> StreamProvider for TorClient<R> {
+	type Stream = DataStream;
+
+	fn open_stream(
+		&self,
+		host: &str,
+		port: u16,
+		isolation: &StreamIsolation,
+	) -> Result<DataStream, StreamError> {
+		self.connect_blocking(host, port, isolation)
+	}
+
+	fn resolve_host(
+		&self,
+		host: &str,
+		isolation: &StreamIsolation,
+	) -> Result<Vec<IpAddr>, StreamError> {
+		self.resolve_blocking(host, isolation)
+	}
+
+	fn resolve_addr(
+		&self,
+		addr: IpAddr,
+		isolation: &StreamIsolation,
+	) -> Result<Vec<String>, StreamError> {
+		self.resolve_ptr_blocking(addr, isolation)
+	}
+}
+
+/// Why a stream couldn't be opened or a name looked up
+#[derive(Debug)]
+pub enum StreamError {
+	/// No circuit could be had to use
+	NoCircuit(Error),
+	/// The exit refused, or the circuit failed
+	Proto(tor_proto::Error),
+	/// The exit found no answer for a lookup
+	NotFound(String),
+}
+
+impl StreamError {
+	/// The reason the exit gave for refusing, if it gave one
+	pub fn end_reason(&self) -> Option<EndReason> {
+		match self {
+			StreamError::Proto(tor_proto::Error::EndReceived(reason)) => Some(*reason),
+			_ => None,
+		}
+	}
+}
+
+impl fmt::Display for StreamError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			StreamError::NoCircuit(e) => write!(f, "no circuit: {}", e.kind()),
+			StreamError::Proto(e) => write!(f, "{}", e),
+			StreamError::NotFound(name) => write!(f, "no answer found for {}", name),
+		}
+	}
+}
+
+impl std::error::Error for StreamError {}
+
+impl From<StreamError> for Error {
+	fn from(e: StreamError) -> Error {
+		match e {
+			StreamError::NoCircuit(e) => e,
+			e => ErrorKind::CircuitError(e.to_string()).into(),
+		}
+	}
+}
+
+impl<R: Runtime> Drop for TorClient<R> {
+	// stops the background threads
+	fn drop(&mut self) {
+		self.stop_state.write().unwrap().stop();
+	}
+}
@@ -0,0 +1,117 @@
+// Copyright 2021 The BMW Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use futures::io::{AsyncReadExt, AsyncWriteExt};
+use std::sync::Arc;
+use tor_proto::circuit::ClientCirc;
+use tor_util::compress::{Decompressor, Encoding, ACCEPT_ENCODINGS};
+use tor_util::{Error, ErrorKind};
+
+// the most we'll read looking for the end of the response headers
+const MAX_HEADER_SIZE: usize = 16 * 1024;
+// how much to read from the stream at a time
+const READ_SIZE: usize = 4096;
+
+fn dir_error(msg: &str) -> Error {
+	ErrorKind::DirRequestError(msg.to_string()).into()
+}
+
+/// Request a directory document over a BEGIN_DIR stream on the given
+/// circuit, using HTTP/1.0 with any extra headers given, and return the
+/// body of the response. The body may be compressed, and is decompressed
+/// as it arrives. Documents larger than `limit` bytes are rejected.
+pub async fn get(
+	circ: &Arc<ClientCirc>,
+	path: &str,
+	headers: &[(&str, &str)],
+	limit: usize,
+) -> Result<String, Error> {
+	let mut stream = circ
+		.clone()
+		.begin_dir_stream()
+		.await
+		.map_err(|e| dir_error(&format!("begin_dir failed: {}", e)))?;
+	let mut request = format!(
+		"GET {} HTTP/1.0\r\nAccept-Encoding: {}\r\n",
+		path, ACCEPT_ENCODINGS
+	);
+	for (name, value) in headers {
+		request.push_str(&format!("{}: {}\r\n", name, value));
+	}
+	request.push_str("\r\n");
+	stream.write_all(request.as_bytes()).await?;
+	stream.flush().await?;
+
+	// read until we have all the headers
+	let mut response = vec![];
+	let mut chunk = [0; READ_SIZE];
+	let end = loop {
+		if let Some(end) = response.windows(4).position(|w| w == b"\r\n\r\n") {
+			break end;
+		}
+		if response.len() > MAX_HEADER_SIZE {
+			return Err(dir_error(&format!("headers too long for {}", path)));
+		}
+		let n = stream.read(&mut chunk).await?;
+		if n == 0 {
+			return Err(dir_error(&format!("incomplete response for {}", path)));
+		}
+		response.extend_from_slice(&chunk[..n]);
+	};
+	let encoding = parse_headers(path, &response[..end])?;
+
+	// the cache closes the stream once the whole response is sent
+	let mut decompressor = Decompressor::new(encoding, limit)?;
+	decompressor.write(&response[end + 4..])?;
+	loop {
+		let n = stream.read(&mut chunk).await?;
+		if n == 0 {
+			break;
+		}
+		decompressor.write(&chunk[..n])?;
+	}
+
+	String::from_utf8(decompressor.finish()?)
+		.map_err(|_| dir_error(&format!("response for {} is not utf-8", path)))
+}
+
+/// Check the status line and headers of an HTTP response, and return the
+/// encoding of its body.
+fn parse_headers(path: &str, head: &[u8]) -> Result<Encoding, Error> {
+	let head = std::str::from_utf8(head)
+		.map_err(|_| dir_error(&format!("invalid headers for {}", path)))?;
+	let mut lines = head.split("\r\n");
+
+	// status line: "HTTP/1.0 200 OK"
+	let status = lines.next().unwrap_or("");
+	let mut parts = status.splitn(3, ' ');
+	match (parts.next(), parts.next()) {
+		(Some(version), Some(code)) if version.starts_with("HTTP/1.") => {
+			if code != "200" {
+				return Err(dir_error(&format!("{} returned '{}'", path, status)));
+			}
+		}
+		_ => return Err(dir_error(&format!("bad status line for {}", path))),
+	}
+
+	let mut encoding = None;
+	for line in lines {
+		let mut header = line.splitn(2, ':');
+		let name = header.next().unwrap_or("").trim();
+		if name.eq_ignore_ascii_case("content-encoding") {
+			encoding = header.next();
+		}
+	}
+	Encoding::from_header(encoding)
+}
@@ -12,63 +12,150 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use rand::seq::SliceRandom;
+use rand::Rng;
+use tor_checkable::{SelfSigned, Timebound};
 use tor_config::config::TorConfig;
+use tor_llcrypto::pk::rsa::RsaIdentity;
+use tor_netdoc::authcert::{parse_fingerprint, AuthCert};
+use tor_netdoc::consdiff::{apply_diff, consensus_signed_digest_hex, is_diff};
+use tor_netdoc::consensus::{Consensus, ConsensusHeader};
+use tor_netdoc::microdesc::{encode_digest, MdDigest, Microdesc};
+use tor_proto::circuit::ClientCirc;
+use tor_rtcompat::{Runtime, SleepProviderExt};
 use tor_util::http::{build_connector_context, do_get, UrlContext};
 use tor_util::logger::Log;
 use tor_util::store::lmdb::Store;
-use tor_util::Error;
 use tor_util::StopState;
+use tor_util::{Error, ErrorKind};
 
+use crate::bootstrap::{microdescs_percent, BootstrapPhase, BootstrapReporter};
+use crate::chanmgr::ChanMgr;
+use crate::circuit::build_dir_circuit;
+use crate::dirclient;
+use crate::ds_store::{
+	self, key, AUTHCERT_PREFIX, CONSENSUS_KEY, GUARD_KEY, HOSTS_KEY, MD_INDEX_KEY, MD_PREFIX,
+	SOURCE_PREFIX, TIMEOUT_KEY,
+};
+use crate::guard::GuardMgr;
+use crate::netdir::{NetDir, Relay};
+use crate::path::NodeRestrictions;
+use crate::timeout::TimeoutEstimator;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
-use std::str::FromStr;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::sync::RwLock;
 use std::thread;
-use std::time::SystemTime;
+use std::time::{Duration, Instant, SystemTime};
 
 use tor_util::core::ser::{self, Readable, Reader, Writeable, Writer};
 
 const DB_NAME: &str = "ds_db";
-const HOSTS_KEY: &[u8] = &[1];
+const CONSENSUS_PATH: &str = "/tor/status-vote/current/consensus-microdesc/";
+// asks for a diff from the consensus whose signed part has this SHA3
+// digest (proposal 140)
+const DIFF_HEADER: &str = "X-Or-Diff-From-Consensus";
+// directory servers won't serve more than this many microdescriptors per request
+const MD_BATCH_SIZE: usize = 92;
+// largest documents we'll accept, after decompression
+const CONSENSUS_SIZE_LIMIT: usize = 16 * 1024 * 1024;
+const AUTHCERT_SIZE_LIMIT: usize = 1024 * 1024;
+const MICRODESC_SIZE_LIMIT: usize = 1024 * 1024;
+// decorrelated jitter backoff for directory sources that failed, in ms
+const RETRY_BASE: u64 = 1000;
+const RETRY_MAX: u64 = 2 * 60 * 1000;
+// the serializer refuses single reads larger than 100,000 bytes
+const TEXT_CHUNK_SIZE: usize = 90_000;
+// DSInfo entries start with a load time, which can never be this, so it
+// marks entries that carry a version
+const DSINFO_MARKER: u64 = u64::MAX;
+const DSINFO_VERSION: u8 = 1;
 
 pub struct DSContext {
-	store: Store,
+	pub(crate) store: Store,
 	http: UrlContext,
+	pub(crate) authorities: Vec<RsaIdentity>,
+	tolerance: Duration,
+	pub(crate) directory_servers: Vec<String>,
+	pub(crate) fallback_dirs: Vec<String>,
+	bootstrap_timeout: Duration,
+	/// ExcludeNodes, EntryNodes and ExitNodes
+	pub restrictions: NodeRestrictions,
+}
+
+/// When to fetch the consensus after the one we have
+struct FetchSchedule {
+	valid_after: SystemTime,
+	fetch_at: SystemTime,
 }
 
 #[derive(Debug)]
 pub struct HostInfo {
-	pub host: String,
-	pub port: u16,
+	/// The relay's addresses, IPv4 first
+	pub addrs: Vec<SocketAddr>,
 }
 
 impl Writeable for HostInfo {
 	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
-		let items = self.host.split(".");
-		let items = items.collect::<Vec<&str>>();
-		for i in items {
-			let val = u8::from_str(i);
-			if val.is_err() {
-				return Err(ser::Error::CorruptedData);
+		writer.write_u8(self.addrs.len().try_into()?)?;
+		for addr in &self.addrs {
+			match addr.ip() {
+				IpAddr::V4(ip) => {
+					writer.write_u8(4)?;
+					writer.write_fixed_bytes(ip.octets())?;
+				}
+				IpAddr::V6(ip) => {
+					writer.write_u8(6)?;
+					writer.write_fixed_bytes(ip.octets())?;
+				}
 			}
-			writer.write_u8(u8::from_str(i).unwrap())?;
+			writer.write_u16(addr.port())?;
 		}
-		writer.write_u16(self.port)?;
 		Ok(())
 	}
 }
 
 impl Readable for HostInfo {
 	fn read<R: Reader>(reader: &mut R) -> Result<Self, ser::Error> {
-		let mut items = vec![];
-		for _ in 0..4 {
-			let item = reader.read_u8()?;
-			items.push(item);
+		let count = reader.read_u8()?;
+		let mut addrs = vec![];
+		for _ in 0..count {
+			let ip = match reader.read_u8()? {
+				4 => {
+					let octets: [u8; 4] = reader.read_fixed_bytes(4)?[..]
+						.try_into()
+						.map_err(|_| ser::Error::CorruptedData)?;
+					IpAddr::from(octets)
+				}
+				6 => {
+					let octets: [u8; 16] = reader.read_fixed_bytes(16)?[..]
+						.try_into()
+						.map_err(|_| ser::Error::CorruptedData)?;
+					IpAddr::from(octets)
+				}
+				_ => return Err(ser::Error::CorruptedData),
+			};
+			let port = reader.read_u16()?;
+			addrs.push(SocketAddr::new(ip, port));
+		}
+		Ok(HostInfo { addrs })
+	}
+}
+
+impl HostInfo {
+	// entries written before DSInfo was versioned hold a single IPv4
+	// address as four bytes and a port
+	fn read_unversioned<R: Reader>(reader: &mut R) -> Result<Self, ser::Error> {
+		let mut octets = [0u8; 4];
+		for octet in &mut octets {
+			*octet = reader.read_u8()?;
 		}
-		let host = format!("{}.{}.{}.{}", items[0], items[1], items[2], items[3]);
 		let port = reader.read_u16()?;
-		Ok(HostInfo { host, port })
+		Ok(HostInfo {
+			addrs: vec![SocketAddr::new(IpAddr::from(octets), port)],
+		})
 	}
 }
 
@@ -80,6 +167,8 @@ pub struct DSInfo {
 
 impl Writeable for DSInfo {
 	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		writer.write_u64(DSINFO_MARKER)?;
+		writer.write_u8(DSINFO_VERSION)?;
 		writer.write_u64(self.load_time.try_into()?)?;
 		writer.write_u64(self.hosts.len() as u64)?;
 		for host in &self.hosts {
@@ -92,11 +181,24 @@ impl Writeable for DSInfo {
 
 impl Readable for DSInfo {
 	fn read<R: Reader>(reader: &mut R) -> Result<Self, ser::Error> {
-		let load_time = reader.read_u64()?;
+		// unversioned entries start with the load time instead of the marker
+		let first = reader.read_u64()?;
+		let (versioned, load_time) = if first == DSINFO_MARKER {
+			if reader.read_u8()? != DSINFO_VERSION {
+				return Err(ser::Error::CorruptedData);
+			}
+			(true, reader.read_u64()?)
+		} else {
+			(false, first)
+		};
 		let count = reader.read_u64()?;
 		let mut hosts = vec![];
 		for _ in 0..count {
-			let host = HostInfo::read(reader)?;
+			let host = if versioned {
+				HostInfo::read(reader)?
+			} else {
+				HostInfo::read_unversioned(reader)?
+			};
 			hosts.push(host);
 		}
 
@@ -107,44 +209,604 @@ impl Readable for DSInfo {
 	}
 }
 
-fn load_ds_info_from_ds(
-	directory_servers: Vec<String>,
+/// A directory document as it was downloaded
+pub(crate) struct StoredText {
+	pub(crate) text: String,
+}
+
+impl Writeable for StoredText {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		let chunks = self.text.as_bytes().chunks(TEXT_CHUNK_SIZE);
+		writer.write_u64(chunks.len() as u64)?;
+		for chunk in chunks {
+			writer.write_bytes(chunk)?;
+		}
+		Ok(())
+	}
+}
+
+impl Readable for StoredText {
+	fn read<R: Reader>(reader: &mut R) -> Result<Self, ser::Error> {
+		let count = reader.read_u64()?;
+		let mut bytes = vec![];
+		for _ in 0..count {
+			bytes.append(&mut reader.read_bytes_len_prefix()?);
+		}
+		let text = String::from_utf8(bytes).map_err(|_| ser::Error::CorruptedData)?;
+		Ok(StoredText { text })
+	}
+}
+
+/// The digests of the microdescriptors listed by the stored consensus
+pub(crate) struct MdIndex {
+	pub(crate) digests: Vec<MdDigest>,
+}
+
+impl Writeable for MdIndex {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		writer.write_u64(self.digests.len() as u64)?;
+		for digest in &self.digests {
+			writer.write_fixed_bytes(digest)?;
+		}
+		Ok(())
+	}
+}
+
+impl Readable for MdIndex {
+	fn read<R: Reader>(reader: &mut R) -> Result<Self, ser::Error> {
+		let count = reader.read_u64()?;
+		let mut digests = vec![];
+		for _ in 0..count {
+			let digest = reader.read_fixed_bytes(32)?;
+			digests.push(digest.try_into().map_err(|_| ser::Error::CorruptedData)?);
+		}
+		Ok(MdIndex { digests })
+	}
+}
+
+fn md_key(digest: &MdDigest) -> Vec<u8> {
+	key(MD_PREFIX, digest)
+}
+
+/// Failure history of a directory source. Kept in the DB so that after a
+/// restart we don't go straight back to sources we know are down.
+#[derive(Default)]
+struct SourceStatus {
+	failures: u32,
+	/// the last backoff delay in milliseconds
+	delay: u64,
+	/// unix time in milliseconds before which the source isn't tried
+	retry_at: u64,
+}
+
+impl Writeable for SourceStatus {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		writer.write_u32(self.failures)?;
+		writer.write_u64(self.delay)?;
+		writer.write_u64(self.retry_at)?;
+		Ok(())
+	}
+}
+
+impl Readable for SourceStatus {
+	fn read<R: Reader>(reader: &mut R) -> Result<Self, ser::Error> {
+		let failures = reader.read_u32()?;
+		let delay = reader.read_u64()?;
+		let retry_at = reader.read_u64()?;
+		Ok(SourceStatus {
+			failures,
+			delay,
+			retry_at,
+		})
+	}
+}
+
+fn source_key(source: &str) -> Vec<u8> {
+	key(SOURCE_PREFIX, source.as_bytes())
+}
+
+fn now_millis() -> u64 {
+	SystemTime::now()
+		.duration_since(SystemTime::UNIX_EPOCH)
+		.expect("time went backwards")
+		.as_millis() as u64
+}
+
+fn get_source_status(context: &DSContext, source: &str) -> Result<SourceStatus, Error> {
+	let batch = context.store.batch()?;
+	let status: Option<SourceStatus> = batch.get_ser(&source_key(source))?;
+	Ok(status.unwrap_or_default())
+}
+
+// mark a source down for a while. With decorrelated jitter each delay is
+// random between the base and three times the last one.
+fn mark_source_failed(context: &DSContext, source: &str) -> Result<(), Error> {
+	let mut status = get_source_status(context, source)?;
+	let upper = std::cmp::max(RETRY_BASE, status.delay * 3);
+	status.delay = std::cmp::min(RETRY_MAX, rand::thread_rng().gen_range(RETRY_BASE..=upper));
+	status.failures += 1;
+	status.retry_at = now_millis() + status.delay;
+	let batch = context.store.batch()?;
+	batch.put_ser(&source_key(source), &status)?;
+	batch.commit()?;
+	Ok(())
+}
+
+fn mark_source_ok(context: &DSContext, source: &str) -> Result<(), Error> {
+	let batch = context.store.batch()?;
+	let source_key = source_key(source);
+	if batch.exists(&source_key)? {
+		batch.delete(&source_key)?;
+		batch.commit()?;
+	}
+	Ok(())
+}
+
+// the configured directory servers first, then the fallbacks, each in
+// random order
+fn http_sources(context: &DSContext) -> Vec<String> {
+	let mut rng = rand::thread_rng();
+	let mut sources = context.directory_servers.clone();
+	sources.shuffle(&mut rng);
+	let mut fallbacks = context.fallback_dirs.clone();
+	fallbacks.shuffle(&mut rng);
+	sources.append(&mut fallbacks);
+	sources
+}
+
+// turns a downloaded document into what we wanted from it
+type Parse<'a, T> = dyn Fn(String) -> Result<T, Error> + 'a;
+// downloads a document with the given headers and parses it
+type Get<'a, T> = dyn Fn(&[(&str, &str)], &Parse<T>) -> Result<T, Error> + 'a;
+
+/// Where directory documents are downloaded from, and who is told how
+/// it goes
+struct DirConn<'a> {
+	source: DirSource,
+	/// the reporter of the bootstrap the download is part of, if any
+	bootstrap: Option<&'a BootstrapReporter>,
+}
+
+enum DirSource {
+	/// plain HTTP straight to the directory servers and fallbacks, until
+	/// the deadline. Only used for the first bootstrap, when we don't know
+	/// any relays yet.
+	Http {
+		sources: Vec<String>,
+		deadline: Instant,
+	},
+	/// BEGIN_DIR streams over a one hop circuit to a directory cache,
+	/// until the deadline
+	Tor {
+		circ: Arc<ClientCirc>,
+		/// the cache's identity, which its failures are recorded under
+		cache: String,
+		deadline: Instant,
+	},
+}
+
+impl DirConn<'_> {
+	// bootstrapping is held up while sources fail, and carries on from
+	// where it was as soon as one answers
+	fn report<T>(&self, source: &str, res: &Result<T, Error>) {
+		if let Some(bootstrap) = self.bootstrap {
+			match res {
+				Ok(_) => {
+					let status = bootstrap.status();
+					bootstrap.advance_to(status.phase, status.percent);
+				}
+				Err(e) => bootstrap.block(&format!("directory source {} failed: {}", source, e)),
+			}
+		}
+	}
+
+	fn microdescs_loaded(&self, have: usize, listed: usize) {
+		if let Some(bootstrap) = self.bootstrap {
+			bootstrap.advance_to(BootstrapPhase::Microdescs, microdescs_percent(have, listed));
+		}
+	}
+}
+
+// download a document and parse it with `parse`. A response that doesn't
+// parse counts as a failed download.
+fn fetch<R: Runtime, T>(
+	conn: &DirConn,
+	path: &str,
+	headers: &[(&str, &str)],
+	limit: usize,
 	context: &DSContext,
-) -> Result<String, Error> {
-	let mut count = 0;
-	let len = directory_servers.len();
-	loop {
-		let response = do_get(
-			&format!(
-				"http://{}/tor/status-vote/current/consensus/",
-				directory_servers[count % len]
-			),
-			&context.http,
-		);
-		if response.is_ok() {
-			return Ok(response?);
+	runtime: &R,
+	parse: &Parse<T>,
+) -> Result<T, Error> {
+	match &conn.source {
+		// try the sources that aren't marked down, those with the fewest
+		// failures first, until one of them gives us a good response or
+		// the deadline passes
+		DirSource::Http { sources, deadline } => {
+			if sources.is_empty() {
+				return Err(ErrorKind::BootstrapTimeout(
+					"no directory sources configured".to_string(),
+				)
+				.into());
+			}
+			loop {
+				if Instant::now() >= *deadline {
+					return Err(ErrorKind::BootstrapTimeout(format!(
+						"no directory source answered {} in time",
+						path
+					))
+					.into());
+				}
+
+				let (usable, next_retry) = usable_sources(sources, context)?;
+				match usable.first() {
+					Some(source) => {
+						let res = try_source(conn, source, path, headers, limit, context, parse);
+						if res.is_ok() {
+							return res;
+						}
+					}
+					None => {
+						// everything is marked down, so wait for the first
+						// one to come back, or the deadline
+						let wait = Duration::from_millis(next_retry.saturating_sub(now_millis()));
+						let left = deadline.saturating_duration_since(Instant::now());
+						thread::sleep(std::cmp::min(wait, left));
+					}
+				}
+			}
+		}
+		// a cache that stalls counts as failed, like one that refuses
+		DirSource::Tor {
+			circ,
+			cache,
+			deadline,
+		} => {
+			let left = deadline.saturating_duration_since(Instant::now());
+			let res = runtime
+				.block_on(runtime.timeout(left, dirclient::get(circ, path, headers, limit)))
+				.unwrap_or_else(|_| {
+					Err(ErrorKind::BootstrapTimeout(format!(
+						"directory cache didn't answer {} in time",
+						path
+					))
+					.into())
+				})
+				.and_then(parse);
+			match res {
+				Ok(_) => mark_source_ok(context, cache)?,
+				Err(_) => mark_source_failed(context, cache)?,
+			}
+			conn.report(cache, &res);
+			res
 		}
-		std::thread::sleep(std::time::Duration::from_millis(100));
-		count += 1;
 	}
 }
 
-fn update_db(directory_servers: Vec<String>, context: &DSContext) -> Result<(), Error> {
-	let response = load_ds_info_from_ds(directory_servers, context)?;
-	let arr = response.split("\n");
-	let mut hosts = vec![];
-	for line in arr {
-		if line.starts_with("r ") {
-			// this is a server to add to our hosts
-			let items = line.split(" ");
-			let items = items.collect::<Vec<&str>>();
-			let host_info = HostInfo {
-				host: items[6].to_string(),
-				port: u16::from_str(items[7])?,
+// like `fetch`, but only ask one source, for downloads we can do without.
+// Trying every source would use up the time left for the ones we need.
+fn fetch_once<R: Runtime, T>(
+	conn: &DirConn,
+	path: &str,
+	headers: &[(&str, &str)],
+	limit: usize,
+	context: &DSContext,
+	runtime: &R,
+	parse: &Parse<T>,
+) -> Result<T, Error> {
+	match &conn.source {
+		DirSource::Http { sources, .. } => match usable_sources(sources, context)?.0.first() {
+			Some(source) => try_source(conn, source, path, headers, limit, context, parse),
+			None => Err(ErrorKind::DirRequestError(format!(
+				"no directory source to ask for {}",
+				path
+			))
+			.into()),
+		},
+		DirSource::Tor { .. } => fetch(conn, path, headers, limit, context, runtime, parse),
+	}
+}
+
+// the sources that aren't marked down, those with the fewest failures
+// first, and when the first of the others comes back
+fn usable_sources<'a>(
+	sources: &'a [String],
+	context: &DSContext,
+) -> Result<(Vec<&'a String>, u64), Error> {
+	let now = now_millis();
+	let mut usable = vec![];
+	let mut next_retry = u64::MAX;
+	for source in sources {
+		let status = get_source_status(context, source)?;
+		if status.retry_at <= now {
+			usable.push((status.failures, source));
+		} else {
+			next_retry = std::cmp::min(next_retry, status.retry_at);
+		}
+	}
+	usable.sort_by_key(|(failures, _)| *failures);
+	Ok((
+		usable.into_iter().map(|(_, source)| source).collect(),
+		next_retry,
+	))
+}
+
+// download from a single source over plain HTTP, and keep track of
+// whether it worked
+fn try_source<T>(
+	conn: &DirConn,
+	source: &str,
+	path: &str,
+	headers: &[(&str, &str)],
+	limit: usize,
+	context: &DSContext,
+	parse: &Parse<T>,
+) -> Result<T, Error> {
+	let res = do_get(
+		&format!("http://{}{}", source, path),
+		&context.http,
+		headers,
+		limit,
+	)
+	.and_then(parse);
+	match res {
+		Ok(_) => mark_source_ok(context, source)?,
+		Err(_) => mark_source_failed(context, source)?,
+	}
+	conn.report(source, &res);
+	res
+}
+
+// check a certificate's signature and lifetime, and that it belongs to one
+// of our authorities
+fn check_authcert(context: &DSContext, text: &str) -> Option<AuthCert> {
+	let cert = AuthCert::parse(text)
+		.ok()?
+		.check_signature()
+		.ok()?
+		.check_valid_now()
+		.ok()?;
+	if context.authorities.contains(&cert.fingerprint) {
+		Some(cert)
+	} else {
+		None
+	}
+}
+
+fn load_authcerts(context: &DSContext) -> Result<Vec<AuthCert>, Error> {
+	let batch = context.store.batch()?;
+	let mut certs = vec![];
+	for authority in &context.authorities {
+		let stored: Option<StoredText> =
+			batch.get_ser(&key(AUTHCERT_PREFIX, authority.as_bytes()))?;
+		if let Some(cert) = stored.and_then(|stored| check_authcert(context, &stored.text)) {
+			certs.push(cert);
+		}
+	}
+	Ok(certs)
+}
+
+// fetch the authority certificates and make sure that enough of the
+// configured authorities signed this consensus. The certificates we have
+// are tried first, so the authorities' keys are only downloaded again
+// when they change.
+fn verify_consensus<R: Runtime>(
+	consensus: &Consensus,
+	conn: &DirConn,
+	context: &DSContext,
+	runtime: &R,
+) -> Result<(), Error> {
+	if consensus
+		.check_signatures(&context.authorities, &load_authcerts(context)?)
+		.is_ok()
+	{
+		return Ok(());
+	}
+
+	let response = fetch(
+		conn,
+		"/tor/keys/all",
+		&[],
+		AUTHCERT_SIZE_LIMIT,
+		context,
+		runtime,
+		&|text| {
+			AuthCert::parse_multiple(&text)?;
+			Ok(text)
+		},
+	)?;
+	let mut certs = vec![];
+	let mut texts = vec![];
+	for text in AuthCert::split(&response) {
+		if let Some(cert) = check_authcert(context, text) {
+			certs.push(cert);
+			texts.push(text);
+		}
+	}
+	consensus.check_signatures(&context.authorities, &certs)?;
+
+	let batch = context.store.batch()?;
+	for (cert, text) in certs.iter().zip(texts) {
+		batch.put_ser(
+			&key(AUTHCERT_PREFIX, cert.fingerprint.as_bytes()),
+			&StoredText {
+				text: text.to_string(),
+			},
+		)?;
+	}
+	batch.commit()?;
+	Ok(())
+}
+
+// download the microdescriptors listed in the consensus that we don't
+// have yet, and drop the ones that are no longer listed
+fn update_microdescs<R: Runtime>(
+	consensus: &Consensus,
+	conn: &DirConn,
+	context: &DSContext,
+	runtime: &R,
+) -> Result<(), Error> {
+	let wanted: Vec<MdDigest> = consensus
+		.routers
+		.iter()
+		.filter_map(|router| router.doc_digest[..].try_into().ok())
+		.collect();
+
+	let mut missing = vec![];
+	{
+		let batch = context.store.batch()?;
+		for digest in &wanted {
+			if !batch.exists(&md_key(digest))? {
+				missing.push(*digest);
+			}
+		}
+	}
+
+	let mut have = wanted.len() - missing.len();
+	conn.microdescs_loaded(have, wanted.len());
+	for digests in missing.chunks(MD_BATCH_SIZE) {
+		let path = format!(
+			"/tor/micro/d/{}",
+			digests
+				.iter()
+				.map(encode_digest)
+				.collect::<Vec<String>>()
+				.join("-")
+		);
+		let response = fetch(
+			conn,
+			&path,
+			&[],
+			MICRODESC_SIZE_LIMIT,
+			context,
+			runtime,
+			&|text| Ok(text),
+		)?;
+		let batch = context.store.batch()?;
+		for text in Microdesc::split(&response) {
+			let md = match Microdesc::parse(text) {
+				Ok(md) => md,
+				Err(_) => continue,
 			};
-			hosts.push(host_info);
+			// only keep what we asked for
+			if digests.contains(&md.digest) {
+				batch.put_ser(
+					&md_key(&md.digest),
+					&StoredText {
+						text: text.to_string(),
+					},
+				)?;
+				have += 1;
+			}
+		}
+		batch.commit()?;
+		conn.microdescs_loaded(have, wanted.len());
+	}
+
+	let batch = context.store.batch()?;
+	let old_index: Option<MdIndex> = batch.get_ser(MD_INDEX_KEY)?;
+	if let Some(old_index) = old_index {
+		let wanted: HashSet<&MdDigest> = wanted.iter().collect();
+		for digest in &old_index.digests {
+			if !wanted.contains(digest) {
+				batch.delete(&md_key(digest))?;
+			}
+		}
+	}
+	batch.put_ser(MD_INDEX_KEY, &MdIndex { digests: wanted })?;
+	batch.commit()?;
+
+	Ok(())
+}
+
+// parse a consensus and make sure it is at least reasonably live
+fn parse_consensus(text: String, context: &DSContext) -> Result<(String, Consensus), Error> {
+	match Consensus::parse(&text)?
+		.timebound()
+		.extend_tolerance(context.tolerance)
+		.extend_pre_tolerance(context.tolerance)
+		.check_valid_now()
+	{
+		Ok(consensus) => Ok((text, consensus)),
+		Err(e) => Err(ErrorKind::ConsensusRejected(format!("consensus is not live: {}", e)).into()),
+	}
+}
+
+// ask for a diff from `base` if we have one, and fall back to a full
+// download if that fails or the diff doesn't apply. `get_diff` and
+// `get_full` download the consensus with the given headers and hand the
+// response to the parser.
+fn fetch_diff_or_full<T>(
+	base: Option<&str>,
+	get_diff: &Get<T>,
+	get_full: &Get<T>,
+	parse: &Parse<T>,
+) -> Result<T, Error> {
+	if let Some(base) = base {
+		let digest = consensus_signed_digest_hex(base);
+		let res = get_diff(&[(DIFF_HEADER, digest.as_str())], &|text| {
+			if is_diff(&text) {
+				parse(apply_diff(base, &text)?)
+			} else {
+				parse(text)
+			}
+		});
+		if res.is_ok() {
+			return res;
 		}
 	}
+	get_full(&[], parse)
+}
+
+// a diff is only asked for once, so a bad one leaves the other sources
+// and the rest of the deadline to the full download
+fn fetch_consensus<R: Runtime>(
+	conn: &DirConn,
+	context: &DSContext,
+	runtime: &R,
+) -> Result<(String, Consensus), Error> {
+	let base: Option<StoredText> = {
+		let batch = context.store.batch()?;
+		batch.get_ser(CONSENSUS_KEY)?
+	};
+	fetch_diff_or_full(
+		base.as_ref().map(|base| base.text.as_str()),
+		&|headers, parse| {
+			fetch_once(
+				conn,
+				CONSENSUS_PATH,
+				headers,
+				CONSENSUS_SIZE_LIMIT,
+				context,
+				runtime,
+				parse,
+			)
+		},
+		&|headers, parse| {
+			fetch(
+				conn,
+				CONSENSUS_PATH,
+				headers,
+				CONSENSUS_SIZE_LIMIT,
+				context,
+				runtime,
+				parse,
+			)
+		},
+		&|text| parse_consensus(text, context),
+	)
+}
+
+fn update_db<R: Runtime>(conn: &DirConn, context: &DSContext, runtime: &R) -> Result<(), Error> {
+	let (response, consensus) = fetch_consensus(conn, context, runtime)?;
+	verify_consensus(&consensus, conn, context, runtime)?;
+	update_microdescs(&consensus, conn, context, runtime)?;
+	let mut hosts = vec![];
+	for router in &consensus.routers {
+		hosts.push(HostInfo {
+			addrs: router.addrs.clone(),
+		});
+	}
 	let load_time = SystemTime::now()
 		.duration_since(SystemTime::UNIX_EPOCH)
 		.expect("time went backwards")
@@ -155,49 +817,252 @@ fn update_db(directory_servers: Vec<String>, context: &DSContext) -> Result<(),
 	{
 		let batch = context.store.batch()?;
 		batch.put_ser(HOSTS_KEY, &dsinfo)?;
+		batch.put_ser(CONSENSUS_KEY, &StoredText { text: response })?;
 		batch.commit()?;
 	}
 
 	Ok(())
 }
 
+// once we have a consensus, fetch over a one hop circuit to one of the
+// directory caches it lists. Only the first bootstrap uses plain HTTP.
+fn update<R: Runtime>(
+	context: &DSContext,
+	chanmgr: &ChanMgr<R>,
+	mainlog: &'static Arc<Mutex<Log>>,
+	runtime: &mut R,
+	bootstrap: Option<&BootstrapReporter>,
+) -> Result<(), Error> {
+	let deadline = Instant::now() + context.bootstrap_timeout;
+	let source = match get_netdir(context)? {
+		Some(netdir) if netdir.relays().next().is_some() => {
+			// caches that failed us recently are left out, like the
+			// HTTP sources
+			let now = now_millis();
+			let usable = |relay: &Relay| {
+				get_source_status(context, &relay.rs.rsa_identity.to_string())
+					.map(|status| status.retry_at <= now)
+					.unwrap_or(true)
+			};
+			let build = build_dir_circuit(
+				&netdir,
+				&context.restrictions,
+				&usable,
+				chanmgr,
+				mainlog,
+				&*runtime,
+			);
+			let (circ, cache) = runtime
+				.block_on(runtime.timeout(context.bootstrap_timeout, build))
+				.unwrap_or_else(|_| {
+					Err(ErrorKind::BootstrapTimeout(
+						"no directory circuit could be built in time".to_string(),
+					)
+					.into())
+				})?;
+			DirSource::Tor {
+				circ,
+				cache: cache.to_string(),
+				deadline,
+			}
+		}
+		_ => DirSource::Http {
+			sources: http_sources(context),
+			deadline,
+		},
+	};
+	let conn = DirConn { source, bootstrap };
+	let res = update_db(&conn, context, runtime);
+	if let DirSource::Tor { circ, .. } = conn.source {
+		runtime.block_on(circ.terminate());
+	}
+	res
+}
+
 fn get_hosts_from_db(context: &DSContext) -> Result<Option<DSInfo>, Error> {
 	let batch = context.store.batch()?;
 	let res: Option<DSInfo> = batch.get_ser(HOSTS_KEY)?;
 	Ok(res)
 }
 
+/// Load the stored consensus, unless it is past its valid-until time by
+/// more than the configured tolerance.
+fn load_consensus(context: &DSContext) -> Result<Option<Consensus>, Error> {
+	let batch = context.store.batch()?;
+	let stored: Option<StoredText> = batch.get_ser(CONSENSUS_KEY)?;
+	let consensus = match stored {
+		Some(stored) => Consensus::parse(&stored.text)?,
+		None => return Ok(None),
+	};
+	Ok(consensus
+		.timebound()
+		.extend_tolerance(context.tolerance)
+		.extend_pre_tolerance(context.tolerance)
+		.check_valid_now()
+		.ok())
+}
+
+// dir-spec 5.1: clients pick a random time between 3/4 of a voting interval
+// after fresh-until and 7/8 of the remaining time before valid-until, so
+// they don't all fetch at once
+fn pick_fetch_time(header: &ConsensusHeader) -> SystemTime {
+	let interval = header
+		.fresh_until
+		.duration_since(header.valid_after)
+		.unwrap_or_default();
+	let start = header.fresh_until + interval * 3 / 4;
+	let window = header.valid_until.duration_since(start).unwrap_or_default() * 7 / 8;
+	start + window.mul_f64(rand::thread_rng().gen::<f64>())
+}
+
+// we refresh once the fetch time of the consensus we have has passed, or
+// right away if we don't have a usable one
+fn refresh_due(context: &DSContext, schedule: &mut Option<FetchSchedule>) -> Result<bool, Error> {
+	let consensus = match load_consensus(context)? {
+		Some(consensus) => consensus,
+		None => return Ok(true),
+	};
+	let header = &consensus.header;
+	let fetch_at = match schedule {
+		Some(schedule) if schedule.valid_after == header.valid_after => schedule.fetch_at,
+		_ => {
+			let fetch_at = pick_fetch_time(header);
+			*schedule = Some(FetchSchedule {
+				valid_after: header.valid_after,
+				fetch_at,
+			});
+			fetch_at
+		}
+	};
+	Ok(SystemTime::now() >= fetch_at)
+}
+
+/// Load the stored consensus and whichever of its microdescriptors we have.
+/// Returns None if we have no consensus that is at least reasonably live.
+pub fn get_netdir(context: &DSContext) -> Result<Option<NetDir>, Error> {
+	let consensus = match load_consensus(context)? {
+		Some(consensus) => consensus,
+		None => return Ok(None),
+	};
+	let batch = context.store.batch()?;
+	let mut microdescs = HashMap::new();
+	for router in &consensus.routers {
+		let digest: MdDigest = match router.doc_digest[..].try_into() {
+			Ok(digest) => digest,
+			Err(_) => continue,
+		};
+		let stored: Option<StoredText> = batch.get_ser(&md_key(&digest))?;
+		if let Some(stored) = stored {
+			if let Ok(md) = Microdesc::parse(&stored.text) {
+				microdescs.insert(md.digest, md);
+			}
+		}
+	}
+	Ok(Some(NetDir::new(consensus, microdescs)))
+}
+
+/// Load the guard state and bring it up to date with a directory.
+pub fn update_guards(context: &DSContext, netdir: &NetDir) -> Result<GuardMgr, Error> {
+	let mut guards: GuardMgr = {
+		let batch = context.store.batch()?;
+		let guards: Option<GuardMgr> = batch.get_ser(GUARD_KEY)?;
+		guards.unwrap_or_default()
+	};
+	guards.update(
+		&mut rand::thread_rng(),
+		netdir,
+		&context.restrictions,
+		SystemTime::now(),
+	);
+	store_guards(context, &guards)?;
+	Ok(guards)
+}
+
+/// Save the guard state, so the same guards are used after a restart.
+pub fn store_guards(context: &DSContext, guards: &GuardMgr) -> Result<(), Error> {
+	let batch = context.store.batch()?;
+	batch.put_ser(GUARD_KEY, guards)?;
+	batch.commit()?;
+	Ok(())
+}
+
+/// Load the circuit build times learned in earlier runs.
+pub fn load_timeouts(context: &DSContext) -> Result<TimeoutEstimator, Error> {
+	let batch = context.store.batch()?;
+	let timeouts: Option<TimeoutEstimator> = batch.get_ser(TIMEOUT_KEY)?;
+	Ok(timeouts.unwrap_or_default())
+}
+
+/// Save the circuit build times, so the timeout needn't be learned again.
+pub fn store_timeouts(context: &DSContext, timeouts: &TimeoutEstimator) -> Result<(), Error> {
+	let batch = context.store.batch()?;
+	batch.put_ser(TIMEOUT_KEY, timeouts)?;
+	batch.commit()?;
+	Ok(())
+}
+
 pub fn build_ds_context(config: &TorConfig) -> Result<DSContext, Error> {
 	let store = Store::new(&config.db_root, None, Some(DB_NAME), None, true)?;
+	ds_store::migrate(&store)?;
 	let http = build_connector_context(20, 20, 20);
+	let mut authorities = vec![];
+	for authority in &config.authorities {
+		authorities.push(parse_fingerprint(authority)?);
+	}
 
-	Ok(DSContext { store, http })
+	Ok(DSContext {
+		store,
+		http,
+		authorities,
+		tolerance: Duration::from_millis(config.ds_validity_tolerance),
+		directory_servers: config.directory_servers.clone(),
+		fallback_dirs: config.fallback_dirs.clone(),
+		bootstrap_timeout: Duration::from_millis(config.bootstrap_timeout),
+		restrictions: NodeRestrictions::from_config(config)?,
+	})
 }
 
-pub fn start_dsinfo_refresh_thread(
+pub fn start_dsinfo_refresh_thread<R: Runtime>(
 	config: &TorConfig,
 	stop_state: Arc<RwLock<StopState>>,
-	mainlog: Arc<Mutex<Log>>,
+	chanmgr: Arc<ChanMgr<R>>,
+	mainlog: &'static Arc<Mutex<Log>>,
+	mut runtime: R,
 ) -> Result<(), Error> {
 	let refresh_frequency = config.ds_refresh_frequency;
-	let directory_servers = config.directory_servers.clone();
 	let context = build_ds_context(config)?;
 	thread::spawn(move || {
 		let mut count = 0;
+		let mut schedule = None;
 		loop {
 			if count != 0 && (count * 100) % refresh_frequency == 0 {
-				{
+				let due = match refresh_due(&context, &mut schedule) {
+					Ok(due) => due,
+					Err(e) => {
+						let mut mainlog = mainlog.lock().unwrap();
+						(*mainlog)
+							.log(&format!("could not load directory information: {}", e))
+							.unwrap();
+						true
+					}
+				};
+				if due {
+					{
+						let mut mainlog = mainlog.lock().unwrap();
+						(*mainlog)
+							.log("updating directory information to DB")
+							.unwrap();
+					}
+					let res = update(&context, &chanmgr, mainlog, &mut runtime, None);
 					let mut mainlog = mainlog.lock().unwrap();
-					(*mainlog)
-						.log("updating directory information to DB")
-						.unwrap();
-				}
-				update_db(directory_servers.clone(), &context).unwrap();
-				{
-					let mut mainlog = mainlog.lock().unwrap();
-					(*mainlog)
-						.log("updating directory information to DB complete")
-						.unwrap();
+					match res {
+						Ok(_) => (*mainlog)
+							.log("updating directory information to DB complete")
+							.unwrap(),
+						Err(e) => (*mainlog)
+							.log(&format!("updating directory information failed: {}", e))
+							.unwrap(),
+					}
 				}
 			}
 			std::thread::sleep(std::time::Duration::from_millis(100));
@@ -212,17 +1077,200 @@ pub fn start_dsinfo_refresh_thread(
 	Ok(())
 }
 
-pub fn get_latest_valid_dsinfo(config: &TorConfig, context: &DSContext) -> Result<DSInfo, Error> {
-	let now = SystemTime::now()
-		.duration_since(SystemTime::UNIX_EPOCH)
-		.expect("time went backwards")
-		.as_millis();
-	let mut hosts = get_hosts_from_db(context)?;
-	if hosts.is_none() || now - hosts.as_ref().unwrap().load_time > config.ds_refresh_timeout.into()
-	{
-		update_db(config.directory_servers.clone(), context)?;
-		hosts = get_hosts_from_db(context)?;
+/// Load the stored directory information, fetching it first if what is
+/// stored is missing or due for a refresh, over channels from `chanmgr`
+/// once there is a consensus. Progress and failing directory sources are
+/// reported to `bootstrap`, if given.
+pub fn get_latest_valid_dsinfo<R: Runtime>(
+	context: &DSContext,
+	chanmgr: &ChanMgr<R>,
+	mainlog: &'static Arc<Mutex<Log>>,
+	runtime: &mut R,
+	bootstrap: Option<&BootstrapReporter>,
+) -> Result<DSInfo, Error> {
+	if load_consensus(context)?.is_none() {
+		// nothing we can use, so we have to get a consensus now
+		update(context, chanmgr, mainlog, runtime, bootstrap)?;
+	} else if refresh_due(context, &mut None)? {
+		// what we have is still usable if this fails
+		if let Err(e) = update(context, chanmgr, mainlog, runtime, bootstrap) {
+			let mut mainlog = mainlog.lock()?;
+			(*mainlog).log(&format!("updating directory information failed: {}", e))?;
+		}
+	}
+
+	match get_hosts_from_db(context)? {
+		Some(hosts) => Ok(hosts),
+		None => Err(ErrorKind::NetDocError("no directory information stored".to_string()).into()),
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::ds_store::test::TempDir;
+	use std::cell::RefCell;
+	use tor_netdoc::consdiff::consensus_digest_hex;
+
+	const BASE: &str = "network-status-version 3 microdesc\nw Bandwidth=7\n\
+		directory-signature sha256 AA BB\n";
+
+	// a diff that changes the bandwidth line, claiming to be against `from`
+	fn make_diff(from: &str, target: &str) -> String {
+		format!(
+			"network-status-diff-version 1\nhash {} {}\n2c\nw Bandwidth=9\n.\n",
+			consensus_digest_hex(from),
+			consensus_digest_hex(target)
+		)
+	}
+
+	fn header_lines(headers: &[(&str, &str)]) -> Vec<String> {
+		headers
+			.iter()
+			.map(|(name, value)| format!("{}: {}", name, value))
+			.collect()
+	}
+
+	#[test]
+	fn dsinfo_round_trip() {
+		let dsinfo = DSInfo {
+			load_time: 1_623_330_000_000,
+			hosts: vec![
+				HostInfo {
+					addrs: vec![
+						"192.0.2.1:9001".parse().unwrap(),
+						"[2001:db8::1]:443".parse().unwrap(),
+					],
+				},
+				HostInfo { addrs: vec![] },
+			],
+		};
+		let bytes = ser::ser_vec(&dsinfo, ser::ProtocolVersion::local()).unwrap();
+		let read: DSInfo =
+			ser::deserialize(&mut &bytes[..], ser::ProtocolVersion::local()).unwrap();
+		assert_eq!(read.load_time, dsinfo.load_time);
+		assert_eq!(read.hosts.len(), 2);
+		assert_eq!(read.hosts[0].addrs, dsinfo.hosts[0].addrs);
+		assert!(read.hosts[1].addrs.is_empty());
+
+		// an unknown version is refused
+		let mut bytes = bytes;
+		bytes[8] = DSINFO_VERSION + 1;
+		let read: Result<DSInfo, _> =
+			ser::deserialize(&mut &bytes[..], ser::ProtocolVersion::local());
+		assert!(read.is_err());
+	}
+
+	#[test]
+	fn dsinfo_unversioned() {
+		// load time, host count, then four octets and a port per host
+		let mut bytes = vec![];
+		bytes.extend_from_slice(&1_623_330_000_000u64.to_be_bytes());
+		bytes.extend_from_slice(&2u64.to_be_bytes());
+		bytes.extend_from_slice(&[192, 0, 2, 1]);
+		bytes.extend_from_slice(&9001u16.to_be_bytes());
+		bytes.extend_from_slice(&[198, 51, 100, 7]);
+		bytes.extend_from_slice(&443u16.to_be_bytes());
+
+		let read: DSInfo =
+			ser::deserialize(&mut &bytes[..], ser::ProtocolVersion::local()).unwrap();
+		assert_eq!(read.load_time, 1_623_330_000_000);
+		let addrs: Vec<SocketAddr> = read
+			.hosts
+			.iter()
+			.flat_map(|host| host.addrs.clone())
+			.collect();
+		assert_eq!(
+			addrs,
+			vec![
+				"192.0.2.1:9001".parse::<SocketAddr>().unwrap(),
+				"198.51.100.7:443".parse().unwrap(),
+			]
+		);
 	}
 
-	Ok(hosts.unwrap())
+	#[test]
+	fn diff_or_full() {
+		let target = BASE.replace("Bandwidth=7", "Bandwidth=9");
+
+		// a diff that applies is used
+		let requests = RefCell::new(vec![]);
+		let get = |headers: &[(&str, &str)], parse: &Parse<String>| {
+			requests.borrow_mut().push(header_lines(headers));
+			parse(make_diff(BASE, &target))
+		};
+		let res = fetch_diff_or_full(Some(BASE), &get, &get, &Ok).unwrap();
+		assert_eq!(res, target);
+		let diff_header = format!("{}: {}", DIFF_HEADER, consensus_signed_digest_hex(BASE));
+		assert_eq!(*requests.borrow(), vec![vec![diff_header.clone()]]);
+
+		// one against some other consensus doesn't, so the whole consensus
+		// is downloaded
+		requests.borrow_mut().clear();
+		let get = |headers: &[(&str, &str)], parse: &Parse<String>| {
+			requests.borrow_mut().push(header_lines(headers));
+			if headers.is_empty() {
+				parse(target.clone())
+			} else {
+				parse(make_diff(&target, &target))
+			}
+		};
+		let res = fetch_diff_or_full(Some(BASE), &get, &get, &Ok).unwrap();
+		assert_eq!(res, target);
+		assert_eq!(*requests.borrow(), vec![vec![diff_header], vec![]]);
+
+		// without a base there is nothing to diff from
+		requests.borrow_mut().clear();
+		let res = fetch_diff_or_full(None, &get, &get, &Ok).unwrap();
+		assert_eq!(res, target);
+		assert_eq!(*requests.borrow(), vec![Vec::<String>::new()]);
+	}
+
+	#[test]
+	fn bootstrap_reports() {
+		let dir = TempDir::new("ds_load");
+		let context = DSContext {
+			store: Store::new(dir.0.to_str().unwrap(), None, Some(DB_NAME), None, true).unwrap(),
+			http: build_connector_context(20, 20, 20),
+			authorities: vec![],
+			tolerance: Duration::from_secs(0),
+			directory_servers: vec![],
+			fallback_dirs: vec![],
+			bootstrap_timeout: Duration::from_secs(1),
+			restrictions: NodeRestrictions::default(),
+		};
+		let bootstrap =
+			BootstrapReporter::new(Box::leak(Box::new(Arc::new(Mutex::new(Log::new())))));
+		bootstrap.advance(BootstrapPhase::Directory);
+		let conn = DirConn {
+			source: DirSource::Http {
+				sources: vec![],
+				deadline: Instant::now(),
+			},
+			bootstrap: Some(&bootstrap),
+		};
+
+		// nothing listens there
+		let source = "127.0.0.1:1";
+		let res = try_source(&conn, source, "/tor/keys/all", &[], 1024, &context, &Ok);
+		assert!(res.is_err());
+		let status = bootstrap.status();
+		assert_eq!(status.phase, BootstrapPhase::Directory);
+		assert!(status
+			.blocked
+			.unwrap()
+			.starts_with("directory source 127.0.0.1:1 failed"));
+		assert_eq!(get_source_status(&context, source).unwrap().failures, 1);
+
+		// the next one to answer lets it carry on
+		conn.report(source, &Ok(()));
+		assert_eq!(bootstrap.status().blocked, None);
+		assert_eq!(bootstrap.status().percent, 5);
+
+		conn.microdescs_loaded(0, 100);
+		assert_eq!(bootstrap.status().percent, 10);
+		conn.microdescs_loaded(50, 100);
+		assert_eq!(bootstrap.status().phase, BootstrapPhase::Microdescs);
+		assert_eq!(bootstrap.status().percent, 44);
+	}
 }
@@ -0,0 +1,343 @@
+// Copyright 2021 The BMW Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::ds_load::{DSContext, DSInfo, MdIndex, StoredText};
+use tor_util::core::ser::{self, Readable, Reader, Writeable, Writer};
+use tor_util::store::lmdb::{Batch, Store};
+use tor_util::{Error, ErrorKind};
+
+/// The layout of the ds_db store that this version writes. Bump it and
+/// add a step to `migrate` whenever a stored type or key changes.
+pub const SCHEMA_VERSION: u64 = 1;
+
+// Every key starts with a byte saying what kind of record it is. Single
+// records use just that byte, the others add an id after it.
+pub(crate) const SCHEMA_KEY: &[u8] = &[0];
+pub(crate) const HOSTS_KEY: &[u8] = &[1];
+pub(crate) const CONSENSUS_KEY: &[u8] = &[2];
+pub(crate) const MD_INDEX_KEY: &[u8] = &[3];
+// followed by the microdescriptor digest
+pub(crate) const MD_PREFIX: u8 = 4;
+// followed by the directory source address
+pub(crate) const SOURCE_PREFIX: u8 = 5;
+// followed by the authority's RSA identity
+pub(crate) const AUTHCERT_PREFIX: u8 = 6;
+pub(crate) const GUARD_KEY: &[u8] = &[7];
+pub(crate) const TIMEOUT_KEY: &[u8] = &[8];
+// every kind of record, for going over the whole store
+const PREFIXES: &[u8] = &[
+	SCHEMA_KEY[0],
+	HOSTS_KEY[0],
+	CONSENSUS_KEY[0],
+	MD_INDEX_KEY[0],
+	MD_PREFIX,
+	SOURCE_PREFIX,
+	AUTHCERT_PREFIX,
+	GUARD_KEY[0],
+	TIMEOUT_KEY[0],
+];
+
+/// Build the key of a record that has an id
+pub(crate) fn key(prefix: u8, id: &[u8]) -> Vec<u8> {
+	let mut key = vec![prefix];
+	key.extend_from_slice(id);
+	key
+}
+
+struct SchemaVersion(u64);
+
+impl Writeable for SchemaVersion {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		writer.write_u64(self.0)
+	}
+}
+
+impl Readable for SchemaVersion {
+	fn read<R: Reader>(reader: &mut R) -> Result<Self, ser::Error> {
+		Ok(SchemaVersion(reader.read_u64()?))
+	}
+}
+
+// any record, skipped over unread, for walking the keys
+struct AnyRecord;
+
+impl Readable for AnyRecord {
+	fn read<R: Reader>(_reader: &mut R) -> Result<Self, ser::Error> {
+		Ok(AnyRecord)
+	}
+}
+
+/// Return the schema version of the store. Stores written before the
+/// version was recorded have none, which is version 0.
+fn schema_version(batch: &Batch) -> Result<u64, Error> {
+	let version: Option<SchemaVersion> = batch.get_ser(SCHEMA_KEY)?;
+	Ok(version.map(|v| v.0).unwrap_or(0))
+}
+
+// version 0 stored DSInfo without a version and only IPv4 hosts. DSInfo
+// can still read that, so rewrite it in the current format. Anything we
+// can't read is dropped and downloaded again.
+fn migrate_v0(batch: &Batch) -> Result<(), Error> {
+	match batch.get_ser::<DSInfo>(HOSTS_KEY) {
+		Ok(Some(dsinfo)) => batch.put_ser(HOSTS_KEY, &dsinfo)?,
+		Ok(None) => {}
+		Err(_) => batch.delete(HOSTS_KEY)?,
+	}
+	Ok(())
+}
+
+/// Bring a store written by an older version up to `SCHEMA_VERSION`. A
+/// store written by a newer version is refused rather than misread.
+pub fn migrate(store: &Store) -> Result<(), Error> {
+	let batch = store.batch()?;
+	let mut version = schema_version(&batch)?;
+	if version > SCHEMA_VERSION {
+		return Err(ErrorKind::StoreError(format!(
+			"ds_db has schema version {}, this version only knows {}",
+			version, SCHEMA_VERSION
+		))
+		.into());
+	}
+	if version == SCHEMA_VERSION {
+		return Ok(());
+	}
+
+	while version < SCHEMA_VERSION {
+		match version {
+			0 => migrate_v0(&batch)?,
+			_ => unreachable!(),
+		}
+		version += 1;
+	}
+	batch.put_ser(SCHEMA_KEY, &SchemaVersion(version))?;
+	batch.commit()?;
+	Ok(())
+}
+
+/// What the directory store holds
+#[derive(Debug)]
+pub struct StoreSummary {
+	/// version of the layout the store was written with
+	pub schema_version: u64,
+	/// number of hosts in the stored DSInfo
+	pub hosts: Option<usize>,
+	/// size of the stored consensus in bytes
+	pub consensus_size: Option<usize>,
+	/// number of stored microdescriptors
+	pub microdescs: usize,
+	/// number of stored authority key certificates
+	pub authcerts: usize,
+	/// directory sources that are currently marked as failing
+	pub failing_sources: usize,
+	/// whether guard state has been saved
+	pub guard_state: bool,
+	/// whether learned circuit build times have been saved
+	pub timeout_state: bool,
+}
+
+/// Report what the store holds.
+pub fn inspect(context: &DSContext) -> Result<StoreSummary, Error> {
+	let batch = context.store.batch()?;
+	let dsinfo: Option<DSInfo> = batch.get_ser(HOSTS_KEY)?;
+	let consensus: Option<StoredText> = batch.get_ser(CONSENSUS_KEY)?;
+	let md_index: Option<MdIndex> = batch.get_ser(MD_INDEX_KEY)?;
+
+	let mut microdescs = 0;
+	for digest in md_index.map(|index| index.digests).unwrap_or_default() {
+		if batch.exists(&key(MD_PREFIX, &digest))? {
+			microdescs += 1;
+		}
+	}
+	let mut authcerts = 0;
+	for authority in &context.authorities {
+		if batch.exists(&key(AUTHCERT_PREFIX, authority.as_bytes()))? {
+			authcerts += 1;
+		}
+	}
+	let mut failing_sources = 0;
+	for source in context
+		.directory_servers
+		.iter()
+		.chain(&context.fallback_dirs)
+	{
+		if batch.exists(&key(SOURCE_PREFIX, source.as_bytes()))? {
+			failing_sources += 1;
+		}
+	}
+
+	Ok(StoreSummary {
+		schema_version: schema_version(&batch)?,
+		hosts: dsinfo.map(|dsinfo| dsinfo.hosts.len()),
+		consensus_size: consensus.map(|consensus| consensus.text.len()),
+		microdescs,
+		authcerts,
+		failing_sources,
+		guard_state: batch.exists(GUARD_KEY)?,
+		timeout_state: batch.exists(TIMEOUT_KEY)?,
+	})
+}
+
+/// Delete everything in the store, so the next start bootstraps from
+/// scratch.
+pub fn clear(context: &DSContext) -> Result<(), Error> {
+	clear_store(&context.store)
+}
+
+fn clear_store(store: &Store) -> Result<(), Error> {
+	let batch = store.batch()?;
+	let mut keys = vec![];
+	for prefix in PREFIXES {
+		keys.extend(batch.iter::<AnyRecord>(&[*prefix])?.map(|(key, _)| key));
+	}
+	for key in keys {
+		batch.delete(&key)?;
+	}
+	batch.put_ser(SCHEMA_KEY, &SchemaVersion(SCHEMA_VERSION))?;
+	batch.commit()?;
+	Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+	use super::*;
+	use std::path::PathBuf;
+
+	// an empty directory under temp_dir, removed again when dropped so that
+	// tests don't leave their stores behind, even when they fail
+	pub(crate) struct TempDir(pub(crate) PathBuf);
+
+	impl TempDir {
+		pub(crate) fn new(name: &str) -> TempDir {
+			let dir = std::env::temp_dir().join(format!("tor_{}_{}", name, std::process::id()));
+			let _ = std::fs::remove_dir_all(&dir);
+			std::fs::create_dir_all(&dir).unwrap();
+			TempDir(dir)
+		}
+	}
+
+	impl Drop for TempDir {
+		fn drop(&mut self) {
+			let _ = std::fs::remove_dir_all(&self.0);
+		}
+	}
+
+	// a new, empty store of its own
+	fn test_store(name: &str) -> (TempDir, Store) {
+		let dir = TempDir::new(&format!("ds_store_{}", name));
+		let store = Store::new(dir.0.to_str().unwrap(), None, Some("ds_db"), None, true).unwrap();
+		(dir, store)
+	}
+
+	#[test]
+	fn clear_everything() {
+		let (_dir, store) = test_store("clear");
+		let text = StoredText {
+			text: "x".to_string(),
+		};
+		let batch = store.batch().unwrap();
+		for prefix in PREFIXES {
+			batch.put_ser(&[*prefix], &text).unwrap();
+			batch.put_ser(&key(*prefix, b"some id"), &text).unwrap();
+		}
+		// a source that isn't configured anymore
+		batch
+			.put_ser(&key(SOURCE_PREFIX, b"192.0.2.1:80"), &text)
+			.unwrap();
+		batch.commit().unwrap();
+
+		clear_store(&store).unwrap();
+		let batch = store.batch().unwrap();
+		let mut keys = vec![];
+		for prefix in PREFIXES {
+			keys.extend(
+				batch
+					.iter::<AnyRecord>(&[*prefix])
+					.unwrap()
+					.map(|(key, _)| key),
+			);
+		}
+		// all that's left is the schema version
+		assert_eq!(keys, vec![SCHEMA_KEY.to_vec()]);
+		assert_eq!(schema_version(&batch).unwrap(), SCHEMA_VERSION);
+	}
+
+	// a DSInfo as version 0 wrote it: no marker, IPv4 hosts only
+	struct V0DSInfo {
+		load_time: u64,
+		hosts: Vec<([u8; 4], u16)>,
+	}
+
+	impl Writeable for V0DSInfo {
+		fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+			writer.write_u64(self.load_time)?;
+			writer.write_u64(self.hosts.len() as u64)?;
+			for (octets, port) in &self.hosts {
+				writer.write_fixed_bytes(octets)?;
+				writer.write_u16(*port)?;
+			}
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn migrate_v0() {
+		let (_dir, store) = test_store("migrate_v0");
+		let v0 = V0DSInfo {
+			load_time: 1_623_330_000_000,
+			hosts: vec![([192, 0, 2, 1], 9001), ([198, 51, 100, 7], 443)],
+		};
+		let batch = store.batch().unwrap();
+		batch.put_ser(HOSTS_KEY, &v0).unwrap();
+		batch.commit().unwrap();
+
+		migrate(&store).unwrap();
+		let batch = store.batch().unwrap();
+		assert_eq!(schema_version(&batch).unwrap(), SCHEMA_VERSION);
+		let dsinfo: DSInfo = batch.get_ser(HOSTS_KEY).unwrap().unwrap();
+		assert_eq!(dsinfo.load_time, 1_623_330_000_000);
+		let addrs: Vec<String> = dsinfo
+			.hosts
+			.iter()
+			.map(|host| host.addrs[0].to_string())
+			.collect();
+		assert_eq!(addrs, vec!["192.0.2.1:9001", "198.51.100.7:443"]);
+
+		// migrating again changes nothing
+		migrate(&store).unwrap();
+		let batch = store.batch().unwrap();
+		assert_eq!(schema_version(&batch).unwrap(), SCHEMA_VERSION);
+		let again: DSInfo = batch.get_ser(HOSTS_KEY).unwrap().unwrap();
+		assert_eq!(again.hosts.len(), 2);
+	}
+
+	#[test]
+	fn newer_schema_refused() {
+		let (_dir, store) = test_store("newer_schema");
+		let batch = store.batch().unwrap();
+		batch
+			.put_ser(SCHEMA_KEY, &SchemaVersion(SCHEMA_VERSION + 1))
+			.unwrap();
+		batch.commit().unwrap();
+
+		match migrate(&store) {
+			Err(e) => match e.kind() {
+				ErrorKind::StoreError(_) => {}
+				kind => panic!("unexpected error {:?}", kind),
+			},
+			Ok(_) => panic!("newer schema accepted"),
+		}
+		let batch = store.batch().unwrap();
+		assert_eq!(schema_version(&batch).unwrap(), SCHEMA_VERSION + 1);
+	}
+}
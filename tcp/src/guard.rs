@@ -0,0 +1,577 @@
+// Copyright 2021 The BMW Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::netdir::{NetDir, Relay};
+use crate::path::{pick_relay, usable, NodeRestrictions, Role, WeightSet};
+use rand::Rng;
+use std::time::{Duration, SystemTime};
+use tor_llcrypto::pk::rsa::{RsaIdentity, RSA_ID_LEN};
+use tor_util::core::ser::{self, Readable, Reader, Writeable, Writer};
+
+// guard-spec section 4 parameters
+const N_PRIMARY_GUARDS: usize = 3;
+const MIN_FILTERED_SAMPLE_SIZE: usize = 20;
+const MAX_SAMPLE_SIZE: usize = 60;
+// at most this share of the listed guards is sampled
+const MAX_SAMPLE_THRESHOLD_PERCENT: usize = 20;
+const HOUR: u64 = 60 * 60;
+const DAY: u64 = 24 * HOUR;
+// unconfirmed guards are dropped this long after they were sampled
+const GUARD_LIFETIME: Duration = Duration::from_secs(120 * DAY);
+// confirmed ones are kept at least this long after they were confirmed
+const GUARD_CONFIRMED_MIN_LIFETIME: Duration = Duration::from_secs(60 * DAY);
+const REMOVE_UNLISTED_GUARDS_AFTER: Duration = Duration::from_secs(20 * DAY);
+
+/// A guard in our sample (guard-spec 4.1)
+#[derive(Debug, Clone)]
+struct Guard {
+	id: RsaIdentity,
+	added_on: SystemTime,
+	/// when we first built a circuit through it
+	confirmed_on: Option<SystemTime>,
+	/// when it stopped being listed as a usable guard
+	unlisted_since: Option<SystemTime>,
+	/// whether the node restrictions allow it as a guard. It is worked out
+	/// on every update, so changing the configuration takes effect
+	/// without losing the sample.
+	permitted: bool,
+	// Reachability isn't persisted; we learn it again each run.
+	failing_since: Option<SystemTime>,
+	last_tried: Option<SystemTime>,
+}
+
+/// How long to wait before trying a guard that is failing again, given
+/// how long it has been failing (guard-spec 4.6).
+fn retry_interval(failing_for: Duration, primary: bool) -> Duration {
+	let failing_for = failing_for.as_secs();
+	let minutes = if failing_for < 6 * HOUR {
+		if primary {
+			10
+		} else {
+			60
+		}
+	} else if failing_for < 4 * DAY {
+		if primary {
+			90
+		} else {
+			4 * 60
+		}
+	} else if failing_for < 7 * DAY {
+		if primary {
+			4 * 60
+		} else {
+			18 * 60
+		}
+	} else if primary {
+		9 * 60
+	} else {
+		36 * 60
+	};
+	Duration::from_secs(minutes * 60)
+}
+
+// time since `then`, or zero if the clock went backwards
+fn elapsed(then: SystemTime, now: SystemTime) -> Duration {
+	now.duration_since(then).unwrap_or_default()
+}
+
+impl Guard {
+	fn new(id: RsaIdentity, now: SystemTime) -> Guard {
+		Guard {
+			id,
+			added_on: now,
+			confirmed_on: None,
+			unlisted_since: None,
+			permitted: true,
+			failing_since: None,
+			last_tried: None,
+		}
+	}
+
+	/// Return true if we may use the guard at all
+	fn usable(&self) -> bool {
+		self.unlisted_since.is_none() && self.permitted
+	}
+
+	/// Return true if the guard isn't known to be down, or it is time to
+	/// try it again.
+	fn worth_trying(&self, primary: bool, now: SystemTime) -> bool {
+		match (self.failing_since, self.last_tried) {
+			(Some(failing_since), Some(last_tried)) => {
+				let interval = retry_interval(elapsed(failing_since, now), primary);
+				elapsed(last_tried, now) >= interval
+			}
+			_ => true,
+		}
+	}
+
+	// guard-spec 4.3: unconfirmed guards expire after GUARD_LIFETIME,
+	// confirmed ones no earlier than GUARD_CONFIRMED_MIN_LIFETIME after
+	// they were confirmed
+	fn expired(&self, now: SystemTime) -> bool {
+		if let Some(unlisted_since) = self.unlisted_since {
+			if elapsed(unlisted_since, now) >= REMOVE_UNLISTED_GUARDS_AFTER {
+				return true;
+			}
+		}
+		match self.confirmed_on {
+			Some(confirmed_on) => {
+				elapsed(self.added_on, now) >= GUARD_LIFETIME
+					&& elapsed(confirmed_on, now) >= GUARD_CONFIRMED_MIN_LIFETIME
+			}
+			None => elapsed(self.added_on, now) >= GUARD_LIFETIME,
+		}
+	}
+}
+
+/// Keeps the sampled, confirmed and primary guard sets of proposal 271
+/// and picks the first hop of every circuit from them. All times are
+/// passed in, so the caller owns the clock.
+#[derive(Debug, Clone, Default)]
+pub struct GuardMgr {
+	/// the sample, in the order guards were added
+	guards: Vec<Guard>,
+	primary: Vec<RsaIdentity>,
+}
+
+impl GuardMgr {
+	pub fn new() -> GuardMgr {
+		GuardMgr::default()
+	}
+
+	/// The sampled guards, in the order they were added
+	pub fn sampled(&self) -> Vec<RsaIdentity> {
+		self.guards.iter().map(|guard| guard.id).collect()
+	}
+
+	/// The primary guards, most preferred first
+	pub fn primary(&self) -> &[RsaIdentity] {
+		&self.primary
+	}
+
+	/// The guards we have built circuits through, oldest first
+	pub fn confirmed(&self) -> Vec<RsaIdentity> {
+		let mut confirmed: Vec<&Guard> = self
+			.guards
+			.iter()
+			.filter(|guard| guard.confirmed_on.is_some())
+			.collect();
+		confirmed.sort_by_key(|guard| guard.confirmed_on);
+		confirmed.iter().map(|guard| guard.id).collect()
+	}
+
+	/// Bring the guard sets up to date with a new directory: note which
+	/// guards are no longer listed or no longer allowed by `restrictions`,
+	/// drop expired ones, and sample new ones until there are enough usable
+	/// guards.
+	pub fn update<R: Rng>(
+		&mut self,
+		rng: &mut R,
+		netdir: &NetDir,
+		restrictions: &NodeRestrictions,
+		now: SystemTime,
+	) {
+		for guard in &mut self.guards {
+			let relay = netdir.by_rsa_id(&guard.id);
+			let listed = relay
+				.as_ref()
+				.map(|relay| usable(relay.rs, Role::Guard, false))
+				.unwrap_or(false);
+			guard.permitted = relay
+				.map(|relay| restrictions.allows(&relay, Role::Guard))
+				.unwrap_or(false);
+			if listed {
+				guard.unlisted_since = None;
+			} else if guard.unlisted_since.is_none() {
+				guard.unlisted_since = Some(now);
+			}
+		}
+		self.guards.retain(|guard| !guard.expired(now));
+
+		let listed_guards = netdir
+			.relays()
+			.filter(|relay| usable(relay.rs, Role::Guard, false))
+			.count();
+		let max_sample = (listed_guards * MAX_SAMPLE_THRESHOLD_PERCENT / 100)
+			.clamp(MIN_FILTERED_SAMPLE_SIZE, MAX_SAMPLE_SIZE);
+		let weights = WeightSet::from_consensus(netdir.consensus());
+		while self.usable_count() < MIN_FILTERED_SAMPLE_SIZE && self.guards.len() < max_sample {
+			let sampled = self.sampled();
+			let relay = pick_relay(rng, netdir, &weights, Role::Guard, false, |relay| {
+				!sampled.contains(&relay.rs.rsa_identity) && restrictions.allows(relay, Role::Guard)
+			});
+			match relay {
+				Some(relay) => self.guards.push(Guard::new(relay.rs.rsa_identity, now)),
+				None => break,
+			}
+		}
+
+		self.update_primary();
+	}
+
+	fn usable_count(&self) -> usize {
+		self.guards.iter().filter(|guard| guard.usable()).count()
+	}
+
+	// the primary guards are the first usable confirmed guards, topped up
+	// from the rest of the sample in sample order
+	fn update_primary(&mut self) {
+		let confirmed = self.confirmed();
+		let unconfirmed = self
+			.guards
+			.iter()
+			.filter(|guard| guard.confirmed_on.is_none())
+			.map(|guard| guard.id);
+		self.primary = confirmed
+			.into_iter()
+			.chain(unconfirmed)
+			.filter(|id| self.guard(id).map(|guard| guard.usable()).unwrap_or(false))
+			.take(N_PRIMARY_GUARDS)
+			.collect();
+	}
+
+	fn guard(&self, id: &RsaIdentity) -> Option<&Guard> {
+		self.guards.iter().find(|guard| &guard.id == id)
+	}
+
+	fn guard_mut(&mut self, id: &RsaIdentity) -> Option<&mut Guard> {
+		self.guards.iter_mut().find(|guard| &guard.id == id)
+	}
+
+	/// Pick the guard to use as the first hop of a new circuit: the first
+	/// primary guard worth trying, or failing that any confirmed and then
+	/// any sampled guard that is (guard-spec 4.6). Report how it went with
+	/// `note_success` or `note_failure`.
+	pub fn pick_first_hop<'a>(&mut self, netdir: &'a NetDir, now: SystemTime) -> Option<Relay<'a>> {
+		let mut candidates = self.primary.clone();
+		for id in self.confirmed().into_iter().chain(self.sampled()) {
+			if !candidates.contains(&id) {
+				candidates.push(id);
+			}
+		}
+
+		for id in candidates {
+			let primary = self.primary.contains(&id);
+			let guard = match self.guard_mut(&id) {
+				Some(guard) => guard,
+				None => continue,
+			};
+			if !guard.usable() || !guard.worth_trying(primary, now) {
+				continue;
+			}
+			if let Some(relay) = netdir.by_rsa_id(&id) {
+				guard.last_tried = Some(now);
+				return Some(relay);
+			}
+		}
+		None
+	}
+
+	/// Record that we built a circuit through a guard. The first success
+	/// confirms it.
+	pub fn note_success(&mut self, id: &RsaIdentity, now: SystemTime) {
+		if let Some(guard) = self.guard_mut(id) {
+			guard.failing_since = None;
+			guard.last_tried = Some(now);
+			if guard.confirmed_on.is_none() {
+				guard.confirmed_on = Some(now);
+				self.update_primary();
+			}
+		}
+	}
+
+	/// Record that we couldn't connect to a guard.
+	pub fn note_failure(&mut self, id: &RsaIdentity, now: SystemTime) {
+		if let Some(guard) = self.guard_mut(id) {
+			if guard.failing_since.is_none() {
+				guard.failing_since = Some(now);
+			}
+			guard.last_tried = Some(now);
+		}
+	}
+}
+
+// times are stored as seconds since the epoch, with 0 for none
+fn to_secs(time: Option<SystemTime>) -> u64 {
+	time.and_then(|time| time.duration_since(SystemTime::UNIX_EPOCH).ok())
+		.map(|since| since.as_secs())
+		.unwrap_or(0)
+}
+
+fn from_secs(secs: u64) -> Option<SystemTime> {
+	match secs {
+		0 => None,
+		secs => Some(SystemTime::UNIX_EPOCH + Duration::from_secs(secs)),
+	}
+}
+
+impl Writeable for GuardMgr {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		writer.write_u64(self.guards.len() as u64)?;
+		for guard in &self.guards {
+			writer.write_fixed_bytes(guard.id.as_bytes())?;
+			writer.write_u64(to_secs(Some(guard.added_on)))?;
+			writer.write_u64(to_secs(guard.confirmed_on))?;
+			writer.write_u64(to_secs(guard.unlisted_since))?;
+		}
+		Ok(())
+	}
+}
+
+fn read_guard<R: Reader>(reader: &mut R) -> Result<Guard, ser::Error> {
+	let id = RsaIdentity::from_bytes(&reader.read_fixed_bytes(RSA_ID_LEN)?)
+		.ok_or(ser::Error::CorruptedData)?;
+	let added_on = from_secs(reader.read_u64()?).ok_or(ser::Error::CorruptedData)?;
+	let mut guard = Guard::new(id, added_on);
+	guard.confirmed_on = from_secs(reader.read_u64()?);
+	guard.unlisted_since = from_secs(reader.read_u64()?);
+	Ok(guard)
+}
+
+// a record that is cut short is as corrupt as a mangled one
+impl Readable for GuardMgr {
+	fn read<R: Reader>(reader: &mut R) -> Result<Self, ser::Error> {
+		let count = reader.read_u64().map_err(|_| ser::Error::CorruptedData)?;
+		let mut guards = vec![];
+		for _ in 0..count {
+			guards.push(read_guard(reader).map_err(|_| ser::Error::CorruptedData)?);
+		}
+		let mut mgr = GuardMgr {
+			guards,
+			primary: vec![],
+		};
+		mgr.update_primary();
+		Ok(mgr)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::path::test::netdir;
+	use crate::path::NodeSet;
+	use rand::rngs::StdRng;
+	use rand::SeedableRng;
+
+	const CONSENSUS: &str = include_str!("../testdata/consensus-paths.txt");
+
+	// the mocked clock
+	fn at(secs: u64) -> SystemTime {
+		SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000 + secs)
+	}
+
+	fn setup() -> (NetDir, GuardMgr) {
+		let netdir = netdir(CONSENSUS);
+		let mut mgr = GuardMgr::new();
+		mgr.update(
+			&mut StdRng::seed_from_u64(1),
+			&netdir,
+			&NodeRestrictions::default(),
+			at(0),
+		);
+		(netdir, mgr)
+	}
+
+	#[test]
+	fn sample() {
+		let (netdir, mgr) = setup();
+		// the canned consensus has three usable guards, so all are sampled
+		// and all are primary
+		let mut names: Vec<String> = mgr
+			.sampled()
+			.iter()
+			.map(|id| netdir.by_rsa_id(id).unwrap().rs.nickname.clone())
+			.collect();
+		names.sort();
+		assert_eq!(names, vec!["guard1", "guard2", "guardexit"]);
+		assert_eq!(mgr.primary(), &mgr.sampled()[..]);
+		assert!(mgr.confirmed().is_empty());
+	}
+
+	#[test]
+	fn retry_schedule() {
+		let (netdir, mut mgr) = setup();
+		let primary = mgr.primary().to_vec();
+		let first = mgr.pick_first_hop(&netdir, at(0)).unwrap();
+		assert_eq!(first.rs.rsa_identity, primary[0]);
+
+		// while the first primary guard is down the next one is used
+		mgr.note_failure(&primary[0], at(0));
+		let next = mgr.pick_first_hop(&netdir, at(60)).unwrap();
+		assert_eq!(next.rs.rsa_identity, primary[1]);
+		let next = mgr.pick_first_hop(&netdir, at(9 * 60)).unwrap();
+		assert_eq!(next.rs.rsa_identity, primary[1]);
+
+		// primary guards are retried after 10 minutes
+		let retry = mgr.pick_first_hop(&netdir, at(10 * 60)).unwrap();
+		assert_eq!(retry.rs.rsa_identity, primary[0]);
+
+		// after 6 hours of failures, only every 90 minutes
+		mgr.note_failure(&primary[0], at(7 * HOUR));
+		let next = mgr.pick_first_hop(&netdir, at(7 * HOUR + 89 * 60)).unwrap();
+		assert_eq!(next.rs.rsa_identity, primary[1]);
+		let retry = mgr.pick_first_hop(&netdir, at(7 * HOUR + 90 * 60)).unwrap();
+		assert_eq!(retry.rs.rsa_identity, primary[0]);
+
+		// with every guard down there is nothing to pick
+		for id in &primary {
+			mgr.note_failure(id, at(8 * HOUR));
+		}
+		assert!(mgr.pick_first_hop(&netdir, at(8 * HOUR + 60)).is_none());
+		mgr.note_success(&primary[2], at(8 * HOUR + 60));
+		let next = mgr.pick_first_hop(&netdir, at(8 * HOUR + 120)).unwrap();
+		assert_eq!(next.rs.rsa_identity, primary[2]);
+	}
+
+	#[test]
+	fn confirmed_first() {
+		let (_, mut mgr) = setup();
+		let sampled = mgr.sampled();
+		mgr.note_success(&sampled[2], at(10));
+		mgr.note_success(&sampled[1], at(20));
+		assert_eq!(mgr.confirmed(), vec![sampled[2], sampled[1]]);
+		assert_eq!(mgr.primary(), &[sampled[2], sampled[1], sampled[0]]);
+	}
+
+	#[test]
+	fn unlisted() {
+		let (_, mut mgr) = setup();
+		let mut rng = StdRng::seed_from_u64(2);
+		let none = NodeRestrictions::default();
+		// guard1 loses its Running flag
+		let text = CONSENSUS.replacen(
+			"s Fast Guard Running Stable Valid",
+			"s Fast Guard Stable Valid",
+			1,
+		);
+		let netdir = netdir(&text);
+		let guard1 = netdir
+			.relays()
+			.find(|relay| relay.rs.nickname == "guard1")
+			.unwrap()
+			.rs
+			.rsa_identity;
+
+		mgr.update(&mut rng, &netdir, &none, at(DAY));
+		assert!(mgr.sampled().contains(&guard1));
+		assert!(!mgr.primary().contains(&guard1));
+		for _ in 0..10 {
+			let relay = mgr.pick_first_hop(&netdir, at(DAY)).unwrap();
+			assert_ne!(relay.rs.rsa_identity, guard1);
+		}
+
+		// it is dropped after 20 days, then sampled again once it's back
+		mgr.update(&mut rng, &netdir, &none, at(21 * DAY));
+		assert!(!mgr.sampled().contains(&guard1));
+		mgr.update(&mut rng, &self::netdir(CONSENSUS), &none, at(22 * DAY));
+		assert_eq!(mgr.sampled().last(), Some(&guard1));
+	}
+
+	#[test]
+	fn expiry() {
+		let (netdir, mut mgr) = setup();
+		let sampled = mgr.sampled();
+		let none = NodeRestrictions::default();
+		mgr.note_success(&sampled[0], at(100 * DAY));
+
+		// after 120 days unconfirmed guards are replaced, but the confirmed
+		// one stays for 60 days after it was confirmed
+		mgr.update(&mut StdRng::seed_from_u64(3), &netdir, &none, at(121 * DAY));
+		assert_eq!(mgr.sampled().len(), 3);
+		assert_eq!(mgr.sampled()[0], sampled[0]);
+		assert_eq!(mgr.confirmed(), vec![sampled[0]]);
+		mgr.update(&mut StdRng::seed_from_u64(3), &netdir, &none, at(161 * DAY));
+		assert!(mgr.confirmed().is_empty());
+	}
+
+	#[test]
+	fn restricted() {
+		let (netdir, mut mgr) = setup();
+		let guard1 = netdir
+			.relays()
+			.find(|relay| relay.rs.nickname == "guard1")
+			.unwrap()
+			.rs
+			.rsa_identity;
+		let restrictions = NodeRestrictions {
+			exclude: NodeSet::parse(&["10.1.0.0/16".to_string()]).unwrap(),
+			..Default::default()
+		};
+
+		// an excluded guard stays in the sample but is never used
+		mgr.update(
+			&mut StdRng::seed_from_u64(4),
+			&netdir,
+			&restrictions,
+			at(60),
+		);
+		assert!(mgr.sampled().contains(&guard1));
+		assert!(!mgr.primary().contains(&guard1));
+		assert_eq!(mgr.primary().len(), 2);
+		for _ in 0..10 {
+			let relay = mgr.pick_first_hop(&netdir, at(60)).unwrap();
+			assert_ne!(relay.rs.rsa_identity, guard1);
+		}
+
+		// and is used again once the restriction is lifted
+		mgr.update(
+			&mut StdRng::seed_from_u64(4),
+			&netdir,
+			&NodeRestrictions::default(),
+			at(120),
+		);
+		assert!(mgr.primary().contains(&guard1));
+
+		// a fresh sample leaves it out
+		let mut mgr = GuardMgr::new();
+		mgr.update(&mut StdRng::seed_from_u64(5), &netdir, &restrictions, at(0));
+		assert!(!mgr.sampled().contains(&guard1));
+		assert_eq!(mgr.sampled().len(), 2);
+	}
+
+	#[test]
+	fn persistence() {
+		let (_, mut mgr) = setup();
+		let sampled = mgr.sampled();
+		// confirmed in the opposite order to the sample
+		mgr.note_success(&sampled[2], at(HOUR));
+		mgr.note_success(&sampled[0], at(2 * HOUR));
+		mgr.guards[1].unlisted_since = Some(at(DAY));
+		mgr.update_primary();
+
+		let version = ser::ProtocolVersion::local();
+		let bytes = ser::ser_vec(&mgr, version).unwrap();
+		let read: GuardMgr = ser::deserialize(&mut &bytes[..], version).unwrap();
+		assert_eq!(read.sampled(), sampled);
+		assert_eq!(read.confirmed(), vec![sampled[2], sampled[0]]);
+		assert_eq!(read.primary(), mgr.primary());
+		for (read, guard) in read.guards.iter().zip(&mgr.guards) {
+			assert_eq!(read.added_on, guard.added_on);
+			assert_eq!(read.confirmed_on, guard.confirmed_on);
+			assert_eq!(read.unlisted_since, guard.unlisted_since);
+		}
+		assert_eq!(read.guards[1].unlisted_since, Some(at(DAY)));
+
+		// cut short anywhere
+		for len in 0..bytes.len() {
+			let res: Result<GuardMgr, _> = ser::deserialize(&mut &bytes[..len], version);
+			assert_eq!(res.err(), Some(ser::Error::CorruptedData), "{}", len);
+		}
+		// a guard without a sample time
+		let mut corrupt = bytes.clone();
+		corrupt[8 + RSA_ID_LEN..8 + RSA_ID_LEN + 8].copy_from_slice(&[0; 8]);
+		let res: Result<GuardMgr, _> = ser::deserialize(&mut &corrupt[..], version);
+		assert_eq!(res.err(), Some(ser::Error::CorruptedData));
+	}
+}
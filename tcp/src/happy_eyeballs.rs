@@ -0,0 +1,283 @@
+// Copyright 2021 The BMW Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Racing connection attempts to a relay's addresses (RFC 8305).
+
+use futures::future::{self, Either};
+use futures::stream::{FuturesUnordered, StreamExt};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// How long to wait for an attempt before starting the next one in
+/// parallel (RFC 8305 section 5)
+pub const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// How connections over each address family have been going from this
+/// host. `None` until one has finished.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AddrFamilies {
+	ipv4: Option<bool>,
+	ipv6: Option<bool>,
+}
+
+impl AddrFamilies {
+	/// Record whether a connection to `addr` worked.
+	pub fn note(&mut self, addr: &SocketAddr, worked: bool) {
+		if addr.is_ipv6() {
+			self.ipv6 = Some(worked);
+		} else {
+			self.ipv4 = Some(worked);
+		}
+	}
+
+	/// IPv4 goes first unless only IPv6 has been working.
+	pub fn prefers_ipv6(&self) -> bool {
+		self.ipv6 == Some(true) && self.ipv4 != Some(true)
+	}
+
+	/// The order to try `addrs` in: alternating between families, starting
+	/// with the preferred one (RFC 8305 section 4).
+	pub fn sort(&self, addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+		let prefers_ipv6 = self.prefers_ipv6();
+		let (first, second): (Vec<SocketAddr>, Vec<SocketAddr>) = addrs
+			.iter()
+			.partition(|addr| addr.is_ipv6() == prefers_ipv6);
+		let mut sorted = vec![];
+		let mut first = first.into_iter();
+		let mut second = second.into_iter();
+		loop {
+			match (first.next(), second.next()) {
+				(None, None) => return sorted,
+				(a, b) => sorted.extend(a.into_iter().chain(b)),
+			}
+		}
+	}
+}
+
+/// Try each of `addrs` in turn, starting the next attempt when the last
+/// one fails or has been running for `delay`, whichever comes first. The
+/// first attempt to succeed wins and the others are dropped. How each
+/// finished attempt went is noted in `families`. If none succeeds, the
+/// errors are returned in the order the attempts failed.
+pub async fn race<T, E, A, AF, S, SF>(
+	addrs: Vec<SocketAddr>,
+	delay: Duration,
+	sleep: S,
+	attempt: A,
+	families: &Mutex<AddrFamilies>,
+) -> Result<T, Vec<E>>
+where
+	A: Fn(SocketAddr) -> AF,
+	AF: Future<Output = Result<T, E>>,
+	S: Fn(Duration) -> SF,
+	SF: Future<Output = ()>,
+{
+	let start = |addr: SocketAddr| {
+		let attempt = attempt(addr);
+		async move { (addr, attempt.await) }
+	};
+	let mut waiting = addrs.into_iter();
+	let mut running = FuturesUnordered::new();
+	let mut errors = vec![];
+
+	match waiting.next() {
+		Some(addr) => running.push(start(addr)),
+		None => return Err(errors),
+	}
+	loop {
+		let stagger = if waiting.len() > 0 {
+			Either::Left(sleep(delay))
+		} else {
+			Either::Right(future::pending())
+		};
+		futures::pin_mut!(stagger);
+		match future::select(running.next(), stagger).await {
+			Either::Left((Some((addr, Ok(t))), _)) => {
+				families.lock().unwrap().note(&addr, true);
+				return Ok(t);
+			}
+			Either::Left((Some((addr, Err(e))), _)) => {
+				families.lock().unwrap().note(&addr, false);
+				errors.push(e);
+				match waiting.next() {
+					Some(addr) => running.push(start(addr)),
+					None if running.is_empty() => break,
+					None => {}
+				}
+			}
+			// only reached once every attempt has been started and failed
+			Either::Left((None, _)) => break,
+			Either::Right(_) => {
+				if let Some(addr) = waiting.next() {
+					running.push(start(addr));
+				}
+			}
+		}
+	}
+	Err(errors)
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use futures::executor::block_on;
+	use std::sync::Arc;
+	use tor_util::{Error, ErrorKind};
+
+	fn addrs(list: &[&str]) -> Vec<SocketAddr> {
+		list.iter().map(|addr| addr.parse().unwrap()).collect()
+	}
+
+	// records when it is dropped without finishing
+	struct Cancelled(Arc<Mutex<Vec<SocketAddr>>>, SocketAddr, bool);
+
+	impl Cancelled {
+		fn finish(mut self) {
+			self.2 = true;
+		}
+	}
+
+	impl Drop for Cancelled {
+		fn drop(&mut self) {
+			if !self.2 {
+				self.0.lock().unwrap().push(self.1);
+			}
+		}
+	}
+
+	// attempts to the addresses in `good` succeed, ones to `hang` never
+	// finish and the rest fail at once. Returns the result and the
+	// addresses started and cancelled.
+	fn run(
+		list: &[SocketAddr],
+		good: &[SocketAddr],
+		hang: &[SocketAddr],
+		stagger: bool,
+		families: &Mutex<AddrFamilies>,
+	) -> (
+		Result<SocketAddr, Vec<Error>>,
+		Vec<SocketAddr>,
+		Vec<SocketAddr>,
+	) {
+		let started = Arc::new(Mutex::new(vec![]));
+		let cancelled = Arc::new(Mutex::new(vec![]));
+		let result = block_on(race(
+			list.to_vec(),
+			ATTEMPT_DELAY,
+			|_| async move {
+				if !stagger {
+					future::pending::<()>().await;
+				}
+			},
+			|addr| {
+				started.lock().unwrap().push(addr);
+				let watch = Cancelled(cancelled.clone(), addr, false);
+				let good = good.contains(&addr);
+				let hang = hang.contains(&addr);
+				async move {
+					if hang {
+						future::pending::<()>().await;
+					}
+					watch.finish();
+					if good {
+						Ok(addr)
+					} else {
+						Err(ErrorKind::TcpConnectError(format!("{} refused", addr)).into())
+					}
+				}
+			},
+			families,
+		));
+		let started = started.lock().unwrap().clone();
+		let cancelled = cancelled.lock().unwrap().clone();
+		(result, started, cancelled)
+	}
+
+	#[test]
+	fn first_wins() {
+		let families = Mutex::new(AddrFamilies::default());
+		let list = addrs(&["1.2.3.4:9001", "[::1]:9001"]);
+		let (result, started, _) = run(&list, &list, &[], false, &families);
+		assert_eq!(result.unwrap(), list[0]);
+		assert_eq!(started, list[..1]);
+	}
+
+	#[test]
+	fn failure_starts_next() {
+		// a failed attempt starts the next one without waiting out the delay
+		let families = Mutex::new(AddrFamilies::default());
+		let list = addrs(&["1.2.3.4:9001", "[::1]:9001"]);
+		let (result, started, _) = run(&list, &list[1..], &[], false, &families);
+		assert_eq!(result.unwrap(), list[1]);
+		assert_eq!(started, list);
+		assert!(families.lock().unwrap().prefers_ipv6());
+
+		// so the next race starts with IPv6
+		let sorted = families.lock().unwrap().sort(&list);
+		assert_eq!(sorted, vec![list[1], list[0]]);
+		let (result, started, _) = run(&sorted, &list[1..], &[], false, &families);
+		assert_eq!(result.unwrap(), list[1]);
+		assert_eq!(started, list[1..]);
+	}
+
+	#[test]
+	fn stagger() {
+		// the first attempt hangs, so the second is started after the delay,
+		// wins, and the first is cancelled
+		let families = Mutex::new(AddrFamilies::default());
+		let list = addrs(&["1.2.3.4:9001", "[::1]:9001", "5.6.7.8:9001"]);
+		let (result, started, cancelled) = run(&list, &list, &list[..1], true, &families);
+		assert_eq!(result.unwrap(), list[1]);
+		assert_eq!(started, list[..2]);
+		assert_eq!(cancelled, list[..1]);
+	}
+
+	#[test]
+	fn all_fail() {
+		let families = Mutex::new(AddrFamilies::default());
+		let list = addrs(&["1.2.3.4:9001", "[::1]:9001"]);
+		let (result, started, _) = run(&list, &[], &[], true, &families);
+		assert_eq!(result.unwrap_err().len(), 2);
+		assert_eq!(started, list);
+		assert!(!families.lock().unwrap().prefers_ipv6());
+
+		let (result, started, _) = run(&[], &[], &[], true, &families);
+		assert!(result.unwrap_err().is_empty());
+		assert!(started.is_empty());
+	}
+
+	#[test]
+	fn sort() {
+		let mut families = AddrFamilies::default();
+		let list = addrs(&["[::1]:1", "[::2]:2", "1.1.1.1:3", "2.2.2.2:4", "3.3.3.3:5"]);
+		assert_eq!(
+			families.sort(&list),
+			addrs(&["1.1.1.1:3", "[::1]:1", "2.2.2.2:4", "[::2]:2", "3.3.3.3:5"])
+		);
+
+		families.note(&list[0], true);
+		assert!(families.prefers_ipv6());
+		assert_eq!(
+			families.sort(&list),
+			addrs(&["[::1]:1", "1.1.1.1:3", "[::2]:2", "2.2.2.2:4", "3.3.3.3:5"])
+		);
+
+		// once IPv4 works again it goes back to being first
+		families.note(&list[2], true);
+		assert!(!families.prefers_ipv6());
+		assert_eq!(families.sort(&list[..2]), list[..2].to_vec());
+	}
+}
@@ -0,0 +1,414 @@
+// Copyright 2021 The BMW Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! An HTTP CONNECT proxy (RFC 7231 section 4.3.6) opening its tunnels
+//! through Tor, for tools that can't speak SOCKS.
+
+use crate::circmgr::StreamIsolation;
+use crate::client::StreamError;
+use crate::proxy::{relay, start_listener, StreamProvider, REQUEST_TIMEOUT};
+use futures::io::{
+	AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use tor_cell::relaycell::msg::EndReason;
+use tor_rtcompat::{Runtime, SleepProvider, SleepProviderExt, SpawnBlocking};
+use tor_util::logger::Log;
+use tor_util::StopState;
+use tor_util::{Error, ErrorKind};
+
+// the most we read of a request before its blank line
+const MAX_HEAD: u64 = 8192;
+
+/// A CONNECT request read from an HTTP client
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectRequest {
+	/// A hostname or address, IPv6 addresses in brackets
+	pub host: String,
+	pub port: u16,
+	/// The Proxy-Authorization header, if there was one
+	pub auth: Option<String>,
+}
+
+impl ConnectRequest {
+	/// Requests with different Proxy-Authorization credentials never share
+	/// a circuit. Basic credentials isolate just as the same SOCKS username
+	/// and password do.
+	pub fn isolation(&self) -> StreamIsolation {
+		let isolation = StreamIsolation::new();
+		match &self.auth {
+			Some(auth) => {
+				let (username, password) = credentials(auth);
+				isolation.socks_auth(&username, &password)
+			}
+			None => isolation,
+		}
+	}
+}
+
+// the username and password of Basic credentials, or any others whole as
+// a username
+fn credentials(auth: &str) -> (Vec<u8>, Vec<u8>) {
+	let mut parts = auth.splitn(2, ' ');
+	if let (Some(scheme), Some(encoded)) = (parts.next(), parts.next()) {
+		if scheme.eq_ignore_ascii_case("basic") {
+			if let Ok(decoded) = base64::decode(encoded.trim()) {
+				let mut parts = decoded.splitn(2, |byte| *byte == b':');
+				let username = parts.next().unwrap_or_default().to_vec();
+				let password = parts.next().unwrap_or_default().to_vec();
+				return (username, password);
+			}
+		}
+	}
+	(auth.as_bytes().to_vec(), vec![])
+}
+
+/// The HTTP status for a tunnel that couldn't be opened because of `error`
+fn status_code(error: &StreamError) -> u16 {
+	match error.end_reason() {
+		Some(EndReason::EXITPOLICY) => 403,
+		Some(EndReason::TIMEOUT) => 504,
+		_ => 502,
+	}
+}
+
+fn response(status: u16) -> String {
+	let reason = match status {
+		200 => return "HTTP/1.1 200 Connection established\r\n\r\n".to_string(),
+		400 => "Bad Request",
+		403 => "Forbidden",
+		405 => "Method Not Allowed",
+		408 => "Request Timeout",
+		431 => "Request Header Fields Too Large",
+		504 => "Gateway Timeout",
+		_ => "Bad Gateway",
+	};
+	format!(
+		"HTTP/1.1 {} {}\r\nConnection: close\r\n\r\n",
+		status, reason
+	)
+}
+
+fn http_error(msg: String) -> Error {
+	ErrorKind::ProxyError(msg).into()
+}
+
+// the request line and headers, up to the blank line that ends them. A
+// head that is too long or cut short is refused.
+async fn read_head<S>(stream: &mut S) -> Result<Vec<String>, Error>
+where
+	S: AsyncBufRead + AsyncWrite + Unpin,
+{
+	let mut head = stream.take(MAX_HEAD);
+	let mut lines = vec![];
+	let (status, msg) = loop {
+		let mut line = String::new();
+		match head.read_line(&mut line).await {
+			Ok(0) if head.limit() == 0 => break (431, "HTTP request head too long"),
+			Ok(0) => break (400, "HTTP request cut short"),
+			Ok(_) => {}
+			Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+				break (400, "HTTP request is not UTF-8")
+			}
+			Err(e) => return Err(e.into()),
+		}
+		let line = line.trim_end_matches(&['\r', '\n'][..]);
+		// blank lines before the request line are allowed
+		match (line.is_empty(), lines.is_empty()) {
+			(true, true) => {}
+			(true, false) => return Ok(lines),
+			(false, _) => lines.push(line.to_string()),
+		}
+	};
+	stream.write_all(response(status).as_bytes()).await?;
+	Err(http_error(msg.to_string()))
+}
+
+// host:port, with an IPv6 host in brackets
+fn split_target(target: &str) -> Option<(String, u16)> {
+	let colon = target.rfind(':')?;
+	let (host, port) = (&target[..colon], &target[colon + 1..]);
+	let port = port.parse().ok()?;
+	if host.is_empty() || port == 0 {
+		return None;
+	}
+	if host.contains(':') && !(host.starts_with('[') && host.ends_with(']')) {
+		return None;
+	}
+	Some((host.to_string(), port))
+}
+
+// the request, or the status to refuse it with and why
+fn parse_request(head: &[String]) -> Result<ConnectRequest, (u16, String)> {
+	let (request_line, headers) = match head.split_first() {
+		Some(split) => split,
+		None => return Err((400, "empty HTTP request".to_string())),
+	};
+	let parts: Vec<&str> = request_line.split(' ').collect();
+	let (method, target) = match parts[..] {
+		[method, target, version] if version.starts_with("HTTP/1.") => (method, target),
+		_ => return Err((400, format!("bad HTTP request line {:?}", request_line))),
+	};
+	if method != "CONNECT" {
+		return Err((405, format!("unsupported HTTP method {}", method)));
+	}
+	let (host, port) = match split_target(target) {
+		Some(target) => target,
+		None => return Err((400, format!("bad CONNECT target {}", target))),
+	};
+	let auth = headers.iter().find_map(|header| {
+		let mut parts = header.splitn(2, ':');
+		match (parts.next(), parts.next()) {
+			(Some(name), Some(value))
+				if name.trim().eq_ignore_ascii_case("proxy-authorization") =>
+			{
+				Some(value.trim().to_string())
+			}
+			_ => None,
+		}
+	});
+	Ok(ConnectRequest { host, port, auth })
+}
+
+/// Read a CONNECT request from an HTTP client. Requests we can't serve
+/// are refused here.
+pub async fn read_request<S>(stream: &mut S) -> Result<ConnectRequest, Error>
+where
+	S: AsyncBufRead + AsyncWrite + Unpin,
+{
+	let head = read_head(stream).await?;
+	match parse_request(&head) {
+		Ok(request) => Ok(request),
+		Err((status, msg)) => {
+			stream.write_all(response(status).as_bytes()).await?;
+			Err(http_error(msg))
+		}
+	}
+}
+
+/// Serve one HTTP proxy connection: read the CONNECT request, open the
+/// tunnel through `provider`, reply, and relay data until both sides are
+/// done. Talking to the client is run on `blocker`; the provider's calls
+/// block on their own. A client that hasn't sent its request head within
+/// `REQUEST_TIMEOUT` is answered with 408.
+pub fn handle_connection<B, S, P>(blocker: &B, stream: S, provider: &P) -> Result<(), Error>
+where
+	B: SpawnBlocking + SleepProvider,
+	S: AsyncRead + AsyncWrite + Unpin,
+	P: StreamProvider,
+{
+	// anything the client sends after the request head stays buffered
+	// here until it is relayed
+	let mut stream = BufReader::new(stream);
+	let request =
+		match blocker.block_on(blocker.timeout(REQUEST_TIMEOUT, read_request(&mut stream))) {
+			Ok(request) => request?,
+			Err(_) => {
+				blocker.block_on(stream.write_all(response(408).as_bytes()))?;
+				return Err(http_error("HTTP request not sent in time".to_string()));
+			}
+		};
+	let exit = match provider.open_stream(&request.host, request.port, &request.isolation()) {
+		Ok(exit) => exit,
+		Err(e) => {
+			blocker.block_on(stream.write_all(response(status_code(&e)).as_bytes()))?;
+			return Err(e.into());
+		}
+	};
+	blocker.block_on(async {
+		stream.write_all(response(200).as_bytes()).await?;
+		relay(stream, exit).await?;
+		Ok(())
+	})
+}
+
+/// Listen for HTTP proxy connections on `addr` until `stop_state` is
+/// stopped, opening their tunnels through `provider`. Returns the address
+/// listened on.
+pub fn start_http_listener<R: Runtime, P: StreamProvider>(
+	runtime: R,
+	addr: SocketAddr,
+	provider: Arc<P>,
+	mainlog: &'static Arc<Mutex<Log>>,
+	stop_state: Arc<RwLock<StopState>>,
+) -> Result<SocketAddr, Error> {
+	start_listener(
+		runtime,
+		addr,
+		provider,
+		handle_connection::<R, R::TcpStream, P>,
+		"HTTP",
+		mainlog,
+		stop_state,
+	)
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::proxy;
+	use crate::proxy::test::{end, FakeProvider};
+
+	fn run(provider: &FakeProvider, input: &str) -> (String, Result<(), Error>) {
+		let (output, result) = proxy::test::run(handle_connection, provider, input.as_bytes());
+		(String::from_utf8(output).unwrap(), result)
+	}
+
+	const ESTABLISHED: &str = "HTTP/1.1 200 Connection established\r\n\r\n";
+
+	#[test]
+	fn connect() {
+		let provider = FakeProvider::default();
+		let input = "CONNECT example.com:443 HTTP/1.1\r\n\
+			Host: example.com:443\r\n\
+			\r\n\
+			early data";
+		let (output, result) = run(&provider, input);
+		assert!(result.is_ok());
+		assert_eq!(output, format!("{}hello", ESTABLISHED));
+		assert_eq!(&provider.uploaded.lock().unwrap()[..], b"early data");
+		let opened = provider.opened.lock().unwrap();
+		assert_eq!((&opened[0].0[..], opened[0].1), ("example.com", 443));
+		assert!(opened[0].2.compatible(&StreamIsolation::new()));
+		drop(opened);
+
+		let (output, _) = run(&provider, "CONNECT [2001:db8::1]:80 HTTP/1.0\n\n");
+		assert_eq!(output, format!("{}hello", ESTABLISHED));
+		let opened = provider.opened.lock().unwrap();
+		assert_eq!((&opened[1].0[..], opened[1].1), ("[2001:db8::1]", 80));
+	}
+
+	#[test]
+	fn auth() {
+		let provider = FakeProvider::default();
+		// user:pass
+		let input = "CONNECT example.com:443 HTTP/1.1\r\n\
+			proxy-authorization: Basic dXNlcjpwYXNz\r\n\
+			\r\n";
+		let (output, _) = run(&provider, input);
+		assert_eq!(output, format!("{}hello", ESTABLISHED));
+		let input = "CONNECT example.com:443 HTTP/1.1\r\n\
+			Proxy-Authorization: Bearer abc\r\n\
+			\r\n";
+		assert!(run(&provider, input).1.is_ok());
+
+		let opened = provider.opened.lock().unwrap();
+		let basic = &opened[0].2;
+		assert!(basic.compatible(&StreamIsolation::new().socks_auth(b"user", b"pass")));
+		assert!(!basic.compatible(&StreamIsolation::new()));
+		let bearer = &opened[1].2;
+		assert!(bearer.compatible(&StreamIsolation::new().socks_auth(b"Bearer abc", b"")));
+
+		assert_eq!(
+			credentials("basic dXNlcg=="),
+			(b"user".to_vec(), b"".to_vec())
+		);
+		assert_eq!(
+			credentials("Basic !!"),
+			(b"Basic !!".to_vec(), b"".to_vec())
+		);
+	}
+
+	#[test]
+	fn refused() {
+		let provider = FakeProvider::default();
+		let refusal = |host: &str| {
+			let input = format!("CONNECT {}:80 HTTP/1.1\r\n\r\n", host);
+			let (output, result) = run(&provider, &input);
+			assert!(result.is_err());
+			output
+		};
+		assert_eq!(
+			refusal("policy.example"),
+			"HTTP/1.1 403 Forbidden\r\nConnection: close\r\n\r\n"
+		);
+		assert_eq!(
+			refusal("slow.example"),
+			"HTTP/1.1 504 Gateway Timeout\r\nConnection: close\r\n\r\n"
+		);
+		assert_eq!(
+			refusal("refused.example"),
+			"HTTP/1.1 502 Bad Gateway\r\nConnection: close\r\n\r\n"
+		);
+	}
+
+	#[test]
+	fn bad_requests() {
+		let provider = FakeProvider::default();
+		let (output, result) = run(&provider, "GET http://example.com/ HTTP/1.1\r\n\r\n");
+		assert!(result.is_err());
+		assert_eq!(
+			output,
+			"HTTP/1.1 405 Method Not Allowed\r\nConnection: close\r\n\r\n"
+		);
+		for input in &[
+			"CONNECT example.com HTTP/1.1\r\n\r\n",
+			"CONNECT example.com:0 HTTP/1.1\r\n\r\n",
+			"CONNECT 2001:db8::1:80 HTTP/1.1\r\n\r\n",
+			"CONNECT example.com:443 SOCKS/5\r\n\r\n",
+		] {
+			let (output, result) = run(&provider, input);
+			assert!(result.is_err());
+			assert_eq!(
+				output,
+				"HTTP/1.1 400 Bad Request\r\nConnection: close\r\n\r\n"
+			);
+		}
+		// no blank line before the client went away
+		let (output, result) = run(&provider, "CONNECT example.com:443 HTTP/1.1\r\n");
+		assert!(result.is_err());
+		assert_eq!(
+			output,
+			"HTTP/1.1 400 Bad Request\r\nConnection: close\r\n\r\n"
+		);
+		// or none in the first MAX_HEAD bytes
+		let input = format!(
+			"CONNECT example.com:443 HTTP/1.1\r\n{}\r\n",
+			"X-Padding: 0123456789abcdef\r\n".repeat(300)
+		);
+		let (output, result) = run(&provider, &input);
+		assert!(result.is_err());
+		assert_eq!(
+			output,
+			"HTTP/1.1 431 Request Header Fields Too Large\r\nConnection: close\r\n\r\n"
+		);
+		assert!(provider.opened.lock().unwrap().is_empty());
+	}
+
+	#[test]
+	fn request_timeout() {
+		// a request line, and then nothing
+		let provider = FakeProvider::default();
+		let input = b"CONNECT example.com:443 HTTP/1.1\r\n";
+		let (output, result) = proxy::test::run_stalled(handle_connection, &provider, input);
+		assert!(result.is_err());
+		assert_eq!(
+			String::from_utf8(output).unwrap(),
+			"HTTP/1.1 408 Request Timeout\r\nConnection: close\r\n\r\n"
+		);
+		assert!(provider.opened.lock().unwrap().is_empty());
+	}
+
+	#[test]
+	fn status_codes() {
+		assert_eq!(status_code(&end(EndReason::EXITPOLICY)), 403);
+		assert_eq!(status_code(&end(EndReason::TIMEOUT)), 504);
+		assert_eq!(status_code(&end(EndReason::CONNECTREFUSED)), 502);
+		assert_eq!(status_code(&end(EndReason::RESOLVEFAILED)), 502);
+		let e = ErrorKind::CircuitError("no usable guard".to_string()).into();
+		assert_eq!(status_code(&StreamError::NoCircuit(e)), 502);
+	}
+}
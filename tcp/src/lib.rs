@@ -12,6 +12,20 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+pub mod bootstrap;
+pub mod chanmgr;
 mod channel;
+pub mod circmgr;
 pub mod circuit;
+pub mod client;
+mod dirclient;
 pub mod ds_load;
+pub mod ds_store;
+pub mod guard;
+mod happy_eyeballs;
+pub mod http;
+pub mod netdir;
+pub mod path;
+pub mod proxy;
+pub mod socks;
+pub mod timeout;
@@ -0,0 +1,177 @@
+// Copyright 2021 The BMW Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::net::SocketAddr;
+use std::time::SystemTime;
+use tor_linkspec::{ChanTarget, CircTarget};
+use tor_llcrypto::pk::curve25519;
+use tor_llcrypto::pk::ed25519::Ed25519Identity;
+use tor_llcrypto::pk::rsa::RsaIdentity;
+use tor_netdoc::consensus::{Consensus, RouterStatus};
+use tor_netdoc::microdesc::{MdDigest, Microdesc};
+use tor_netdoc::policy::PortPolicy;
+use tor_protover::Protocols;
+
+/// A microdesc consensus together with the microdescriptors we have
+/// for the relays it lists.
+pub struct NetDir {
+	consensus: Consensus,
+	microdescs: HashMap<MdDigest, Microdesc>,
+}
+
+/// How current a directory is
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Liveness {
+	/// Between the consensus' valid-after and valid-until times
+	Live,
+	/// Outside of that, but within the tolerance we allow
+	ReasonablyLive,
+}
+
+/// A relay that we know enough about to build a circuit through.
+#[derive(Clone)]
+pub struct Relay<'a> {
+	/// The relay's entry in the consensus
+	pub rs: &'a RouterStatus,
+	/// The relay's microdescriptor
+	pub md: &'a Microdesc,
+	ed_identity: Option<Ed25519Identity>,
+	rsa_identity: RsaIdentity,
+}
+
+/// Return the microdescriptor digest listed for a router, if it has one.
+fn md_digest(rs: &RouterStatus) -> Option<MdDigest> {
+	rs.doc_digest[..].try_into().ok()
+}
+
+impl NetDir {
+	pub fn new(consensus: Consensus, microdescs: HashMap<MdDigest, Microdesc>) -> NetDir {
+		NetDir {
+			consensus,
+			microdescs,
+		}
+	}
+
+	pub fn consensus(&self) -> &Consensus {
+		&self.consensus
+	}
+
+	/// Return whether the consensus is live at the given time. A NetDir is
+	/// only built from a consensus that is at least reasonably live.
+	pub fn liveness(&self, now: SystemTime) -> Liveness {
+		let header = &self.consensus.header;
+		if header.valid_after <= now && now <= header.valid_until {
+			Liveness::Live
+		} else {
+			Liveness::ReasonablyLive
+		}
+	}
+
+	/// Return every relay in the consensus whose microdescriptor we have.
+	pub fn relays(&self) -> impl Iterator<Item = Relay<'_>> {
+		self.consensus.routers.iter().filter_map(move |rs| {
+			let md = self.microdescs.get(&md_digest(rs)?)?;
+			Some(Relay {
+				rs,
+				md,
+				ed_identity: md.ed25519_id,
+				rsa_identity: rs.rsa_identity,
+			})
+		})
+	}
+
+	/// Return the relay with the given RSA identity, if we can use it.
+	pub fn by_rsa_id(&self, id: &RsaIdentity) -> Option<Relay<'_>> {
+		self.relays().find(|relay| &relay.rs.rsa_identity == id)
+	}
+
+	/// Return the digests of the microdescriptors listed in the consensus
+	/// that we don't have yet.
+	pub fn missing_microdescs(&self) -> Vec<MdDigest> {
+		self.consensus
+			.routers
+			.iter()
+			.filter_map(md_digest)
+			.filter(|d| !self.microdescs.contains_key(d))
+			.collect()
+	}
+
+	/// Return how many of the microdescriptors listed in the consensus we
+	/// have, and how many are listed.
+	pub fn microdesc_coverage(&self) -> (usize, usize) {
+		let listed = self.consensus.routers.iter().filter_map(md_digest).count();
+		(listed - self.missing_microdescs().len(), listed)
+	}
+}
+
+// a relay that doesn't give a policy, or gives one we can't parse, is
+// not used as an exit
+fn port_policy(summary: Option<&String>) -> PortPolicy {
+	summary
+		.and_then(|summary| PortPolicy::parse(summary).ok())
+		.unwrap_or_else(PortPolicy::reject_all)
+}
+
+impl<'a> Relay<'a> {
+	/// The ports the relay allows exiting to over IPv4, from its
+	/// microdescriptor or else from the consensus
+	pub fn ipv4_policy(&self) -> PortPolicy {
+		port_policy(
+			self.md
+				.policy_summary
+				.as_ref()
+				.or(self.rs.policy_summary.as_ref()),
+		)
+	}
+
+	/// The ports the relay allows exiting to over IPv6
+	pub fn ipv6_policy(&self) -> PortPolicy {
+		port_policy(self.md.ipv6_policy_summary.as_ref())
+	}
+
+	/// Return true if the relay allows exiting to every one of `ports`
+	pub fn allows_ports(&self, ports: &[u16]) -> bool {
+		let policy = self.ipv4_policy();
+		ports.iter().all(|port| policy.allows_port(*port))
+	}
+}
+
+impl<'a> ChanTarget for Relay<'a> {
+	fn addrs(&self) -> &[SocketAddr] {
+		&self.rs.addrs[..]
+	}
+	fn ed_identity(&self) -> Option<Ed25519Identity> {
+		self.ed_identity
+	}
+	fn rsa_identity(&self) -> Option<RsaIdentity> {
+		Some(self.rsa_identity)
+	}
+	fn set_ed_identity(&mut self, ed: Ed25519Identity) {
+		self.ed_identity = Some(ed);
+	}
+	fn set_rsa_identity(&mut self, rsa: RsaIdentity) {
+		self.rsa_identity = rsa;
+	}
+}
+
+impl<'a> CircTarget for Relay<'a> {
+	fn ntor_onion_key(&self) -> &curve25519::PublicKey {
+		&self.md.ntor_onion_key
+	}
+	fn protovers(&self) -> &Protocols {
+		&self.rs.protos
+	}
+}
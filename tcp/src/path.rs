@@ -0,0 +1,796 @@
+// Copyright 2021 The BMW Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::netdir::{NetDir, Relay};
+use rand::Rng;
+use std::net::IpAddr;
+use tor_config::config::TorConfig;
+use tor_linkspec::ChanTarget;
+use tor_llcrypto::pk::rsa::RsaIdentity;
+use tor_netdoc::authcert::parse_fingerprint;
+use tor_netdoc::consensus::{Consensus, RelayFlags, RouterStatus};
+use tor_util::{Error, ErrorKind};
+
+// used for every weight when the consensus has no bandwidth-weights
+const DEFAULT_WEIGHT_SCALE: i32 = 10000;
+
+/// The position in a circuit a relay is picked for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+	Guard,
+	Middle,
+	Exit,
+}
+
+impl Role {
+	fn name(self) -> &'static str {
+		match self {
+			Role::Guard => "guard",
+			Role::Middle => "middle",
+			Role::Exit => "exit",
+		}
+	}
+}
+
+/// The bandwidth-weights of a consensus (dir-spec 3.8.3). Each role
+/// weights relays by whether they have the Guard and Exit flags.
+#[derive(Debug, Clone)]
+pub struct WeightSet {
+	// by role, then by kind of relay: neither flag, guard, exit, both
+	weights: [[u64; 4]; 3],
+}
+
+impl WeightSet {
+	pub fn from_consensus(consensus: &Consensus) -> WeightSet {
+		let bw = &consensus.header.bandwidth_weights;
+		let scale = consensus
+			.header
+			.params
+			.get_or("bwweightscale", DEFAULT_WEIGHT_SCALE);
+		let has_weights = bw.iter().next().is_some();
+		// weights missing from a bandwidth-weights line are 0, like Wge
+		let w = |name: &str| {
+			let value = if has_weights {
+				bw.get_or(name, 0)
+			} else {
+				scale
+			};
+			std::cmp::max(value, 0) as u64
+		};
+		WeightSet {
+			weights: [
+				[w("Wgm"), w("Wgg"), w("Wge"), w("Wgd")],
+				[w("Wmm"), w("Wmg"), w("Wme"), w("Wmd")],
+				[w("Wem"), w("Weg"), w("Wee"), w("Wed")],
+			],
+		}
+	}
+
+	/// Return the weight of a relay when picking it for `role`.
+	pub fn weight(&self, rs: &RouterStatus, role: Role) -> u64 {
+		// a BadExit is never used as an exit, so weight it as a non-exit
+		let guard = rs.has_flags(RelayFlags::GUARD);
+		let exit = rs.has_flags(RelayFlags::EXIT) && !rs.has_flags(RelayFlags::BAD_EXIT);
+		let kind = match (guard, exit) {
+			(false, false) => 0,
+			(true, false) => 1,
+			(false, true) => 2,
+			(true, true) => 3,
+		};
+		let role = match role {
+			Role::Guard => 0,
+			Role::Middle => 1,
+			Role::Exit => 2,
+		};
+		rs.weight.bandwidth as u64 * self.weights[role][kind]
+	}
+}
+
+/// Return true if a relay's flags allow it to be used for `role`. Relays
+/// for long lived streams must also be Stable.
+pub fn usable(rs: &RouterStatus, role: Role, need_stable: bool) -> bool {
+	let mut flags = RelayFlags::RUNNING | RelayFlags::VALID | RelayFlags::FAST;
+	match role {
+		Role::Guard => flags |= RelayFlags::GUARD | RelayFlags::STABLE,
+		Role::Middle => {}
+		Role::Exit => {
+			if rs.has_flags(RelayFlags::BAD_EXIT) {
+				return false;
+			}
+			flags |= RelayFlags::EXIT;
+		}
+	}
+	if need_stable {
+		flags |= RelayFlags::STABLE;
+	}
+	rs.has_flags(flags)
+}
+
+/// A set of relays, given by fingerprint or by address range
+#[derive(Debug, Clone, Default)]
+pub struct NodeSet {
+	ids: Vec<RsaIdentity>,
+	/// network address and prefix length
+	nets: Vec<(IpAddr, u8)>,
+}
+
+// true if the first `bits` bits of two addresses are the same
+fn same_prefix(a: &[u8], b: &[u8], bits: u8) -> bool {
+	let bytes = bits as usize / 8;
+	if a[..bytes] != b[..bytes] {
+		return false;
+	}
+	match bits % 8 {
+		0 => true,
+		rest => {
+			let mask = 0xffu8 << (8 - rest);
+			a[bytes] & mask == b[bytes] & mask
+		}
+	}
+}
+
+fn in_net(addr: IpAddr, net: IpAddr, bits: u8) -> bool {
+	match (addr, net) {
+		(IpAddr::V4(addr), IpAddr::V4(net)) => same_prefix(&addr.octets(), &net.octets(), bits),
+		(IpAddr::V6(addr), IpAddr::V6(net)) => same_prefix(&addr.octets(), &net.octets(), bits),
+		_ => false,
+	}
+}
+
+impl NodeSet {
+	/// Parse a list of fingerprints, with or without a leading '$', and
+	/// address ranges like "10.0.0.0/8". A bare address is a single host.
+	pub fn parse(entries: &[String]) -> Result<NodeSet, Error> {
+		let mut set = NodeSet::default();
+		for entry in entries {
+			let invalid = || -> Error {
+				ErrorKind::ConfigError(format!("invalid relay or address range '{}'", entry)).into()
+			};
+			let fingerprint = entry.trim_start_matches('$');
+			if fingerprint.len() == 40 && fingerprint.chars().all(|c| c.is_ascii_hexdigit()) {
+				set.ids
+					.push(parse_fingerprint(fingerprint).map_err(|_| invalid())?);
+				continue;
+			}
+			let mut parts = entry.splitn(2, '/');
+			let net: IpAddr = parts.next().unwrap_or("").parse().map_err(|_| invalid())?;
+			let max = if net.is_ipv4() { 32 } else { 128 };
+			let bits = match parts.next() {
+				Some(bits) => bits.parse::<u8>().map_err(|_| invalid())?,
+				None => max,
+			};
+			if bits > max {
+				return Err(invalid());
+			}
+			set.nets.push((net, bits));
+		}
+		Ok(set)
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.ids.is_empty() && self.nets.is_empty()
+	}
+
+	/// Return true if the relay's fingerprint or any of its addresses is
+	/// in the set.
+	pub fn contains(&self, relay: &Relay) -> bool {
+		self.ids.contains(&relay.rs.rsa_identity)
+			|| relay.addrs().iter().any(|addr| {
+				self.nets
+					.iter()
+					.any(|(net, bits)| in_net(addr.ip(), *net, *bits))
+			})
+	}
+}
+
+/// The ExcludeNodes, EntryNodes and ExitNodes restrictions from the
+/// configuration
+#[derive(Debug, Clone, Default)]
+pub struct NodeRestrictions {
+	pub exclude: NodeSet,
+	/// if not empty, the only relays used as guards
+	pub entry: NodeSet,
+	/// if not empty, the only relays used as exits
+	pub exit: NodeSet,
+}
+
+impl NodeRestrictions {
+	pub fn from_config(config: &TorConfig) -> Result<NodeRestrictions, Error> {
+		Ok(NodeRestrictions {
+			exclude: NodeSet::parse(&config.exclude_nodes)?,
+			entry: NodeSet::parse(&config.entry_nodes)?,
+			exit: NodeSet::parse(&config.exit_nodes)?,
+		})
+	}
+
+	/// Return true if the configuration allows `relay` to be used for
+	/// `role`.
+	pub fn allows(&self, relay: &Relay, role: Role) -> bool {
+		if self.exclude.contains(relay) {
+			return false;
+		}
+		match role {
+			Role::Guard => self.entry.is_empty() || self.entry.contains(relay),
+			Role::Middle => true,
+			Role::Exit => self.exit.is_empty() || self.exit.contains(relay),
+		}
+	}
+}
+
+// true if `relay` lists `other` in the family line of its microdescriptor
+fn lists_in_family(relay: &Relay, other: &Relay) -> bool {
+	let id = other.rs.rsa_identity.to_string();
+	relay.md.family.iter().any(|member| {
+		// "$fingerprint" may be followed by "=nickname" or "~nickname"
+		let fingerprint = member.split(&['=', '~'][..]).next().unwrap_or("");
+		fingerprint.eq_ignore_ascii_case(&id)
+	})
+}
+
+// true if two addresses are in the same /16 (IPv4) or /32 (IPv6)
+fn same_subnet(a: IpAddr, b: IpAddr) -> bool {
+	match (a, b) {
+		(IpAddr::V4(_), IpAddr::V4(_)) => in_net(a, b, 16),
+		(IpAddr::V6(_), IpAddr::V6(_)) => in_net(a, b, 32),
+		_ => false,
+	}
+}
+
+/// Return true if two relays must not be in the same circuit: they are
+/// the same relay, they are in the same family (each lists the other),
+/// or they have addresses in the same /16 or IPv6 /32.
+pub fn related(a: &Relay, b: &Relay) -> bool {
+	if a.rs.rsa_identity == b.rs.rsa_identity {
+		return true;
+	}
+	if lists_in_family(a, b) && lists_in_family(b, a) {
+		return true;
+	}
+	a.addrs()
+		.iter()
+		.any(|x| b.addrs().iter().any(|y| same_subnet(x.ip(), y.ip())))
+}
+
+/// Pick one of the items with probability proportional to its weight. If
+/// all the weights are 0 every item is equally likely.
+fn pick_weighted<R: Rng, T>(rng: &mut R, mut items: Vec<(T, u64)>) -> Option<T> {
+	if items.is_empty() {
+		return None;
+	}
+	let total: u64 = items.iter().map(|(_, weight)| weight).sum();
+	if total == 0 {
+		let i = rng.gen_range(0..items.len());
+		return Some(items.swap_remove(i).0);
+	}
+	let mut point = rng.gen_range(0..total);
+	for (item, weight) in items {
+		if point < weight {
+			return Some(item);
+		}
+		point -= weight;
+	}
+	unreachable!()
+}
+
+/// Pick a relay for `role`, weighted by bandwidth, from those usable for
+/// it that also pass `filter`.
+pub fn pick_relay<'a, R, F>(
+	rng: &mut R,
+	netdir: &'a NetDir,
+	weights: &WeightSet,
+	role: Role,
+	need_stable: bool,
+	filter: F,
+) -> Option<Relay<'a>>
+where
+	R: Rng,
+	F: Fn(&Relay<'a>) -> bool,
+{
+	let candidates = netdir
+		.relays()
+		.filter(|relay| usable(relay.rs, role, need_stable) && filter(relay))
+		.map(|relay| {
+			let weight = weights.weight(relay.rs, role);
+			(relay, weight)
+		})
+		.collect();
+	pick_weighted(rng, candidates)
+}
+
+/// The relays of a three hop circuit
+#[derive(Clone)]
+pub struct Path<'a> {
+	pub guard: Relay<'a>,
+	pub middle: Relay<'a>,
+	pub exit: Relay<'a>,
+}
+
+fn no_relay(role: &str) -> Error {
+	ErrorKind::CircuitError(format!("no usable {} relay", role)).into()
+}
+
+/// Pick the relays for a three hop circuit. Circuits normally go through
+/// a `guard` from the guard manager; without one, a guard is picked here.
+/// The exit is picked before the guard, as it is usually the most
+/// constrained, unless EntryNodes narrows down the guards. No two of the
+/// relays may be related, and all must be allowed by `restrictions`. The
+/// exit's policy must allow every one of `ports`.
+pub fn pick_path<'a, R: Rng>(
+	rng: &mut R,
+	netdir: &'a NetDir,
+	guard: Option<Relay<'a>>,
+	restrictions: &NodeRestrictions,
+	ports: &[u16],
+	need_stable: bool,
+) -> Result<Path<'a>, Error> {
+	let weights = WeightSet::from_consensus(netdir.consensus());
+	let mut pick = |role, other: Option<&Relay<'a>>| {
+		pick_relay(rng, netdir, &weights, role, need_stable, |relay| {
+			restrictions.allows(relay, role)
+				&& (role != Role::Exit || relay.allows_ports(ports))
+				&& other.map(|other| !related(relay, other)).unwrap_or(true)
+		})
+		.ok_or_else(|| no_relay(role.name()))
+	};
+	let (guard, exit) = match guard {
+		Some(guard) => {
+			let exit = pick(Role::Exit, Some(&guard))?;
+			(guard, exit)
+		}
+		None if !restrictions.entry.is_empty() => {
+			let guard = pick(Role::Guard, None)?;
+			let exit = pick(Role::Exit, Some(&guard))?;
+			(guard, exit)
+		}
+		None => {
+			let exit = pick(Role::Exit, None)?;
+			(pick(Role::Guard, Some(&exit))?, exit)
+		}
+	};
+	let middle = pick_relay(rng, netdir, &weights, Role::Middle, need_stable, |relay| {
+		restrictions.allows(relay, Role::Middle)
+			&& !related(relay, &exit)
+			&& !related(relay, &guard)
+	})
+	.ok_or_else(|| no_relay(Role::Middle.name()))?;
+	Ok(Path {
+		guard,
+		middle,
+		exit,
+	})
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+	use super::*;
+	use rand::rngs::StdRng;
+	use rand::SeedableRng;
+	use std::collections::HashMap;
+	use std::convert::TryInto;
+	use tor_netdoc::microdesc::{MdDigest, Microdesc};
+
+	const CONSENSUS: &str = include_str!("../testdata/consensus-paths.txt");
+
+	// a NetDir with a made up microdescriptor for every relay, shared with
+	// the guard tests
+	pub(crate) fn netdir(text: &str) -> NetDir {
+		netdir_with_family(text, &[])
+	}
+
+	// the same, where each relay in `family` lists the others
+	fn netdir_with_family(text: &str, family: &[&str]) -> NetDir {
+		let consensus = Consensus::parse(text).unwrap();
+		let members: Vec<String> = consensus
+			.routers
+			.iter()
+			.filter(|rs| family.contains(&rs.nickname.as_str()))
+			.map(|rs| {
+				format!(
+					"{}~{}",
+					rs.rsa_identity.to_string().to_uppercase(),
+					rs.nickname
+				)
+			})
+			.collect();
+		let mut microdescs = HashMap::new();
+		for rs in &consensus.routers {
+			let digest: MdDigest = rs.doc_digest[..].try_into().unwrap();
+			let family = if family.contains(&rs.nickname.as_str()) {
+				members.clone()
+			} else {
+				vec![]
+			};
+			let md = Microdesc {
+				digest,
+				ntor_onion_key: [0u8; 32].into(),
+				family,
+				ed25519_id: None,
+				policy_summary: None,
+				ipv6_policy_summary: None,
+			};
+			microdescs.insert(digest, md);
+		}
+		NetDir::new(consensus, microdescs)
+	}
+
+	fn by_name<'a>(netdir: &'a NetDir, name: &str) -> &'a RouterStatus {
+		relay(netdir, name).rs
+	}
+
+	fn relay<'a>(netdir: &'a NetDir, name: &str) -> Relay<'a> {
+		netdir.relays().find(|r| r.rs.nickname == name).unwrap()
+	}
+
+	// the nicknames of the exits picked for paths through `guard`
+	fn exits_through(netdir: &NetDir, guard: &str, restrictions: &NodeRestrictions) -> Vec<String> {
+		let mut rng = StdRng::seed_from_u64(13);
+		let mut exits = vec![];
+		for _ in 0..100 {
+			let guard = relay(netdir, guard);
+			let path = pick_path(&mut rng, netdir, Some(guard), restrictions, &[], false).unwrap();
+			if !exits.contains(&path.exit.rs.nickname) {
+				exits.push(path.exit.rs.nickname.clone());
+			}
+		}
+		exits.sort();
+		exits
+	}
+
+	// pick `n` relays for a role and count how often each was picked
+	fn pick_many(netdir: &NetDir, role: Role, n: usize) -> HashMap<String, usize> {
+		let mut rng = StdRng::seed_from_u64(7);
+		let weights = WeightSet::from_consensus(netdir.consensus());
+		let mut counts = HashMap::new();
+		for _ in 0..n {
+			let relay = pick_relay(&mut rng, netdir, &weights, role, false, |_| true).unwrap();
+			*counts.entry(relay.rs.nickname.clone()).or_insert(0) += 1;
+		}
+		counts
+	}
+
+	fn assert_share(counts: &HashMap<String, usize>, name: &str, n: usize, share: f64) {
+		let got = *counts.get(name).unwrap_or(&0) as f64 / n as f64;
+		assert!(
+			(got - share).abs() < 0.02,
+			"{} picked {} of the time, expected {}",
+			name,
+			got,
+			share
+		);
+	}
+
+	#[test]
+	fn weights() {
+		let netdir = netdir(CONSENSUS);
+		let weights = WeightSet::from_consensus(netdir.consensus());
+		let guard1 = by_name(&netdir, "guard1");
+		let guardexit = by_name(&netdir, "guardexit");
+		let badexit = by_name(&netdir, "badexit");
+		assert_eq!(weights.weight(guard1, Role::Guard), 1000 * 6000);
+		assert_eq!(weights.weight(guard1, Role::Middle), 1000 * 4000);
+		assert_eq!(weights.weight(guardexit, Role::Guard), 4000 * 3000);
+		assert_eq!(weights.weight(guardexit, Role::Exit), 4000 * 5000);
+		assert_eq!(weights.weight(guardexit, Role::Middle), 0);
+		// weighted as a middle-only relay
+		assert_eq!(weights.weight(badexit, Role::Middle), 1500 * 10000);
+
+		// without bandwidth-weights only the bandwidth counts
+		let start = CONSENSUS.find("bandwidth-weights").unwrap();
+		let end = start + CONSENSUS[start..].find('\n').unwrap() + 1;
+		let text = format!("{}{}", &CONSENSUS[..start], &CONSENSUS[end..]);
+		let netdir = self::netdir(&text);
+		let weights = WeightSet::from_consensus(netdir.consensus());
+		let guardexit = by_name(&netdir, "guardexit");
+		assert_eq!(weights.weight(guardexit, Role::Middle), 4000 * 10000);
+	}
+
+	#[test]
+	fn flags() {
+		let netdir = netdir(CONSENSUS);
+		let usable_for = |role, need_stable| {
+			let mut names: Vec<&str> = netdir
+				.relays()
+				.filter(|r| usable(r.rs, role, need_stable))
+				.map(|r| r.rs.nickname.as_str())
+				.collect();
+			names.sort_unstable();
+			names
+		};
+		assert_eq!(
+			usable_for(Role::Guard, false),
+			vec!["guard1", "guard2", "guardexit"]
+		);
+		assert_eq!(usable_for(Role::Exit, false), vec!["exit1", "guardexit"]);
+		assert_eq!(
+			usable_for(Role::Middle, false),
+			vec![
+				"badexit",
+				"exit1",
+				"guard1",
+				"guard2",
+				"guardexit",
+				"middle1"
+			]
+		);
+		assert_eq!(
+			usable_for(Role::Middle, true),
+			vec!["exit1", "guard1", "guard2", "guardexit"]
+		);
+	}
+
+	#[test]
+	fn distribution() {
+		let netdir = netdir(CONSENSUS);
+		let n = 20000;
+
+		// 6000 * (1000, 3000) for the guards, 3000 * 4000 for guardexit
+		let counts = pick_many(&netdir, Role::Guard, n);
+		assert_eq!(counts.len(), 3);
+		assert_share(&counts, "guard1", n, 1.0 / 6.0);
+		assert_share(&counts, "guard2", n, 1.0 / 2.0);
+		assert_share(&counts, "guardexit", n, 1.0 / 3.0);
+
+		let counts = pick_many(&netdir, Role::Exit, n);
+		assert_eq!(counts.len(), 2);
+		assert_share(&counts, "exit1", n, 1.0 / 2.0);
+		assert_share(&counts, "guardexit", n, 1.0 / 2.0);
+
+		// exits get no middle weight, and down or slow relays are never used
+		let counts = pick_many(&netdir, Role::Middle, n);
+		assert_eq!(counts.len(), 4);
+		assert_share(&counts, "guard1", n, 4.0 / 36.0);
+		assert_share(&counts, "guard2", n, 12.0 / 36.0);
+		assert_share(&counts, "middle1", n, 5.0 / 36.0);
+		assert_share(&counts, "badexit", n, 15.0 / 36.0);
+	}
+
+	#[test]
+	fn paths() {
+		let netdir = netdir(CONSENSUS);
+		let mut rng = StdRng::seed_from_u64(11);
+		let none = NodeRestrictions::default();
+		for _ in 0..200 {
+			let path = pick_path(&mut rng, &netdir, None, &none, &[], false).unwrap();
+			assert!(usable(path.guard.rs, Role::Guard, false));
+			assert!(usable(path.middle.rs, Role::Middle, false));
+			assert!(usable(path.exit.rs, Role::Exit, false));
+			assert_ne!(path.guard.rs.rsa_identity, path.exit.rs.rsa_identity);
+			assert_ne!(path.middle.rs.rsa_identity, path.exit.rs.rsa_identity);
+			assert_ne!(path.middle.rs.rsa_identity, path.guard.rs.rsa_identity);
+		}
+
+		// the same seed gives the same path
+		let pick = |seed| {
+			let path = pick_path(
+				&mut StdRng::seed_from_u64(seed),
+				&netdir,
+				None,
+				&none,
+				&[],
+				false,
+			)
+			.unwrap();
+			(
+				path.guard.rs.nickname.clone(),
+				path.middle.rs.nickname.clone(),
+				path.exit.rs.nickname.clone(),
+			)
+		};
+		assert_eq!(pick(3), pick(3));
+
+		// a given guard is used as it is, and the exit is never the same
+		let guardexit = netdir
+			.relays()
+			.find(|r| r.rs.nickname == "guardexit")
+			.unwrap();
+		for _ in 0..50 {
+			let path = pick_path(
+				&mut rng,
+				&netdir,
+				Some(guardexit.clone()),
+				&none,
+				&[],
+				false,
+			)
+			.unwrap();
+			assert_eq!(path.guard.rs.nickname, "guardexit");
+			assert_eq!(path.exit.rs.nickname, "exit1");
+		}
+	}
+
+	#[test]
+	fn family() {
+		let none = NodeRestrictions::default();
+		assert_eq!(
+			exits_through(&netdir(CONSENSUS), "guard1", &none),
+			vec!["exit1", "guardexit"]
+		);
+
+		let netdir = netdir_with_family(CONSENSUS, &["guard1", "exit1"]);
+		assert!(related(&relay(&netdir, "guard1"), &relay(&netdir, "exit1")));
+		assert!(!related(
+			&relay(&netdir, "guard1"),
+			&relay(&netdir, "guard2")
+		));
+		assert_eq!(exits_through(&netdir, "guard1", &none), vec!["guardexit"]);
+
+		// a family only counts if both relays list each other
+		let both = netdir_with_family(CONSENSUS, &["guard1", "exit1"]);
+		let exit1 = relay(&both, "exit1").md.digest;
+		let mut microdescs = HashMap::new();
+		for relay in both.relays() {
+			let mut md = relay.md.clone();
+			if md.digest == exit1 {
+				md.family.clear();
+			}
+			microdescs.insert(md.digest, md);
+		}
+		let netdir = NetDir::new(Consensus::parse(CONSENSUS).unwrap(), microdescs);
+		assert!(!related(
+			&relay(&netdir, "guard1"),
+			&relay(&netdir, "exit1")
+		));
+	}
+
+	#[test]
+	fn subnet() {
+		let none = NodeRestrictions::default();
+		// exit1 moves into guard1's /16
+		let text = CONSENSUS.replace("10.3.0.1 9001", "10.1.200.7 9001");
+		let netdir = netdir(&text);
+		assert!(related(&relay(&netdir, "guard1"), &relay(&netdir, "exit1")));
+		assert_eq!(exits_through(&netdir, "guard1", &none), vec!["guardexit"]);
+
+		// but a different /16 is fine
+		let text = CONSENSUS.replace("10.3.0.1 9001", "10.2.0.1 9001");
+		let netdir = self::netdir(&text);
+		assert!(!related(
+			&relay(&netdir, "guard1"),
+			&relay(&netdir, "exit1")
+		));
+		assert_eq!(
+			exits_through(&netdir, "guard1", &none),
+			vec!["exit1", "guardexit"]
+		);
+
+		// the middle is kept apart from both ends too
+		let mut rng = StdRng::seed_from_u64(17);
+		for _ in 0..100 {
+			let path = pick_path(&mut rng, &netdir, None, &none, &[], false).unwrap();
+			assert!(!related(&path.middle, &path.guard));
+			assert!(!related(&path.middle, &path.exit));
+		}
+	}
+
+	#[test]
+	fn node_set() {
+		let set = |entries: &[&str]| {
+			NodeSet::parse(&entries.iter().map(|e| e.to_string()).collect::<Vec<_>>())
+		};
+		assert!(set(&[]).unwrap().is_empty());
+		let ok = set(&[
+			"$0303030303030303030303030303030303030303",
+			"0101010101010101010101010101010101010101",
+			"10.5.0.0/16",
+			"10.6.0.1",
+			"2001:db8::/32",
+		])
+		.unwrap();
+		let netdir = netdir(CONSENSUS);
+		let mut names: Vec<String> = netdir
+			.relays()
+			.filter(|r| ok.contains(r))
+			.map(|r| r.rs.nickname.clone())
+			.collect();
+		names.sort();
+		assert_eq!(names, vec!["badexit", "exit1", "guard1", "middle1"]);
+
+		for bad in &["guard1", "10.0.0.0/33", "::/129", "10.0.0/8", "$0303"] {
+			assert!(set(&[bad]).is_err(), "{} accepted", bad);
+		}
+	}
+
+	#[test]
+	fn restrictions() {
+		let netdir = netdir(CONSENSUS);
+		let set = |entry: &str| NodeSet::parse(&[entry.to_string()]).unwrap();
+		let check = |restrictions: NodeRestrictions, check: &dyn Fn(&Path)| {
+			let mut rng = StdRng::seed_from_u64(19);
+			for _ in 0..100 {
+				let path = pick_path(&mut rng, &netdir, None, &restrictions, &[], false).unwrap();
+				check(&path);
+			}
+		};
+
+		check(
+			NodeRestrictions {
+				exit: set("$0303030303030303030303030303030303030303"),
+				..Default::default()
+			},
+			&|path| assert_eq!(path.exit.rs.nickname, "exit1"),
+		);
+		check(
+			NodeRestrictions {
+				exclude: set("10.2.0.0/16"),
+				..Default::default()
+			},
+			&|path| {
+				assert_ne!(path.guard.rs.nickname, "guard2");
+				assert_ne!(path.middle.rs.nickname, "guard2");
+			},
+		);
+		check(
+			NodeRestrictions {
+				entry: set("10.4.0.1"),
+				..Default::default()
+			},
+			&|path| {
+				assert_eq!(path.guard.rs.nickname, "guardexit");
+				assert_eq!(path.exit.rs.nickname, "exit1");
+			},
+		);
+
+		// with every exit excluded there is no path
+		let restrictions = NodeRestrictions {
+			exclude: NodeSet::parse(&["10.3.0.0/16".to_string(), "10.4.0.0/16".to_string()])
+				.unwrap(),
+			..Default::default()
+		};
+		let mut rng = StdRng::seed_from_u64(23);
+		assert!(pick_path(&mut rng, &netdir, None, &restrictions, &[], false).is_err());
+	}
+
+	#[test]
+	fn exit_ports() {
+		let text = CONSENSUS
+			.replacen(
+				"s Exit Fast Running Stable Valid\nw Bandwidth=2000\n",
+				"s Exit Fast Running Stable Valid\nw Bandwidth=2000\np accept 80,443\n",
+				1,
+			)
+			.replacen(
+				"s Exit Fast Guard Running Stable Valid\nw Bandwidth=4000\n",
+				"s Exit Fast Guard Running Stable Valid\nw Bandwidth=4000\np reject 25\n",
+				1,
+			);
+		let netdir = netdir(&text);
+		let none = NodeRestrictions::default();
+		let exits = |ports: &[u16]| {
+			let mut rng = StdRng::seed_from_u64(29);
+			let mut exits = vec![];
+			for _ in 0..100 {
+				match pick_path(&mut rng, &netdir, None, &none, ports, false) {
+					Ok(path) => {
+						if !exits.contains(&path.exit.rs.nickname) {
+							exits.push(path.exit.rs.nickname.clone());
+						}
+					}
+					Err(_) => return None,
+				}
+			}
+			exits.sort();
+			Some(exits)
+		};
+		assert_eq!(exits(&[]).unwrap(), vec!["exit1", "guardexit"]);
+		assert_eq!(exits(&[443]).unwrap(), vec!["exit1", "guardexit"]);
+		assert_eq!(exits(&[22]).unwrap(), vec!["guardexit"]);
+		assert_eq!(exits(&[80, 22]).unwrap(), vec!["guardexit"]);
+		assert_eq!(exits(&[25]), None);
+
+		// relays that give no policy are never exits for a port
+		let netdir = self::netdir(CONSENSUS);
+		assert!(!relay(&netdir, "exit1").allows_ports(&[80]));
+		assert!(relay(&netdir, "exit1").allows_ports(&[]));
+	}
+}
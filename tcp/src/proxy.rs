@@ -0,0 +1,322 @@
+// Copyright 2021 The BMW Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! What the local proxy listeners have in common: where their streams
+//! come from, accepting connections, and relaying data.
+
+use crate::circmgr::StreamIsolation;
+use crate::circuit::log;
+use crate::client::StreamError;
+use futures::future;
+use futures::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::Duration;
+use tor_rtcompat::{Runtime, SleepProviderExt, TcpListener};
+use tor_util::logger::Log;
+use tor_util::Error;
+use tor_util::StopState;
+
+// how often the accept loop checks whether it should stop
+const ACCEPT_POLL: Duration = Duration::from_millis(100);
+// connections served at once, each on its own thread. Those accepted
+// beyond this are closed straight away.
+const MAX_CONNECTIONS: usize = 256;
+/// How long a client has to send its request once connected
+pub(crate) const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Opens streams and looks up names through Tor on behalf of proxy
+/// clients: a `TorClient`, or a fake in tests. The calls block until
+/// they are done.
+pub trait StreamProvider: Send + Sync + 'static {
+	type Stream: AsyncRead + AsyncWrite + Send + Unpin + 'static;
+
+	/// Open a stream to `host`:`port` through an exit.
+	fn open_stream(
+		&self,
+		host: &str,
+		port: u16,
+		isolation: &StreamIsolation,
+	) -> Result<Self::Stream, StreamError>;
+
+	/// Look up the addresses for `host` at an exit.
+	fn resolve_host(
+		&self,
+		host: &str,
+		isolation: &StreamIsolation,
+	) -> Result<Vec<IpAddr>, StreamError>;
+
+	/// Look up the hostnames for `addr` at an exit.
+	fn resolve_addr(
+		&self,
+		addr: IpAddr,
+		isolation: &StreamIsolation,
+	) -> Result<Vec<String>, StreamError>;
+}
+
+/// Copy data both ways between a proxy client's connection and a Tor
+/// stream, until both sides have finished sending.
+pub async fn relay<A, B>(client: A, exit: B) -> io::Result<()>
+where
+	A: AsyncRead + AsyncWrite + Unpin,
+	B: AsyncRead + AsyncWrite + Unpin,
+{
+	let (mut client_r, mut client_w) = client.split();
+	let (mut exit_r, mut exit_w) = exit.split();
+	let upload = async {
+		io::copy(&mut client_r, &mut exit_w).await?;
+		exit_w.close().await
+	};
+	let download = async {
+		io::copy(&mut exit_r, &mut client_w).await?;
+		client_w.close().await
+	};
+	future::try_join(upload, download).await?;
+	Ok(())
+}
+
+/// Accept connections on `addr` until `stop_state` is stopped, serving
+/// each on its own thread with `handler`, up to `MAX_CONNECTIONS` at once.
+/// `name` says what kind of proxy this is in the log. Returns the address
+/// listened on.
+pub(crate) fn start_listener<R, P, H>(
+	runtime: R,
+	addr: SocketAddr,
+	provider: Arc<P>,
+	handler: H,
+	name: &'static str,
+	mainlog: &'static Arc<Mutex<Log>>,
+	stop_state: Arc<RwLock<StopState>>,
+) -> Result<SocketAddr, Error>
+where
+	R: Runtime,
+	P: StreamProvider,
+	H: Fn(&R, R::TcpStream, &P) -> Result<(), Error> + Copy + Send + 'static,
+{
+	let listener = runtime.block_on(runtime.listen(&addr))?;
+	let local_addr = listener.local_addr()?;
+	log(
+		mainlog,
+		&format!("listening for {} connections on {}", name, local_addr),
+	);
+	let active = Arc::new(AtomicUsize::new(0));
+	thread::spawn(move || loop {
+		match runtime.block_on(runtime.timeout(ACCEPT_POLL, listener.accept())) {
+			Ok(Ok((_, peer))) if active.load(Ordering::SeqCst) >= MAX_CONNECTIONS => log(
+				mainlog,
+				&format!("too many {} connections, closed {}", name, peer),
+			),
+			Ok(Ok((stream, peer))) => {
+				let runtime = runtime.clone();
+				let provider = provider.clone();
+				let active = active.clone();
+				active.fetch_add(1, Ordering::SeqCst);
+				thread::spawn(move || {
+					if let Err(e) = handler(&runtime, stream, &*provider) {
+						log(
+							mainlog,
+							&format!("{} connection from {} failed: {}", name, peer, e.kind()),
+						);
+					}
+					active.fetch_sub(1, Ordering::SeqCst);
+				});
+			}
+			Ok(Err(e)) => log(
+				mainlog,
+				&format!("could not accept {} connection: {}", name, e),
+			),
+			// nothing yet
+			Err(_) => {}
+		}
+		let stop_state = stop_state.read().unwrap();
+		if stop_state.is_stopped() {
+			break;
+		}
+	});
+	Ok(local_addr)
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+	use super::*;
+	use futures::io::Cursor;
+	use std::future::Future;
+	use std::pin::Pin;
+	use std::task::{Context, Poll};
+	use tor_cell::relaycell::msg::EndReason;
+	use tor_rtcompat::{SleepProvider, SpawnBlocking};
+
+	// reads from `input`, and keeps what is written to it in `output`. A
+	// stalled stream waits forever once `input` is used up, rather than
+	// ending.
+	pub(crate) struct FakeStream {
+		pub(crate) input: Cursor<Vec<u8>>,
+		pub(crate) output: Arc<Mutex<Vec<u8>>>,
+		pub(crate) stalled: bool,
+	}
+
+	impl AsyncRead for FakeStream {
+		fn poll_read(
+			mut self: Pin<&mut Self>,
+			cx: &mut Context<'_>,
+			buf: &mut [u8],
+		) -> Poll<io::Result<usize>> {
+			let used_up = self.input.position() == self.input.get_ref().len() as u64;
+			if self.stalled && used_up {
+				return Poll::Pending;
+			}
+			Pin::new(&mut self.input).poll_read(cx, buf)
+		}
+	}
+
+	impl AsyncWrite for FakeStream {
+		fn poll_write(
+			self: Pin<&mut Self>,
+			_cx: &mut Context<'_>,
+			buf: &[u8],
+		) -> Poll<io::Result<usize>> {
+			self.output.lock().unwrap().extend_from_slice(buf);
+			Poll::Ready(Ok(buf.len()))
+		}
+		fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+			Poll::Ready(Ok(()))
+		}
+		fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+			Poll::Ready(Ok(()))
+		}
+	}
+
+	pub(crate) fn end(reason: EndReason) -> StreamError {
+		StreamError::Proto(tor_proto::Error::EndReceived(reason))
+	}
+
+	// stands in for the circuit layer. Its streams send "hello".
+	#[derive(Default)]
+	pub(crate) struct FakeProvider {
+		pub(crate) opened: Mutex<Vec<(String, u16, StreamIsolation)>>,
+		pub(crate) uploaded: Arc<Mutex<Vec<u8>>>,
+	}
+
+	impl StreamProvider for FakeProvider {
+		type Stream = FakeStream;
+
+		fn open_stream(
+			&self,
+			host: &str,
+			port: u16,
+			isolation: &StreamIsolation,
+		) -> Result<FakeStream, StreamError> {
+			self.opened
+				.lock()
+				.unwrap()
+				.push((host.to_string(), port, isolation.clone()));
+			match host {
+				"refused.example" => Err(end(EndReason::CONNECTREFUSED)),
+				"policy.example" => Err(end(EndReason::EXITPOLICY)),
+				"slow.example" => Err(end(EndReason::TIMEOUT)),
+				_ => Ok(FakeStream {
+					input: Cursor::new(b"hello".to_vec()),
+					output: self.uploaded.clone(),
+					stalled: false,
+				}),
+			}
+		}
+
+		fn resolve_host(
+			&self,
+			host: &str,
+			_isolation: &StreamIsolation,
+		) -> Result<Vec<IpAddr>, StreamError> {
+			match host {
+				"example.com" => Ok(vec![
+					"2001:db8::1".parse().unwrap(),
+					"192.0.2.1".parse().unwrap(),
+				]),
+				_ => Err(end(EndReason::RESOLVEFAILED)),
+			}
+		}
+
+		fn resolve_addr(
+			&self,
+			_addr: IpAddr,
+			_isolation: &StreamIsolation,
+		) -> Result<Vec<String>, StreamError> {
+			Ok(vec!["example.com".to_string()])
+		}
+	}
+
+	pub(crate) struct Blocking;
+
+	impl SpawnBlocking for Blocking {
+		fn block_on<F: Future>(&self, future: F) -> F::Output {
+			futures::executor::block_on(future)
+		}
+	}
+
+	// nothing the fakes do waits on the network, so time is up as soon as
+	// anything would have to wait
+	impl SleepProvider for Blocking {
+		type SleepFuture = future::Ready<()>;
+		fn sleep(&self, _duration: Duration) -> Self::SleepFuture {
+			future::ready(())
+		}
+	}
+
+	// a client that sends `input` and then closes, served by `handler`.
+	// Returns what it was sent back, and how the connection went.
+	pub(crate) fn run<H>(
+		handler: H,
+		provider: &FakeProvider,
+		input: &[u8],
+	) -> (Vec<u8>, Result<(), Error>)
+	where
+		H: Fn(&Blocking, FakeStream, &FakeProvider) -> Result<(), Error>,
+	{
+		serve(handler, provider, input, false)
+	}
+
+	// a client that sends `input` and then nothing more, without closing
+	pub(crate) fn run_stalled<H>(
+		handler: H,
+		provider: &FakeProvider,
+		input: &[u8],
+	) -> (Vec<u8>, Result<(), Error>)
+	where
+		H: Fn(&Blocking, FakeStream, &FakeProvider) -> Result<(), Error>,
+	{
+		serve(handler, provider, input, true)
+	}
+
+	fn serve<H>(
+		handler: H,
+		provider: &FakeProvider,
+		input: &[u8],
+		stalled: bool,
+	) -> (Vec<u8>, Result<(), Error>)
+	where
+		H: Fn(&Blocking, FakeStream, &FakeProvider) -> Result<(), Error>,
+	{
+		let output = Arc::new(Mutex::new(vec![]));
+		let stream = FakeStream {
+			input: Cursor::new(input.to_vec()),
+			output: output.clone(),
+			stalled,
+		};
+		let result = handler(&Blocking, stream, provider);
+		let output = output.lock().unwrap().clone();
+		(output, result)
+	}
+}
@@ -0,0 +1,687 @@
+// Copyright 2021 The BMW Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A SOCKS4, SOCKS4a and SOCKS5 proxy (RFC 1928 and RFC 1929) with Tor's
+//! RESOLVE and RESOLVE_PTR extensions, opening its streams through Tor.
+
+use crate::circmgr::StreamIsolation;
+use crate::client::StreamError;
+use crate::proxy::{relay, start_listener, StreamProvider, REQUEST_TIMEOUT};
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex, RwLock};
+use tor_cell::relaycell::msg::EndReason;
+use tor_rtcompat::{Runtime, SleepProvider, SleepProviderExt, SpawnBlocking};
+use tor_util::logger::Log;
+use tor_util::StopState;
+use tor_util::{Error, ErrorKind};
+
+const SOCKS4: u8 = 4;
+const SOCKS5: u8 = 5;
+
+const CMD_CONNECT: u8 = 1;
+// Tor's extensions
+const CMD_RESOLVE: u8 = 0xF0;
+const CMD_RESOLVE_PTR: u8 = 0xF1;
+
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+const METHOD_NO_AUTH: u8 = 0;
+const METHOD_USER_PASS: u8 = 2;
+const METHOD_NONE_ACCEPTABLE: u8 = 0xFF;
+// the username/password subnegotiation of RFC 1929
+const USER_PASS_VERSION: u8 = 1;
+const USER_PASS_OK: u8 = 0;
+
+// SOCKS5 reply codes. SOCKS4 only has granted or rejected.
+const REPLY_SUCCEEDED: u8 = 0;
+const REPLY_GENERAL_FAILURE: u8 = 1;
+const REPLY_NOT_ALLOWED: u8 = 2;
+const REPLY_NETWORK_UNREACHABLE: u8 = 3;
+const REPLY_HOST_UNREACHABLE: u8 = 4;
+const REPLY_CONNECTION_REFUSED: u8 = 5;
+const REPLY_TTL_EXPIRED: u8 = 6;
+const REPLY_COMMAND_NOT_SUPPORTED: u8 = 7;
+const REPLY_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 8;
+const SOCKS4_GRANTED: u8 = 0x5A;
+const SOCKS4_REJECTED: u8 = 0x5B;
+
+// the longest user id or hostname taken in a SOCKS4 request
+const SOCKS4_MAX_FIELD: usize = 255;
+
+/// What a SOCKS client asked for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SocksCmd {
+	Connect,
+	/// Look up a hostname's address
+	Resolve,
+	/// Look up an address's hostname
+	ResolvePtr,
+}
+
+/// The address in a SOCKS request, or in the answer to a lookup
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SocksAddr {
+	Hostname(String),
+	Ip(IpAddr),
+}
+
+impl fmt::Display for SocksAddr {
+	// as it goes in a BEGIN cell, with IPv6 addresses in brackets
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			SocksAddr::Hostname(name) => write!(f, "{}", name),
+			SocksAddr::Ip(IpAddr::V4(addr)) => write!(f, "{}", addr),
+			SocksAddr::Ip(IpAddr::V6(addr)) => write!(f, "[{}]", addr),
+		}
+	}
+}
+
+/// A request read from a SOCKS client
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SocksRequest {
+	pub version: u8,
+	pub cmd: SocksCmd,
+	pub addr: SocksAddr,
+	pub port: u16,
+	/// The SOCKS5 username and password, or the SOCKS4 user id
+	pub auth: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+impl SocksRequest {
+	/// Requests with different credentials never share a circuit, so
+	/// clients can use them to keep their activities apart.
+	pub fn isolation(&self) -> StreamIsolation {
+		let isolation = StreamIsolation::new();
+		match &self.auth {
+			Some((username, password)) => isolation.socks_auth(username, password),
+			None => isolation,
+		}
+	}
+
+	// the reply to this request, given as a SOCKS5 reply code and the
+	// answer to a lookup
+	fn reply(&self, code: u8, bound: Option<&SocksAddr>) -> Vec<u8> {
+		if self.version == SOCKS5 {
+			return socks5_reply(code, bound);
+		}
+		let (status, addr) = match (code, bound) {
+			(REPLY_SUCCEEDED, Some(SocksAddr::Ip(IpAddr::V4(addr)))) => (SOCKS4_GRANTED, *addr),
+			(REPLY_SUCCEEDED, _) => (SOCKS4_GRANTED, Ipv4Addr::UNSPECIFIED),
+			_ => (SOCKS4_REJECTED, Ipv4Addr::UNSPECIFIED),
+		};
+		let mut reply = vec![0, status, 0, 0];
+		reply.extend_from_slice(&addr.octets());
+		reply
+	}
+}
+
+fn socks5_reply(code: u8, bound: Option<&SocksAddr>) -> Vec<u8> {
+	let mut reply = vec![SOCKS5, code, 0];
+	match bound {
+		Some(SocksAddr::Ip(IpAddr::V4(addr))) => {
+			reply.push(ATYP_IPV4);
+			reply.extend_from_slice(&addr.octets());
+		}
+		Some(SocksAddr::Ip(IpAddr::V6(addr))) => {
+			reply.push(ATYP_IPV6);
+			reply.extend_from_slice(&addr.octets());
+		}
+		Some(SocksAddr::Hostname(name)) => {
+			reply.push(ATYP_DOMAIN);
+			reply.push(name.len() as u8);
+			reply.extend_from_slice(name.as_bytes());
+		}
+		None => {
+			reply.push(ATYP_IPV4);
+			reply.extend_from_slice(&Ipv4Addr::UNSPECIFIED.octets());
+		}
+	}
+	// no port to speak of
+	reply.extend_from_slice(&[0, 0]);
+	reply
+}
+
+/// The SOCKS5 reply code for a stream or lookup that failed with `error`
+fn reply_code(error: &StreamError) -> u8 {
+	match error.end_reason() {
+		Some(EndReason::RESOLVEFAILED) => REPLY_HOST_UNREACHABLE,
+		Some(EndReason::CONNECTREFUSED) | Some(EndReason::CONNRESET) => REPLY_CONNECTION_REFUSED,
+		Some(EndReason::EXITPOLICY) => REPLY_NOT_ALLOWED,
+		Some(EndReason::TIMEOUT) => REPLY_TTL_EXPIRED,
+		Some(EndReason::NOROUTE) => REPLY_NETWORK_UNREACHABLE,
+		Some(_) => REPLY_GENERAL_FAILURE,
+		None => match error {
+			StreamError::NotFound(_) | StreamError::Proto(tor_proto::Error::ResolveFailed(_)) => {
+				REPLY_HOST_UNREACHABLE
+			}
+			_ => REPLY_GENERAL_FAILURE,
+		},
+	}
+}
+
+fn socks_error(msg: String) -> Error {
+	ErrorKind::ProxyError(msg).into()
+}
+
+async fn read_u8<S: AsyncRead + Unpin>(stream: &mut S) -> Result<u8, Error> {
+	let mut buf = [0; 1];
+	stream.read_exact(&mut buf).await?;
+	Ok(buf[0])
+}
+
+async fn read_bytes<S: AsyncRead + Unpin>(stream: &mut S, len: usize) -> Result<Vec<u8>, Error> {
+	let mut buf = vec![0; len];
+	stream.read_exact(&mut buf).await?;
+	Ok(buf)
+}
+
+async fn read_port<S: AsyncRead + Unpin>(stream: &mut S) -> Result<u16, Error> {
+	let mut buf = [0; 2];
+	stream.read_exact(&mut buf).await?;
+	Ok(u16::from_be_bytes(buf))
+}
+
+async fn read_ipv4<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Ipv4Addr, Error> {
+	let mut octets = [0; 4];
+	stream.read_exact(&mut octets).await?;
+	Ok(Ipv4Addr::from(octets))
+}
+
+async fn read_ipv6<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Ipv6Addr, Error> {
+	let mut octets = [0; 16];
+	stream.read_exact(&mut octets).await?;
+	Ok(Ipv6Addr::from(octets))
+}
+
+// the SOCKS4 user id and hostname end with a NUL
+async fn read_nul_terminated<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Vec<u8>, Error> {
+	let mut field = vec![];
+	loop {
+		match read_u8(stream).await? {
+			0 => return Ok(field),
+			_ if field.len() == SOCKS4_MAX_FIELD => {
+				return Err(socks_error("SOCKS4 request field too long".to_string()))
+			}
+			byte => field.push(byte),
+		}
+	}
+}
+
+/// Read a request from a SOCKS client, answering the SOCKS5 method
+/// negotiation on the way. Requests we can't serve are refused here.
+pub async fn read_request<S>(stream: &mut S) -> Result<SocksRequest, Error>
+where
+	S: AsyncRead + AsyncWrite + Unpin,
+{
+	match read_u8(stream).await? {
+		SOCKS4 => read_socks4(stream).await,
+		SOCKS5 => read_socks5(stream).await,
+		version => Err(socks_error(format!("unknown SOCKS version {}", version))),
+	}
+}
+
+async fn read_socks4<S>(stream: &mut S) -> Result<SocksRequest, Error>
+where
+	S: AsyncRead + AsyncWrite + Unpin,
+{
+	let cmd = read_u8(stream).await?;
+	let port = read_port(stream).await?;
+	let ip = read_ipv4(stream).await?;
+	let user_id = read_nul_terminated(stream).await?;
+	// SOCKS4a: an address of 0.0.0.x, x not 0, means a hostname follows
+	let octets = ip.octets();
+	let addr = if octets[..3] == [0, 0, 0] && octets[3] != 0 {
+		let name = read_nul_terminated(stream).await?;
+		match String::from_utf8(name) {
+			Ok(name) => Some(SocksAddr::Hostname(name)),
+			Err(_) => None,
+		}
+	} else {
+		Some(SocksAddr::Ip(IpAddr::V4(ip)))
+	};
+	let cmd = match cmd {
+		CMD_CONNECT => Some(SocksCmd::Connect),
+		CMD_RESOLVE => Some(SocksCmd::Resolve),
+		_ => None,
+	};
+
+	match (cmd, addr) {
+		(Some(cmd), Some(addr)) => Ok(SocksRequest {
+			version: SOCKS4,
+			cmd,
+			addr,
+			port,
+			auth: if user_id.is_empty() {
+				None
+			} else {
+				Some((user_id, vec![]))
+			},
+		}),
+		(cmd, _) => {
+			stream
+				.write_all(&[0, SOCKS4_REJECTED, 0, 0, 0, 0, 0, 0])
+				.await?;
+			Err(socks_error(match cmd {
+				None => "unsupported SOCKS4 command".to_string(),
+				Some(_) => "SOCKS4a hostname is not UTF-8".to_string(),
+			}))
+		}
+	}
+}
+
+async fn read_socks5<S>(stream: &mut S) -> Result<SocksRequest, Error>
+where
+	S: AsyncRead + AsyncWrite + Unpin,
+{
+	let n_methods = read_u8(stream).await?;
+	let methods = read_bytes(stream, n_methods as usize).await?;
+	// credentials are taken whenever they are offered, for isolation. They
+	// aren't checked.
+	let method = if methods.contains(&METHOD_USER_PASS) {
+		METHOD_USER_PASS
+	} else if methods.contains(&METHOD_NO_AUTH) {
+		METHOD_NO_AUTH
+	} else {
+		METHOD_NONE_ACCEPTABLE
+	};
+	stream.write_all(&[SOCKS5, method]).await?;
+	let auth = match method {
+		METHOD_USER_PASS => Some(read_user_pass(stream).await?),
+		METHOD_NO_AUTH => None,
+		_ => {
+			return Err(socks_error(
+				"no acceptable SOCKS5 authentication method".to_string(),
+			))
+		}
+	};
+
+	let mut header = [0; 4];
+	stream.read_exact(&mut header).await?;
+	let [version, cmd, _, atyp] = header;
+	if version != SOCKS5 {
+		return Err(socks_error(format!("unknown SOCKS version {}", version)));
+	}
+	let addr = match atyp {
+		ATYP_IPV4 => Some(SocksAddr::Ip(IpAddr::V4(read_ipv4(stream).await?))),
+		ATYP_IPV6 => Some(SocksAddr::Ip(IpAddr::V6(read_ipv6(stream).await?))),
+		ATYP_DOMAIN => {
+			let len = read_u8(stream).await?;
+			String::from_utf8(read_bytes(stream, len as usize).await?)
+				.ok()
+				.map(SocksAddr::Hostname)
+		}
+		_ => {
+			// we can't tell where the request ends
+			stream
+				.write_all(&socks5_reply(REPLY_ADDRESS_TYPE_NOT_SUPPORTED, None))
+				.await?;
+			return Err(socks_error(format!("unknown SOCKS5 address type {}", atyp)));
+		}
+	};
+	let port = read_port(stream).await?;
+
+	let (code, msg) = match (cmd, addr) {
+		(CMD_CONNECT, Some(addr)) | (CMD_RESOLVE, Some(addr)) => {
+			return Ok(SocksRequest {
+				version: SOCKS5,
+				cmd: if cmd == CMD_CONNECT {
+					SocksCmd::Connect
+				} else {
+					SocksCmd::Resolve
+				},
+				addr,
+				port,
+				auth,
+			})
+		}
+		(CMD_RESOLVE_PTR, Some(SocksAddr::Ip(addr))) => {
+			return Ok(SocksRequest {
+				version: SOCKS5,
+				cmd: SocksCmd::ResolvePtr,
+				addr: SocksAddr::Ip(addr),
+				port,
+				auth,
+			})
+		}
+		(CMD_RESOLVE_PTR, _) => (
+			REPLY_ADDRESS_TYPE_NOT_SUPPORTED,
+			"SOCKS5 RESOLVE_PTR needs an address".to_string(),
+		),
+		(CMD_CONNECT, None) | (CMD_RESOLVE, None) => (
+			REPLY_GENERAL_FAILURE,
+			"SOCKS5 hostname is not UTF-8".to_string(),
+		),
+		(cmd, _) => (
+			REPLY_COMMAND_NOT_SUPPORTED,
+			format!("unsupported SOCKS5 command {}", cmd),
+		),
+	};
+	stream.write_all(&socks5_reply(code, None)).await?;
+	Err(socks_error(msg))
+}
+
+async fn read_user_pass<S>(stream: &mut S) -> Result<(Vec<u8>, Vec<u8>), Error>
+where
+	S: AsyncRead + AsyncWrite + Unpin,
+{
+	let version = read_u8(stream).await?;
+	if version != USER_PASS_VERSION {
+		return Err(socks_error(format!(
+			"unknown SOCKS5 username/password version {}",
+			version
+		)));
+	}
+	let len = read_u8(stream).await?;
+	let username = read_bytes(stream, len as usize).await?;
+	let len = read_u8(stream).await?;
+	let password = read_bytes(stream, len as usize).await?;
+	stream.write_all(&[USER_PASS_VERSION, USER_PASS_OK]).await?;
+	Ok((username, password))
+}
+
+// carry out a RESOLVE or RESOLVE_PTR, and pick the answer to send back
+fn lookup<P: StreamProvider>(
+	provider: &P,
+	request: &SocksRequest,
+	isolation: &StreamIsolation,
+) -> Result<SocksAddr, StreamError> {
+	match &request.addr {
+		SocksAddr::Ip(addr) if request.cmd == SocksCmd::ResolvePtr => provider
+			.resolve_addr(*addr, isolation)?
+			.into_iter()
+			.find(|name| name.len() <= u8::MAX as usize)
+			.map(SocksAddr::Hostname)
+			.ok_or_else(|| StreamError::NotFound(addr.to_string())),
+		// nothing to look up
+		SocksAddr::Ip(addr) => Ok(SocksAddr::Ip(*addr)),
+		SocksAddr::Hostname(host) => provider
+			.resolve_host(host, isolation)?
+			.into_iter()
+			// SOCKS4 can only carry an IPv4 address back
+			.find(|addr| request.version == SOCKS5 || addr.is_ipv4())
+			.map(SocksAddr::Ip)
+			.ok_or_else(|| StreamError::NotFound(host.clone())),
+	}
+}
+
+/// Serve one SOCKS connection: read the request, carry it out through
+/// `provider`, reply, and for a CONNECT relay data until both sides are
+/// done. Talking to the client is run on `blocker`; the provider's calls
+/// block on their own. A client that hasn't sent its request within
+/// `REQUEST_TIMEOUT` is dropped.
+pub fn handle_connection<B, S, P>(blocker: &B, mut stream: S, provider: &P) -> Result<(), Error>
+where
+	B: SpawnBlocking + SleepProvider,
+	S: AsyncRead + AsyncWrite + Unpin,
+	P: StreamProvider,
+{
+	let request =
+		match blocker.block_on(blocker.timeout(REQUEST_TIMEOUT, read_request(&mut stream))) {
+			Ok(request) => request?,
+			Err(_) => return Err(socks_error("SOCKS request not sent in time".to_string())),
+		};
+	let isolation = request.isolation();
+	if request.cmd != SocksCmd::Connect {
+		let answer = lookup(provider, &request, &isolation);
+		let reply = match &answer {
+			Ok(bound) => request.reply(REPLY_SUCCEEDED, Some(bound)),
+			Err(e) => request.reply(reply_code(e), None),
+		};
+		blocker.block_on(stream.write_all(&reply))?;
+		return answer.map(|_| ()).map_err(Error::from);
+	}
+
+	let exit = match provider.open_stream(&request.addr.to_string(), request.port, &isolation) {
+		Ok(exit) => exit,
+		Err(e) => {
+			blocker.block_on(stream.write_all(&request.reply(reply_code(&e), None)))?;
+			return Err(e.into());
+		}
+	};
+	blocker.block_on(async {
+		stream
+			.write_all(&request.reply(REPLY_SUCCEEDED, None))
+			.await?;
+		relay(stream, exit).await?;
+		Ok(())
+	})
+}
+
+/// Listen for SOCKS connections on `addr` until `stop_state` is stopped,
+/// opening their streams through `provider`. Returns the address listened
+/// on.
+pub fn start_socks_listener<R: Runtime, P: StreamProvider>(
+	runtime: R,
+	addr: SocketAddr,
+	provider: Arc<P>,
+	mainlog: &'static Arc<Mutex<Log>>,
+	stop_state: Arc<RwLock<StopState>>,
+) -> Result<SocketAddr, Error> {
+	start_listener(
+		runtime,
+		addr,
+		provider,
+		handle_connection::<R, R::TcpStream, P>,
+		"SOCKS",
+		mainlog,
+		stop_state,
+	)
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::proxy;
+	use crate::proxy::test::{end, FakeProvider};
+
+	fn run(provider: &FakeProvider, input: &[u8]) -> (Vec<u8>, Result<(), Error>) {
+		proxy::test::run(handle_connection, provider, input)
+	}
+
+	fn concat(parts: &[&[u8]]) -> Vec<u8> {
+		parts.concat()
+	}
+
+	const SOCKS5_OK: &[u8] = &[5, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+
+	#[test]
+	fn socks5_connect() {
+		let provider = FakeProvider::default();
+		let input = concat(&[
+			&[5, 1, 0],
+			&[5, 1, 0, 3, 11],
+			b"example.com",
+			&[0, 80],
+			b"GET /",
+		]);
+		let (output, result) = run(&provider, &input);
+		assert!(result.is_ok());
+		assert_eq!(output, concat(&[&[5, 0], SOCKS5_OK, b"hello"]));
+		assert_eq!(&provider.uploaded.lock().unwrap()[..], b"GET /");
+		let opened = provider.opened.lock().unwrap();
+		assert_eq!((&opened[0].0[..], opened[0].1), ("example.com", 80));
+		assert!(opened[0].2.compatible(&StreamIsolation::new()));
+
+		let provider = FakeProvider::default();
+		let mut input = vec![5, 1, 0, 5, 1, 0, 4];
+		input.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+		input.extend_from_slice(&[1, 187]);
+		let (output, _) = run(&provider, &input);
+		assert_eq!(output, concat(&[&[5, 0], SOCKS5_OK, b"hello"]));
+		let opened = provider.opened.lock().unwrap();
+		assert_eq!((&opened[0].0[..], opened[0].1), ("[2001:db8::1]", 443));
+	}
+
+	#[test]
+	fn socks5_auth() {
+		// username and password are preferred, and isolate the stream
+		let provider = FakeProvider::default();
+		let input = concat(&[
+			&[5, 2, 0, 2],
+			&[1, 4],
+			b"user",
+			&[4],
+			b"pass",
+			&[5, 1, 0, 1, 192, 0, 2, 1, 0, 80],
+		]);
+		let (output, result) = run(&provider, &input);
+		assert!(result.is_ok());
+		assert_eq!(output, concat(&[&[5, 2], &[1, 0], SOCKS5_OK, b"hello"]));
+		let opened = provider.opened.lock().unwrap();
+		assert_eq!(&opened[0].0[..], "192.0.2.1");
+		let isolation = &opened[0].2;
+		assert!(isolation.compatible(&StreamIsolation::new().socks_auth(b"user", b"pass")));
+		assert!(!isolation.compatible(&StreamIsolation::new()));
+
+		// a client that can't do either is turned away
+		let (output, result) = run(&provider, &[5, 1, 1]);
+		assert!(result.is_err());
+		assert_eq!(output, vec![5, 0xFF]);
+	}
+
+	#[test]
+	fn socks4() {
+		let provider = FakeProvider::default();
+		let input = concat(&[&[4, 1, 0, 80, 192, 0, 2, 1], b"alice\0", b"GET"]);
+		let (output, result) = run(&provider, &input);
+		assert!(result.is_ok());
+		assert_eq!(output, concat(&[&[0, 0x5A, 0, 0, 0, 0, 0, 0], b"hello"]));
+		let opened = provider.opened.lock().unwrap();
+		assert_eq!((&opened[0].0[..], opened[0].1), ("192.0.2.1", 80));
+		assert!(opened[0]
+			.2
+			.compatible(&StreamIsolation::new().socks_auth(b"alice", b"")));
+		drop(opened);
+
+		// SOCKS4a
+		let provider = FakeProvider::default();
+		let input = concat(&[&[4, 1, 1, 187, 0, 0, 0, 1], b"\0", b"example.com\0"]);
+		let (output, result) = run(&provider, &input);
+		assert!(result.is_ok());
+		assert_eq!(output, concat(&[&[0, 0x5A, 0, 0, 0, 0, 0, 0], b"hello"]));
+		let opened = provider.opened.lock().unwrap();
+		assert_eq!((&opened[0].0[..], opened[0].1), ("example.com", 443));
+		assert!(opened[0].2.compatible(&StreamIsolation::new()));
+	}
+
+	#[test]
+	fn refused() {
+		let provider = FakeProvider::default();
+		let request = |host: &str| {
+			concat(&[
+				&[5, 1, 0],
+				&[5, 1, 0, 3, host.len() as u8],
+				host.as_bytes(),
+				&[0, 80],
+			])
+		};
+		let (output, result) = run(&provider, &request("refused.example"));
+		assert!(result.is_err());
+		assert_eq!(output, vec![5, 0, 5, 5, 0, 1, 0, 0, 0, 0, 0, 0]);
+		let (output, _) = run(&provider, &request("policy.example"));
+		assert_eq!(output, vec![5, 0, 5, 2, 0, 1, 0, 0, 0, 0, 0, 0]);
+
+		let input = concat(&[&[4, 1, 0, 80, 0, 0, 0, 1], b"\0", b"refused.example\0"]);
+		let (output, result) = run(&provider, &input);
+		assert!(result.is_err());
+		assert_eq!(output, vec![0, 0x5B, 0, 0, 0, 0, 0, 0]);
+	}
+
+	#[test]
+	fn resolve() {
+		let provider = FakeProvider::default();
+		let input = concat(&[&[5, 1, 0], &[5, 0xF0, 0, 3, 11], b"example.com", &[0, 0]]);
+		let (output, result) = run(&provider, &input);
+		assert!(result.is_ok());
+		let mut expected = vec![5, 0, 5, 0, 0, 4];
+		expected.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+		expected.extend_from_slice(&[0, 0]);
+		assert_eq!(output, expected);
+
+		// SOCKS4a gets the IPv4 address
+		let input = concat(&[&[4, 0xF0, 0, 0, 0, 0, 0, 1], b"\0", b"example.com\0"]);
+		let (output, _) = run(&provider, &input);
+		assert_eq!(output, vec![0, 0x5A, 0, 0, 192, 0, 2, 1]);
+
+		let input = [5, 1, 0, 5, 0xF1, 0, 1, 192, 0, 2, 1, 0, 0];
+		let (output, result) = run(&provider, &input);
+		assert!(result.is_ok());
+		assert_eq!(
+			output,
+			concat(&[&[5, 0, 5, 0, 0, 3, 11], b"example.com", &[0, 0]])
+		);
+
+		let input = concat(&[&[5, 1, 0], &[5, 0xF0, 0, 3, 7], b"nowhere", &[0, 0]]);
+		let (output, result) = run(&provider, &input);
+		assert!(result.is_err());
+		assert_eq!(output, vec![5, 0, 5, 4, 0, 1, 0, 0, 0, 0, 0, 0]);
+		assert!(provider.opened.lock().unwrap().is_empty());
+	}
+
+	#[test]
+	fn unsupported() {
+		let provider = FakeProvider::default();
+		// BIND
+		let (output, result) = run(&provider, &[5, 1, 0, 5, 2, 0, 1, 192, 0, 2, 1, 0, 80]);
+		assert!(result.is_err());
+		assert_eq!(output, vec![5, 0, 5, 7, 0, 1, 0, 0, 0, 0, 0, 0]);
+		// unknown address type
+		let (output, _) = run(&provider, &[5, 1, 0, 5, 1, 0, 9]);
+		assert_eq!(output, vec![5, 0, 5, 8, 0, 1, 0, 0, 0, 0, 0, 0]);
+		// RESOLVE_PTR of a hostname
+		let input = concat(&[&[5, 1, 0], &[5, 0xF1, 0, 3, 11], b"example.com", &[0, 0]]);
+		let (output, _) = run(&provider, &input);
+		assert_eq!(output, vec![5, 0, 5, 8, 0, 1, 0, 0, 0, 0, 0, 0]);
+		// SOCKS4 BIND
+		let (output, _) = run(&provider, &[4, 2, 0, 80, 192, 0, 2, 1, 0]);
+		assert_eq!(output, vec![0, 0x5B, 0, 0, 0, 0, 0, 0]);
+		// not SOCKS at all
+		let (output, result) = run(&provider, b"GET / HTTP/1.1\r\n\r\n");
+		assert!(result.is_err());
+		assert!(output.is_empty());
+		assert!(provider.opened.lock().unwrap().is_empty());
+	}
+
+	#[test]
+	fn request_timeout() {
+		// a greeting, and then nothing
+		let provider = FakeProvider::default();
+		let (output, result) = proxy::test::run_stalled(handle_connection, &provider, &[5, 1, 0]);
+		assert!(result.is_err());
+		assert_eq!(output, [5, 0]);
+		assert!(provider.opened.lock().unwrap().is_empty());
+	}
+
+	#[test]
+	fn reply_codes() {
+		assert_eq!(reply_code(&end(EndReason::RESOLVEFAILED)), 4);
+		assert_eq!(reply_code(&end(EndReason::CONNECTREFUSED)), 5);
+		assert_eq!(reply_code(&end(EndReason::EXITPOLICY)), 2);
+		assert_eq!(reply_code(&end(EndReason::TIMEOUT)), 6);
+		assert_eq!(reply_code(&end(EndReason::NOROUTE)), 3);
+		assert_eq!(reply_code(&end(EndReason::DESTROY)), 1);
+		assert_eq!(
+			reply_code(&StreamError::NotFound("example.com".to_string())),
+			4
+		);
+		let failed = tor_proto::Error::ResolveFailed(tor_proto::ResolveError::Transient);
+		assert_eq!(reply_code(&StreamError::Proto(failed)), 4);
+		let e = ErrorKind::CircuitError("no usable guard".to_string()).into();
+		assert_eq!(reply_code(&StreamError::NoCircuit(e)), 1);
+	}
+}
@@ -0,0 +1,588 @@
+// Copyright 2021 The BMW Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+use tor_netdoc::consensus::Consensus;
+use tor_util::core::ser::{self, Readable, Reader, Writeable, Writer};
+
+// build times are kept for this many circuits (path-spec 2.4.1)
+const NCIRCUITS_TO_OBSERVE: usize = 1000;
+// width of the histogram bins, in milliseconds
+const BIN_WIDTH: u32 = 10;
+
+/// The circuit build timeout parameters, from the consensus (path-spec
+/// 2.4.5)
+#[derive(Debug, Clone)]
+pub struct CbtParams {
+	/// don't learn, always use `initial_timeout`
+	pub disabled: bool,
+	/// how many builds to see before the timeout is learned
+	pub min_circs: usize,
+	/// how many of the most common histogram bins give Xm
+	pub num_modes: usize,
+	/// the quantile of the fitted distribution used as the timeout, in
+	/// percent
+	pub quantile: u32,
+	/// the quantile after which a circuit is given up, in percent
+	pub close_quantile: u32,
+	/// how many recent circuits to look at for network changes
+	pub recent_count: usize,
+	/// how many of those may time out before the history is thrown away
+	pub max_timeouts: usize,
+	pub initial_timeout: Duration,
+	pub min_timeout: Duration,
+}
+
+impl Default for CbtParams {
+	fn default() -> CbtParams {
+		CbtParams {
+			disabled: false,
+			min_circs: 100,
+			num_modes: 10,
+			quantile: 80,
+			close_quantile: 99,
+			recent_count: 20,
+			max_timeouts: 18,
+			initial_timeout: Duration::from_millis(60_000),
+			min_timeout: Duration::from_millis(10),
+		}
+	}
+}
+
+impl CbtParams {
+	/// Read the parameters from a consensus, keeping each within the range
+	/// the spec allows.
+	pub fn from_consensus(consensus: &Consensus) -> CbtParams {
+		let params = &consensus.header.params;
+		let param = |name: &str, default: usize, min: usize, max: usize| -> usize {
+			match params.get(name) {
+				Some(value) if value >= 0 => (value as usize).clamp(min, max),
+				_ => default,
+			}
+		};
+		let quantile = param("cbtquantile", 80, 10, 99) as u32;
+		let recent_count = param("cbtrecentcount", 20, 3, 1000);
+		let min_timeout = param("cbtmintimeout", 10, 10, i32::MAX as usize);
+		CbtParams {
+			disabled: param("cbtdisabled", 0, 0, 1) == 1,
+			min_circs: param("cbtmincircs", 100, 1, 10_000),
+			num_modes: param("cbtnummodes", 10, 1, 20),
+			quantile,
+			close_quantile: param("cbtclosequantile", 99, quantile as usize, 99) as u32,
+			recent_count,
+			max_timeouts: param("cbtmaxtimeouts", 18, 3, recent_count),
+			initial_timeout: Duration::from_millis(param(
+				"cbtinitialtimeout",
+				60_000,
+				min_timeout,
+				i32::MAX as usize,
+			) as u64),
+			min_timeout: Duration::from_millis(min_timeout as u64),
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+	ms: u32,
+	/// false if the circuit was given up at `ms`
+	completed: bool,
+}
+
+/// Learns how long circuits take to build and from that how long to wait
+/// for one, by fitting a Pareto distribution to the build times (path-spec
+/// 2.4). Circuits that take longer than `timeout` shouldn't be used, but
+/// are still waited for until `close_timeout` so that slow builds are
+/// measured too. All times are passed in, so the caller owns the clock.
+#[derive(Debug, Clone)]
+pub struct TimeoutEstimator {
+	params: CbtParams,
+	samples: VecDeque<Sample>,
+	/// whether each of the latest circuits timed out, newest last
+	recent: VecDeque<bool>,
+	/// the last time we heard from the network
+	last_live: Option<Instant>,
+	timeout: Duration,
+	close_timeout: Duration,
+}
+
+impl Default for TimeoutEstimator {
+	fn default() -> TimeoutEstimator {
+		TimeoutEstimator::new(CbtParams::default())
+	}
+}
+
+impl TimeoutEstimator {
+	pub fn new(params: CbtParams) -> TimeoutEstimator {
+		let mut estimator = TimeoutEstimator {
+			timeout: params.initial_timeout,
+			close_timeout: params.initial_timeout,
+			params,
+			samples: VecDeque::new(),
+			recent: VecDeque::new(),
+			last_live: None,
+		};
+		estimator.recompute();
+		estimator
+	}
+
+	/// Use new parameters, normally from a new consensus.
+	pub fn set_params(&mut self, params: CbtParams) {
+		self.params = params;
+		while self.recent.len() > self.params.recent_count {
+			self.recent.pop_front();
+		}
+		self.recompute();
+	}
+
+	/// How long to wait for a circuit before building another one
+	pub fn timeout(&self) -> Duration {
+		self.timeout
+	}
+
+	/// How long to keep building a circuit before giving up on it
+	pub fn close_timeout(&self) -> Duration {
+		self.close_timeout
+	}
+
+	/// The number of build times the estimate is based on
+	pub fn build_times(&self) -> usize {
+		self.samples
+			.iter()
+			.filter(|sample| sample.completed)
+			.count()
+	}
+
+	/// Record that we heard from the network, e.g. that a hop completed.
+	pub fn note_network_live(&mut self, now: Instant) {
+		self.last_live = Some(now);
+	}
+
+	// we only learn from timeouts while the network is up
+	fn network_live_since(&self, started: Instant) -> bool {
+		self.last_live.map(|live| live >= started).unwrap_or(false)
+	}
+
+	/// Record a circuit that was built in `elapsed`.
+	pub fn note_circ_built(&mut self, elapsed: Duration) {
+		let timed_out = elapsed > self.timeout;
+		self.add_sample(Sample {
+			ms: millis(elapsed),
+			completed: true,
+		});
+		self.note_recent(timed_out);
+		self.recompute();
+	}
+
+	/// Record a circuit that we gave up on after `elapsed`. If we haven't
+	/// heard from the network since it was `started`, the network was down
+	/// and the timeout says nothing about circuits, so it is ignored.
+	pub fn note_circ_abandoned(&mut self, elapsed: Duration, started: Instant) {
+		if !self.network_live_since(started) {
+			return;
+		}
+		self.add_sample(Sample {
+			ms: millis(elapsed),
+			completed: false,
+		});
+		self.note_recent(true);
+		self.recompute();
+	}
+
+	fn add_sample(&mut self, sample: Sample) {
+		if self.params.disabled {
+			return;
+		}
+		if self.samples.len() >= NCIRCUITS_TO_OBSERVE {
+			self.samples.pop_front();
+		}
+		self.samples.push_back(sample);
+	}
+
+	// When nearly all recent circuits time out, the network has probably
+	// changed under us: throw the history away and learn again from the
+	// initial timeout (path-spec 2.4.2).
+	fn note_recent(&mut self, timed_out: bool) {
+		if self.recent.len() >= self.params.recent_count {
+			self.recent.pop_front();
+		}
+		self.recent.push_back(timed_out);
+		let timeouts = self.recent.iter().filter(|t| **t).count();
+		if timeouts > self.params.max_timeouts {
+			self.samples.clear();
+			self.recent.clear();
+		}
+	}
+
+	fn recompute(&mut self) {
+		let initial = self.params.initial_timeout;
+		let (timeout, close_timeout) =
+			if self.params.disabled || self.build_times() < self.params.min_circs {
+				(initial, initial)
+			} else {
+				match self.fit() {
+					Some((xm, alpha)) => (
+						pareto_quantile(xm, alpha, self.params.quantile),
+						pareto_quantile(xm, alpha, self.params.close_quantile),
+					),
+					None => (initial, initial),
+				}
+			};
+		self.timeout = timeout.max(self.params.min_timeout);
+		self.close_timeout = close_timeout.max(self.timeout);
+	}
+
+	// Fit a Pareto distribution to the samples (path-spec 2.4.3): Xm is
+	// the mean of the most common histogram bins, and alpha the maximum
+	// likelihood estimate for it, counting abandoned circuits as right
+	// censored. Returns None if there is nothing to fit. An infinite alpha
+	// means every build took Xm or less.
+	fn fit(&self) -> Option<(f64, f64)> {
+		let mut bins: HashMap<u32, u32> = HashMap::new();
+		for sample in self.samples.iter().filter(|sample| sample.completed) {
+			*bins.entry(sample.ms / BIN_WIDTH).or_insert(0) += 1;
+		}
+		let mut bins: Vec<(u32, u32)> = bins.into_iter().collect();
+		// most common first, ties to the faster bin
+		bins.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+		let modes = &bins[..bins.len().min(self.params.num_modes)];
+		let count: u32 = modes.iter().map(|(_, count)| count).sum();
+		if count == 0 {
+			return None;
+		}
+		let xm = modes
+			.iter()
+			.map(|(bin, count)| (bin * BIN_WIDTH + BIN_WIDTH / 2) as f64 * *count as f64)
+			.sum::<f64>()
+			/ count as f64;
+
+		// every sample adds to the sum, but only completed builds count as
+		// observations
+		let completed = self.build_times() as f64;
+		let sum: f64 = self
+			.samples
+			.iter()
+			.map(|sample| ((sample.ms as f64).max(xm) / xm).ln())
+			.sum();
+		if sum <= 0.0 {
+			return Some((xm, f64::INFINITY));
+		}
+		Some((xm, completed / sum))
+	}
+}
+
+fn millis(duration: Duration) -> u32 {
+	duration.as_millis().min(u32::MAX as u128) as u32
+}
+
+// the value below which `quantile` percent of the distribution lies
+fn pareto_quantile(xm: f64, alpha: f64, quantile: u32) -> Duration {
+	let q = quantile as f64 / 100.0;
+	let ms = xm / (1.0 - q).powf(1.0 / alpha);
+	Duration::from_millis(ms.min(u32::MAX as f64) as u64)
+}
+
+// The history is stored as a histogram, like Tor's state file. Loading it
+// gives each build the middle of its bin. The bins are interleaved so that
+// the oldest samples dropped later aren't all from the same bin.
+impl Writeable for TimeoutEstimator {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), ser::Error> {
+		let mut bins: HashMap<u32, (u32, u32)> = HashMap::new();
+		for sample in &self.samples {
+			let bin = bins.entry(sample.ms / BIN_WIDTH).or_insert((0, 0));
+			if sample.completed {
+				bin.0 += 1;
+			} else {
+				bin.1 += 1;
+			}
+		}
+		let mut bins: Vec<(u32, (u32, u32))> = bins.into_iter().collect();
+		bins.sort_unstable();
+		writer.write_u32(bins.len() as u32)?;
+		for (bin, (completed, abandoned)) in bins {
+			writer.write_u32(bin)?;
+			writer.write_u32(completed)?;
+			writer.write_u32(abandoned)?;
+		}
+		Ok(())
+	}
+}
+
+impl Readable for TimeoutEstimator {
+	fn read<R: Reader>(reader: &mut R) -> Result<Self, ser::Error> {
+		let count = reader.read_u32()?;
+		let mut bins = vec![];
+		for _ in 0..count {
+			let bin = reader.read_u32()?;
+			// no more than fit in the history, so that a corrupt count
+			// can't keep us here
+			let completed = reader.read_u32()?.min(NCIRCUITS_TO_OBSERVE as u32);
+			let abandoned = reader.read_u32()?.min(NCIRCUITS_TO_OBSERVE as u32);
+			if bin > u32::MAX / BIN_WIDTH - 1 {
+				return Err(ser::Error::CorruptedData);
+			}
+			bins.push((bin * BIN_WIDTH + BIN_WIDTH / 2, completed, abandoned));
+		}
+
+		let mut samples = VecDeque::new();
+		let mut left = true;
+		while left && samples.len() < NCIRCUITS_TO_OBSERVE {
+			left = false;
+			for (ms, completed, abandoned) in &mut bins {
+				if samples.len() >= NCIRCUITS_TO_OBSERVE {
+					break;
+				}
+				let sample = if *completed > 0 {
+					*completed -= 1;
+					Sample {
+						ms: *ms,
+						completed: true,
+					}
+				} else if *abandoned > 0 {
+					*abandoned -= 1;
+					Sample {
+						ms: *ms,
+						completed: false,
+					}
+				} else {
+					continue;
+				};
+				left = true;
+				samples.push_back(sample);
+			}
+		}
+
+		let mut estimator = TimeoutEstimator {
+			samples,
+			..Default::default()
+		};
+		estimator.recompute();
+		Ok(estimator)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	// the mocked clock
+	fn at(start: Instant, ms: u64) -> Instant {
+		start + Duration::from_millis(ms)
+	}
+
+	// build times spread like a Pareto distribution with the given xm and
+	// alpha, in a mixed up order
+	fn pareto_times(xm: f64, alpha: f64, n: usize) -> Vec<Duration> {
+		(0..n)
+			.map(|i| {
+				let u = ((i * 7919) % n) as f64 / n as f64;
+				Duration::from_millis((xm / (1.0 - u).powf(1.0 / alpha)) as u64)
+			})
+			.collect()
+	}
+
+	#[test]
+	fn initial() {
+		let mut estimator = TimeoutEstimator::default();
+		assert_eq!(estimator.timeout(), Duration::from_secs(60));
+		assert_eq!(estimator.close_timeout(), Duration::from_secs(60));
+		// too few builds to learn from
+		for time in pareto_times(1000.0, 2.0, 99) {
+			estimator.note_circ_built(time);
+		}
+		assert_eq!(estimator.timeout(), Duration::from_secs(60));
+		estimator.note_circ_built(Duration::from_millis(1200));
+		assert!(estimator.timeout() < Duration::from_secs(10));
+	}
+
+	#[test]
+	fn pareto_fit() {
+		let mut estimator = TimeoutEstimator::default();
+		for time in pareto_times(1000.0, 2.0, 1000) {
+			estimator.note_circ_built(time);
+		}
+		// the 80% quantile is 1000 / 0.2^(1/2), about 2236ms, and the 99%
+		// one 10000ms. Xm comes from the histogram, so allow some slack.
+		let timeout = estimator.timeout().as_millis() as f64;
+		assert!((timeout - 2236.0).abs() < 2236.0 * 0.15, "{}", timeout);
+		let close = estimator.close_timeout().as_millis() as f64;
+		assert!((close - 10000.0).abs() < 10000.0 * 0.25, "{}", close);
+
+		// only the last 1000 builds count
+		for _ in 0..1000 {
+			estimator.note_circ_built(Duration::from_millis(500));
+		}
+		assert_eq!(estimator.build_times(), 1000);
+		assert_eq!(estimator.timeout(), Duration::from_millis(505));
+	}
+
+	#[test]
+	fn censored_fit() {
+		let sample = |ms, completed| Sample { ms, completed };
+		let mut estimator = TimeoutEstimator::new(CbtParams {
+			num_modes: 1,
+			..Default::default()
+		});
+		estimator.samples = vec![
+			sample(1005, true),
+			sample(1005, true),
+			sample(1005, true),
+			sample(1005, true),
+			sample(2005, true),
+			sample(2005, true),
+			sample(4005, false),
+			sample(4005, false),
+		]
+		.into();
+
+		// alpha = r / sum(ln(max(x, Xm) / Xm)), with r the 6 completed
+		// builds and the 2 abandoned ones only in the sum
+		let sum = 2.0 * (2005.0f64 / 1005.0).ln() + 2.0 * (4005.0f64 / 1005.0).ln();
+		let (xm, alpha) = estimator.fit().unwrap();
+		assert_eq!(xm, 1005.0);
+		assert!((alpha - 6.0 / sum).abs() < 1e-9, "{}", alpha);
+
+		// all at Xm
+		estimator.samples = vec![sample(1005, true); 3].into();
+		assert_eq!(estimator.fit(), Some((1005.0, f64::INFINITY)));
+	}
+
+	#[test]
+	fn liveness() {
+		let start = Instant::now();
+		let mut estimator = TimeoutEstimator::default();
+		for time in pareto_times(1000.0, 2.0, 200) {
+			estimator.note_circ_built(time);
+		}
+		let learned = estimator.timeout();
+
+		// while the network is down, timeouts are ignored
+		estimator.note_network_live(at(start, 0));
+		for i in 0..50 {
+			let started = at(start, 1 + i * 20_000);
+			estimator.note_circ_abandoned(estimator.close_timeout(), started);
+		}
+		assert_eq!(estimator.timeout(), learned);
+		assert_eq!(estimator.samples.len(), 200);
+
+		// but not once we hear from it during the builds
+		let started = at(start, 2_000_000);
+		estimator.note_network_live(at(start, 2_000_100));
+		estimator.note_circ_abandoned(estimator.close_timeout(), started);
+		assert_eq!(estimator.samples.len(), 201);
+		assert!(estimator.timeout() > learned);
+	}
+
+	#[test]
+	fn network_change() {
+		let start = Instant::now();
+		let mut estimator = TimeoutEstimator::default();
+		for time in pareto_times(200.0, 3.0, 200) {
+			estimator.note_circ_built(time);
+		}
+		assert!(estimator.timeout() < Duration::from_secs(1));
+
+		for _ in 0..20 {
+			estimator.note_circ_built(Duration::from_millis(200));
+		}
+
+		// the network got much slower: after 19 of the last 20 circuits
+		// timed out, the history is dropped and learning starts again
+		let abandon = |estimator: &mut TimeoutEstimator, i: u64| {
+			estimator.note_network_live(at(start, i * 1000 + 500));
+			estimator.note_circ_abandoned(estimator.close_timeout(), at(start, i * 1000));
+		};
+		for i in 0..18 {
+			abandon(&mut estimator, i);
+		}
+		assert!(estimator.timeout() < Duration::from_secs(1));
+		estimator.note_circ_built(Duration::from_millis(200));
+		assert!(estimator.timeout() < Duration::from_secs(1));
+		abandon(&mut estimator, 18);
+		assert_eq!(estimator.timeout(), Duration::from_secs(60));
+		assert_eq!(estimator.build_times(), 0);
+	}
+
+	#[test]
+	fn persistence() {
+		let start = Instant::now();
+		let mut estimator = TimeoutEstimator::default();
+		for time in pareto_times(1000.0, 2.0, 300) {
+			estimator.note_circ_built(time);
+		}
+		estimator.note_network_live(at(start, 100));
+		for _ in 0..5 {
+			estimator.note_circ_abandoned(Duration::from_millis(20_003), start);
+		}
+		let bytes = ser::ser_vec(&estimator, ser::ProtocolVersion::local()).unwrap();
+		let loaded: TimeoutEstimator =
+			ser::deserialize(&mut &bytes[..], ser::ProtocolVersion::local()).unwrap();
+
+		// every build comes back in the middle of its bin
+		assert_eq!(loaded.build_times(), 300);
+		assert_eq!(loaded.samples.len(), 305);
+		let mut saved: Vec<(u32, bool)> = estimator
+			.samples
+			.iter()
+			.map(|s| (s.ms / BIN_WIDTH * BIN_WIDTH + BIN_WIDTH / 2, s.completed))
+			.collect();
+		let mut restored: Vec<(u32, bool)> =
+			loaded.samples.iter().map(|s| (s.ms, s.completed)).collect();
+		saved.sort_unstable();
+		restored.sort_unstable();
+		assert_eq!(saved, restored);
+		assert!(restored.contains(&(20_005, false)));
+		let diff = loaded.timeout().as_millis() as i64 - estimator.timeout().as_millis() as i64;
+		assert!(diff.abs() < 50, "{:?} {:?}", loaded.timeout(), estimator.timeout());
+
+		// huge counts are cut down to the size of the history
+		let mut bytes = vec![];
+		for n in &[2, 120, u32::MAX, u32::MAX, 50, 3, 0] {
+			bytes.extend_from_slice(&n.to_be_bytes());
+		}
+		let loaded: TimeoutEstimator =
+			ser::deserialize(&mut &bytes[..], ser::ProtocolVersion::local()).unwrap();
+		assert_eq!(loaded.samples.len(), NCIRCUITS_TO_OBSERVE);
+		assert_eq!(loaded.samples.iter().filter(|s| s.ms == 505).count(), 3);
+		assert!(loaded
+			.samples
+			.iter()
+			.take(6)
+			.all(|s| s.completed && (s.ms == 505 || s.ms == 1205)));
+	}
+
+	#[test]
+	fn params() {
+		let mut params = CbtParams {
+			disabled: true,
+			..Default::default()
+		};
+		let mut estimator = TimeoutEstimator::new(params.clone());
+		for time in pareto_times(1000.0, 2.0, 200) {
+			estimator.note_circ_built(time);
+		}
+		assert_eq!(estimator.timeout(), Duration::from_secs(60));
+
+		params.disabled = false;
+		params.min_circs = 10;
+		params.initial_timeout = Duration::from_secs(30);
+		estimator.set_params(params);
+		assert_eq!(estimator.timeout(), Duration::from_secs(30));
+		for time in pareto_times(1000.0, 2.0, 10) {
+			estimator.note_circ_built(time);
+		}
+		assert!(estimator.timeout() < Duration::from_secs(10));
+	}
+}
@@ -89,6 +89,10 @@ pub use handshake::{OutboundClientHandshake, UnverifiedChannel, VerifiedChannel}
 /// a cell-based communication mechanism.
 type CellFrame<T> = futures_codec::Framed<T, crate::channel::codec::ChannelCodec>;
 
+/// The reactor returned by [`VerifiedChannel::finish`] for a channel over
+/// a connection of type `T`.
+pub type ChannelReactor<T> = reactor::Reactor<futures::stream::SplitStream<CellFrame<T>>>;
+
 /// An open client channel, ready to send and receive Tor cells.
 ///
 /// A channel is a direct connection to a Tor relay, implemented using TLS.
@@ -55,10 +55,10 @@ use crate::crypto::cell::{
 	RelayCellBody,
 };
 use crate::crypto::handshake::{ClientHandshake, KeyGenerator};
-use crate::stream::{DataStream, RawCellStream};
-use crate::{Error, Result};
+use crate::stream::{DataStream, RawCellStream, ResolveStream};
+use crate::{Error, ResolveError, Result};
 use tor_cell::chancell::{self, msg::ChanMsg, ChanCell, CircId};
-use tor_cell::relaycell::msg::{RelayMsg, Sendme};
+use tor_cell::relaycell::msg::{RelayMsg, Resolve, Resolved, ResolvedVal, Sendme};
 use tor_cell::relaycell::{RelayCell, RelayCmd, StreamId};
 
 use tor_linkspec::{ChanTarget, CircTarget, LinkSpec};
@@ -69,9 +69,10 @@ use futures::channel::{mpsc, oneshot};
 use futures::lock::Mutex;
 use futures::sink::SinkExt;
 
+use std::net::IpAddr;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-// use std::time::Duration;
+use std::time::Duration;
 
 use rand::{thread_rng, CryptoRng, Rng};
 
@@ -574,7 +575,58 @@ impl ClientCirc {
 	pub async fn begin_dir_stream(self: Arc<Self>) -> Result<DataStream> {
 		self.begin_data_stream(RelayMsg::BeginDir).await
 	}
-	// XXXX Add a RESOLVE implementation, it will be simple.
+
+	/// Look up the addresses for `hostname` at the last relay in the
+	/// circuit, using a RESOLVE cell.
+	///
+	/// If the relay answers with an error, that is returned as
+	/// [`Error::ResolveFailed`].
+	pub async fn resolve(self: Arc<Self>, hostname: &str) -> Result<Vec<IpAddr>> {
+		let answers = self.resolve_with_ttl(hostname).await?;
+		Ok(answers.into_iter().map(|(addr, _)| addr).collect())
+	}
+
+	/// Look up the addresses for `hostname` as `resolve` does, along with
+	/// how long each of them may be cached for.
+	pub async fn resolve_with_ttl(
+		self: Arc<Self>,
+		hostname: &str,
+	) -> Result<Vec<(IpAddr, Duration)>> {
+		let resolved = self.resolve_impl(Resolve::new(hostname)).await?;
+		resolved_answers(resolved, |val| match val {
+			ResolvedVal::Ip(ip) => Some(ip),
+			_ => None,
+		})
+	}
+
+	/// Look up the hostnames for `addr` at the last relay in the circuit,
+	/// using a reverse RESOLVE cell.
+	///
+	/// Hostnames that aren't valid UTF-8 are left out.  If the relay
+	/// answers with an error, that is returned as [`Error::ResolveFailed`].
+	pub async fn resolve_ptr(self: Arc<Self>, addr: IpAddr) -> Result<Vec<String>> {
+		let answers = self.resolve_ptr_with_ttl(addr).await?;
+		Ok(answers.into_iter().map(|(name, _)| name).collect())
+	}
+
+	/// Look up the hostnames for `addr` as `resolve_ptr` does, along with
+	/// how long each of them may be cached for.
+	pub async fn resolve_ptr_with_ttl(
+		self: Arc<Self>,
+		addr: IpAddr,
+	) -> Result<Vec<(String, Duration)>> {
+		let resolved = self.resolve_impl(Resolve::new_reverse(&addr)).await?;
+		resolved_answers(resolved, |val| match val {
+			ResolvedVal::Hostname(name) => String::from_utf8(name).ok(),
+			_ => None,
+		})
+	}
+
+	/// Helper: send a RESOLVE cell and wait for the answer.
+	async fn resolve_impl(self: &Arc<Self>, resolve_msg: Resolve) -> Result<Resolved> {
+		let stream = self.begin_stream_impl(resolve_msg.into()).await?;
+		ResolveStream::new(stream).read_msg().await
+	}
 
 	/// Helper: Encode the relay cell `cell`, encrypt it, and send it to the
 	/// 'hop'th hop.
@@ -634,6 +686,38 @@ impl ClientCirc {
 	}
 }
 
+/// The longest we let an answer to a RESOLVE be cached for, whatever TTL
+/// the relay gave it: relays don't keep their own answers any longer.
+const MAX_RESOLVE_TTL: u32 = 60 * 60;
+
+/// Helper: take the answers `keep` accepts out of a RESOLVED message,
+/// along with how long each may be cached for.
+///
+/// A message whose only answers are errors gives back the error instead.
+fn resolved_answers<T, F>(resolved: Resolved, keep: F) -> Result<Vec<(T, Duration)>>
+where
+	F: Fn(ResolvedVal) -> Option<T>,
+{
+	let mut answers = Vec::new();
+	let mut error = None;
+	for (val, ttl) in resolved.into_answers() {
+		match val {
+			ResolvedVal::TransientError => error = Some(ResolveError::Transient),
+			ResolvedVal::NontransientError => error = Some(ResolveError::Nontransient),
+			val => {
+				if let Some(answer) = keep(val) {
+					let ttl = Duration::from_secs(ttl.min(MAX_RESOLVE_TTL).into());
+					answers.push((answer, ttl));
+				}
+			}
+		}
+	}
+	match error {
+		Some(e) if answers.is_empty() => Err(Error::ResolveFailed(e)),
+		_ => Ok(answers),
+	}
+}
+
 impl ClientCircImpl {
 	/// Return a mutable reference to the nth hop of this circuit, if one
 	/// exists.
@@ -1640,6 +1724,79 @@ mod test {
 		let (_stream, _, _) = futures::join!(begin_and_send_fut, reply_fut, reactor_fut);
 	}
 
+	#[async_test]
+	async fn resolve() {
+		let (chan, mut ch) = fake_channel();
+		let (circ, mut reactor, mut sink) = newcirc(chan).await;
+		let addr: IpAddr = "192.0.2.7".parse().unwrap();
+
+		let resolve_fut = async move {
+			let answers = circ.resolve_with_ttl("www.example.com").await.unwrap();
+			assert_eq!(answers, vec![(addr, Duration::from_secs(600))]);
+		};
+		let reply_fut = async move {
+			let (id, chmsg) = ch.cells.next().await.unwrap().into_circid_and_msg();
+			assert_eq!(id, 128.into());
+			let rmsg = match chmsg {
+				ChanMsg::Relay(r) => RelayCell::decode(r.into_relay_body()).unwrap(),
+				_ => panic!(),
+			};
+			let (streamid, rmsg) = rmsg.into_streamid_and_msg();
+			assert!(matches!(rmsg, RelayMsg::Resolve(_)));
+
+			let mut resolved = relaymsg::Resolved::new_empty();
+			resolved.add_answer(relaymsg::ResolvedVal::Ip(addr), 600);
+			sink.send(rmsg_to_ccmsg(streamid, resolved.into()))
+				.await
+				.unwrap();
+
+			sink // gotta keep the sink alive, or the reactor will exit.
+		};
+		let reactor_fut = async move {
+			reactor.run_once().await.unwrap(); // AddStream
+			reactor.run_once().await.unwrap(); // Register stream closer
+			reactor.run_once().await.unwrap(); // Resolved cell
+			reactor
+		};
+
+		let (_, _, _) = futures::join!(resolve_fut, reply_fut, reactor_fut);
+	}
+
+	#[test]
+	fn resolved_answers_ttls_and_errors() {
+		let v4: IpAddr = "192.0.2.7".parse().unwrap();
+		let v6: IpAddr = "2001:db8::7".parse().unwrap();
+		let mut resolved = relaymsg::Resolved::new_empty();
+		resolved.add_answer(ResolvedVal::Ip(v4), 600);
+		resolved.add_answer(ResolvedVal::Hostname(b"www.example.com".to_vec()), 600);
+		resolved.add_answer(ResolvedVal::Ip(v6), 86400);
+		let answers = resolved_answers(resolved, |val| match val {
+			ResolvedVal::Ip(ip) => Some(ip),
+			_ => None,
+		})
+		.unwrap();
+		// TTLs over an hour are cut down to one
+		assert_eq!(
+			answers,
+			vec![
+				(v4, Duration::from_secs(600)),
+				(v6, Duration::from_secs(3600))
+			]
+		);
+
+		let err = resolved_answers(relaymsg::Resolved::new_err(false, 0), Some).unwrap_err();
+		assert!(matches!(
+			err,
+			Error::ResolveFailed(ResolveError::Nontransient)
+		));
+		let err = resolved_answers(relaymsg::Resolved::new_err(true, 0), Some).unwrap_err();
+		assert!(matches!(err, Error::ResolveFailed(ResolveError::Transient)));
+
+		// no answers at all isn't an error
+		let answers = resolved_answers(relaymsg::Resolved::new_empty(), Some).unwrap();
+		assert!(answers.is_empty());
+	}
+
 	// Set up a circuit and stream that expects some incoming SENDMEs.
 	async fn setup_incoming_sendme_case(
 		n_to_send: usize,
@@ -115,7 +115,7 @@ mod crypto;
 pub mod stream;
 mod util;
 
-pub use util::err::Error;
+pub use util::err::{Error, ResolveError};
 
 /// A vector of bytes that gets cleared when it's dropped.
 type SecretBytes = zeroize::Zeroizing<Vec<u8>>;
@@ -15,7 +15,6 @@ impl ResolveStream {
 	/// Wrap a RawCellStream into a ResolveStream.
 	///
 	/// Call only after sending a RESOLVE cell.
-	#[allow(dead_code)] // need to implement a caller for this.
 	pub(crate) fn new(s: RawCellStream) -> Self {
 		ResolveStream { s }
 	}
@@ -79,6 +79,21 @@ pub enum Error {
 	/// Tried to configure an impossible value
 	#[error("bad configuration value: {0}")]
 	BadConfig(String),
+	/// The relay answered a RESOLVE with an error.
+	#[error("name lookup failed: {0}")]
+	ResolveFailed(ResolveError),
+}
+
+/// The kind of error a relay can give in answer to a RESOLVE.
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ResolveError {
+	/// The lookup failed, but might work if tried again.
+	#[error("transient error")]
+	Transient,
+	/// The lookup failed, and trying again won't help.
+	#[error("nontransient error")]
+	Nontransient,
 }
 
 impl From<tor_cell::Error> for Error {
@@ -110,6 +125,8 @@ impl From<Error> for std::io::Error {
 
 			NotConnected => ErrorKind::NotConnected,
 
+			ResolveFailed(ResolveError::Nontransient) => ErrorKind::NotFound,
+
 			EndReceived(end_reason) => end_reason.into(),
 
 			CircDestroy(_) | ChannelClosed | CircuitClosed => ErrorKind::ConnectionReset,
@@ -117,7 +134,9 @@ impl From<Error> for std::io::Error {
 			BytesErr(_) | MissingKey | BadCellAuth | BadHandshake | ChanProto(_) | CircProto(_)
 			| CellErr(_) | ChanMismatch(_) | StreamProto(_) => ErrorKind::InvalidData,
 
-			InternalError(_) | IdRangeFull | CircExtend(_) | BadConfig(_) => ErrorKind::Other,
+			InternalError(_) | IdRangeFull | CircExtend(_) | BadConfig(_) | ResolveFailed(_) => {
+				ErrorKind::Other
+			}
 		};
 		std::io::Error::new(kind, err)
 	}
@@ -0,0 +1,242 @@
+// Copyright 2021 The BMW Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::{Error, ErrorKind};
+use std::io::{self, Write};
+
+/// Value for the Accept-Encoding header of directory requests, listing
+/// the encodings we can decompress from most to least preferred.
+pub const ACCEPT_ENCODINGS: &str = "x-zstd, x-tor-lzma, deflate, identity";
+
+/// Content encodings used by directory servers
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+	Identity,
+	Deflate,
+	Zstd,
+	Lzma,
+}
+
+impl Encoding {
+	/// Parse the value of a Content-Encoding header. No header at all
+	/// means identity.
+	pub fn from_header(value: Option<&str>) -> Result<Encoding, Error> {
+		match value.map(|v| v.trim()) {
+			None | Some("") | Some("identity") => Ok(Encoding::Identity),
+			Some("deflate") => Ok(Encoding::Deflate),
+			Some("x-zstd") => Ok(Encoding::Zstd),
+			Some("x-tor-lzma") => Ok(Encoding::Lzma),
+			Some(other) => Err(ErrorKind::DecompressionError(format!(
+				"unsupported content encoding '{}'",
+				other
+			))
+			.into()),
+		}
+	}
+}
+
+/// Collects decompressed output and refuses to grow past a limit.
+struct LimitedBuf {
+	buf: Vec<u8>,
+	limit: usize,
+}
+
+impl Write for LimitedBuf {
+	fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+		if self.buf.len() + data.len() > self.limit {
+			return Err(io::Error::new(
+				io::ErrorKind::Other,
+				"document exceeds size limit",
+			));
+		}
+		self.buf.extend_from_slice(data);
+		Ok(data.len())
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		Ok(())
+	}
+}
+
+enum Decoder {
+	Identity(LimitedBuf),
+	Deflate(flate2::write::ZlibDecoder<LimitedBuf>),
+	Zstd(zstd::stream::zio::Writer<LimitedBuf, zstd::stream::raw::Decoder<'static>>),
+	Lzma(xz2::write::XzDecoder<LimitedBuf>),
+}
+
+/// Decompresses a document as it arrives. Both the compressed input and
+/// the decompressed output are limited to `limit` bytes, so a hostile
+/// server can't make us use unbounded memory.
+pub struct Decompressor {
+	decoder: Decoder,
+	received: usize,
+	limit: usize,
+}
+
+fn decompression_error(e: io::Error) -> Error {
+	ErrorKind::DecompressionError(e.to_string()).into()
+}
+
+impl Decompressor {
+	pub fn new(encoding: Encoding, limit: usize) -> Result<Decompressor, Error> {
+		let buf = LimitedBuf { buf: vec![], limit };
+		let decoder = match encoding {
+			Encoding::Identity => Decoder::Identity(buf),
+			Encoding::Deflate => Decoder::Deflate(flate2::write::ZlibDecoder::new(buf)),
+			Encoding::Zstd => {
+				let decoder = zstd::stream::raw::Decoder::new().map_err(decompression_error)?;
+				Decoder::Zstd(zstd::stream::zio::Writer::new(buf, decoder))
+			}
+			Encoding::Lzma => Decoder::Lzma(xz2::write::XzDecoder::new(buf)),
+		};
+		Ok(Decompressor {
+			decoder,
+			received: 0,
+			limit,
+		})
+	}
+
+	/// Feed the next chunk of the response body.
+	pub fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+		self.received += data.len();
+		if self.received > self.limit {
+			return Err(
+				ErrorKind::DecompressionError("response exceeds size limit".to_string()).into(),
+			);
+		}
+		let res = match &mut self.decoder {
+			Decoder::Identity(w) => w.write_all(data),
+			Decoder::Deflate(w) => w.write_all(data),
+			Decoder::Zstd(w) => w.write_all(data),
+			Decoder::Lzma(w) => w.write_all(data),
+		};
+		res.map_err(decompression_error)
+	}
+
+	/// Finish decompressing and return the whole document.
+	pub fn finish(self) -> Result<Vec<u8>, Error> {
+		let buf = match self.decoder {
+			Decoder::Identity(w) => w,
+			Decoder::Deflate(w) => w.finish().map_err(decompression_error)?,
+			// unlike flush, finish fails if the last frame is incomplete
+			Decoder::Zstd(mut w) => {
+				w.finish().map_err(decompression_error)?;
+				w.into_inner().0
+			}
+			Decoder::Lzma(mut w) => w.finish().map_err(decompression_error)?,
+		};
+		Ok(buf.buf)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	fn compress(encoding: Encoding, data: &[u8]) -> Vec<u8> {
+		match encoding {
+			Encoding::Identity => data.to_vec(),
+			Encoding::Deflate => {
+				let mut w = flate2::write::ZlibEncoder::new(vec![], flate2::Compression::default());
+				w.write_all(data).unwrap();
+				w.finish().unwrap()
+			}
+			Encoding::Zstd => zstd::stream::encode_all(data, 0).unwrap(),
+			Encoding::Lzma => {
+				let mut w = xz2::write::XzEncoder::new(vec![], 6);
+				w.write_all(data).unwrap();
+				w.finish().unwrap()
+			}
+		}
+	}
+
+	// feed the data in small chunks, like it arrives from the network
+	fn decompress(encoding: Encoding, data: &[u8], limit: usize) -> Result<Vec<u8>, Error> {
+		let mut decompressor = Decompressor::new(encoding, limit)?;
+		for chunk in data.chunks(1000) {
+			decompressor.write(chunk)?;
+		}
+		decompressor.finish()
+	}
+
+	const ENCODINGS: &[Encoding] = &[
+		Encoding::Identity,
+		Encoding::Deflate,
+		Encoding::Zstd,
+		Encoding::Lzma,
+	];
+
+	#[test]
+	fn round_trip() {
+		let data: Vec<u8> = (0..50_000u32)
+			.flat_map(|i| format!("r relay{} {}\n", i % 97, i).into_bytes())
+			.collect();
+		for encoding in ENCODINGS {
+			let compressed = compress(*encoding, &data);
+			assert_eq!(
+				decompress(*encoding, &compressed, data.len()).unwrap(),
+				data,
+				"{:?}",
+				encoding
+			);
+		}
+	}
+
+	#[test]
+	fn limit() {
+		// compresses very well, so only the output is over the limit
+		let data = vec![b'a'; 100_000];
+		for encoding in ENCODINGS {
+			let compressed = compress(*encoding, &data);
+			match decompress(*encoding, &compressed, 99_999) {
+				Err(e) => match e.kind() {
+					ErrorKind::DecompressionError(_) => {}
+					kind => panic!("{:?}: unexpected error {:?}", encoding, kind),
+				},
+				Ok(_) => panic!("{:?}: limit not enforced", encoding),
+			}
+		}
+	}
+
+	#[test]
+	fn truncated() {
+		let data: Vec<u8> = (0..10_000u32)
+			.flat_map(|i| format!("{}\n", i).into_bytes())
+			.collect();
+		for encoding in &[Encoding::Zstd, Encoding::Lzma] {
+			let compressed = compress(*encoding, &data);
+			let truncated = &compressed[..compressed.len() - 10];
+			assert!(
+				decompress(*encoding, truncated, data.len()).is_err(),
+				"{:?}",
+				encoding
+			);
+		}
+	}
+
+	#[test]
+	fn from_header() {
+		assert_eq!(Encoding::from_header(None).unwrap(), Encoding::Identity);
+		assert_eq!(
+			Encoding::from_header(Some(" x-zstd")).unwrap(),
+			Encoding::Zstd
+		);
+		assert_eq!(
+			Encoding::from_header(Some("x-tor-lzma")).unwrap(),
+			Encoding::Lzma
+		);
+		assert!(Encoding::from_header(Some("gzip")).is_err());
+	}
+}
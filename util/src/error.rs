@@ -79,6 +79,27 @@ pub enum ErrorKind {
 	SpawnError(String),
 	#[fail(display = "TcpConnectError: {}", _0)]
 	TcpConnectError(String),
+	/// Directory document parse error
+	#[fail(display = "NetDoc Error: {}", _0)]
+	NetDocError(String),
+	/// Consensus failed signature verification
+	#[fail(display = "Consensus Rejected: {}", _0)]
+	ConsensusRejected(String),
+	/// Circuit could not be built
+	#[fail(display = "Circuit Error: {}", _0)]
+	CircuitError(String),
+	/// Directory request failed
+	#[fail(display = "Directory Request Error: {}", _0)]
+	DirRequestError(String),
+	/// Could not decompress a document
+	#[fail(display = "Decompression Error: {}", _0)]
+	DecompressionError(String),
+	/// No directory source answered in time
+	#[fail(display = "Bootstrap Timeout: {}", _0)]
+	BootstrapTimeout(String),
+	/// A proxy client sent a request we can't serve
+	#[fail(display = "Proxy Error: {}", _0)]
+	ProxyError(String),
 }
 
 impl Display for Error {
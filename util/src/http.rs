@@ -11,10 +11,12 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::compress::{Decompressor, Encoding, ACCEPT_ENCODINGS};
 use crate::{Error, ErrorKind};
+use hyper::body::HttpBody;
 use hyper::client::HttpConnector;
-use hyper::header::{ACCEPT, CONTENT_TYPE, USER_AGENT};
-use hyper::{body, Body, Client, Request};
+use hyper::header::{ACCEPT_ENCODING, CONTENT_ENCODING, USER_AGENT};
+use hyper::{Body, Client, Request};
 use hyper_rustls::HttpsConnector;
 use hyper_timeout::TimeoutConnector;
 use lazy_static::lazy_static;
@@ -61,22 +63,46 @@ pub fn build_connector_context(
 	UrlContext { client }
 }
 
-/// asynchronously make the request
-pub async fn async_do_get(url: &str, context: &UrlContext) -> Result<String, Error> {
-	let builder = Request::builder();
-	let req = builder
+/// asynchronously make the request, with any extra headers given. The
+/// response may be compressed, and is decompressed as it arrives.
+/// Responses larger than `limit` bytes, compressed or not, are rejected,
+/// as are responses whose status isn't a success.
+pub async fn async_do_get(
+	url: &str,
+	context: &UrlContext,
+	headers: &[(&str, &str)],
+	limit: usize,
+) -> Result<String, Error> {
+	let mut builder = Request::builder()
 		.method("get")
 		.uri(url)
 		.header(USER_AGENT, "rust-tor-client")
-		.header(ACCEPT, "application/json")
-		.header(CONTENT_TYPE, "application/json")
-		.body(Body::empty())?;
+		.header(ACCEPT_ENCODING, ACCEPT_ENCODINGS);
+	for (name, value) in headers {
+		builder = builder.header(*name, *value);
+	}
+	let req = builder.body(Body::empty())?;
 
-	let resp = context.client.request(req).await?;
+	let mut resp = context.client.request(req).await?;
+	if !resp.status().is_success() {
+		return Err(
+			ErrorKind::RequestError(format!("{} returned '{}'", url, resp.status())).into(),
+		);
+	}
 
-	let raw = body::to_bytes(resp)
-		.await
-		.map_err(|e| ErrorKind::RequestError(format!("Cannot read response body: {}", e)))?;
+	let encoding = resp
+		.headers()
+		.get(CONTENT_ENCODING)
+		.map(|value| value.to_str())
+		.transpose()
+		.map_err(|e| ErrorKind::RequestError(format!("Invalid content encoding: {}", e)))?;
+	let mut decompressor = Decompressor::new(Encoding::from_header(encoding)?, limit)?;
+	while let Some(chunk) = resp.body_mut().data().await {
+		let chunk = chunk
+			.map_err(|e| ErrorKind::RequestError(format!("Cannot read response body: {}", e)))?;
+		decompressor.write(&chunk)?;
+	}
+	let raw = decompressor.finish()?;
 
 	Ok(String::from_utf8_lossy(&raw).to_string())
 }
@@ -85,6 +111,14 @@ pub async fn async_do_get(url: &str, context: &UrlContext) -> Result<String, Err
 /// to other threads due to the use of await.
 /// Note: only a single HTTP request at a time due to the global Mutex.
 /// This is ok for our uses, but should be fixed if more is needed from this module.
-pub fn do_get(url: &str, context: &UrlContext) -> Result<String, Error> {
-	RUNTIME.lock().unwrap().block_on(async_do_get(url, context))
+pub fn do_get(
+	url: &str,
+	context: &UrlContext,
+	headers: &[(&str, &str)],
+	limit: usize,
+) -> Result<String, Error> {
+	RUNTIME
+		.lock()
+		.unwrap()
+		.block_on(async_do_get(url, context, headers, limit))
 }
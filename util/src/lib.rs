@@ -16,6 +16,7 @@ pub use crate::error::{Error, ErrorKind};
 pub use grin_core as core;
 pub use grin_store as store;
 
+pub mod compress;
 mod error;
 pub mod http;
 pub mod logger;